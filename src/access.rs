@@ -0,0 +1,83 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::{debug, warn};
+
+use crate::{
+    state::AppConfig,
+    storage::{is_not_found, update_meta_unrecorded},
+    tenant::Tenant,
+};
+
+// How often buffered access counts are merged into the metadata files
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+struct PendingAccess {
+    count: u64,
+    last_accessed: SystemTime,
+}
+
+// Reads are counted in memory and written to the metadata store in batches,
+// so serving an image never waits on a metadata write
+#[derive(Debug, Clone, Default)]
+pub struct AccessTracker {
+    // keyed by (tenant id, image id)
+    pending: Arc<Mutex<HashMap<(String, String), PendingAccess>>>,
+}
+
+impl AccessTracker {
+    pub fn record(&self, tenant_id: &str, img_id: &str) {
+        let now = SystemTime::now();
+        let mut pending = self.pending.lock().unwrap();
+        let key = (tenant_id.to_string(), img_id.to_string());
+        let entry = pending.entry(key).or_insert(PendingAccess {
+            count: 0,
+            last_accessed: now,
+        });
+        entry.count += 1;
+        entry.last_accessed = now;
+    }
+
+    pub async fn flush(&self, conf: &AppConfig) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        for ((tenant_id, img_id), access) in pending {
+            let conf = Tenant {
+                id: tenant_id,
+                ..Default::default()
+            }
+            .scope(conf);
+
+            // one read-modify-write under the store's lock, a separate read
+            // first would race uploads and deletes
+            let res = update_meta_unrecorded(&conf, &img_id, |meta| {
+                meta.access_count += access.count;
+                meta.last_accessed = OffsetDateTime::from(access.last_accessed)
+                    .format(&Rfc3339)
+                    .ok();
+                Ok(())
+            })
+            .await;
+            match res {
+                Ok(_) => {}
+                // transform outputs have no metadata to record into
+                Err(e) if is_not_found(&e) => {
+                    debug!("not recording access to {}: {}", img_id, e);
+                }
+                Err(e) => warn!("failed to write access stats for {}: {}", img_id, e),
+            }
+        }
+    }
+}
+
+pub async fn run(conf: AppConfig, tracker: AccessTracker) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        tracker.flush(&conf).await;
+    }
+}
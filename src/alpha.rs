@@ -0,0 +1,123 @@
+use photon_rs::PhotonImage;
+use serde::Deserialize;
+
+// "#rrggbb" colour, e.g. the background of flattened images
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color(pub [u8; 3]);
+
+impl Color {
+    pub const WHITE: Color = Color([255, 255, 255]);
+}
+
+impl Default for Color {
+    fn default() -> Self {
+        Color::WHITE
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value.strip_prefix('#').unwrap_or(&value);
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+        };
+        match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Color([r, g, b])),
+            _ => Err(format!("{:?} is not a \"#rrggbb\" colour", value)),
+        }
+    }
+}
+
+// Whether any pixel is not fully opaque. Opaque images skip the alpha
+// handling below.
+pub fn has_transparency(img: &PhotonImage) -> bool {
+    img.get_raw_pixels().chunks_exact(4).any(|px| px[3] < 255)
+}
+
+// Scales the colour channels by alpha, so filters that mix neighbouring
+// pixels do not pull in the colour of transparent ones (dark halos around
+// the edges of cut-outs)
+pub fn premultiply(img: &PhotonImage) -> PhotonImage {
+    let mut pixels = img.get_raw_pixels();
+    for px in pixels.chunks_exact_mut(4) {
+        let a = px[3] as u32;
+        for c in &mut px[..3] {
+            *c = ((*c as u32 * a + 127) / 255) as u8;
+        }
+    }
+    PhotonImage::new(pixels, img.get_width(), img.get_height())
+}
+
+// Inverse of premultiply
+pub fn unpremultiply(img: &PhotonImage) -> PhotonImage {
+    let mut pixels = img.get_raw_pixels();
+    for px in pixels.chunks_exact_mut(4) {
+        let a = px[3] as u32;
+        for c in &mut px[..3] {
+            *c = match a {
+                0 => 0,
+                a => ((*c as u32 * 255 + a / 2) / a).min(255) as u8,
+            };
+        }
+    }
+    PhotonImage::new(pixels, img.get_width(), img.get_height())
+}
+
+// Copies the alpha channel of `from` onto `img`, both of the same size
+pub fn restore(img: &PhotonImage, from: &PhotonImage) -> PhotonImage {
+    let mut pixels = img.get_raw_pixels();
+    for (px, src) in pixels
+        .chunks_exact_mut(4)
+        .zip(from.get_raw_pixels().chunks_exact(4))
+    {
+        px[3] = src[3];
+    }
+    PhotonImage::new(pixels, img.get_width(), img.get_height())
+}
+
+// Draws the RGBA pixels of `top`, top_width wide, over those of an image
+// `width` wide with its top left corner at (x, y). Parts outside the image
+// are cut off.
+pub fn overlay(pixels: &mut [u8], width: u32, top: &[u8], top_width: u32, x: i64, y: i64) {
+    let (width, top_width) = (width as i64, top_width as i64);
+    let height = (pixels.len() / 4) as i64 / width.max(1);
+    let top_height = (top.len() / 4) as i64 / top_width.max(1);
+
+    for ty in (-y).max(0)..top_height.min(height - y) {
+        for tx in (-x).max(0)..top_width.min(width - x) {
+            let s = ((ty * top_width + tx) * 4) as usize;
+            let src = &top[s..s + 4];
+            if src[3] == 0 {
+                continue;
+            }
+            let d = (((y + ty) * width + x + tx) * 4) as usize;
+            let dst = &mut pixels[d..d + 4];
+
+            let a = src[3] as f32 / 255.0;
+            let below = dst[3] as f32 / 255.0 * (1.0 - a);
+            let alpha = a + below;
+            for (c, s) in dst[..3].iter_mut().zip(&src[..3]) {
+                *c = ((*s as f32 * a + *c as f32 * below) / alpha).round() as u8;
+            }
+            dst[3] = (alpha * 255.0).round() as u8;
+        }
+    }
+}
+
+// Composites the image onto an opaque background, for formats without an
+// alpha channel (transparent pixels would otherwise turn black)
+pub fn flatten(img: &PhotonImage, background: Color) -> PhotonImage {
+    let mut pixels = img.get_raw_pixels();
+    for px in pixels.chunks_exact_mut(4) {
+        let a = px[3] as u32;
+        for (c, bg) in px[..3].iter_mut().zip(background.0) {
+            *c = ((*c as u32 * a + bg as u32 * (255 - a) + 127) / 255) as u8;
+        }
+        px[3] = 255;
+    }
+    PhotonImage::new(pixels, img.get_width(), img.get_height())
+}
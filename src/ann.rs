@@ -0,0 +1,150 @@
+use std::collections::{HashMap, HashSet};
+
+// Random hyperplane LSH: every table hashes a vector to the side of BITS
+// hyperplanes it lies on, similar vectors mostly land in the same bucket
+const TABLES: usize = 8;
+const BITS: usize = 12;
+// fixed so the hyperplanes are the same after every restart
+const SEED: u64 = 0x6272_7573_6862_6c6f;
+
+// Approximate nearest neighbour index of unit vectors by cosine similarity.
+// Candidates come from the query's bucket and the buckets one bit away in
+// each table and are ranked exactly; small indexes are scanned in full.
+#[derive(Debug, Clone)]
+pub struct Index {
+    dims: usize,
+    planes: Vec<Vec<f32>>,
+    ids: Vec<String>,
+    vectors: Vec<Vec<f32>>,
+    positions: HashMap<String, usize>,
+    buckets: Vec<HashMap<u32, Vec<usize>>>,
+}
+
+impl Index {
+    pub fn new(dims: usize) -> Self {
+        let mut state = SEED;
+        let planes = (0..TABLES * BITS)
+            .map(|_| (0..dims).map(|_| uniform(&mut state)).collect())
+            .collect();
+        Self {
+            dims,
+            planes,
+            ids: Vec::new(),
+            vectors: Vec::new(),
+            positions: HashMap::new(),
+            buckets: vec![HashMap::new(); TABLES],
+        }
+    }
+
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    // Adds or replaces the vector of `id`, which must have `dims` entries
+    pub fn insert(&mut self, id: &str, vector: Vec<f32>) {
+        let vector = normalize(vector);
+        let hashes: Vec<u32> = (0..TABLES).map(|t| self.hash(t, &vector)).collect();
+
+        let pos = match self.positions.get(id) {
+            Some(&pos) => {
+                for (t, bucket) in self.buckets.iter_mut().enumerate() {
+                    let old = hash_with(&self.planes, t, &self.vectors[pos]);
+                    if let Some(entries) = bucket.get_mut(&old) {
+                        entries.retain(|p| *p != pos);
+                    }
+                }
+                self.vectors[pos] = vector;
+                pos
+            }
+            None => {
+                self.ids.push(id.to_string());
+                self.vectors.push(vector);
+                self.positions.insert(id.to_string(), self.ids.len() - 1);
+                self.ids.len() - 1
+            }
+        };
+        for (t, hash) in hashes.into_iter().enumerate() {
+            self.buckets[t].entry(hash).or_default().push(pos);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&[f32]> {
+        self.positions
+            .get(id)
+            .map(|&pos| self.vectors[pos].as_slice())
+    }
+
+    // Up to `limit` ids most similar to `query`, best first with their cosine
+    // similarity, leaving out `exclude`
+    pub fn search(&self, query: &[f32], limit: usize, exclude: Option<&str>) -> Vec<(String, f32)> {
+        let query = normalize(query.to_vec());
+        let mut candidates: HashSet<usize> = HashSet::new();
+        for t in 0..TABLES {
+            let hash = self.hash(t, &query);
+            let probes = std::iter::once(hash).chain((0..BITS).map(|bit| hash ^ (1 << bit)));
+            for probe in probes {
+                if let Some(entries) = self.buckets[t].get(&probe) {
+                    candidates.extend(entries);
+                }
+            }
+        }
+        // too few to fill the page, e.g. in a small or uneven index
+        if candidates.len() < limit * 2 {
+            candidates = (0..self.ids.len()).collect();
+        }
+
+        let mut hits: Vec<(usize, f32)> = candidates
+            .into_iter()
+            .filter(|&pos| exclude != Some(self.ids[pos].as_str()))
+            .map(|pos| (pos, dot(&query, &self.vectors[pos])))
+            .collect();
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+        hits.truncate(limit);
+        hits.into_iter()
+            .map(|(pos, score)| (self.ids[pos].clone(), score))
+            .collect()
+    }
+
+    fn hash(&self, table: usize, vector: &[f32]) -> u32 {
+        hash_with(&self.planes, table, vector)
+    }
+}
+
+fn hash_with(planes: &[Vec<f32>], table: usize, vector: &[f32]) -> u32 {
+    planes[table * BITS..(table + 1) * BITS]
+        .iter()
+        .enumerate()
+        .fold(0, |hash, (bit, plane)| {
+            hash | (((dot(plane, vector) >= 0.0) as u32) << bit)
+        })
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+pub fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = dot(&vector, &vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+// splitmix64, mapped to -1..1
+fn uniform(state: &mut u64) -> f32 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+}
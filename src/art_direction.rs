@@ -0,0 +1,75 @@
+use crate::{
+    handlers::{ImgMetadata, Region},
+    state::Breakpoint,
+};
+
+// Part of a width x height image shown at a breakpoint and the size it is
+// scaled to. The composition has the breakpoint's aspect ratio and covers its
+// region with the padding around it, or is the largest one around the focal
+// point for images without the region. A region that does not fit the aspect
+// ratio gets the largest composition centred on it.
+pub fn compose(width: u32, height: u32, bp: &Breakpoint, meta: &ImgMetadata) -> (Region, u32, u32) {
+    // regions are in pixels of the stored image, which may have been decoded
+    // at another size
+    let (scale_x, scale_y) = match (meta.width, meta.height) {
+        (Some(w), Some(h)) if w > 0 && h > 0 => (width as f64 / w as f64, height as f64 / h as f64),
+        _ => (1.0, 1.0),
+    };
+    let padding = bp.padding_pct as f64 / 100.0;
+    let region = bp
+        .region
+        .as_ref()
+        .and_then(|name| meta.regions.get(name))
+        .map(|r| {
+            let (w, h) = (r.width as f64 * scale_x, r.height as f64 * scale_y);
+            let (cx, cy) = (
+                (r.x as f64 + r.width as f64 / 2.0) * scale_x,
+                (r.y as f64 + r.height as f64 / 2.0) * scale_y,
+            );
+            (cx, cy, w * (1.0 + 2.0 * padding), h * (1.0 + 2.0 * padding))
+        });
+
+    let aspect = match (bp.height, region) {
+        (Some(h), _) => bp.width as f64 / h.max(1) as f64,
+        (None, Some((_, _, w, h))) => w / h,
+        (None, None) => width as f64 / height as f64,
+    };
+
+    // the largest window of the aspect ratio inside the image
+    let (max_w, max_h) = if width as f64 / height as f64 > aspect {
+        (height as f64 * aspect, height as f64)
+    } else {
+        (width as f64, width as f64 / aspect)
+    };
+    let (centre, (w, h)) = match region {
+        Some((cx, cy, rw, rh)) => {
+            let w = rw.max(rh * aspect).min(max_w);
+            ((cx, cy), (w, w / aspect))
+        }
+        None => {
+            let centre = meta
+                .focal_point()
+                .map(|(x, y)| (x as f64 * scale_x, y as f64 * scale_y))
+                .unwrap_or((width as f64 / 2.0, height as f64 / 2.0));
+            (centre, (max_w, max_h))
+        }
+    };
+
+    let w = (w.round() as u32).clamp(1, width);
+    let h = (h.round() as u32).clamp(1, height);
+    let place = |centre: f64, len: u32, window: u32| {
+        (centre - window as f64 / 2.0)
+            .round()
+            .clamp(0.0, (len - window) as f64) as u32
+    };
+    let crop = Region {
+        x: place(centre.0, width, w),
+        y: place(centre.1, height, h),
+        width: w,
+        height: h,
+    };
+    let out_height = bp
+        .height
+        .unwrap_or_else(|| ((bp.width as f64 / aspect).round() as u32).max(1));
+    (crop, bp.width, out_height)
+}
@@ -0,0 +1,99 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+use crate::state::AppConfig;
+
+// Next to the metadata of the tenant, one JSON event per line. The leading dot
+// keeps it out of listings.
+const AUDIT_LOG: &str = ".audit.log";
+
+// Administrative change to an image, e.g. a legal hold placed on it, or to
+// a recipe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    // RFC 3339
+    pub time: String,
+    // e.g. "hold_placed", "hold_released" or "recipe_updated"
+    pub action: String,
+    // empty for changes of recipes
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub img_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipe: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(action: &str, img_id: &str, reason: Option<String>) -> Self {
+        AuditEvent {
+            time: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            action: action.to_string(),
+            img_id: img_id.to_string(),
+            recipe: None,
+            reason,
+        }
+    }
+
+    pub fn for_recipe(action: &str, name: &str, reason: Option<String>) -> Self {
+        AuditEvent {
+            recipe: Some(name.to_string()),
+            ..AuditEvent::new(action, "", reason)
+        }
+    }
+}
+
+// Appends `event` to the audit log of the tenant. Events are only ever added,
+// the log is not rotated.
+pub async fn record(conf: &AppConfig, event: &AuditEvent) -> Result<()> {
+    // appends of concurrent requests must not interleave
+    static APPENDS: Mutex<()> = Mutex::const_new(());
+
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+
+    let _guard = APPENDS.lock().await;
+    tokio::fs::create_dir_all(&conf.meta_path).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(audit_path(conf))
+        .await?;
+    file.write_all(&line).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+// Events of the tenant, oldest first, optionally only those of `img_id` or
+// `recipe`
+pub async fn list(
+    conf: &AppConfig,
+    img_id: Option<&str>,
+    recipe: Option<&str>,
+) -> Result<Vec<AuditEvent>> {
+    let data = match tokio::fs::read_to_string(audit_path(conf)).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut events = Vec::new();
+    for line in data.lines().filter(|l| !l.is_empty()) {
+        let event: AuditEvent = serde_json::from_str(line)?;
+        if img_id.is_none_or(|id| id == event.img_id)
+            && recipe.is_none_or(|name| event.recipe.as_deref() == Some(name))
+        {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+fn audit_path(conf: &AppConfig) -> PathBuf {
+    Path::new(&conf.meta_path).join(AUDIT_LOG)
+}
@@ -0,0 +1,293 @@
+// Just enough of Unicode's bidirectional algorithm (UAX #9) and Arabic
+// shaping to draw a single line of mixed Arabic, Hebrew and Latin text with a
+// font renderer that only places glyphs left to right. Explicit embeddings,
+// isolates and the finer rules for numbers are not handled.
+
+const ALEF_MADDA: char = '\u{0622}';
+const ALEF_HAMZA_ABOVE: char = '\u{0623}';
+const ALEF_HAMZA_BELOW: char = '\u{0625}';
+const ALEF: char = '\u{0627}';
+const LAM: char = '\u{0644}';
+const TATWEEL: char = '\u{0640}';
+
+// Arabic letters with the first of their presentation forms (isolated, then
+// final, initial and medial) and whether they join on both sides. The others
+// only join to the letter before them and have no initial or medial form.
+const ARABIC_FORMS: &[(char, u32, bool)] = &[
+    ('\u{0621}', 0xFE80, false),
+    ('\u{0622}', 0xFE81, false),
+    ('\u{0623}', 0xFE83, false),
+    ('\u{0624}', 0xFE85, false),
+    ('\u{0625}', 0xFE87, false),
+    ('\u{0626}', 0xFE89, true),
+    ('\u{0627}', 0xFE8D, false),
+    ('\u{0628}', 0xFE8F, true),
+    ('\u{0629}', 0xFE93, false),
+    ('\u{062A}', 0xFE95, true),
+    ('\u{062B}', 0xFE99, true),
+    ('\u{062C}', 0xFE9D, true),
+    ('\u{062D}', 0xFEA1, true),
+    ('\u{062E}', 0xFEA5, true),
+    ('\u{062F}', 0xFEA9, false),
+    ('\u{0630}', 0xFEAB, false),
+    ('\u{0631}', 0xFEAD, false),
+    ('\u{0632}', 0xFEAF, false),
+    ('\u{0633}', 0xFEB1, true),
+    ('\u{0634}', 0xFEB5, true),
+    ('\u{0635}', 0xFEB9, true),
+    ('\u{0636}', 0xFEBD, true),
+    ('\u{0637}', 0xFEC1, true),
+    ('\u{0638}', 0xFEC5, true),
+    ('\u{0639}', 0xFEC9, true),
+    ('\u{063A}', 0xFECD, true),
+    ('\u{0641}', 0xFED1, true),
+    ('\u{0642}', 0xFED5, true),
+    ('\u{0643}', 0xFED9, true),
+    ('\u{0644}', 0xFEDD, true),
+    ('\u{0645}', 0xFEE1, true),
+    ('\u{0646}', 0xFEE5, true),
+    ('\u{0647}', 0xFEE9, true),
+    ('\u{0648}', 0xFEED, false),
+    ('\u{0649}', 0xFEEF, false),
+    ('\u{064A}', 0xFEF1, true),
+];
+
+// Lam followed by an alef is written as one ligature (isolated form, the
+// final one follows it)
+const LAM_ALEF: &[(char, u32)] = &[
+    (ALEF_MADDA, 0xFEF5),
+    (ALEF_HAMZA_ABOVE, 0xFEF7),
+    (ALEF_HAMZA_BELOW, 0xFEF9),
+    (ALEF, 0xFEFB),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Class {
+    // strong left to right: Latin, CJK, ...
+    L,
+    // strong right to left: Hebrew and Arabic
+    R,
+    // digits, European or Arabic-Indic, always read left to right but
+    // placed like right to left text next to it
+    Number,
+    // spaces and punctuation, they take the direction around them
+    Neutral,
+}
+
+fn class(c: char) -> Class {
+    match c as u32 {
+        _ if c.is_numeric() => Class::Number,
+        0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFF => Class::R,
+        _ if c.is_alphabetic() => Class::L,
+        _ => Class::Neutral,
+    }
+}
+
+// Harakat and other marks, skipped when looking for the letters a letter
+// joins to
+fn is_transparent(c: char) -> bool {
+    matches!(c, '\u{064B}'..='\u{065F}' | '\u{0670}')
+}
+
+fn forms(c: char) -> Option<(u32, bool)> {
+    ARABIC_FORMS
+        .iter()
+        .find(|(base, ..)| *base == c)
+        .map(|(_, first, dual)| (*first, *dual))
+}
+
+// Whether the letter connects to the one after it
+fn joins_next(c: char) -> bool {
+    c == TATWEEL || forms(c).is_some_and(|(_, dual)| dual)
+}
+
+// Whether the letter connects to the one before it
+fn joins_previous(c: char) -> bool {
+    c == TATWEEL || forms(c).is_some_and(|(first, _)| first != 0xFE80)
+}
+
+// Whether a paragraph is right to left, decided by its first strong
+// character (UAX #9 rules P2 and P3)
+pub fn is_rtl(text: &str) -> bool {
+    text.chars()
+        .map(class)
+        .find(|c| matches!(c, Class::L | Class::R))
+        .is_some_and(|c| c == Class::R)
+}
+
+// Replaces Arabic letters by the presentation form matching the letters
+// they join to, so that a font without shaping tables draws them connected
+pub fn shape_arabic(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let neighbour = |i: usize, step: isize| {
+        let mut j = i as isize + step;
+        while let Some(&c) = usize::try_from(j).ok().and_then(|j| chars.get(j)) {
+            if !is_transparent(c) {
+                return Some(c);
+            }
+            j += step;
+        }
+        None
+    };
+
+    let mut shaped = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let Some((first, dual)) = forms(c) else {
+            shaped.push(c);
+            i += 1;
+            continue;
+        };
+        let after_joining = neighbour(i, -1).is_some_and(joins_next);
+
+        if c == LAM
+            && let Some(&(_, ligature)) = chars
+                .get(i + 1)
+                .and_then(|next| LAM_ALEF.iter().find(|(alef, _)| alef == next))
+        {
+            shaped.extend(char::from_u32(ligature + after_joining as u32));
+            i += 2;
+            continue;
+        }
+
+        let before_joining = neighbour(i, 1).is_some_and(joins_previous);
+        let form = match (after_joining && first != 0xFE80, before_joining && dual) {
+            (false, false) => 0,
+            (true, false) => 1,
+            (false, true) => 2,
+            (true, true) => 3,
+        };
+        shaped.extend(char::from_u32(first + form));
+        i += 1;
+    }
+    shaped
+}
+
+// Characters of a line in the order they are drawn from left to right:
+// embedding levels resolved as in UAX #9 rules W, N and I, then right to left
+// runs reversed (rule L2) and their brackets mirrored (rule L4)
+pub fn reorder(text: &str, rtl: bool) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut classes: Vec<Class> = chars.iter().map(|c| class(*c)).collect();
+    let base = if rtl { Class::R } else { Class::L };
+
+    // neutrals between text of one direction take it, numbers count as
+    // right to left there (rule N1)
+    let direction = |class: &Class| match class {
+        Class::Neutral => None,
+        Class::Number => Some(Class::R),
+        class => Some(*class),
+    };
+
+    // both brackets of a pair get the direction of the text inside, or of
+    // the paragraph when it has both (rule N0)
+    for (open, close) in bracket_pairs(&chars) {
+        let inside: Vec<Class> = classes[open + 1..close]
+            .iter()
+            .filter_map(direction)
+            .collect();
+        if inside.is_empty() {
+            continue;
+        }
+        // text of the other direction only: that of the text before them
+        let resolved = match inside.contains(&base) {
+            true => base,
+            false => classes[..open]
+                .iter()
+                .rev()
+                .find_map(direction)
+                .unwrap_or(base),
+        };
+        classes[open] = resolved;
+        classes[close] = resolved;
+    }
+
+    let neutral = |i: usize| {
+        let before = classes[..i]
+            .iter()
+            .rev()
+            .find_map(direction)
+            .unwrap_or(base);
+        let after = classes[i + 1..].iter().find_map(direction).unwrap_or(base);
+        if before == after { before } else { base }
+    };
+    // numbers after right to left text are placed with it (rule W7)
+    let after_rtl = |i: usize| {
+        classes[..i]
+            .iter()
+            .rev()
+            .find(|c| matches!(c, Class::L | Class::R))
+            .map_or(rtl, |c| *c == Class::R)
+    };
+    let levels: Vec<u8> = (0..chars.len())
+        .map(|i| match classes[i] {
+            Class::R => 1,
+            Class::Number if rtl || after_rtl(i) => 2,
+            Class::Neutral if neutral(i) == Class::R => 1,
+            _ if rtl => 2,
+            _ => 0,
+        })
+        .collect();
+
+    let mut order: Vec<usize> = (0..chars.len()).collect();
+    let max_level = levels.iter().copied().max().unwrap_or(0);
+    for level in (1..=max_level).rev() {
+        let mut i = 0;
+        while i < order.len() {
+            if levels[order[i]] < level {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < order.len() && levels[order[i]] >= level {
+                i += 1;
+            }
+            order[start..i].reverse();
+        }
+    }
+
+    order
+        .into_iter()
+        .map(|i| match levels[i] % 2 {
+            1 => mirror(chars[i]),
+            _ => chars[i],
+        })
+        .collect()
+}
+
+// Positions of matching (), [] and {}, ordered by the opening bracket
+fn bracket_pairs(chars: &[char]) -> Vec<(usize, usize)> {
+    let mut open: Vec<(usize, char)> = Vec::new();
+    let mut pairs = Vec::new();
+    for (i, c) in chars.iter().enumerate() {
+        match c {
+            '(' | '[' | '{' => open.push((i, mirror(*c))),
+            ')' | ']' | '}' => {
+                if let Some(depth) = open.iter().rposition(|(_, closing)| closing == c) {
+                    pairs.push((open[depth].0, i));
+                    open.truncate(depth);
+                }
+            }
+            _ => {}
+        }
+    }
+    pairs.sort_unstable();
+    pairs
+}
+
+fn mirror(c: char) -> char {
+    match c {
+        '(' => ')',
+        ')' => '(',
+        '[' => ']',
+        ']' => '[',
+        '{' => '}',
+        '}' => '{',
+        '<' => '>',
+        '>' => '<',
+        '«' => '»',
+        '»' => '«',
+        c => c,
+    }
+}
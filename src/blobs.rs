@@ -0,0 +1,118 @@
+use anyhow::Result;
+use std::{
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+use crate::{spool, state::AppConfig};
+
+// Content addressed store of uploads with dedup_uploads, see
+// storage::store_staged_image. Every distinct file is kept once, as
+// blobs/{hash[..2]}/{hash} named after its SHA-256 (the content_hash of the
+// images), and the file of every image with that content is a hard link to
+// it. The link count of a blob is its reference count: one for the blob
+// itself and one for every image or kept version sharing it.
+const BLOB_DIR: &str = "blobs";
+
+pub fn blob_path(conf: &AppConfig, hash: &str) -> PathBuf {
+    let prefix = hash.get(..2).unwrap_or("00");
+    Path::new(&conf.file_path)
+        .join(BLOB_DIR)
+        .join(prefix)
+        .join(hash)
+}
+
+// Links `dest`, the file of a new image, to the blob of `hash`. Unless the
+// blob exists already `staged` becomes it, otherwise `staged` is removed.
+// Returns whether the content was stored before.
+pub async fn store(conf: &AppConfig, hash: &str, staged: &Path, dest: &Path) -> Result<bool> {
+    let blob = blob_path(conf, hash);
+    match tokio::fs::hard_link(&blob, dest).await {
+        Ok(()) => {
+            if let Err(e) = tokio::fs::remove_file(staged).await {
+                warn!("failed to remove staged upload {:?}: {}", staged, e);
+            }
+            return Ok(true);
+        }
+        // not stored before, or released in the meantime
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    if let Some(dir) = blob.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    spool::persist_blocking(staged, &blob).await?;
+    if let Err(e) = tokio::fs::hard_link(&blob, dest).await {
+        release(conf, hash).await?;
+        return Err(e.into());
+    }
+    Ok(false)
+}
+
+// Removes the blob of `hash` once nothing refers to it anymore, after the
+// file of an image with that content was deleted or replaced. Images stored
+// without dedup_uploads have no blob.
+pub async fn release(conf: &AppConfig, hash: &str) -> Result<()> {
+    let blob = blob_path(conf, hash);
+    let res = match tokio::fs::metadata(&blob).await {
+        Ok(m) if m.nlink() == 1 => tokio::fs::remove_file(&blob).await,
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    };
+    match res {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Blob {
+    pub hash: String,
+    // on disk, encrypted when encryption at rest is enabled
+    pub size: u64,
+    // images and kept versions sharing it
+    pub refs: u64,
+    // to tell which image files are links to it
+    pub ino: u64,
+}
+
+// Every blob of the tenant
+pub async fn list(conf: &AppConfig) -> Result<Vec<Blob>> {
+    let mut blobs = Vec::new();
+    let mut dirs = match tokio::fs::read_dir(Path::new(&conf.file_path).join(BLOB_DIR)).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(blobs),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(dir) = dirs.next_entry().await? {
+        if !dir.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut entries = tokio::fs::read_dir(dir.path()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(hash) = entry.file_name().to_str().map(|s| s.to_string()) else {
+                continue;
+            };
+            // blobs still being moved into place
+            if hash.starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            blobs.push(Blob {
+                hash,
+                size: metadata.len(),
+                refs: metadata.nlink().saturating_sub(1),
+                ino: metadata.ino(),
+            });
+        }
+    }
+    Ok(blobs)
+}
+
+// Inode of an image file, to compare with Blob::ino
+pub async fn inode(path: &Path) -> Option<u64> {
+    tokio::fs::metadata(path).await.ok().map(|m| m.ino())
+}
@@ -0,0 +1,162 @@
+use axum::{
+    body::Body,
+    http::{HeaderValue, Response, StatusCode, header},
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::{error::build_err_response, format::ImageFormat, state::MemoryBudgetConfig};
+
+const BYTES_PER_PIXEL: u64 = 4;
+// Decoder output, photon's RGBA copy and the transform result are alive at
+// the same time
+const DECODE_COPIES: u64 = 3;
+
+// Assumed decoded/encoded size ratio when the header cannot be parsed
+const UNKNOWN_EXPANSION: u64 = 16;
+
+// Estimated memory of decoding and transforming a width x height image
+pub fn decode_bytes(width: u32, height: u32) -> u64 {
+    width as u64 * height as u64 * BYTES_PER_PIXEL * DECODE_COPIES
+}
+
+// Estimate for encoded image data, from the dimensions in its header
+pub fn estimate(fmt: &ImageFormat, data: &[u8]) -> u64 {
+    match fmt.dimensions(data) {
+        Some((width, height)) => decode_bytes(width, height),
+        None => data.len() as u64 * UNKNOWN_EXPANSION,
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum BudgetError {
+    // needs more than the whole budget
+    #[error("Image needs about {needed_mb} MB to process, the memory budget is {budget_mb} MB")]
+    TooLarge { needed_mb: u64, budget_mb: u64 },
+    #[error("Too many image operations are waiting, retry later")]
+    QueueFull,
+    #[error("Timed out waiting for memory to process the image")]
+    Timeout,
+}
+
+impl BudgetError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            BudgetError::TooLarge { .. } | BudgetError::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            BudgetError::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let mut res = build_err_response(self.status(), self.to_string());
+        if let BudgetError::Timeout = self {
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("5"));
+        }
+        res
+    }
+}
+
+// Held while an operation keeps its decoded image in memory
+#[derive(Debug)]
+pub struct BudgetPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+// Global budget for the memory of decoded images. Operations wait until
+// enough of it is free, up to max_queued of them for queue_timeout_secs.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBudget {
+    inner: Option<Arc<BudgetInner>>,
+}
+
+#[derive(Debug)]
+struct BudgetInner {
+    // one permit per KiB
+    permits: Arc<Semaphore>,
+    budget_mb: u64,
+    max_queued: usize,
+    queued: AtomicUsize,
+    timeout: Duration,
+}
+
+impl MemoryBudget {
+    pub fn new(conf: Option<&MemoryBudgetConfig>) -> Self {
+        Self {
+            inner: conf.map(|c| {
+                Arc::new(BudgetInner {
+                    permits: Arc::new(Semaphore::new((c.max_mb * 1024) as usize)),
+                    budget_mb: c.max_mb,
+                    max_queued: c.max_queued,
+                    queued: AtomicUsize::new(0),
+                    timeout: Duration::from_secs(c.queue_timeout_secs),
+                })
+            }),
+        }
+    }
+
+    // Without waiting, None when that much is not free right now
+    pub fn try_acquire(&self, bytes: u64) -> Option<BudgetPermit> {
+        let Some(inner) = &self.inner else {
+            return Some(BudgetPermit { _permit: None });
+        };
+        let kib = bytes.div_ceil(1024).max(1);
+        let permit = inner
+            .permits
+            .clone()
+            .try_acquire_many_owned(u32::try_from(kib).ok()?)
+            .ok()?;
+        Some(BudgetPermit {
+            _permit: Some(permit),
+        })
+    }
+
+    pub async fn acquire(&self, bytes: u64) -> Result<BudgetPermit, BudgetError> {
+        let Some(inner) = &self.inner else {
+            return Ok(BudgetPermit { _permit: None });
+        };
+
+        let kib = bytes.div_ceil(1024).max(1);
+        if kib > inner.budget_mb * 1024 {
+            return Err(BudgetError::TooLarge {
+                needed_mb: kib.div_ceil(1024),
+                budget_mb: inner.budget_mb,
+            });
+        }
+
+        if let Ok(permit) = inner.permits.clone().try_acquire_many_owned(kib as u32) {
+            return Ok(BudgetPermit {
+                _permit: Some(permit),
+            });
+        }
+
+        if inner.queued.fetch_add(1, Ordering::SeqCst) >= inner.max_queued {
+            inner.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(BudgetError::QueueFull);
+        }
+        let res = tokio::time::timeout(
+            inner.timeout,
+            inner.permits.clone().acquire_many_owned(kib as u32),
+        )
+        .await;
+        inner.queued.fetch_sub(1, Ordering::SeqCst);
+
+        match res {
+            Ok(Ok(permit)) => Ok(BudgetPermit {
+                _permit: Some(permit),
+            }),
+            Ok(Err(_)) => Err(BudgetError::Timeout),
+            Err(_) => {
+                warn!("timed out waiting for {} KiB of the memory budget", kib);
+                Err(BudgetError::Timeout)
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use photon_rs::{
+    PhotonImage,
+    transform::{SamplingFilter, resize},
+};
+use serde::{Deserialize, Serialize};
+
+// dHash grid: each row compares HASH_SIZE + 1 neighbouring pixels
+const HASH_SIZE: u32 = 8;
+
+// What happens to the frames that lose to the sharpest one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateAction {
+    // tagged "duplicate", still listed and served
+    #[default]
+    Tag,
+    // no longer listed or served, the file is kept
+    SoftDelete,
+}
+
+// Tag of frames marked with DuplicateAction::Tag
+pub const DUPLICATE_TAG: &str = "duplicate";
+
+// One analysed frame of a burst
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub img_id: String,
+    pub score: f64,
+    pub sharpness: f64,
+    pub fingerprint: u64,
+}
+
+// Result of select: the frame to keep, the frames close enough to it to be
+// duplicates and the frames that show something else
+#[derive(Debug, Clone)]
+pub struct Selection<'a> {
+    pub keep: &'a Frame,
+    pub duplicates: Vec<(&'a Frame, u32)>,
+    pub distinct: Vec<(&'a Frame, u32)>,
+}
+
+// Difference hash of the image: its luma scaled down to a 9x8 grid, one bit
+// per pair of horizontal neighbours. Near identical frames differ in a few bits.
+pub fn fingerprint(img: &PhotonImage) -> u64 {
+    let small = resize(img, HASH_SIZE + 1, HASH_SIZE, SamplingFilter::Triangle);
+    let luma: Vec<u32> = small
+        .get_raw_pixels()
+        .chunks_exact(4)
+        .map(|p| 299 * p[0] as u32 + 587 * p[1] as u32 + 114 * p[2] as u32)
+        .collect();
+
+    let mut hash = 0u64;
+    for y in 0..HASH_SIZE as usize {
+        for x in 0..HASH_SIZE as usize {
+            let at = y * (HASH_SIZE as usize + 1) + x;
+            hash = (hash << 1) | (luma[at] > luma[at + 1]) as u64;
+        }
+    }
+    hash
+}
+
+// Bits in which two fingerprints differ, 0-64
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// Keeps the frame with the best quality score (ties go to the sharper one).
+// The other frames are duplicates when their fingerprint is within
+// `max_distance` of it. Returns None without frames.
+pub fn select(frames: &[Frame], max_distance: u32) -> Option<Selection<'_>> {
+    let keep = frames.iter().max_by(|a, b| {
+        a.score
+            .total_cmp(&b.score)
+            .then(a.sharpness.total_cmp(&b.sharpness))
+    })?;
+
+    let (duplicates, distinct): (Vec<_>, Vec<_>) = frames
+        .iter()
+        .filter(|f| f.img_id != keep.img_id)
+        .map(|f| (f, distance(f.fingerprint, keep.fingerprint)))
+        .partition(|(_, d)| *d <= max_distance);
+    Some(Selection {
+        keep,
+        duplicates,
+        distinct,
+    })
+}
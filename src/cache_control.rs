@@ -0,0 +1,45 @@
+use axum::{
+    body::Body,
+    http::{HeaderValue, Response, StatusCode, header},
+};
+use std::time::{Duration, SystemTime};
+
+use crate::state::AppConfig;
+
+// Which policy of [cache_control] an image response falls under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Served {
+    // the stored file as it is
+    Original,
+    // made from it for the request, or cached from an earlier one
+    Derived,
+}
+
+// Adds the configured Cache-Control, and Expires, to `resp`. Errors are left
+// alone, so a cache does not keep them for as long as the image.
+pub fn apply(conf: &AppConfig, served: Served, resp: &mut Response<Body>) {
+    let Some(cache) = &conf.cache_control else {
+        return;
+    };
+    let policy = match served {
+        Served::Original => &cache.originals,
+        Served::Derived => &cache.derived,
+    };
+    let Some(policy) = policy else {
+        return;
+    };
+    if !resp.status().is_success() && resp.status() != StatusCode::NOT_MODIFIED {
+        return;
+    }
+
+    // checked by config_check
+    if let Ok(value) = HeaderValue::from_str(&policy.value) {
+        resp.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    if let Some(secs) = policy.expires_secs {
+        let expires = SystemTime::now() + Duration::from_secs(secs);
+        if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(expires)) {
+            resp.headers_mut().insert(header::EXPIRES, value);
+        }
+    }
+}
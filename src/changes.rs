@@ -0,0 +1,156 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::state::AppConfig;
+
+// Next to the metadata of the tenant, one JSON change per line. Cursors are
+// byte offsets into it, so reading from one is a seek. It holds only ids and
+// is not encrypted.
+const CHANGES_LOG: &str = ".changes.log";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Change {
+    img_id: String,
+    kind: ChangeKind,
+}
+
+// A cursor not handed out by the server, or from before the log was reset
+#[derive(Debug, thiserror::Error)]
+#[error("invalid cursor {0}")]
+pub struct InvalidCursor(pub String);
+
+// Appends a change of the metadata of `img_id`, see GET /api/sync. The change
+// itself already happened, so failures are only logged.
+pub async fn record(conf: &AppConfig, kind: ChangeKind, img_id: &str) {
+    // appends of concurrent requests must not interleave
+    static APPENDS: Mutex<()> = Mutex::const_new(());
+
+    let res = async {
+        let mut line = serde_json::to_vec(&Change {
+            img_id: img_id.to_string(),
+            kind,
+        })?;
+        line.push(b'\n');
+
+        let _guard = APPENDS.lock().await;
+        tokio::fs::create_dir_all(&conf.meta_path).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(conf))
+            .await?;
+        file.write_all(&line).await?;
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = res {
+        warn!("failed to record the change of {}: {}", img_id, e);
+    }
+}
+
+// Cursor of the end of the log, where a client that has just listed every
+// image continues from
+pub async fn cursor(conf: &AppConfig) -> Result<u64> {
+    match tokio::fs::metadata(log_path(conf)).await {
+        Ok(v) => Ok(v.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Changes after `since`, at most `limit` of them read. They are compacted to
+// one per image in the order of their last change: an image created and
+// deleted again is left out, one created and updated is only created.
+// Returns the changes, the cursor after the last one read and whether there
+// are more.
+pub async fn since(
+    conf: &AppConfig,
+    since: u64,
+    limit: usize,
+) -> Result<(Vec<(String, ChangeKind)>, u64, bool)> {
+    let mut file = match tokio::fs::File::open(log_path(conf)).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && since == 0 => {
+            return Ok((Vec::new(), 0, false));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(InvalidCursor(since.to_string()).into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // a cursor is the end of a line
+    if since > file.metadata().await?.len() {
+        return Err(InvalidCursor(since.to_string()).into());
+    }
+    if since > 0 {
+        let mut prev = [0u8];
+        file.seek(SeekFrom::Start(since - 1)).await?;
+        file.read_exact(&mut prev).await?;
+        if prev[0] != b'\n' {
+            return Err(InvalidCursor(since.to_string()).into());
+        }
+    }
+    file.seek(SeekFrom::Start(since)).await?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).await?;
+
+    // first and last kind of each image, and the position of its last change
+    let mut seen: HashMap<String, (ChangeKind, ChangeKind, usize)> = HashMap::new();
+    let (mut read, mut consumed) = (0, 0);
+    // a line without its newline is still being written
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if read == limit || !line.ends_with(b"\n") {
+            break;
+        }
+        let change: Change = serde_json::from_slice(line)?;
+        seen.entry(change.img_id)
+            .and_modify(|(_, last, at)| (*last, *at) = (change.kind, read))
+            .or_insert((change.kind, change.kind, read));
+        read += 1;
+        consumed += line.len();
+    }
+
+    let mut changes: Vec<(String, ChangeKind, usize)> = seen
+        .into_iter()
+        .filter_map(|(img_id, (first, last, at))| match (first, last) {
+            (ChangeKind::Created, ChangeKind::Deleted) => None,
+            (ChangeKind::Created, _) => Some((img_id, ChangeKind::Created, at)),
+            (_, last) => Some((img_id, last, at)),
+        })
+        .collect();
+    changes.sort_by_key(|(_, _, at)| *at);
+
+    let cursor = since + consumed as u64;
+    let more = consumed < data.len();
+    Ok((
+        changes
+            .into_iter()
+            .map(|(img_id, kind, _)| (img_id, kind))
+            .collect(),
+        cursor,
+        more,
+    ))
+}
+
+fn log_path(conf: &AppConfig) -> PathBuf {
+    Path::new(&conf.meta_path).join(CHANGES_LOG)
+}
@@ -0,0 +1,32 @@
+use clap::{Parser, Subcommand};
+
+use crate::state::CONFIG_ENV;
+
+// Command line of the server, see AppConfig::new for how the config is put
+// together
+#[derive(Debug, Parser)]
+#[command(
+    name = "brushbloom",
+    version,
+    about = "Image storage and processing server"
+)]
+pub struct Cli {
+    /// Config file, config.toml when it exists
+    #[arg(long, short, global = true, env = CONFIG_ENV)]
+    pub config: Option<String>,
+    /// Overrides a config key, e.g. --set limits.body_mb=50, applied after
+    /// the file and BRUSHBLOOM_* environment variables
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    pub overrides: Vec<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Reports problems of the config and exits, with status 1 if it has any
+    CheckConfig {
+        /// Config file, instead of --config
+        path: Option<String>,
+    },
+}
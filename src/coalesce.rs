@@ -0,0 +1,46 @@
+use axum::body::Bytes;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+
+use crate::error::AppError;
+
+// Result of a coalesced render: the encoded image, or the error every waiter
+// gets
+pub type Rendered = Result<Bytes, AppError>;
+
+// Runs identical concurrent renders once. The first request for a key does
+// the work, requests for the same key arriving before it finishes wait for
+// it and get a copy of the result. When the first request goes away (the
+// client disconnected), one of the waiters takes over.
+#[derive(Debug, Clone, Default)]
+pub struct Coalescer {
+    inflight: Arc<Mutex<HashMap<String, Arc<OnceCell<Rendered>>>>>,
+}
+
+impl Coalescer {
+    pub async fn run<F, Fut>(&self, key: String, render: F) -> Rendered
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Rendered>,
+    {
+        let cell = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let rendered = cell.get_or_init(render).await.clone();
+
+        // the next request after this one renders again (or finds a cache)
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.get(&key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            inflight.remove(&key);
+        }
+        rendered
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{Result, anyhow};
+use photon_rs::PhotonImage;
+use serde::Deserialize;
+use std::borrow::Cow;
+
+use crate::resize::{self, Resizer};
+
+// Width in pixels of the line drawn between the two halves of a wipe
+const DIVIDER_WIDTH: u32 = 2;
+const DIVIDER_COLOR: [u8; 4] = [255, 255, 255, 255];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompareMode {
+    // before and after next to each other, after scaled to the same height
+    #[default]
+    SideBySide,
+    // before left of the split and after right of it, after scaled to the
+    // size of before
+    Wipe,
+}
+
+// Composes a before/after image. `split` is the fraction of the width at
+// which a wipe switches from before to after.
+pub fn compose(
+    before: &PhotonImage,
+    after: &PhotonImage,
+    mode: CompareMode,
+    split: f32,
+    resizer: Resizer,
+) -> Result<PhotonImage> {
+    if !(0.0..=1.0).contains(&split) {
+        return Err(anyhow!("split must be between 0 and 1"));
+    }
+
+    match mode {
+        CompareMode::SideBySide => Ok(side_by_side(before, after, resizer)),
+        CompareMode::Wipe => Ok(wipe(before, after, split, resizer)),
+    }
+}
+
+fn side_by_side(before: &PhotonImage, after: &PhotonImage, resizer: Resizer) -> PhotonImage {
+    let height = before.get_height();
+    let after_width = ((after.get_width() as u64 * height as u64)
+        .div_ceil(after.get_height() as u64) as u32)
+        .max(1);
+    let after = fit(after, after_width, height, resizer);
+
+    let (before_px, after_px) = (before.get_raw_pixels(), after.get_raw_pixels());
+    let (before_row, after_row) = (before.get_width() as usize * 4, after_width as usize * 4);
+
+    let mut pixels = Vec::with_capacity((before_row + after_row) * height as usize);
+    for row in 0..height as usize {
+        pixels.extend_from_slice(&before_px[row * before_row..(row + 1) * before_row]);
+        pixels.extend_from_slice(&after_px[row * after_row..(row + 1) * after_row]);
+    }
+
+    PhotonImage::new(pixels, before.get_width() + after_width, height)
+}
+
+fn wipe(before: &PhotonImage, after: &PhotonImage, split: f32, resizer: Resizer) -> PhotonImage {
+    let (width, height) = (before.get_width(), before.get_height());
+    let after = fit(after, width, height, resizer);
+    let split_x = (split * width as f32).round() as u32;
+    // no divider when one of the halves is empty
+    let divider = if split_x == 0 || split_x == width {
+        0..0
+    } else {
+        split_x.saturating_sub(DIVIDER_WIDTH / 2)..(split_x + DIVIDER_WIDTH.div_ceil(2)).min(width)
+    };
+
+    let mut pixels = before.get_raw_pixels();
+    let after_px = after.get_raw_pixels();
+    for row in 0..height as usize {
+        for col in split_x..width {
+            let i = (row * width as usize + col as usize) * 4;
+            pixels[i..i + 4].copy_from_slice(&after_px[i..i + 4]);
+        }
+        for col in divider.clone() {
+            let i = (row * width as usize + col as usize) * 4;
+            pixels[i..i + 4].copy_from_slice(&DIVIDER_COLOR);
+        }
+    }
+
+    PhotonImage::new(pixels, width, height)
+}
+
+fn fit(img: &PhotonImage, width: u32, height: u32, resizer: Resizer) -> Cow<'_, PhotonImage> {
+    if img.get_width() == width && img.get_height() == height {
+        return Cow::Borrowed(img);
+    }
+    Cow::Owned(resize::lanczos3(img, width, height, resizer))
+}
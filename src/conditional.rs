@@ -0,0 +1,68 @@
+use axum::http::{HeaderMap, HeaderValue, header};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// ETag and Last-Modified of a stored file, which caches revalidate with
+#[derive(Debug, Clone)]
+pub struct Validators {
+    // quoted, absent for images stored before their hash was recorded
+    etag: Option<String>,
+    last_modified: SystemTime,
+}
+
+impl Validators {
+    // `hash` is the content_hash of the image
+    pub fn new(hash: Option<&str>, last_modified: SystemTime) -> Self {
+        Validators {
+            etag: hash.map(|h| format!("\"{}\"", h)),
+            last_modified,
+        }
+    }
+
+    // Whether a GET with `headers` can be answered with 304. If-None-Match
+    // takes precedence, If-Modified-Since is only looked at without it (RFC
+    // 9110, section 13.2.2).
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+            let Ok(value) = value.to_str() else {
+                return false;
+            };
+            // weak comparison, W/"x" matches "x"
+            return value.split(',').map(str::trim).any(|tag| {
+                tag == "*"
+                    || self
+                        .etag
+                        .as_deref()
+                        .is_some_and(|etag| tag.trim_start_matches("W/") == etag)
+            });
+        }
+
+        let Some(since) = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok())
+        else {
+            return false;
+        };
+        // HTTP dates have whole seconds
+        secs(self.last_modified) <= secs(since)
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = self
+            .etag
+            .as_deref()
+            .and_then(|v| HeaderValue::from_str(v).ok())
+        {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Ok(date) = HeaderValue::from_str(&httpdate::fmt_http_date(self.last_modified)) {
+            headers.insert(header::LAST_MODIFIED, date);
+        }
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,844 @@
+use axum::http::HeaderValue;
+use lettre::message::Mailbox;
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    crypt,
+    format::{self, ImageFormat},
+    logging, recipe,
+    report::ErrorReporter,
+    state::{AfterIngest, AppConfig, LogRotation, NotificationTarget, UploadPolicy},
+    text::FontStack,
+    tls,
+};
+
+// Checks the whole config and returns every problem found, each prefixed
+// with the key it concerns. An empty list means the config is usable.
+pub fn check(conf: &AppConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if conf.max_file_size == 0 {
+        problems.push("max_file_size: must be at least 1 (MegaBytes)".to_string());
+    }
+    if conf.max_inpaint_pixels == 0 {
+        problems.push("max_inpaint_pixels: must be at least 1".to_string());
+    }
+    check_creatable_dir(&mut problems, "file_path", &conf.file_path);
+    check_creatable_dir(&mut problems, "meta_path", &conf.meta_path);
+    if same_path(&conf.file_path, &conf.meta_path) {
+        problems.push("meta_path: must not be the same directory as file_path".to_string());
+    }
+    if let Some(url) = &conf.public_url {
+        check_url(&mut problems, "public_url", url);
+    }
+
+    if let Some(watch) = &conf.watch {
+        if !Path::new(&watch.dir).is_dir() {
+            problems.push(format!(
+                "watch.dir: {:?} does not exist or is not a directory",
+                watch.dir
+            ));
+        }
+        if watch.interval_secs == 0 {
+            problems.push("watch.interval_secs: must be at least 1".to_string());
+        }
+        if watch.after_ingest == AfterIngest::Delete && watch.processed_dir.is_some() {
+            problems.push(
+                "watch.processed_dir: only used with after_ingest = \"move\", remove it or switch to move"
+                    .to_string(),
+            );
+        }
+        if let Some(name) = &watch.recipe {
+            check_recipe_name(&mut problems, "watch.recipe", name);
+        }
+    }
+
+    if let Some(email) = &conf.email {
+        if email.listen.parse::<SocketAddr>().is_err() {
+            problems.push(format!(
+                "email.listen: {:?} is not an \"ip:port\" address",
+                email.listen
+            ));
+        }
+        if !email.mailbox.contains('@') {
+            problems.push(format!(
+                "email.mailbox: {:?} is not an email address",
+                email.mailbox
+            ));
+        }
+        if email.max_message_size == 0 {
+            problems.push("email.max_message_size: must be at least 1 (MegaBytes)".to_string());
+        }
+        if email.allowed_senders.is_empty() && email.secret.is_none() {
+            problems.push(
+                "email.allowed_senders / email.secret: set at least one, otherwise anyone can store images"
+                    .to_string(),
+            );
+        }
+        for sender in &email.allowed_senders {
+            if !sender.contains('@') {
+                problems.push(format!(
+                    "email.allowed_senders: {:?} is not an email address or \"@domain\"",
+                    sender
+                ));
+            }
+        }
+        if let Some(secret) = &email.secret
+            && (secret.is_empty()
+                || !secret
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        {
+            problems
+                .push("email.secret: must be non-empty letters, digits, '-' and '_'".to_string());
+        }
+        if let Some(name) = &email.recipe {
+            check_recipe_name(&mut problems, "email.recipe", name);
+        }
+        if email.reply_relay.is_some() != email.reply_from.is_some() {
+            problems.push(
+                "email.reply_relay / email.reply_from: replies need both to be set".to_string(),
+            );
+        }
+        if let Some(url) = &email.public_url {
+            check_url(&mut problems, "email.public_url", url);
+        }
+    }
+
+    if let Some(s3) = &conf.s3 {
+        let valid_bucket = (1..=63).contains(&s3.bucket.len())
+            && s3
+                .bucket
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.');
+        if !valid_bucket {
+            problems.push(format!(
+                "s3.bucket: {:?} must be 1-63 lowercase letters, digits, '-' or '.'",
+                s3.bucket
+            ));
+        }
+        if ["api", "dav"].contains(&s3.bucket.as_str()) {
+            problems.push(format!(
+                "s3.bucket: {:?} collides with the built-in /{} routes",
+                s3.bucket, s3.bucket
+            ));
+        }
+        if s3.access_key.is_empty() || s3.secret_key.is_empty() {
+            problems.push("s3.access_key / s3.secret_key: must not be empty".to_string());
+        }
+    }
+
+    // they store into the default namespace, outside any tenant's quota and
+    // keys, which no tenant could reach anyway
+    if !conf.tenants.is_empty() {
+        for (key, enabled) in [
+            ("s3", conf.s3.is_some()),
+            ("watch", conf.watch.is_some()),
+            ("email", conf.email.is_some()),
+        ] {
+            if enabled {
+                problems.push(format!(
+                    "{}: not available once [[tenants]] are configured, remove one of them",
+                    key
+                ));
+            }
+        }
+    }
+
+    let mut tenant_ids = HashSet::new();
+    let mut api_keys = HashSet::new();
+    for tenant in &conf.tenants {
+        if !is_safe_name(&tenant.id) {
+            problems.push(format!(
+                "tenants.id: {:?} may only contain ASCII letters, digits, '-' and '_'",
+                tenant.id
+            ));
+        }
+        if !tenant_ids.insert(&tenant.id) {
+            problems.push(format!("tenants.id: {:?} is listed twice", tenant.id));
+        }
+        if tenant.api_key.is_empty() {
+            problems.push(format!("tenants.api_key: empty for tenant {:?}", tenant.id));
+        } else if !api_keys.insert(&tenant.api_key) {
+            problems.push(format!(
+                "tenants.api_key: tenant {:?} reuses another tenant's key",
+                tenant.id
+            ));
+        }
+        if tenant.quota_mb == Some(0) {
+            problems.push(format!(
+                "tenants.quota_mb: 0 for tenant {:?} would reject every upload",
+                tenant.id
+            ));
+        }
+    }
+    if let Some(admin) = &conf.admin {
+        if admin.api_key.is_empty() {
+            problems.push("admin.api_key: must not be empty".to_string());
+        } else if api_keys.contains(&admin.api_key) {
+            problems
+                .push("admin.api_key: must differ from the api_key of every tenant".to_string());
+        }
+    }
+    for tenant in &conf.tenants {
+        for target in &tenant.link_to {
+            if !conf.tenants.iter().any(|t| &t.id == target) {
+                problems.push(format!(
+                    "tenants.link_to: tenant {:?} links to unknown tenant {:?}",
+                    tenant.id, target
+                ));
+            }
+        }
+    }
+
+    for (name, policy) in &conf.collection_policies {
+        check_upload_policy(
+            &mut problems,
+            conf,
+            &format!("collection_policies.{}", name),
+            policy,
+        );
+    }
+    for tenant in &conf.tenants {
+        if let Some(policy) = &tenant.upload_policy {
+            check_upload_policy(
+                &mut problems,
+                conf,
+                &format!("tenants.upload_policy of tenant {:?}", tenant.id),
+                policy,
+            );
+        }
+    }
+
+    if let Some(compression) = &conf.compression
+        && !compression.gzip
+        && !compression.br
+    {
+        problems.push(
+            "compression: gzip and br are both disabled, enable one or remove the section"
+                .to_string(),
+        );
+    }
+
+    if let Some(deltas) = &conf.version_deltas
+        && deltas.snapshot_every < 2
+    {
+        problems.push("version_deltas.snapshot_every: must be at least 2".to_string());
+    }
+
+    if let Some(cache) = &conf.cache_control {
+        for (name, policy) in [("originals", &cache.originals), ("derived", &cache.derived)] {
+            if let Some(policy) = policy
+                && (policy.value.trim().is_empty() || HeaderValue::from_str(&policy.value).is_err())
+            {
+                problems.push(format!(
+                    "cache_control.{}.value: {:?} is not a valid header value",
+                    name, policy.value
+                ));
+            }
+        }
+    }
+
+    if let Some(log) = &conf.logging {
+        if let Err(e) = logging::parse_level(&log.level) {
+            problems.push(format!("logging.level: {}", e));
+        }
+        for (module, level) in &log.modules {
+            if let Err(e) = logging::parse_level(level) {
+                problems.push(format!("logging.modules.{}: {}", module, e));
+            }
+        }
+        if let Some(file) = &log.file {
+            check_creatable_dir(&mut problems, "logging.file.dir", &file.dir);
+            if file.rotation == LogRotation::Size && file.max_size_mb == 0 {
+                problems.push("logging.file.max_size_mb: must be at least 1".to_string());
+            }
+            if file.max_files == Some(0) {
+                problems.push("logging.file.max_files: must be at least 1".to_string());
+            }
+        }
+    }
+
+    if let Some(reporting) = &conf.error_reporting {
+        if reporting.sentry_dsn.is_none() && reporting.webhook_url.is_none() {
+            problems.push(
+                "error_reporting: set sentry_dsn and/or webhook_url, or remove the section"
+                    .to_string(),
+            );
+        }
+        if let Some(url) = &reporting.webhook_url {
+            check_url(&mut problems, "error_reporting.webhook_url", url);
+        }
+        if let Err(e) = ErrorReporter::new(reporting) {
+            problems.push(format!("error_reporting.sentry_dsn: {}", e));
+        }
+    }
+
+    if let Some(notifications) = &conf.notifications {
+        if notifications.channels.is_empty() {
+            problems.push(
+                "notifications.channels: add at least one channel, or remove the section"
+                    .to_string(),
+            );
+        }
+        for (i, channel) in notifications.channels.iter().enumerate() {
+            let key = format!("notifications.channels[{}]", i);
+            match &channel.target {
+                NotificationTarget::Slack { webhook_url } => {
+                    check_url(&mut problems, &format!("{}.webhook_url", key), webhook_url);
+                }
+                NotificationTarget::Webhook { url } => {
+                    check_url(&mut problems, &format!("{}.url", key), url);
+                }
+                NotificationTarget::Email { relay, from, to } => {
+                    if relay
+                        .rsplit_once(':')
+                        .is_some_and(|(_, port)| port.parse::<u16>().is_err())
+                    {
+                        problems.push(format!("{}.relay: {:?} is not host:port", key, relay));
+                    }
+                    if to.is_empty() {
+                        problems.push(format!("{}.to: must list at least one address", key));
+                    }
+                    for addr in std::iter::once(from).chain(to) {
+                        if addr.parse::<Mailbox>().is_err() {
+                            problems.push(format!("{}: {:?} is not an email address", key, addr));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(limits) = &conf.limits {
+        if limits.max_connections == Some(0) {
+            problems.push("limits.max_connections: must be at least 1".to_string());
+        }
+        if limits.requests_per_sec == Some(0) {
+            problems.push("limits.requests_per_sec: must be at least 1".to_string());
+        }
+        if limits.max_header_bytes < 1024 {
+            problems.push("limits.max_header_bytes: must be at least 1024".to_string());
+        }
+        if limits.header_timeout_secs == 0 {
+            problems.push("limits.header_timeout_secs: must be at least 1".to_string());
+        }
+        for (route, mb) in &limits.body_mb {
+            if !route.starts_with('/') {
+                problems.push(format!(
+                    "limits.body_mb: {:?} is not a route, e.g. \"/api/images/upload\"",
+                    route
+                ));
+            }
+            if *mb == 0 {
+                problems.push(format!("limits.body_mb.{:?}: must be at least 1", route));
+            }
+        }
+        for (name, rate) in [("per_key", &limits.per_key), ("per_ip", &limits.per_ip)] {
+            let Some(rate) = rate else { continue };
+            if rate.requests_per_sec == 0 {
+                problems.push(format!(
+                    "limits.{}.requests_per_sec: must be at least 1",
+                    name
+                ));
+            }
+            if rate.burst == Some(0) {
+                problems.push(format!("limits.{}.burst: must be at least 1", name));
+            }
+        }
+    }
+
+    if let Some(budget) = &conf.memory_budget {
+        if budget.max_mb == 0 {
+            problems.push("memory_budget.max_mb: must be at least 1".to_string());
+        }
+        if budget.max_mb > (u32::MAX / 1024) as u64 {
+            problems.push(format!(
+                "memory_budget.max_mb: must be at most {}",
+                u32::MAX / 1024
+            ));
+        }
+        if budget.queue_timeout_secs == 0 {
+            problems.push("memory_budget.queue_timeout_secs: must be at least 1".to_string());
+        }
+    }
+
+    if let Some(cache) = &conf.decode_cache {
+        if cache.max_mb == 0 {
+            problems.push("decode_cache.max_mb: must be at least 1".to_string());
+        }
+        if cache.ttl_secs == 0 {
+            problems.push("decode_cache.ttl_secs: must be at least 1".to_string());
+        }
+        if let Some(budget) = &conf.memory_budget
+            && cache.max_mb >= budget.max_mb
+        {
+            problems.push(
+                "decode_cache.max_mb: must be below memory_budget.max_mb, transforms need room too"
+                    .to_string(),
+            );
+        }
+    }
+
+    for (key, names) in [
+        ("formats.input", &conf.formats.input),
+        ("formats.output", &conf.formats.output),
+    ] {
+        let Some(names) = names else {
+            continue;
+        };
+        if names.is_empty() {
+            problems.push(format!("{}: must list at least one format", key));
+        }
+        for name in names {
+            let fmt = ImageFormat::from_name(name);
+            if fmt == ImageFormat::Unknown {
+                problems.push(format!("{}: unknown format {:?}", key, name));
+            } else if key == "formats.output" && !fmt.is_decodable() {
+                problems.push(format!("{}: {:?} images cannot be encoded", key, name));
+            }
+        }
+    }
+
+    for (name, profile) in &conf.export_profiles {
+        let key = format!("export_profiles.{}", name);
+        if !is_safe_name(name) {
+            problems.push(format!(
+                "{}: name may only contain letters, digits, '-' and '_'",
+                key
+            ));
+        }
+        if profile.outputs.is_empty() {
+            problems.push(format!("{}.outputs: must list at least one file", key));
+        }
+        let mut names = HashSet::new();
+        for out in &profile.outputs {
+            if out.name.starts_with('/')
+                || out
+                    .name
+                    .split('/')
+                    .any(|s| s.is_empty() || s == "." || s == "..")
+            {
+                problems.push(format!(
+                    "{}.outputs: {:?} is not a relative path",
+                    key, out.name
+                ));
+            } else if !names.insert(out.name.as_str()) {
+                problems.push(format!("{}.outputs: {:?} is listed twice", key, out.name));
+            }
+            let fmt = out.format();
+            if !fmt.is_decodable() {
+                problems.push(format!(
+                    "{}.outputs: {:?} does not end in the extension of a format that can be encoded",
+                    key, out.name
+                ));
+            } else if !format::produces(conf, fmt) {
+                problems.push(format!(
+                    "{}.outputs: {:?} is in a format not listed in formats.output",
+                    key, out.name
+                ));
+            }
+            let (width, height) = out.size();
+            if width == 0 || height == 0 {
+                problems.push(format!(
+                    "{}.outputs: {:?} must be at least 1x1",
+                    key, out.name
+                ));
+            }
+        }
+    }
+
+    for (name, preset) in &conf.thumbnail_presets {
+        let key = format!("thumbnail_presets.{}", name);
+        if !is_safe_name(name) {
+            problems.push(format!(
+                "{}: name may only contain letters, digits, '-' and '_'",
+                key
+            ));
+        }
+        if !(16..=1024).contains(&preset.size) {
+            problems.push(format!("{}.size: must be between 16 and 1024", key));
+        }
+    }
+
+    for (name, set) in &conf.art_direction {
+        let key = format!("art_direction.{}", name);
+        if !is_safe_name(name) {
+            problems.push(format!(
+                "{}: name may only contain letters, digits, '-' and '_'",
+                key
+            ));
+        }
+        if set.breakpoints.is_empty() {
+            problems.push(format!("{}.breakpoints: must list at least one", key));
+        }
+        let mut names = HashSet::new();
+        for bp in &set.breakpoints {
+            if bp.name.trim().is_empty() {
+                problems.push(format!("{}.breakpoints: name must not be empty", key));
+            } else if !names.insert(bp.name.as_str()) {
+                problems.push(format!(
+                    "{}.breakpoints: {:?} is listed twice",
+                    key, bp.name
+                ));
+            }
+            if bp.width == 0 || bp.height == Some(0) {
+                problems.push(format!(
+                    "{}.breakpoints: {:?} must be at least 1x1",
+                    key, bp.name
+                ));
+            }
+        }
+    }
+
+    if let Some(print) = &conf.print {
+        match std::fs::read(&print.icc_profile) {
+            Ok(data) => match lcms2::Profile::new_icc(&data) {
+                Ok(profile) if profile.color_space() == lcms2::ColorSpaceSignature::CmykData => {}
+                Ok(_) => problems.push(format!(
+                    "print.icc_profile: {:?} is not a CMYK profile",
+                    print.icc_profile
+                )),
+                Err(e) => problems.push(format!(
+                    "print.icc_profile: {:?} is not an ICC profile: {}",
+                    print.icc_profile, e
+                )),
+            },
+            Err(e) => problems.push(format!(
+                "print.icc_profile: cannot read {:?}: {}",
+                print.icc_profile, e
+            )),
+        }
+    }
+
+    if let Some(tagging) = &conf.tagging {
+        match (&tagging.url, &tagging.model_path) {
+            (Some(_), Some(_)) => {
+                problems.push("tagging: set either url or model_path, not both".to_string())
+            }
+            (None, None) => problems.push("tagging: url or model_path is required".to_string()),
+            (None, Some(model_path)) => {
+                if !cfg!(feature = "onnx") {
+                    problems.push(
+                        "tagging.model_path: this build does not include the onnx feature"
+                            .to_string(),
+                    );
+                }
+                if !Path::new(model_path).is_file() {
+                    problems.push(format!(
+                        "tagging.model_path: {:?} does not exist",
+                        model_path
+                    ));
+                }
+                match &tagging.labels_path {
+                    Some(labels_path) if !Path::new(labels_path).is_file() => problems.push(
+                        format!("tagging.labels_path: {:?} does not exist", labels_path),
+                    ),
+                    Some(_) => {}
+                    None => {
+                        problems.push("tagging.labels_path: required with model_path".to_string())
+                    }
+                }
+            }
+            (Some(_), None) => {}
+        }
+        if !(0.0..=1.0).contains(&tagging.min_confidence) {
+            problems.push("tagging.min_confidence: must be between 0 and 1".to_string());
+        }
+        if tagging.input_size < 32 {
+            problems.push("tagging.input_size: must be at least 32".to_string());
+        }
+        if tagging.timeout_secs == 0 {
+            problems.push("tagging.timeout_secs: must be at least 1".to_string());
+        }
+    }
+
+    if let Some(embeddings) = &conf.embeddings {
+        if !cfg!(feature = "embeddings") {
+            problems
+                .push("embeddings: this build does not include the embeddings feature".to_string());
+        }
+        for (key, path) in [
+            ("image_model", &embeddings.image_model),
+            ("text_model", &embeddings.text_model),
+            ("tokenizer_path", &embeddings.tokenizer_path),
+        ] {
+            if !Path::new(path).is_file() {
+                problems.push(format!("embeddings.{}: {:?} does not exist", key, path));
+            }
+        }
+        if embeddings.dimensions == 0 {
+            problems.push("embeddings.dimensions: must be at least 1".to_string());
+        }
+        if embeddings.input_size < 32 {
+            problems.push("embeddings.input_size: must be at least 32".to_string());
+        }
+    }
+
+    if let Some(public_reads) = &conf.public_reads {
+        if public_reads.max_edge == 0 {
+            problems.push("public_reads.max_edge: must be at least 1".to_string());
+        }
+        if let Err(e) = public_reads.watermark.validate() {
+            problems.push(format!("public_reads.watermark: {}", e));
+        }
+    }
+
+    if let Some(public_ids) = &conf.public_ids
+        && public_ids.secret.len() < 16
+    {
+        problems.push("public_ids.secret: must be at least 16 characters".to_string());
+    }
+    if let Some(signed) = &conf.signed_urls {
+        if signed.secret.len() < 16 {
+            problems.push("signed_urls.secret: must be at least 16 characters".to_string());
+        }
+        if signed.max_ttl_secs == 0 {
+            problems.push("signed_urls.max_ttl_secs: must be at least 1".to_string());
+        }
+        if signed.default_ttl_secs == 0 || signed.default_ttl_secs > signed.max_ttl_secs {
+            problems.push(
+                "signed_urls.default_ttl_secs: must be between 1 and max_ttl_secs".to_string(),
+            );
+        }
+    }
+
+    if conf.decode.timeout_secs == 0 {
+        problems.push("decode.timeout_secs: must be at least 1".to_string());
+    }
+    if conf.decode.workers == 0 {
+        problems.push("decode.workers: must be at least 1".to_string());
+    }
+    if conf.jobs.workers == 0 {
+        problems.push("jobs.workers: must be at least 1".to_string());
+    }
+    if conf
+        .jobs
+        .async_above_megapixels
+        .is_some_and(|mp| !mp.is_finite() || mp <= 0.0)
+    {
+        problems.push("jobs.async_above_megapixels: must be above 0".to_string());
+    }
+
+    if let Some(cache) = &conf.raster_cache {
+        check_creatable_dir(&mut problems, "raster_cache.dir", &cache.dir);
+        if cache.min_megapixels == 0 {
+            problems.push("raster_cache.min_megapixels: must be at least 1".to_string());
+        }
+    }
+
+    if let Some(spool) = &conf.spool {
+        check_creatable_dir(&mut problems, "spool.dir", &spool.dir);
+        if spool.max_age_secs == 0 {
+            problems.push("spool.max_age_secs: must be at least 1".to_string());
+        }
+    }
+
+    if let Some(disk) = &conf.disk_space {
+        if disk.min_free_mb == 0 {
+            problems.push("disk_space.min_free_mb: must be at least 1".to_string());
+        }
+        if disk.check_interval_secs == 0 {
+            problems.push("disk_space.check_interval_secs: must be at least 1".to_string());
+        }
+    }
+
+    if let Some(proxy) = &conf.proxy {
+        check_creatable_dir(&mut problems, "proxy.cache_dir", &proxy.cache_dir);
+        if proxy.allowed_hosts.is_empty() {
+            problems.push("proxy.allowed_hosts: must list at least one host".to_string());
+        }
+        for host in &proxy.allowed_hosts {
+            if host.is_empty()
+                || host.contains(['/', ':'])
+                || host.trim_start_matches("*.").contains('*')
+            {
+                problems.push(format!(
+                    "proxy.allowed_hosts: {:?} is not a host name or \"*.domain\" pattern",
+                    host
+                ));
+            }
+        }
+    }
+
+    if let Some(encryption) = &conf.encryption {
+        if let Err(e) = crypt::Keyring::from_config(encryption) {
+            problems.push(format!("encryption.keys: {}", e));
+        }
+        let mut ids = HashSet::new();
+        for key in &encryption.keys {
+            if !ids.insert(&key.id) {
+                problems.push(format!("encryption.keys: id {:?} is used twice", key.id));
+            }
+        }
+        if conf.raster_cache.is_some() {
+            problems.push(
+                "raster_cache: not used with encryption, it would store decrypted pixels, remove it"
+                    .to_string(),
+            );
+        }
+    }
+
+    if let Some(text) = &conf.text
+        && let Err(e) = FontStack::load(text)
+    {
+        problems.push(format!("text.fonts: {}", e));
+    }
+
+    let egress = &conf.egress;
+    if egress.allowed_schemes.is_empty() {
+        problems.push("egress.allowed_schemes: must list at least one scheme".to_string());
+    }
+    for scheme in &egress.allowed_schemes {
+        if scheme != "http" && scheme != "https" {
+            problems.push(format!(
+                "egress.allowed_schemes: {:?} is not supported, use \"http\" or \"https\"",
+                scheme
+            ));
+        }
+    }
+    if egress.allowed_ports.is_empty() {
+        problems.push("egress.allowed_ports: must list at least one port".to_string());
+    }
+    if egress.max_response_mb == 0 {
+        problems.push("egress.max_response_mb: must be at least 1".to_string());
+    }
+    if egress.timeout_secs == 0 {
+        problems.push("egress.timeout_secs: must be at least 1".to_string());
+    }
+
+    if let Some(tls_conf) = &conf.tls {
+        if tls_conf.listen.parse::<SocketAddr>().is_err() {
+            problems.push(format!(
+                "tls.listen: {:?} is not an \"ip:port\" address",
+                tls_conf.listen
+            ));
+        }
+        if let Err(e) = tls::server_config(tls_conf, &[]) {
+            problems.push(format!("tls.cert_path / tls.key_path: {}", e));
+        }
+        if let Some(listen) = &tls_conf.http3_listen {
+            if listen.parse::<SocketAddr>().is_err() {
+                problems.push(format!(
+                    "tls.http3_listen: {:?} is not an \"ip:port\" address",
+                    listen
+                ));
+            }
+            if !cfg!(feature = "http3") {
+                problems.push(
+                    "tls.http3_listen: this build does not include the http3 feature".to_string(),
+                );
+            }
+        }
+    }
+
+    problems
+}
+
+// Human readable report of `check`'s result
+pub fn report(problems: &[String]) -> String {
+    if problems.is_empty() {
+        return "configuration OK".to_string();
+    }
+
+    let mut out = format!("configuration has {} problem(s):", problems.len());
+    for p in problems {
+        out.push_str("\n  - ");
+        out.push_str(p);
+    }
+    out
+}
+
+// Tenant ids and the names of export profiles, thumbnail presets and art
+// direction sets end up in paths and routes, so they are limited to ASCII
+// letters, digits, '-' and '_'
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Directories that are created with all their parents when missing
+fn check_creatable_dir(problems: &mut Vec<String>, key: &str, dir: &str) {
+    let existing = Path::new(dir)
+        .ancestors()
+        .find(|p| p.exists() || p.as_os_str().is_empty())
+        .map(|p| {
+            if p.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                p.to_path_buf()
+            }
+        });
+
+    match existing {
+        Some(p) => check_writable(problems, key, &p),
+        None => problems.push(format!("{}: {:?} cannot be created", key, dir)),
+    }
+}
+
+fn check_writable(problems: &mut Vec<String>, key: &str, dir: &Path) {
+    if !dir.is_dir() {
+        problems.push(format!("{}: {:?} is not a directory", key, dir));
+        return;
+    }
+    if let Err(e) = tempfile::tempfile_in(dir) {
+        problems.push(format!("{}: {:?} is not writable: {}", key, dir, e));
+    }
+}
+
+fn same_path(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
+    a.as_ref().components().eq(b.as_ref().components())
+}
+
+fn check_url(problems: &mut Vec<String>, key: &str, url: &str) {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        problems.push(format!(
+            "{}: {:?} must start with http:// or https://",
+            key, url
+        ));
+    }
+}
+
+fn check_upload_policy(
+    problems: &mut Vec<String>,
+    conf: &AppConfig,
+    key: &str,
+    policy: &UploadPolicy,
+) {
+    if let Some(name) = &policy.recipe {
+        check_recipe_name(problems, &format!("{}.recipe", key), name);
+    }
+    if policy.max_dimension == Some(0) {
+        problems.push(format!("{}.max_dimension: must be at least 1", key));
+    }
+    if let Some(name) = &policy.convert_to {
+        let fmt = ImageFormat::from_name(name);
+        if !format::produces(conf, fmt) {
+            problems.push(format!(
+                "{}.convert_to: {:?} is not a format listed in formats.output that can be encoded",
+                key, name
+            ));
+        } else if !format::accepts(conf, fmt) {
+            problems.push(format!(
+                "{}.convert_to: {:?} is not listed in formats.input, converted uploads would be refused",
+                key, name
+            ));
+        }
+    }
+}
+
+// The recipe itself is only looked up when used, it may be created later
+fn check_recipe_name(problems: &mut Vec<String>, key: &str, name: &str) {
+    if !recipe::is_valid_name(name) {
+        problems.push(format!(
+            "{}: {:?} is not a recipe name, those are 1 to 64 of a-z, 0-9, - and _",
+            key, name
+        ));
+    }
+}
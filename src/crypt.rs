@@ -0,0 +1,259 @@
+use anyhow::{Result, anyhow};
+use ring::{
+    aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    hkdf::{HKDF_SHA256, Salt},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::state::{AppConfig, EncryptionConfig, EncryptionKey};
+
+// Encrypted files are
+//   MAGIC | key id length (u8) | key id | salt | chunk...
+// where every chunk is up to CHUNK_LEN bytes of the plaintext sealed with
+// AES-256-GCM, under a key derived from the configured one and the random
+// salt of the file. A chunk's nonce is its index, and its associated data the
+// header and whether it is the last chunk, so chunks can neither be reordered
+// nor cut off. Files without the magic are plaintext, written before
+// encryption was enabled.
+const MAGIC: &[u8] = b"BBENC1";
+const SALT_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+const KEY_INFO: &[u8] = b"brushbloom file key";
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+pub struct Keyring {
+    // the first key encrypts
+    keys: Vec<(String, [u8; 32])>,
+}
+
+impl Keyring {
+    pub fn from_config(conf: &EncryptionConfig) -> Result<Self> {
+        if conf.keys.is_empty() {
+            return Err(anyhow!("encryption needs at least one key"));
+        }
+
+        let keys = conf
+            .keys
+            .iter()
+            .map(|key| Ok((key.id.clone(), load_key(key)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Keyring { keys })
+    }
+
+    pub fn current_id(&self) -> &str {
+        &self.keys[0].0
+    }
+
+    pub fn sealer(&self) -> Result<Sealer> {
+        let (id, key) = &self.keys[0];
+        let mut salt = [0; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| anyhow!("failed to generate a salt"))?;
+
+        let mut header = MAGIC.to_vec();
+        header.push(id.len() as u8);
+        header.extend_from_slice(id.as_bytes());
+        header.extend_from_slice(&salt);
+
+        Ok(Sealer {
+            key: file_key(key, &salt)?,
+            header,
+            index: 0,
+            buf: Vec::new(),
+            started: false,
+        })
+    }
+
+    pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut sealer = self.sealer()?;
+        let mut sealed = sealer.update(data)?;
+        sealed.extend(sealer.finish()?);
+        Ok(sealed)
+    }
+
+    // Decrypts sealed data, plaintext is returned as it is
+    pub fn open(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(id) = key_id(&data) else {
+            return Ok(data);
+        };
+        let key = self
+            .keys
+            .iter()
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, key)| key)
+            .ok_or_else(|| anyhow!("no key {:?} to decrypt with", id))?;
+
+        let header_len = MAGIC.len() + 1 + id.len() + SALT_LEN;
+        let (header, body) = data
+            .split_at_checked(header_len)
+            .ok_or_else(|| anyhow!("truncated encryption header"))?;
+        let key = file_key(key, &header[header_len - SALT_LEN..])?;
+        if body.is_empty() {
+            return Err(anyhow!("encrypted data has no chunks"));
+        }
+
+        let chunks = body.chunks(CHUNK_LEN + TAG_LEN);
+        let last = chunks.len() - 1;
+        let mut plain = Vec::with_capacity(body.len());
+        for (index, chunk) in chunks.enumerate() {
+            let mut chunk = chunk.to_vec();
+            let opened = key
+                .open_in_place(
+                    nonce(index as u32),
+                    Aad::from(aad(header, index == last)),
+                    &mut chunk,
+                )
+                .map_err(|_| anyhow!("failed to decrypt chunk {}", index))?;
+            plain.extend_from_slice(opened);
+        }
+        Ok(plain)
+    }
+}
+
+// Encrypts data handed to it in pieces, e.g. an upload as it arrives
+pub struct Sealer {
+    key: LessSafeKey,
+    header: Vec<u8>,
+    index: u32,
+    buf: Vec<u8>,
+    started: bool,
+}
+
+impl Sealer {
+    // Encrypted bytes to append to the output, full chunks are sealed as soon
+    // as a byte of the next one arrives
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        if !self.started {
+            out.extend_from_slice(&self.header);
+            self.started = true;
+        }
+
+        self.buf.extend_from_slice(data);
+        while self.buf.len() > CHUNK_LEN {
+            let rest = self.buf.split_off(CHUNK_LEN);
+            let chunk = std::mem::replace(&mut self.buf, rest);
+            out.extend(self.seal_chunk(chunk, false)?);
+        }
+        Ok(out)
+    }
+
+    // The rest of the output, the last (possibly empty) chunk
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let mut out = self.update(&[])?;
+        let chunk = std::mem::take(&mut self.buf);
+        out.extend(self.seal_chunk(chunk, true)?);
+        Ok(out)
+    }
+
+    fn seal_chunk(&mut self, mut chunk: Vec<u8>, last: bool) -> Result<Vec<u8>> {
+        self.key
+            .seal_in_place_append_tag(
+                nonce(self.index),
+                Aad::from(aad(&self.header, last)),
+                &mut chunk,
+            )
+            .map_err(|_| anyhow!("failed to encrypt chunk {}", self.index))?;
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("too much data for one encrypted file"))?;
+        Ok(chunk)
+    }
+}
+
+// Keyring of the config, None when encryption is disabled
+pub fn keyring(conf: &AppConfig) -> Result<Option<Keyring>> {
+    conf.encryption
+        .as_ref()
+        .map(Keyring::from_config)
+        .transpose()
+}
+
+// Whether `prefix`, the start of a file, belongs to an encrypted one
+pub fn is_sealed(prefix: &[u8]) -> bool {
+    prefix.starts_with(MAGIC)
+}
+
+// Id of the key `data` was encrypted with, None for plaintext
+pub fn key_id(data: &[u8]) -> Option<&str> {
+    let rest = data.strip_prefix(MAGIC)?;
+    let (&len, rest) = rest.split_first()?;
+    std::str::from_utf8(rest.get(..len as usize)?).ok()
+}
+
+// Encrypts with the current key, unchanged when encryption is disabled
+pub fn seal(conf: &AppConfig, data: Vec<u8>) -> Result<Vec<u8>> {
+    match keyring(conf)? {
+        Some(keyring) => keyring.seal(&data),
+        None => Ok(data),
+    }
+}
+
+// Decrypts data written by `seal`, plaintext is returned as it is
+pub fn open(conf: &AppConfig, data: Vec<u8>) -> Result<Vec<u8>> {
+    if key_id(&data).is_none() {
+        return Ok(data);
+    }
+    match keyring(conf)? {
+        Some(keyring) => keyring.open(data),
+        None => Err(anyhow!(
+            "data is encrypted but no [encryption] keys are configured"
+        )),
+    }
+}
+
+fn load_key(key: &EncryptionKey) -> Result<[u8; 32]> {
+    if key.id.is_empty() || key.id.len() > u8::MAX as usize {
+        return Err(anyhow!("key id must be 1 to 255 bytes long"));
+    }
+
+    let hex = match (&key.key, &key.key_env) {
+        (Some(hex), None) => hex.clone(),
+        (None, Some(var)) => std::env::var(var)
+            .map_err(|_| anyhow!("key {}: environment variable {} is not set", key.id, var))?,
+        _ => {
+            return Err(anyhow!(
+                "key {} needs exactly one of key and key_env",
+                key.id
+            ));
+        }
+    };
+
+    decode_hex(hex.trim())
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| anyhow!("key {} must be 64 hex characters (256 bits)", key.id))
+}
+
+// AES key of one file, HKDF of the configured key and the file's salt
+fn file_key(key: &[u8; 32], salt: &[u8]) -> Result<LessSafeKey> {
+    let prk = Salt::new(HKDF_SHA256, salt).extract(key);
+    let okm = prk
+        .expand(&[KEY_INFO], &AES_256_GCM)
+        .map_err(|_| anyhow!("failed to derive the file key"))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Every file has its own key, so the chunk index is a unique nonce
+fn nonce(index: u32) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[NONCE_LEN - 4..].copy_from_slice(&index.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn aad(header: &[u8], last: bool) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.push(last as u8);
+    aad
+}
@@ -0,0 +1,331 @@
+use anyhow::{Result, anyhow};
+use jpeg_decoder::{Decoder, PixelFormat};
+use photon_rs::PhotonImage;
+use std::{
+    any::Any,
+    io::{self, Cursor, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    process::{Command, Stdio},
+    sync::OnceLock,
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::{exif, format::ImageFormat, state::DecodeConfig};
+
+// Argument that makes the binary run as a decode worker, see worker_main
+pub const WORKER_COMMAND: &str = "decode-worker";
+
+// Maps the source dimensions to the smallest dimensions the caller needs
+pub type MinSize<'a> = &'a (dyn Fn(u32, u32) -> (u32, u32) + Sync);
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("decoder panicked: {0}")]
+    Panicked(String),
+    #[error("decoding took longer than {0} seconds")]
+    TimedOut(u64),
+    #[error("decode worker failed: {0}")]
+    Worker(String),
+}
+
+// Decodes image data off the async threads, within the limits of `conf`.
+// Decodes running past timeout_secs are abandoned, in process their thread
+// only ends with the decoder. With `isolate` every decode runs in a child
+// process (see worker_main) that is killed on timeout, so a decoder that
+// aborts, overflows its stack or runs away takes only that process down.
+// With `auto_orient` the EXIF orientation is applied, the image comes out
+// the way it is displayed.
+pub async fn run(
+    conf: &DecodeConfig,
+    fmt: ImageFormat,
+    data: Vec<u8>,
+    min_size: Option<MinSize<'_>>,
+) -> Result<PhotonImage> {
+    let orientation = orientation(conf, &data);
+    // the DCT scale is picked here, the closure cannot go to another thread.
+    // `min_size` is in displayed dimensions, the decoder works on stored ones.
+    let target = min_size.and_then(|min_size| {
+        fmt.dimensions(&data).map(|(width, height)| {
+            if orientation.is_some_and(swaps_axes) {
+                let (min_width, min_height) = min_size(height, width);
+                (min_height, min_width)
+            } else {
+                min_size(width, height)
+            }
+        })
+    });
+    let timeout = Duration::from_secs(conf.timeout_secs);
+    let img = if conf.isolate {
+        decode_in_worker(conf, fmt, data, target, timeout).await?
+    } else {
+        let task = tokio::task::spawn_blocking(move || decode_to(&fmt, data, target));
+        match tokio::time::timeout(timeout, task).await {
+            Ok(res) => res??,
+            Err(_) => return Err(DecodeError::TimedOut(conf.timeout_secs).into()),
+        }
+    };
+
+    match orientation {
+        Some(orientation) => {
+            Ok(tokio::task::spawn_blocking(move || orient(&img, orientation)).await?)
+        }
+        None => Ok(img),
+    }
+}
+
+// EXIF orientation (2-8) to apply to the decoded `data`, None when the image
+// is stored upright or auto_orient is off
+pub fn orientation(conf: &DecodeConfig, data: &[u8]) -> Option<u32> {
+    if !conf.auto_orient {
+        return None;
+    }
+    exif::read(data)
+        .ok()
+        .flatten()
+        .and_then(|e| e.orientation)
+        .filter(|o| (2..=8).contains(o))
+}
+
+// Orientations 5-8 are rotated by 90 degrees, width and height swap
+pub fn swaps_axes(orientation: u32) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+// Turns stored pixels into displayed ones: mirrored for 2 and 4, rotated by
+// 180 degrees for 3, transposed for 5, rotated clockwise for 6, transversed
+// for 7 and rotated counterclockwise for 8
+pub fn orient(img: &PhotonImage, orientation: u32) -> PhotonImage {
+    let (width, height) = (img.get_width() as usize, img.get_height() as usize);
+    let (new_width, new_height) = if swaps_axes(orientation) {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let pixels = img.get_raw_pixels();
+
+    let mut out = Vec::with_capacity(pixels.len());
+    for y in 0..new_height {
+        for x in 0..new_width {
+            // the stored pixel shown at (x, y)
+            let (src_x, src_y) = match orientation {
+                2 => (width - 1 - x, y),
+                3 => (width - 1 - x, height - 1 - y),
+                4 => (x, height - 1 - y),
+                5 => (y, x),
+                6 => (y, height - 1 - x),
+                7 => (width - 1 - y, height - 1 - x),
+                8 => (width - 1 - y, x),
+                _ => (x, y),
+            };
+            let at = (src_y * width + src_x) * 4;
+            out.extend_from_slice(&pixels[at..at + 4]);
+        }
+    }
+    PhotonImage::new(out, new_width as u32, new_height as u32)
+}
+
+// decode with a fixed minimum size
+fn decode_to(fmt: &ImageFormat, data: Vec<u8>, target: Option<(u32, u32)>) -> Result<PhotonImage> {
+    match target {
+        Some(target) => decode(fmt, data, Some(&move |_: u32, _: u32| target)),
+        None => decode(fmt, data, None),
+    }
+}
+
+async fn decode_in_worker(
+    conf: &DecodeConfig,
+    fmt: ImageFormat,
+    data: Vec<u8>,
+    target: Option<(u32, u32)>,
+    timeout: Duration,
+) -> Result<PhotonImage> {
+    static SLOTS: OnceLock<Semaphore> = OnceLock::new();
+    let _slot = SLOTS
+        .get_or_init(|| Semaphore::new(conf.workers))
+        .acquire()
+        .await?;
+
+    // the worker only gets the image on stdin, no environment or open files
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg(WORKER_COMMAND).arg(fmt.as_str());
+    if let Some((width, height)) = target {
+        command.arg(width.to_string()).arg(height.to_string());
+    }
+    let mut child = command
+        .env_clear()
+        .current_dir(std::env::temp_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let (Some(mut stdin), Some(mut stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        let _ = child.kill();
+        return Err(DecodeError::Worker("no pipes to the worker".to_string()).into());
+    };
+
+    let io = tokio::task::spawn_blocking(move || {
+        // written from another thread, the worker may answer (fail) before
+        // it has read everything
+        let writer = std::thread::spawn(move || {
+            let _ = stdin.write_all(&data);
+        });
+        let mut out = Vec::new();
+        let res = stdout.read_to_end(&mut out);
+        let _ = writer.join();
+        res.map(|_| out)
+    });
+    let out = match tokio::time::timeout(timeout, io).await {
+        Ok(res) => res??,
+        Err(_) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(DecodeError::TimedOut(conf.timeout_secs).into());
+        }
+    };
+
+    let status = tokio::task::spawn_blocking(move || child.wait()).await??;
+    if !status.success() {
+        return Err(DecodeError::Worker(status.to_string()).into());
+    }
+    let (Some(width), Some(height)) = (
+        out.get(0..4)
+            .and_then(|b| b.try_into().ok())
+            .map(u32::from_le_bytes),
+        out.get(4..8)
+            .and_then(|b| b.try_into().ok())
+            .map(u32::from_le_bytes),
+    ) else {
+        return Err(DecodeError::Worker("truncated output".to_string()).into());
+    };
+    if out.len() as u64 != 8 + width as u64 * height as u64 * 4 {
+        return Err(DecodeError::Worker("malformed output".to_string()).into());
+    }
+    Ok(PhotonImage::new(out[8..].to_vec(), width, height))
+}
+
+// `brushbloom decode-worker <fmt> [<min width> <min height>]`: decodes the
+// image on stdin and writes its width and height (u32 little endian) and
+// RGBA pixels to stdout
+pub fn worker_main(args: &[String]) -> Result<()> {
+    let fmt = ImageFormat::from_fmt(args.first().map_or("", |s| s.as_str()));
+    let target = match args.get(1..3) {
+        Some([width, height]) => Some((width.parse()?, height.parse()?)),
+        _ => None,
+    };
+
+    let mut data = Vec::new();
+    io::stdin().read_to_end(&mut data)?;
+    let img = decode_to(&fmt, data, target)?;
+
+    let mut out = io::stdout().lock();
+    out.write_all(&img.get_width().to_le_bytes())?;
+    out.write_all(&img.get_height().to_le_bytes())?;
+    out.write_all(&img.get_raw_pixels())?;
+    out.flush()?;
+    Ok(())
+}
+
+// Decodes image data. With `min_size` JPEGs are decoded at the smallest DCT
+// scale (1/8, 1/4, 1/2) that still covers it, instead of in full and then
+// downscaled. Photon panics on malformed data, which is returned as an error.
+pub fn decode(fmt: &ImageFormat, data: Vec<u8>, min_size: Option<MinSize>) -> Result<PhotonImage> {
+    panic::catch_unwind(AssertUnwindSafe(|| decode_unchecked(fmt, data, min_size)))
+        .map_err(|panic| DecodeError::Panicked(panic_message(panic.as_ref())).into())
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn decode_unchecked(fmt: &ImageFormat, data: Vec<u8>, min_size: Option<MinSize>) -> PhotonImage {
+    if *fmt == ImageFormat::Jpeg
+        && let Some(min_size) = min_size
+    {
+        match decode_jpeg_scaled(&data, min_size) {
+            Ok(img) => return img,
+            Err(e) => warn!("scaled jpeg decode failed, decoding in full: {}", e),
+        }
+    }
+
+    PhotonImage::new_from_byteslice(data)
+}
+
+// Dimensions `decode` produces, from the header of `data`
+pub fn decoded_size(
+    fmt: &ImageFormat,
+    data: &[u8],
+    min_size: Option<MinSize>,
+) -> Option<(u32, u32)> {
+    let (width, height) = fmt.dimensions(data)?;
+    match min_size {
+        Some(min_size) if *fmt == ImageFormat::Jpeg => {
+            let scale = jpeg_scale(width, height, min_size(width, height));
+            Some((scaled(width, scale), scaled(height, scale)))
+        }
+        _ => Some((width, height)),
+    }
+}
+
+fn decode_jpeg_scaled(data: &[u8], min_size: MinSize) -> Result<PhotonImage> {
+    let mut decoder = Decoder::new(Cursor::new(data));
+    decoder.read_info()?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| anyhow!("jpeg header is missing"))?;
+
+    let (width, height) = (info.width as u32, info.height as u32);
+    let scale = jpeg_scale(width, height, min_size(width, height));
+    let (width, height) = decoder.scale(
+        scaled(width, scale).min(u16::MAX as u32) as u16,
+        scaled(height, scale).min(u16::MAX as u32) as u16,
+    )?;
+    let pixels = decoder.decode()?;
+
+    let rgba = match info.pixel_format {
+        PixelFormat::L8 => pixels.iter().flat_map(|&l| [l, l, l, 255]).collect(),
+        PixelFormat::L16 => pixels
+            .chunks_exact(2)
+            .flat_map(|l| {
+                let l = (u16::from_ne_bytes([l[0], l[1]]) >> 8) as u8;
+                [l, l, l, 255]
+            })
+            .collect(),
+        PixelFormat::RGB24 => pixels
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        // same conversion as the image crate's decoder
+        PixelFormat::CMYK32 => pixels
+            .chunks_exact(4)
+            .flat_map(|p| {
+                let k = 255 - p[3] as u16;
+                let channel = |c: u8| ((255 - c as u16) * k / 255) as u8;
+                [channel(p[0]), channel(p[1]), channel(p[2]), 255]
+            })
+            .collect(),
+    };
+
+    Ok(PhotonImage::new(rgba, width as u32, height as u32))
+}
+
+// Numerator of the n/8 DCT scale, the smallest one still covering `min`
+fn jpeg_scale(width: u32, height: u32, (min_width, min_height): (u32, u32)) -> u32 {
+    [1, 2, 4, 8]
+        .into_iter()
+        .find(|&n| {
+            scaled(width, n) >= min_width.min(width) && scaled(height, n) >= min_height.min(height)
+        })
+        .unwrap_or(8)
+}
+
+fn scaled(size: u32, n: u32) -> u32 {
+    (size as u64 * n as u64).div_ceil(8) as u32
+}
@@ -0,0 +1,184 @@
+use photon_rs::PhotonImage;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    budget::{BudgetPermit, MemoryBudget},
+    handlers::Frame,
+    state::DecodeCacheConfig,
+};
+
+const BYTES_PER_PIXEL: u64 = 4;
+
+// Decoded originals kept in memory, so that the variants of a hot source
+// (thumbnails, resizes, crops of the same upload) decode it once. Entries are
+// keyed by the image file and the frame option of the read, and dropped when
+// the size or mtime of the file changes. They
+// hold their memory from the memory budget, which they give back when
+// operations wait for it, see handlers::image::read_image.
+struct DecodeCache {
+    max_bytes: u64,
+    ttl: Duration,
+    entries: HashMap<Key, Entry>,
+    bytes: u64,
+}
+
+type Key = (PathBuf, Option<Frame>);
+
+struct Entry {
+    img: PhotonImage,
+    stamp: Stamp,
+    bytes: u64,
+    last_used: Instant,
+    _permit: BudgetPermit,
+}
+
+// Size and mtime of the file an entry was decoded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Stamp {
+    pub async fn of(path: &Path) -> Option<Stamp> {
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        Some(Stamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+fn cache() -> &'static OnceLock<Mutex<DecodeCache>> {
+    static CACHE: OnceLock<Mutex<DecodeCache>> = OnceLock::new();
+    &CACHE
+}
+
+// Called once at startup, without it nothing is cached
+pub fn init(conf: &DecodeCacheConfig) {
+    let _ = cache().set(Mutex::new(DecodeCache {
+        max_bytes: conf.max_mb * 1024 * 1024,
+        ttl: Duration::from_secs(conf.ttl_secs),
+        entries: HashMap::new(),
+        bytes: 0,
+    }));
+}
+
+pub fn is_enabled() -> bool {
+    cache().get().is_some()
+}
+
+// Width and height of the cached decode of `path`, for the permit of the
+// copy get makes
+pub fn dimensions(path: &Path, frame: Option<Frame>, stamp: Stamp) -> Option<(u32, u32)> {
+    let cache = cache().get()?.lock().unwrap();
+    let entry = cache.entries.get(&(path.to_path_buf(), frame))?;
+    (entry.stamp == stamp).then(|| (entry.img.get_width(), entry.img.get_height()))
+}
+
+// A copy of the image decoded from `path`, unless it changed since. Callers
+// hold a budget permit for it.
+pub fn get(path: &Path, frame: Option<Frame>, stamp: Stamp) -> Option<PhotonImage> {
+    let mut cache = cache().get()?.lock().unwrap();
+    cache.expire();
+    let key = (path.to_path_buf(), frame);
+    let entry = cache.entries.get_mut(&key)?;
+    if entry.stamp != stamp {
+        let bytes = entry.bytes;
+        cache.entries.remove(&key);
+        cache.bytes -= bytes;
+        return None;
+    }
+    entry.last_used = Instant::now();
+    let img = &entry.img;
+    Some(PhotonImage::new(
+        img.get_raw_pixels(),
+        img.get_width(),
+        img.get_height(),
+    ))
+}
+
+// Keeps a copy of `img`, decoded in full from `path`. Least recently used
+// entries make room for it, it is not kept when the memory budget has no
+// room for it right away.
+pub fn insert(
+    budget: &MemoryBudget,
+    path: &Path,
+    frame: Option<Frame>,
+    stamp: Stamp,
+    img: &PhotonImage,
+) {
+    let Some(cache) = cache().get() else {
+        return;
+    };
+    let bytes = img.get_width() as u64 * img.get_height() as u64 * BYTES_PER_PIXEL;
+    let mut cache = cache.lock().unwrap();
+    if bytes > cache.max_bytes {
+        return;
+    }
+    cache.expire();
+    let key = (path.to_path_buf(), frame);
+    if let Some(old) = cache.entries.remove(&key) {
+        cache.bytes -= old.bytes;
+    }
+    while cache.bytes + bytes > cache.max_bytes && cache.evict_lru() {}
+    let Some(permit) = budget.try_acquire(bytes) else {
+        return;
+    };
+
+    cache.entries.insert(
+        key,
+        Entry {
+            img: PhotonImage::new(img.get_raw_pixels(), img.get_width(), img.get_height()),
+            stamp,
+            bytes,
+            last_used: Instant::now(),
+            _permit: permit,
+        },
+    );
+    cache.bytes += bytes;
+}
+
+// Drops every entry, giving their memory back to the budget
+pub fn clear() {
+    if let Some(cache) = cache().get() {
+        let mut cache = cache.lock().unwrap();
+        cache.entries.clear();
+        cache.bytes = 0;
+    }
+}
+
+impl DecodeCache {
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        let mut freed = 0;
+        self.entries.retain(|_, e| {
+            let keep = e.last_used.elapsed() < ttl;
+            if !keep {
+                freed += e.bytes;
+            }
+            keep
+        });
+        self.bytes -= freed;
+    }
+
+    fn evict_lru(&mut self) -> bool {
+        let Some(key) = self
+            .entries
+            .iter()
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(k, _)| k.clone())
+        else {
+            return false;
+        };
+        if let Some(entry) = self.entries.remove(&key) {
+            self.bytes -= entry.bytes;
+        }
+        true
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{Result, anyhow, bail};
+use std::collections::HashMap;
+
+// Binary deltas between versions of an image, see storage::replace_image.
+// A delta is a list of instructions building the target: copy a range of the
+// base, or insert bytes carried in the delta. Ranges are found by matching
+// blocks of the target against the base, like rsync.
+
+const MAGIC: &[u8; 4] = b"BBD1";
+const BLOCK: usize = 16;
+const OP_COPY: u8 = 1;
+const OP_INSERT: u8 = 2;
+
+// Delta building `target` out of `base`
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    // first offset of every aligned block of the base
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in base.chunks_exact(BLOCK).enumerate() {
+        blocks.entry(block).or_insert(i * BLOCK);
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&(target.len() as u64).to_le_bytes());
+    let (mut pos, mut pending) = (0, 0);
+    while pos + BLOCK <= target.len() {
+        let Some(&at) = blocks.get(&target[pos..pos + BLOCK]) else {
+            pos += 1;
+            continue;
+        };
+
+        // grow the match in both directions, backwards into what would be
+        // inserted
+        let (mut start, mut base_start) = (pos, at);
+        while start > pending && base_start > 0 && target[start - 1] == base[base_start - 1] {
+            start -= 1;
+            base_start -= 1;
+        }
+        let mut len = pos + BLOCK - start;
+        while start + len < target.len()
+            && base_start + len < base.len()
+            && target[start + len] == base[base_start + len]
+        {
+            len += 1;
+        }
+
+        insert(&mut out, &target[pending..start]);
+        out.push(OP_COPY);
+        out.extend_from_slice(&(base_start as u32).to_le_bytes());
+        out.extend_from_slice(&(len as u32).to_le_bytes());
+        pos = start + len;
+        pending = pos;
+    }
+    insert(&mut out, &target[pending..]);
+    out
+}
+
+fn insert(out: &mut Vec<u8>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    out.push(OP_INSERT);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+// The target `delta` was made for, out of the same `base`
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let Some(mut rest) = delta.strip_prefix(MAGIC) else {
+        bail!("not a delta");
+    };
+    let len = u64::from_le_bytes(take(&mut rest, 8)?.try_into()?) as usize;
+
+    let mut out = Vec::with_capacity(len);
+    while let Some((&op, tail)) = rest.split_first() {
+        rest = tail;
+        match op {
+            OP_COPY => {
+                let start = u32::from_le_bytes(take(&mut rest, 4)?.try_into()?) as usize;
+                let count = u32::from_le_bytes(take(&mut rest, 4)?.try_into()?) as usize;
+                let range = base
+                    .get(start..start + count)
+                    .ok_or_else(|| anyhow!("delta copies past the end of its base"))?;
+                out.extend_from_slice(range);
+            }
+            OP_INSERT => {
+                let count = u32::from_le_bytes(take(&mut rest, 4)?.try_into()?) as usize;
+                out.extend_from_slice(take(&mut rest, count)?);
+            }
+            op => bail!("unknown delta instruction {}", op),
+        }
+    }
+    if out.len() != len {
+        bail!("delta built {} bytes instead of {}", out.len(), len);
+    }
+    Ok(out)
+}
+
+fn take<'a>(data: &mut &'a [u8], count: usize) -> Result<&'a [u8]> {
+    if data.len() < count {
+        bail!("delta is truncated");
+    }
+    let (head, tail) = data.split_at(count);
+    *data = tail;
+    Ok(head)
+}
@@ -0,0 +1,213 @@
+use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{Method, Response, StatusCode},
+    middleware::Next,
+};
+use serde::Serialize;
+use std::{
+    io,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::{
+    error::build_err_response,
+    notify::{Alert, AlertKind},
+    report::ErrorEvent,
+    state::{AppConfig, AppState, DiskSpaceConfig},
+    tenant::Tenant,
+};
+
+// Read-only mode ends once this much more than min_free_mb is free again, so
+// it does not flap around the threshold
+const RESUME_MARGIN_PCT: u64 = 10;
+
+// POST routes that only read
+const READ_ONLY_POSTS: &[&str] = &["/api/images/search/by-image", "/api/images/search/by-text"];
+
+// Free space seen by the last check of disk::run
+#[derive(Debug, Clone, Default)]
+pub struct DiskStatus {
+    read_only: Arc<AtomicBool>,
+    free_mb: Arc<AtomicU64>,
+}
+
+impl DiskStatus {
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn report(&self, conf: &DiskSpaceConfig) -> DiskReport {
+        DiskReport {
+            free_mb: self.free_mb.load(Ordering::Relaxed),
+            min_free_mb: conf.min_free_mb,
+            read_only: self.is_read_only(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskReport {
+    pub free_mb: u64,
+    pub min_free_mb: u64,
+    pub read_only: bool,
+}
+
+// Space left on the fuller of the volumes of file_path and meta_path
+pub fn free_mb(conf: &AppConfig) -> io::Result<u64> {
+    let free = fs4::available_space(&conf.file_path)?.min(fs4::available_space(&conf.meta_path)?);
+    Ok(free / (1024 * 1024))
+}
+
+// Checks the free space every check_interval_secs and switches read-only
+// mode on below min_free_mb and off again once there is room
+pub async fn run(state: AppState, disk: DiskSpaceConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(disk.check_interval_secs));
+    loop {
+        interval.tick().await;
+        let conf = state.conf.clone();
+        let free = match tokio::task::spawn_blocking(move || free_mb(&conf)).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => {
+                warn!("failed to check free disk space: {}", e);
+                continue;
+            }
+            Err(e) => {
+                warn!("failed to check free disk space: {}", e);
+                continue;
+            }
+        };
+        state.disk.free_mb.store(free, Ordering::Relaxed);
+
+        let read_only = state.disk.is_read_only();
+        if !read_only && free < disk.min_free_mb {
+            state.disk.read_only.store(true, Ordering::Relaxed);
+            alert(
+                &state,
+                AlertKind::DiskSpaceLow,
+                format!(
+                    "{} MB free, below disk_space.min_free_mb {}: storing is disabled",
+                    free, disk.min_free_mb
+                ),
+            );
+            if disk.evict_caches {
+                evict_caches(&state.conf).await;
+            }
+        } else if read_only && free >= disk.min_free_mb * (100 + RESUME_MARGIN_PCT) / 100 {
+            state.disk.read_only.store(false, Ordering::Relaxed);
+            alert(
+                &state,
+                AlertKind::DiskSpaceRecovered,
+                format!("{} MB free: storing is enabled again", free),
+            );
+        }
+    }
+}
+
+fn alert(state: &AppState, kind: AlertKind, message: String) {
+    warn!("{}", message);
+    if let Some(notifier) = &state.notifier {
+        notifier.notify(Alert {
+            kind,
+            message: message.clone(),
+            tenant: None,
+        });
+    }
+    if let Some(reporter) = &state.reporter {
+        reporter.report(ErrorEvent {
+            kind: "disk_space",
+            message,
+            status: None,
+            request_id: None,
+            route: None,
+            img_id: None,
+        });
+    }
+}
+
+// Removes the thumbnails of every tenant and the raster cache, files the
+// server makes again when they are asked for
+async fn evict_caches(conf: &AppConfig) {
+    let mut dirs: Vec<String> = conf
+        .tenants
+        .iter()
+        .map(|t| Tenant::from(t).scope(conf).file_path)
+        .chain([conf.file_path.clone()])
+        .map(|dir| {
+            Path::new(&dir)
+                .join("thumbs")
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    if let Some(cache) = &conf.raster_cache {
+        dirs.push(cache.dir.clone());
+    }
+
+    for dir in dirs {
+        match empty_dir(Path::new(&dir)).await {
+            Ok(0) => {}
+            Ok(n) => info!("evicted {} cached files from {}", n, dir),
+            Err(e) => warn!("failed to evict cached files from {}: {}", dir, e),
+        }
+    }
+}
+
+// Removes what is in `dir`, keeping the directory. Returns how many entries
+// were removed.
+async fn empty_dir(dir: &Path) -> Result<usize> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let res = if entry.file_type().await?.is_dir() {
+            tokio::fs::remove_dir_all(entry.path()).await
+        } else {
+            tokio::fs::remove_file(entry.path()).await
+        };
+        match res {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(removed)
+}
+
+// In read-only mode everything that may store data gets a 507. Reads go on,
+// and so do deletes, which free space.
+pub async fn reject_writes(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    if !state.disk.is_read_only() || is_read(&req) {
+        return next.run(req).await;
+    }
+    build_err_response(
+        StatusCode::INSUFFICIENT_STORAGE,
+        "Storage is almost full, the service is read-only".to_string(),
+    )
+}
+
+fn is_read(req: &Request) -> bool {
+    match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::DELETE => true,
+        Method::POST => req
+            .extensions()
+            .get::<MatchedPath>()
+            .is_some_and(|route| READ_ONLY_POSTS.contains(&route.as_str())),
+        // WebDAV listings
+        ref method => method.as_str() == "PROPFIND",
+    }
+}
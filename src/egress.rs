@@ -0,0 +1,191 @@
+use anyhow::{Result, anyhow};
+use bytes::{Bytes, BytesMut};
+use reqwest::{
+    Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    header, redirect,
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::state::EgressConfig;
+
+// Response of `EgressPolicy::fetch`
+#[derive(Debug)]
+pub struct Fetched {
+    pub url: Url,
+    pub content_type: Option<String>,
+    pub body: Bytes,
+}
+
+// Fetches user supplied URLs without letting them reach the internal network.
+// Host names are resolved once and only public addresses are connected to, so
+// a DNS answer cannot change between the check and the connection.
+#[derive(Debug, Clone)]
+pub struct EgressPolicy {
+    conf: Arc<EgressConfig>,
+    client: reqwest::Client,
+}
+
+impl EgressPolicy {
+    pub fn new(conf: EgressConfig) -> Result<Self> {
+        let conf = Arc::new(conf);
+        let redirect_conf = conf.clone();
+
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .timeout(Duration::from_secs(conf.timeout_secs))
+            .dns_resolver(Arc::new(PinnedResolver {
+                allow_private: conf.allow_private,
+            }))
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= redirect_conf.max_redirects {
+                    return attempt.error(anyhow!(
+                        "more than {} redirects",
+                        redirect_conf.max_redirects
+                    ));
+                }
+                match check_url(&redirect_conf, attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e),
+                }
+            }))
+            .build()?;
+
+        Ok(Self { conf, client })
+    }
+
+    // Checks the scheme, port and, for IP literals, the address of `url`
+    pub fn check(&self, url: &str) -> Result<Url> {
+        let url = Url::parse(url).map_err(|e| anyhow!("invalid url {:?}: {}", url, e))?;
+        check_url(&self.conf, &url)?;
+        Ok(url)
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<Fetched> {
+        let url = self.check(url)?;
+        let max_bytes = self.conf.max_response_mb * 1024 * 1024;
+
+        let mut res = self.client.get(url).send().await?.error_for_status()?;
+        if res.content_length().is_some_and(|len| len > max_bytes) {
+            return Err(anyhow!(
+                "response is larger than {} MB",
+                self.conf.max_response_mb
+            ));
+        }
+
+        let url = res.url().clone();
+        let content_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        let mut body = BytesMut::new();
+        while let Some(chunk) = res.chunk().await? {
+            if (body.len() + chunk.len()) as u64 > max_bytes {
+                return Err(anyhow!(
+                    "response is larger than {} MB",
+                    self.conf.max_response_mb
+                ));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(Fetched {
+            url,
+            content_type,
+            body: body.freeze(),
+        })
+    }
+}
+
+fn check_url(conf: &EgressConfig, url: &Url) -> Result<()> {
+    if !conf.allowed_schemes.iter().any(|s| s == url.scheme()) {
+        return Err(anyhow!("scheme {:?} is not allowed", url.scheme()));
+    }
+
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("url {} has no port", url))?;
+    if !conf.allowed_ports.contains(&port) {
+        return Err(anyhow!("port {} is not allowed", port));
+    }
+
+    // IP literals are connected to directly, without the resolver
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("url {} has no host", url))?;
+    if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>()
+        && !conf.allow_private
+        && !is_public(ip)
+    {
+        return Err(anyhow!("address {} is not public", ip));
+    }
+
+    Ok(())
+}
+
+// Resolves host names and drops every non public address from the answer
+struct PinnedResolver {
+    allow_private: bool,
+}
+
+impl Resolve for PinnedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allow_private = self.allow_private;
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<_> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| allow_private || is_public(addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(anyhow!("{} does not resolve to a public address", host).into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network", carrier-grade NAT, benchmarking and reserved
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local fc00::/7, link local fe80::/10, documentation 2001:db8::/32
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
@@ -0,0 +1,285 @@
+use anyhow::{Result, anyhow};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+use tracing::{info, warn};
+
+use crate::{
+    ann::{self, Index},
+    decode,
+    format::ImageFormat,
+    state::AppConfig,
+    storage::{image_path, read_image_data, read_meta},
+};
+
+// Embeddings are kept next to the metadata, a file of little endian f32s per
+// image. Names starting with a dot are skipped by the metadata stores.
+const EMBEDDINGS_DIR: &str = ".embeddings";
+
+pub fn embedding_path(conf: &AppConfig, img_id: &str) -> PathBuf {
+    Path::new(&conf.meta_path)
+        .join(EMBEDDINGS_DIR)
+        .join(format!("{}.f32", img_id))
+}
+
+// The stored embedding of an image, computed first when it has none
+pub async fn of_image(conf: &AppConfig, img_id: &str) -> Result<Vec<f32>> {
+    match read(&embedding_path(conf, img_id)).await {
+        Ok(vector) => Ok(vector),
+        Err(_) => embed_image(conf, img_id).await,
+    }
+}
+
+// Computes the embedding of a stored image with the image model, stores it and
+// adds it to the loaded index
+pub async fn embed_image(conf: &AppConfig, img_id: &str) -> Result<Vec<f32>> {
+    let embeddings = conf
+        .embeddings
+        .as_ref()
+        .ok_or_else(|| anyhow!("embeddings are not configured"))?;
+    let meta = read_meta(conf, img_id).await?;
+    let fmt = ImageFormat::from_fmt(&meta.fmt);
+    if !fmt.is_decodable() {
+        return Err(anyhow!("{} images cannot be embedded", meta.fmt));
+    }
+
+    let data = read_image_data(conf, &image_path(conf, img_id, &meta.fmt)).await?;
+    // the model looks at input_size pixels, the shorter edge is decoded to cover it
+    let size = embeddings.input_size;
+    let min_size = move |width: u32, height: u32| {
+        let ratio = (size as f32 / width.min(height) as f32).min(1.0);
+        (
+            ((width as f32 * ratio).round() as u32).max(1),
+            ((height as f32 * ratio).round() as u32).max(1),
+        )
+    };
+    let img = decode::run(&conf.decode, fmt, data, Some(&min_size)).await?;
+
+    let (task_conf, resizer) = (embeddings.clone(), conf.resizer);
+    let vector =
+        tokio::task::spawn_blocking(move || model::image(&task_conf, resizer, &img)).await??;
+    let vector = checked(conf, vector)?;
+
+    let path = embedding_path(conf, img_id);
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let bytes: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+    tokio::fs::write(&path, bytes).await?;
+
+    if let Some(index) = loaded_index(conf) {
+        index
+            .write()
+            .map_err(|_| anyhow!("embedding index poisoned"))?
+            .insert(img_id, vector.clone());
+    }
+    Ok(vector)
+}
+
+// Embedding of a text with the text model, comparable to image embeddings
+pub async fn embed_text(conf: &AppConfig, text: &str) -> Result<Vec<f32>> {
+    let embeddings = conf
+        .embeddings
+        .clone()
+        .ok_or_else(|| anyhow!("embeddings are not configured"))?;
+    let text = text.to_string();
+    let vector = tokio::task::spawn_blocking(move || model::text(&embeddings, &text)).await??;
+    checked(conf, vector)
+}
+
+// Embeds a new upload in the background, failures are only logged
+pub fn spawn(conf: AppConfig, img_id: String) {
+    if conf.embeddings.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        match embed_image(&conf, &img_id).await {
+            Ok(_) => info!("embedded {}", img_id),
+            Err(e) => warn!("failed to embed {}: {}", img_id, e),
+        }
+    });
+}
+
+// Ids of the images most similar to `query`, best first with their cosine
+// similarity, leaving out `exclude`
+pub async fn search(
+    conf: &AppConfig,
+    query: &[f32],
+    limit: usize,
+    exclude: Option<&str>,
+) -> Result<Vec<(String, f32)>> {
+    let index = index(conf).await?;
+    let index = index
+        .read()
+        .map_err(|_| anyhow!("embedding index poisoned"))?;
+    Ok(index.search(query, limit, exclude))
+}
+
+// Normalised, with the configured number of dimensions
+fn checked(conf: &AppConfig, vector: Vec<f32>) -> Result<Vec<f32>> {
+    let dimensions = conf.embeddings.as_ref().map_or(0, |e| e.dimensions);
+    if vector.len() != dimensions {
+        return Err(anyhow!(
+            "the model returned {} dimensions instead of {}",
+            vector.len(),
+            dimensions
+        ));
+    }
+    Ok(ann::normalize(vector))
+}
+
+async fn read(path: &Path) -> Result<Vec<f32>> {
+    let bytes = tokio::fs::read(path).await?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+// Indexes by meta_path, one per tenant
+fn indexes() -> &'static Mutex<HashMap<String, Arc<RwLock<Index>>>> {
+    static INDEXES: OnceLock<Mutex<HashMap<String, Arc<RwLock<Index>>>>> = OnceLock::new();
+    INDEXES.get_or_init(Default::default)
+}
+
+fn loaded_index(conf: &AppConfig) -> Option<Arc<RwLock<Index>>> {
+    indexes().lock().unwrap().get(&conf.meta_path).cloned()
+}
+
+// Built from the stored embeddings on first use, kept up to date by
+// embed_image afterwards
+async fn index(conf: &AppConfig) -> Result<Arc<RwLock<Index>>> {
+    if let Some(index) = loaded_index(conf) {
+        return Ok(index);
+    }
+
+    let dimensions = conf.embeddings.as_ref().map_or(0, |e| e.dimensions);
+    let dir = Path::new(&conf.meta_path).join(EMBEDDINGS_DIR);
+    let mut index = Index::new(dimensions);
+    match tokio::fs::read_dir(&dir).await {
+        Ok(mut entries) => {
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let Some(img_id) = path.file_stem().and_then(|s| s.to_str()) else {
+                    continue;
+                };
+                match read(&path).await {
+                    Ok(vector) if vector.len() == dimensions => index.insert(img_id, vector),
+                    Ok(_) => warn!("skipping {:?}, made with another model", path),
+                    Err(e) => warn!("skipping {:?}: {}", path, e),
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    info!("loaded {} embeddings of {}", index.len(), conf.meta_path);
+
+    Ok(indexes()
+        .lock()
+        .unwrap()
+        .entry(conf.meta_path.clone())
+        .or_insert_with(|| Arc::new(RwLock::new(index)))
+        .clone())
+}
+
+#[cfg(feature = "embeddings")]
+mod model {
+    use anyhow::{Result, anyhow};
+    use ort::{session::Session, value::Tensor};
+    use photon_rs::PhotonImage;
+    use std::sync::{Mutex, OnceLock};
+    use tokenizers::Tokenizer;
+
+    use crate::{
+        onnx::{self, CLIP},
+        resize::Resizer,
+        state::EmbeddingConfig,
+    };
+
+    // context length of CLIP text encoders
+    const CONTEXT_LEN: usize = 77;
+
+    // the two encoders of a CLIP-style model, mapping into one space
+    struct Clip {
+        image: Mutex<Session>,
+        text: Mutex<Session>,
+        tokenizer: Tokenizer,
+    }
+
+    // loaded on first use, every tenant shares it
+    fn clip(conf: &EmbeddingConfig) -> Result<&'static Clip> {
+        static MODEL: OnceLock<Clip> = OnceLock::new();
+        if let Some(clip) = MODEL.get() {
+            return Ok(clip);
+        }
+        let clip = Clip {
+            image: Mutex::new(Session::builder()?.commit_from_file(&conf.image_model)?),
+            text: Mutex::new(Session::builder()?.commit_from_file(&conf.text_model)?),
+            tokenizer: Tokenizer::from_file(&conf.tokenizer_path).map_err(|e| anyhow!("{}", e))?,
+        };
+        Ok(MODEL.get_or_init(|| clip))
+    }
+
+    pub fn image(conf: &EmbeddingConfig, resizer: Resizer, img: &PhotonImage) -> Result<Vec<f32>> {
+        let clip = clip(conf)?;
+        let input = onnx::image_input(img, conf.input_size, &CLIP, resizer)?;
+        let mut session = clip
+            .image
+            .lock()
+            .map_err(|_| anyhow!("onnx session poisoned"))?;
+        let outputs = session.run(ort::inputs![input])?;
+        let (_, embedding) = outputs[0].try_extract_tensor::<f32>()?;
+        Ok(embedding.to_vec())
+    }
+
+    pub fn text(conf: &EmbeddingConfig, text: &str) -> Result<Vec<f32>> {
+        let clip = clip(conf)?;
+        let encoding = clip
+            .tokenizer
+            .encode(text, true)
+            .map_err(|e| anyhow!("{}", e))?;
+        let mut ids: Vec<i64> = encoding.get_ids().iter().map(|&id| id as i64).collect();
+        ids.truncate(CONTEXT_LEN);
+        let mut mask = vec![1i64; ids.len()];
+        ids.resize(CONTEXT_LEN, 0);
+        mask.resize(CONTEXT_LEN, 0);
+
+        let ids = Tensor::from_array(([1usize, CONTEXT_LEN], ids))?;
+        let mut session = clip
+            .text
+            .lock()
+            .map_err(|_| anyhow!("onnx session poisoned"))?;
+        // some exports take the attention mask, others only the ids
+        let outputs = if session.inputs.len() > 1 {
+            let mask = Tensor::from_array(([1usize, CONTEXT_LEN], mask))?;
+            session.run(ort::inputs![ids, mask])?
+        } else {
+            session.run(ort::inputs![ids])?
+        };
+        let (_, embedding) = outputs[0].try_extract_tensor::<f32>()?;
+        Ok(embedding.to_vec())
+    }
+}
+
+#[cfg(not(feature = "embeddings"))]
+mod model {
+    use anyhow::{Result, anyhow};
+    use photon_rs::PhotonImage;
+
+    use crate::{resize::Resizer, state::EmbeddingConfig};
+
+    pub fn image(_: &EmbeddingConfig, _: Resizer, _: &PhotonImage) -> Result<Vec<f32>> {
+        Err(anyhow!(
+            "this build does not include the embeddings feature"
+        ))
+    }
+
+    pub fn text(_: &EmbeddingConfig, _: &str) -> Result<Vec<f32>> {
+        Err(anyhow!(
+            "this build does not include the embeddings feature"
+        ))
+    }
+}
@@ -0,0 +1,166 @@
+use axum::{
+    Json,
+    body::Body,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+};
+use serde::Serialize;
+
+use crate::{
+    budget::BudgetError,
+    format::FormatNotAllowed,
+    handlers::{StampTooLarge, TooManyTiles},
+    jobs::queue::QueueFull,
+    raster::OutOfBounds,
+    review::InvalidTransition,
+    storage, store,
+};
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+// Error of a handler. Each kind is answered with its status and the message
+// as {"error": "..."}.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum AppError {
+    // malformed or invalid parameters
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    // the image, job or whatever else the request names does not exist
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    TooLarge(String),
+    // a format the server cannot read or does not accept
+    #[error("{0}")]
+    UnsupportedFormat(String),
+    // well-formed parameters that cannot be applied, e.g. a params object of
+    // the wrong shape
+    #[error("{0}")]
+    Unprocessable(String),
+    // the stored image could not be decoded
+    #[error("{0}")]
+    DecodeError(String),
+    #[error("{0}")]
+    QuotaExceeded(String),
+    // a server the request was passed on to failed
+    #[error("{0}")]
+    Upstream(String),
+    // reading or writing images or metadata failed
+    #[error("{0}")]
+    StorageError(String),
+    #[error("{0}")]
+    Internal(String),
+    // memory for decoding is not available, see budget
+    #[error(transparent)]
+    Budget(#[from] BudgetError),
+    // the status of an extractor's rejection, e.g. 413 for a multipart body
+    // over the limit
+    #[error("{1}")]
+    Status(StatusCode, String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedFormat(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::Unprocessable(_) | AppError::DecodeError(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            AppError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::StorageError(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Budget(e) => e.status(),
+            AppError::Status(status, _) => *status,
+        }
+    }
+
+    pub fn image_not_found() -> Self {
+        AppError::NotFound("Image not found".to_string())
+    }
+
+    pub fn invalid_id() -> Self {
+        AppError::BadRequest("Invalid image id".to_string())
+    }
+
+    // Error of reading an image or its metadata: 404 when it does not exist,
+    // a storage error when reading it failed
+    pub fn from_read(e: &anyhow::Error) -> Self {
+        if storage::is_not_found(e) {
+            AppError::image_not_found()
+        } else {
+            AppError::StorageError("Failed to read image".to_string())
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response<Body> {
+        match self {
+            AppError::Budget(e) => e.into_response(),
+            e => build_err_response(e.status(), e.to_string()),
+        }
+    }
+}
+
+// Errors of the transforms and storage functions, which return anyhow. The
+// typed ones are mapped to their kind, anything else is internal.
+impl From<anyhow::Error> for AppError {
+    fn from(e: anyhow::Error) -> Self {
+        if e.is::<OutOfBounds>() {
+            AppError::BadRequest(e.to_string())
+        } else if e.is::<InvalidTransition>() {
+            AppError::Conflict(e.to_string())
+        } else if e.is::<storage::Held>() {
+            AppError::Conflict(e.to_string())
+        } else if e.is::<store::NotFound>() {
+            AppError::image_not_found()
+        } else if e.is::<TooManyTiles>() || e.is::<StampTooLarge>() {
+            AppError::BadRequest(e.to_string())
+        } else if let Some(e) = e.downcast_ref::<FormatNotAllowed>() {
+            e.into()
+        } else {
+            AppError::Internal(e.to_string())
+        }
+    }
+}
+
+impl From<QueueFull> for AppError {
+    fn from(e: QueueFull) -> Self {
+        AppError::Status(StatusCode::TOO_MANY_REQUESTS, e.to_string())
+    }
+}
+
+impl From<&FormatNotAllowed> for AppError {
+    fn from(e: &FormatNotAllowed) -> Self {
+        match e {
+            FormatNotAllowed::Input(_) => AppError::UnsupportedFormat(e.to_string()),
+            FormatNotAllowed::Output(_) => AppError::BadRequest(e.to_string()),
+        }
+    }
+}
+
+impl From<FormatNotAllowed> for AppError {
+    fn from(e: FormatNotAllowed) -> Self {
+        (&e).into()
+    }
+}
+
+// Body of an AppError, also answered by the middleware before a handler runs
+pub fn build_err_response(code: StatusCode, msg: String) -> Response<Body> {
+    (code, Json(ErrorResponse { error: msg })).into_response()
+}
@@ -0,0 +1,171 @@
+use ::exif::{DateTime, Exif, In, Reader, Tag, Value};
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Cursor;
+
+// What a photographer wants from the EXIF of an image, absent fields were
+// not recorded by the camera
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExifData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lens_make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lens_model: Option<String>,
+    // 1-8 as in the EXIF specification, 1 is upright
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<u32>,
+    // RFC 3339 when the camera recorded its offset, local time without it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<String>,
+    // e.g. "1/250"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exposure_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub f_number: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iso: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focal_length_mm: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps: Option<GpsPosition>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GpsPosition {
+    // decimal degrees, negative south and west
+    pub latitude: f64,
+    pub longitude: f64,
+    // meters, negative below sea level
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+}
+
+// EXIF of an encoded image (JPEG, PNG, WebP), None when it has none
+pub fn read(data: &[u8]) -> Result<Option<ExifData>> {
+    let exif = match Reader::new().read_from_container(&mut Cursor::new(data)) {
+        Ok(v) => v,
+        Err(::exif::Error::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(ExifData {
+        camera_make: ascii(&exif, Tag::Make),
+        camera_model: ascii(&exif, Tag::Model),
+        lens_make: ascii(&exif, Tag::LensMake),
+        lens_model: ascii(&exif, Tag::LensModel),
+        orientation: uint(&exif, Tag::Orientation),
+        captured_at: captured_at(&exif),
+        exposure_time: exif
+            .get_field(Tag::ExposureTime, In::PRIMARY)
+            .and_then(|f| match &f.value {
+                Value::Rational(v) => v.first().map(|r| format!("{}/{}", r.num, r.denom)),
+                _ => None,
+            }),
+        f_number: rational(&exif, Tag::FNumber, 0),
+        iso: uint(&exif, Tag::PhotographicSensitivity),
+        focal_length_mm: rational(&exif, Tag::FocalLength, 0),
+        gps: gps(&exif),
+    }))
+}
+
+// The EXIF of an encoded image as it is stored, a TIFF structure, None when it
+// has none
+pub fn raw(data: &[u8]) -> Option<Vec<u8>> {
+    Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()
+        .map(|exif| exif.buf().to_vec())
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(v) => v
+            .first()
+            .map(|s| {
+                String::from_utf8_lossy(s)
+                    .trim_end_matches('\0')
+                    .trim()
+                    .to_string()
+            })
+            .filter(|s| !s.is_empty()),
+        _ => None,
+    }
+}
+
+fn uint(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+fn rational(exif: &Exif, tag: Tag, index: usize) -> Option<f64> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(v) => v.get(index).filter(|r| r.denom != 0).map(|r| r.to_f64()),
+        _ => None,
+    }
+}
+
+fn captured_at(exif: &Exif) -> Option<String> {
+    let field = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .or_else(|| exif.get_field(Tag::DateTime, In::PRIMARY))?;
+    let Value::Ascii(v) = &field.value else {
+        return None;
+    };
+    let mut dt = DateTime::from_ascii(v.first()?).ok()?;
+    if let Some(Value::Ascii(offset)) = exif
+        .get_field(Tag::OffsetTimeOriginal, In::PRIMARY)
+        .map(|f| &f.value)
+        && let Some(offset) = offset.first()
+    {
+        let _ = dt.parse_offset(offset);
+    }
+
+    let local = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+    );
+    Some(match dt.offset {
+        Some(offset) => format!(
+            "{}{}{:02}:{:02}",
+            local,
+            if offset < 0 { '-' } else { '+' },
+            offset.unsigned_abs() / 60,
+            offset.unsigned_abs() % 60
+        ),
+        None => local,
+    })
+}
+
+// Degrees, minutes and seconds with their N/S and E/W references
+fn gps(exif: &Exif) -> Option<GpsPosition> {
+    let degrees = |tag: Tag, negative_ref: &str| {
+        let value = rational(exif, tag, 0)?
+            + rational(exif, tag, 1).unwrap_or(0.0) / 60.0
+            + rational(exif, tag, 2).unwrap_or(0.0) / 3600.0;
+        let reference = match tag {
+            Tag::GPSLatitude => ascii(exif, Tag::GPSLatitudeRef),
+            _ => ascii(exif, Tag::GPSLongitudeRef),
+        };
+        Some(match reference.as_deref() {
+            Some(r) if r.eq_ignore_ascii_case(negative_ref) => -value,
+            _ => value,
+        })
+    };
+
+    let altitude = rational(exif, Tag::GPSAltitude, 0).map(|altitude| {
+        // 1 means below sea level
+        match uint(exif, Tag::GPSAltitudeRef) {
+            Some(1) => -altitude,
+            _ => altitude,
+        }
+    });
+
+    Some(GpsPosition {
+        latitude: degrees(Tag::GPSLatitude, "S")?,
+        longitude: degrees(Tag::GPSLongitude, "W")?,
+        altitude,
+    })
+}
@@ -0,0 +1,75 @@
+use photon_rs::{PhotonImage, channels, filters, monochrome};
+
+// Names accepted by POST /api/images/{img_id}/filter, listed in
+// GET /api/capabilities
+pub const NAMES: &[&str] = &[
+    // monochrome
+    "grayscale",
+    "grayscale_human_corrected",
+    "desaturate",
+    "decompose_min",
+    "decompose_max",
+    "r_grayscale",
+    "g_grayscale",
+    "b_grayscale",
+    "sepia",
+    "invert",
+    // colour presets of photon's filters::filter
+    "oceanic",
+    "islands",
+    "marine",
+    "seagreen",
+    "flagblue",
+    "diamante",
+    "liquid",
+    "radio",
+    "twenties",
+    "rosetint",
+    "mauve",
+    "bluechrome",
+    "vintage",
+    "perfume",
+    "serenity",
+    "golden",
+    "pastel_pink",
+    "cali",
+    "dramatic",
+    "firenze",
+    "obsidian",
+    "lofi",
+    "neue",
+    "lix",
+    "ryo",
+    "duotone_violette",
+    "duotone_horizon",
+    "duotone_lilac",
+    "duotone_ochre",
+];
+
+// Applies the filter called `name`, false when there is none. Alpha is left
+// as it is.
+pub fn apply(img: &mut PhotonImage, name: &str) -> bool {
+    match name {
+        "grayscale" => monochrome::grayscale(img),
+        "grayscale_human_corrected" => monochrome::grayscale_human_corrected(img),
+        "desaturate" => monochrome::desaturate(img),
+        "decompose_min" => monochrome::decompose_min(img),
+        "decompose_max" => monochrome::decompose_max(img),
+        "r_grayscale" => monochrome::r_grayscale(img),
+        "g_grayscale" => monochrome::g_grayscale(img),
+        "b_grayscale" => monochrome::b_grayscale(img),
+        "sepia" => monochrome::sepia(img),
+        "invert" => channels::invert(img),
+        "neue" => filters::neue(img),
+        "lix" => filters::lix(img),
+        "ryo" => filters::ryo(img),
+        "duotone_violette" => filters::duotone_violette(img),
+        "duotone_horizon" => filters::duotone_horizon(img),
+        "duotone_lilac" => filters::duotone_lilac(img),
+        "duotone_ochre" => filters::duotone_ochre(img),
+        // photon turns unknown names into mauve, so they are checked first
+        name if NAMES.contains(&name) => filters::filter(img, name),
+        _ => return false,
+    }
+    true
+}
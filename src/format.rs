@@ -0,0 +1,371 @@
+use axum::http::StatusCode;
+use std::path::Path;
+
+use crate::state::AppConfig;
+
+// Bytes of the start of a file ImageFormat::sniff needs to look at
+pub const SNIFF_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Avif,
+    Unknown,
+}
+
+impl ImageFormat {
+    // Every format brushbloom can store and serve
+    pub const KNOWN: [ImageFormat; 5] = [
+        ImageFormat::Jpeg,
+        ImageFormat::Png,
+        ImageFormat::Gif,
+        ImageFormat::WebP,
+        ImageFormat::Avif,
+    ];
+
+    // Format named in a request, e.g. "jpeg", "jpg" or "png"
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "jpg" => ImageFormat::Jpeg,
+            name => ImageFormat::from_fmt(&format!(".{}", name)),
+        }
+    }
+
+    // Formats photon can decode, the others are stored and served as they are
+    pub fn is_decodable(&self) -> bool {
+        matches!(
+            self,
+            ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP
+        )
+    }
+
+    // Formats that can store transparency, the others are flattened onto a
+    // background, see alpha::flatten
+    pub fn has_alpha(&self) -> bool {
+        matches!(
+            self,
+            ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP | ImageFormat::Avif
+        )
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            ImageFormat::Jpeg => ".jpeg",
+            ImageFormat::Png => ".png",
+            ImageFormat::Gif => ".gif",
+            ImageFormat::WebP => ".webp",
+            ImageFormat::Avif => ".avif",
+            ImageFormat::Unknown => "",
+        }
+    }
+
+    // Maps the extension stored in ImgMetadata.fmt back to a format
+    pub fn from_fmt(fmt: &str) -> Self {
+        match fmt {
+            ".jpeg" => ImageFormat::Jpeg,
+            ".png" => ImageFormat::Png,
+            ".gif" => ImageFormat::Gif,
+            ".webp" => ImageFormat::WebP,
+            ".avif" => ImageFormat::Avif,
+            _ => ImageFormat::Unknown,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::WebP => "image/webp",
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Unknown => "application/octet-stream",
+        }
+    }
+
+    // Width and height from the start of the file, without decoding it
+    pub fn dimensions(&self, data: &[u8]) -> Option<(u32, u32)> {
+        let be16 = |i: usize| Some(u16::from_be_bytes(data.get(i..i + 2)?.try_into().ok()?) as u32);
+        let le16 = |i: usize| Some(u16::from_le_bytes(data.get(i..i + 2)?.try_into().ok()?) as u32);
+        let le24 = |i: usize| {
+            let b = data.get(i..i + 3)?;
+            Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+        };
+
+        match self {
+            ImageFormat::Png => {
+                let w = u32::from_be_bytes(data.get(16..20)?.try_into().ok()?);
+                let h = u32::from_be_bytes(data.get(20..24)?.try_into().ok()?);
+                Some((w, h))
+            }
+            ImageFormat::Gif => Some((le16(6)?, le16(8)?)),
+            ImageFormat::WebP => match data.get(12..16)? {
+                b"VP8 " => Some((le16(26)? & 0x3fff, le16(28)? & 0x3fff)),
+                b"VP8L" => {
+                    let b = data.get(21..25)?;
+                    let w = 1 + (b[0] as u32 | (b[1] as u32 & 0x3f) << 8);
+                    let h =
+                        1 + (b[1] as u32 >> 6 | (b[2] as u32) << 2 | (b[3] as u32 & 0x0f) << 10);
+                    Some((w, h))
+                }
+                b"VP8X" => Some((1 + le24(24)?, 1 + le24(27)?)),
+                _ => None,
+            },
+            ImageFormat::Jpeg => {
+                // walk the segments up to the start of frame
+                let mut pos = 2;
+                loop {
+                    if *data.get(pos)? != 0xff {
+                        return None;
+                    }
+                    let marker = *data.get(pos + 1)?;
+                    match marker {
+                        0xff => pos += 1,
+                        0xc0..=0xcf if ![0xc4, 0xc8, 0xcc].contains(&marker) => {
+                            return Some((be16(pos + 7)?, be16(pos + 5)?));
+                        }
+                        _ => pos += 2 + be16(pos + 2)? as usize,
+                    }
+                }
+            }
+            ImageFormat::Avif | ImageFormat::Unknown => None,
+        }
+    }
+
+    // Whether `data` is a GIF of more than one frame. Walks its blocks up to
+    // the second image descriptor.
+    pub fn is_animated(&self, data: &[u8]) -> bool {
+        *self == ImageFormat::Gif && gif_frames(data, 2) >= 2
+    }
+
+    // Format from the magic bytes (the first SNIFF_LEN are enough), for data
+    // whose Content-Type is not trusted
+    pub fn sniff(data: &[u8]) -> Self {
+        if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            ImageFormat::Jpeg
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            ImageFormat::Png
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            ImageFormat::Gif
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+            ImageFormat::WebP
+        } else if is_avif(data) {
+            ImageFormat::Avif
+        } else {
+            ImageFormat::Unknown
+        }
+    }
+
+    // Used for files that arrive without a Content-Type (e.g. the watch folder)
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+
+        match ext.as_deref() {
+            Some("jpg") | Some("jpeg") => ImageFormat::Jpeg,
+            Some("png") => ImageFormat::Png,
+            Some("gif") => ImageFormat::Gif,
+            Some("webp") => ImageFormat::WebP,
+            Some("avif") => ImageFormat::Avif,
+            _ => ImageFormat::Unknown,
+        }
+    }
+}
+
+// Format a deployment does not work with, see [formats] in config.toml
+#[derive(Debug, thiserror::Error)]
+pub enum FormatNotAllowed {
+    #[error("{0} images are not accepted")]
+    Input(String),
+    #[error("Cannot convert to {0:?}")]
+    Output(String),
+}
+
+impl FormatNotAllowed {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FormatNotAllowed::Input(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            FormatNotAllowed::Output(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+// Whether images in `fmt` may be stored, by upload or ingestion
+pub fn accepts(conf: &AppConfig, fmt: ImageFormat) -> bool {
+    fmt != ImageFormat::Unknown && allows(conf.formats.input.as_deref(), fmt)
+}
+
+// Whether clients may convert images to `fmt`
+pub fn produces(conf: &AppConfig, fmt: ImageFormat) -> bool {
+    fmt.is_decodable() && allows(conf.formats.output.as_deref(), fmt)
+}
+
+fn allows(names: Option<&[String]>, fmt: ImageFormat) -> bool {
+    names.is_none_or(|names| names.iter().any(|n| ImageFormat::from_name(n) == fmt))
+}
+
+pub fn check_input(conf: &AppConfig, fmt: ImageFormat) -> Result<(), FormatNotAllowed> {
+    match accepts(conf, fmt) {
+        true => Ok(()),
+        false => Err(FormatNotAllowed::Input(
+            fmt.as_str().trim_start_matches('.').to_uppercase(),
+        )),
+    }
+}
+
+// Output format named in a request, e.g. `?fmt=webp`
+pub fn output_format(conf: &AppConfig, name: &str) -> Result<ImageFormat, FormatNotAllowed> {
+    let fmt = ImageFormat::from_name(name);
+    match produces(conf, fmt) {
+        true => Ok(fmt),
+        false => Err(FormatNotAllowed::Output(name.to_string())),
+    }
+}
+
+pub fn detect_image_format(content_type: String) -> ImageFormat {
+    match content_type.to_lowercase().as_str() {
+        "image/jpeg" => ImageFormat::Jpeg,
+        "image/png" => ImageFormat::Png,
+        "image/gif" => ImageFormat::Gif,
+        "image/webp" => ImageFormat::WebP,
+        "image/avif" => ImageFormat::Avif,
+        _ => ImageFormat::Unknown,
+    }
+}
+
+// Image descriptors in GIF `data`, counted up to `max`
+fn gif_frames(data: &[u8], max: usize) -> usize {
+    // size of a color table from the flags of the block it follows
+    let table = |flags: u8| match flags & 0x80 {
+        0 => 0,
+        _ => 3 << ((flags & 0x07) + 1),
+    };
+    // data sub-blocks, up to the empty one that ends them
+    let skip_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *data.get(pos)? as usize;
+            pos += 1 + len;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    };
+
+    let Some(&flags) = data.get(10) else {
+        return 0;
+    };
+    let mut pos = 13 + table(flags);
+    let mut frames = 0;
+    while frames < max {
+        let next = match data.get(pos) {
+            // extension: label, then its sub-blocks
+            Some(0x21) => skip_blocks(pos + 2),
+            Some(0x2c) => {
+                frames += 1;
+                // descriptor, local color table and LZW code size
+                data.get(pos + 9)
+                    .and_then(|&flags| skip_blocks(pos + 11 + table(flags)))
+            }
+            // the trailer, or not a GIF
+            _ => None,
+        };
+        match next {
+            Some(next) => pos = next,
+            None => break,
+        }
+    }
+    frames
+}
+
+// An ISO-BMFF file whose ftyp box lists the avif (still) or avis (sequence)
+// brand, as major or compatible brand
+fn is_avif(data: &[u8]) -> bool {
+    if data.get(4..8) != Some(b"ftyp") {
+        return false;
+    }
+    let Some(size) = data.get(0..4).and_then(|b| b.try_into().ok()) else {
+        return false;
+    };
+    let Some(ftyp) = data.get(8..(u32::from_be_bytes(size) as usize).min(data.len())) else {
+        return false;
+    };
+
+    // major brand, minor version, compatible brands
+    ftyp.chunks_exact(4)
+        .enumerate()
+        .any(|(i, brand)| i != 1 && (brand == b"avif" || brand == b"avis"))
+}
+
+// Luminance quantization table of the JPEG specification (Annex K), which
+// encoders like libjpeg scale for their quality setting
+const JPEG_LUMINANCE_TABLE: [u32; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+// Quality (1-100) a JPEG was most likely saved with, found by comparing its
+// luminance table with the standard one scaled the way libjpeg does. None
+// when the file has no such table before its image data.
+pub fn jpeg_quality(data: &[u8]) -> Option<u8> {
+    let be16 = |i: usize| Some(u16::from_be_bytes(data.get(i..i + 2)?.try_into().ok()?) as usize);
+
+    let mut pos = 2;
+    let table_sum = loop {
+        if *data.get(pos)? != 0xff {
+            return None;
+        }
+        match *data.get(pos + 1)? {
+            0xff => pos += 1,
+            // start of scan, the tables come before it
+            0xda => return None,
+            0xdb => {
+                let end = pos + 2 + be16(pos + 2)?;
+                if let Some(sum) = luminance_table_sum(data.get(pos + 4..end)?) {
+                    break sum;
+                }
+                pos = end;
+            }
+            _ => pos += 2 + be16(pos + 2)?,
+        }
+    };
+
+    (1..=100u32)
+        .min_by_key(|quality| {
+            let scale = if *quality < 50 {
+                5000 / quality
+            } else {
+                200 - 2 * quality
+            };
+            let sum: u32 = JPEG_LUMINANCE_TABLE
+                .iter()
+                .map(|v| ((v * scale + 50) / 100).clamp(1, 255))
+                .sum();
+            sum.abs_diff(table_sum)
+        })
+        .map(|quality| quality as u8)
+}
+
+// Sum of table 0 among the tables of a DQT segment, each an id and
+// precision byte followed by 64 values of 8 or 16 bits
+fn luminance_table_sum(mut tables: &[u8]) -> Option<u32> {
+    while let Some(&info) = tables.first() {
+        let wide = info >> 4 != 0;
+        let table = tables.get(1..if wide { 129 } else { 65 })?;
+        if info & 0x0f == 0 {
+            return Some(match wide {
+                false => table.iter().map(|v| *v as u32).sum(),
+                true => table
+                    .chunks_exact(2)
+                    .map(|v| u16::from_be_bytes([v[0], v[1]]) as u32)
+                    .sum(),
+            });
+        }
+        tables = &tables[1 + table.len()..];
+    }
+    None
+}
@@ -0,0 +1,923 @@
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{Response, StatusCode, header},
+    middleware::Next,
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{self, AuditEvent},
+    blobs, budget, decode,
+    disk::DiskReport,
+    embedding,
+    error::{AppError, build_err_response},
+    format::{self, ImageFormat, jpeg_quality},
+    handlers::{
+        ImgId, ImgMetadata, LegalHold, encode_image,
+        thumbnail::{self, ThumbnailSpec},
+    },
+    jobs::JobProgress,
+    state::{AppConfig, AppState, ThumbnailPreset},
+    storage::{
+        image_path, list_images, list_untracked_files, locate_image, read_image_data,
+        reencrypt_file, reencrypt_meta, remove_thumbnails, replace_image, thumbnail_path,
+        unlink_image, update_meta,
+    },
+    tenant::Tenant,
+};
+
+const ADMIN_KEY_HEADER: &str = "X-Admin-Key";
+
+const DEFAULT_REPORT_LIMIT: usize = 20;
+const MAX_REPORT_LIMIT: usize = 500;
+const DEFAULT_REENCODE_AGE_DAYS: u64 = 30;
+// Typical size reduction of WebP over JPEG at comparable quality
+const WEBP_SAVINGS_RATIO: f64 = 0.3;
+
+// Admin routes act on the tenant of the API key, see tenant::authenticate,
+// and need the key of [admin] on top. Tenant keys alone never reach them.
+pub async fn authorize(State(state): State<AppState>, req: Request, next: Next) -> Response<Body> {
+    let Some(admin) = &state.conf.admin else {
+        return build_err_response(
+            StatusCode::FORBIDDEN,
+            "Admin routes are not enabled".to_string(),
+        );
+    };
+    let key = req
+        .headers()
+        .get(ADMIN_KEY_HEADER)
+        .and_then(|v| v.to_str().ok());
+    if key.is_none_or(|key| key.trim() != admin.api_key) {
+        warn!("rejected admin request: {}", req.uri().path());
+        return build_err_response(StatusCode::UNAUTHORIZED, "Invalid admin key".to_string());
+    }
+    next.run(req).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReencodeRequest {
+    // format to convert to, e.g. "webp"
+    target: String,
+    // JPEG quality of the new files
+    quality: Option<u8>,
+    // formats of the originals to convert
+    #[serde(default = "default_reencode_formats")]
+    formats: Vec<String>,
+    // only JPEGs saved with at least this (estimated) quality
+    min_quality: Option<u8>,
+    // only images stored before this RFC 3339 time
+    older_than: Option<String>,
+    // keep the old files as versions of the images instead of deleting them
+    #[serde(default)]
+    keep_originals: bool,
+}
+
+fn default_reencode_formats() -> Vec<String> {
+    vec!["jpeg".to_string()]
+}
+
+// Answer to admin requests working through many images in a job
+#[derive(Serialize)]
+struct BatchJobResponse {
+    job_id: String,
+    total: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HoldRequest {
+    // e.g. the matter or case number, recorded in the audit log
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    img_id: Option<String>,
+    recipe: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DedupReportQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct DedupReport {
+    // distinct files of uploads, see blobs
+    blobs: usize,
+    stored_bytes: u64,
+    // what the images and versions sharing them would take as copies
+    referenced_bytes: u64,
+    saved_bytes: u64,
+    // blobs nothing refers to, left behind by failures
+    unreferenced_blobs: usize,
+    // what storing the duplicates that have files of their own once would
+    // save, e.g. of images uploaded before dedup_uploads
+    duplicate_bytes: u64,
+    // contents of the most images
+    duplicates: Vec<DuplicateContent>,
+}
+
+#[derive(Serialize)]
+struct DuplicateContent {
+    content_hash: String,
+    size_in_bytes: u32,
+    img_ids: Vec<String>,
+    // how many of the images share the blob of the content
+    shared: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TopImagesQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct TopImage {
+    img_id: String,
+    fmt: String,
+    size_in_bytes: u32,
+    access_count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_accessed: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpaceReportQuery {
+    limit: Option<usize>,
+    older_than_days: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SpaceReport {
+    total_bytes: u64,
+    image_count: usize,
+    by_format: BTreeMap<String, FormatUsage>,
+    largest: Vec<LargeImage>,
+    derived: DerivedUsage,
+    reencode: ReencodeEstimate,
+    // free space of the volume with [disk_space]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disk: Option<DiskReport>,
+}
+
+#[derive(Default, Serialize)]
+struct FormatUsage {
+    count: usize,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct LargeImage {
+    img_id: String,
+    fmt: String,
+    size_in_bytes: u32,
+    modified: String,
+    access_count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_accessed: Option<String>,
+}
+
+// Transform outputs are stored without metadata, so they are only visible
+// as files in the upload directory
+#[derive(Serialize)]
+struct DerivedUsage {
+    count: usize,
+    bytes: u64,
+    largest: Vec<DerivedFile>,
+}
+
+#[derive(Serialize)]
+struct DerivedFile {
+    name: String,
+    size_in_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct ReencodeEstimate {
+    target: &'static str,
+    older_than_days: u64,
+    candidates: usize,
+    candidate_bytes: u64,
+    estimated_savings_bytes: u64,
+}
+
+#[derive(Default, Serialize)]
+struct ReencryptReport {
+    rewritten: usize,
+    current: usize,
+    // names of the images and files that could not be rewritten
+    failed: Vec<String>,
+}
+
+impl ReencryptReport {
+    fn count(&mut self, name: &str, res: anyhow::Result<bool>) {
+        match res {
+            Ok(true) => self.rewritten += 1,
+            Ok(false) => self.current += 1,
+            Err(e) => {
+                warn!("failed to reencrypt {}: {}", name, e);
+                self.failed.push(name.to_string());
+            }
+        }
+    }
+}
+
+pub async fn space_report(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<SpaceReportQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    info!("space report request: {:?}", query);
+
+    let conf = tenant.scope(&state.conf);
+
+    let (images, mut derived_files) =
+        match tokio::try_join!(list_images(&conf), list_untracked_files(&conf)) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("failed to scan storage: {}", e);
+                return Err(AppError::Internal("Failed to scan storage".to_string()));
+            }
+        };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_REPORT_LIMIT)
+        .clamp(1, MAX_REPORT_LIMIT);
+    let older_than_days = query.older_than_days.unwrap_or(DEFAULT_REENCODE_AGE_DAYS);
+    let cutoff = SystemTime::now()
+        .checked_sub(Duration::from_secs(older_than_days * 24 * 60 * 60))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut by_format: BTreeMap<String, FormatUsage> = BTreeMap::new();
+    let mut candidates = 0;
+    let mut candidate_bytes = 0;
+    for (_, meta, modified) in &images {
+        let usage = by_format.entry(meta.fmt.clone()).or_default();
+        usage.count += 1;
+        usage.bytes += meta.size_in_bytes as u64;
+
+        if ImageFormat::from_fmt(&meta.fmt) == ImageFormat::Jpeg && *modified < cutoff {
+            candidates += 1;
+            candidate_bytes += meta.size_in_bytes as u64;
+        }
+    }
+
+    let mut largest: Vec<_> = images.iter().collect();
+    largest.sort_by_key(|(_, meta, _)| Reverse(meta.size_in_bytes));
+    let largest = largest
+        .into_iter()
+        .take(limit)
+        .map(|(img_id, meta, modified)| LargeImage {
+            img_id: img_id.clone(),
+            fmt: meta.fmt.clone(),
+            size_in_bytes: meta.size_in_bytes,
+            modified: OffsetDateTime::from(*modified)
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            access_count: meta.access_count,
+            last_accessed: meta.last_accessed.clone(),
+        })
+        .collect();
+
+    let derived_bytes = derived_files.iter().map(|(_, size)| size).sum();
+    derived_files.sort_by_key(|(_, size)| Reverse(*size));
+    let derived = DerivedUsage {
+        count: derived_files.len(),
+        bytes: derived_bytes,
+        largest: derived_files
+            .into_iter()
+            .take(limit)
+            .map(|(name, size_in_bytes)| DerivedFile {
+                name,
+                size_in_bytes,
+            })
+            .collect(),
+    };
+
+    let report = SpaceReport {
+        total_bytes: by_format.values().map(|u| u.bytes).sum::<u64>() + derived_bytes,
+        image_count: images.len(),
+        by_format,
+        largest,
+        derived,
+        reencode: ReencodeEstimate {
+            target: "webp",
+            older_than_days,
+            candidates,
+            candidate_bytes,
+            estimated_savings_bytes: (candidate_bytes as f64 * WEBP_SAVINGS_RATIO) as u64,
+        },
+        disk: state
+            .conf
+            .disk_space
+            .as_ref()
+            .map(|disk| state.disk.report(disk)),
+    };
+
+    Ok(Json(report))
+}
+
+// How much dedup_uploads saves, and the images with the same content
+pub async fn dedup_report(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<DedupReportQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    info!("dedup report request: {:?}", query);
+
+    let conf = tenant.scope(&state.conf);
+
+    let (blobs, images) = match tokio::try_join!(blobs::list(&conf), list_images(&conf)) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to scan storage: {}", e);
+            return Err(AppError::Internal("Failed to scan storage".to_string()));
+        }
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_REPORT_LIMIT)
+        .clamp(1, MAX_REPORT_LIMIT);
+
+    let mut by_hash: BTreeMap<String, Vec<(String, ImgMetadata)>> = BTreeMap::new();
+    for (img_id, meta, _) in images {
+        if let Some(hash) = meta.content_hash.clone() {
+            by_hash.entry(hash).or_default().push((img_id, meta));
+        }
+    }
+    let inodes: HashMap<&str, u64> = blobs.iter().map(|b| (b.hash.as_str(), b.ino)).collect();
+
+    let mut duplicates = Vec::new();
+    let mut duplicate_bytes = 0;
+    for (hash, group) in by_hash.into_iter().filter(|(_, group)| group.len() > 1) {
+        let mut shared = 0;
+        for (img_id, meta) in &group {
+            let ino = blobs::inode(&image_path(&conf, img_id, &meta.fmt)).await;
+            if ino.is_some() && ino == inodes.get(hash.as_str()).copied() {
+                shared += 1;
+            }
+        }
+        // one copy stays unless the blob is kept already
+        let copies = (group.len() - shared).saturating_sub((shared == 0) as usize);
+        let size_in_bytes = group[0].1.size_in_bytes;
+        duplicate_bytes += copies as u64 * size_in_bytes as u64;
+        duplicates.push(DuplicateContent {
+            content_hash: hash,
+            size_in_bytes,
+            img_ids: group.into_iter().map(|(img_id, _)| img_id).collect(),
+            shared,
+        });
+    }
+    duplicates.sort_by_key(|d| Reverse(d.img_ids.len()));
+    duplicates.truncate(limit);
+
+    let report = DedupReport {
+        blobs: blobs.len(),
+        stored_bytes: blobs.iter().map(|b| b.size).sum(),
+        referenced_bytes: blobs.iter().map(|b| b.size * b.refs).sum(),
+        saved_bytes: blobs
+            .iter()
+            .map(|b| b.size * b.refs.saturating_sub(1))
+            .sum(),
+        unreferenced_blobs: blobs.iter().filter(|b| b.refs == 0).count(),
+        duplicate_bytes,
+        duplicates,
+    };
+
+    Ok(Json(report))
+}
+
+// Most read images first. Counts lag behind by up to one access tracker flush.
+pub async fn top_images(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<TopImagesQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    info!("top images request: {:?}", query);
+
+    let conf = tenant.scope(&state.conf);
+
+    let mut images = match list_images(&conf).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to list images: {}", e);
+            return Err(AppError::Internal("Failed to list images".to_string()));
+        }
+    };
+
+    images.sort_by_key(|(_, meta, _)| Reverse(meta.access_count));
+    let top: Vec<TopImage> = images
+        .into_iter()
+        .take(
+            query
+                .limit
+                .unwrap_or(DEFAULT_REPORT_LIMIT)
+                .clamp(1, MAX_REPORT_LIMIT),
+        )
+        .map(|(img_id, meta, _)| TopImage {
+            img_id,
+            fmt: meta.fmt,
+            size_in_bytes: meta.size_in_bytes,
+            access_count: meta.access_count,
+            last_accessed: meta.last_accessed,
+        })
+        .collect();
+
+    Ok(Json(top))
+}
+
+// Rewrites every image, metadata file and transform output of the tenant that
+// is not encrypted with the current (first) key, e.g. after a new key was
+// added for rotation or encryption was enabled on existing storage. Once it
+// reports no failures, the old key can be removed from the config.
+pub async fn reencrypt(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Result<impl IntoResponse, AppError> {
+    info!("reencrypt request");
+
+    let conf = tenant.scope(&state.conf);
+    if conf.encryption.is_none() {
+        return Err(AppError::BadRequest(
+            "Encryption at rest is not configured".to_string(),
+        ));
+    }
+
+    let (images, derived_files) =
+        match tokio::try_join!(list_images(&conf), list_untracked_files(&conf)) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("failed to scan storage: {}", e);
+                return Err(AppError::Internal("Failed to scan storage".to_string()));
+            }
+        };
+
+    let mut report = ReencryptReport::default();
+    for (img_id, meta, _) in &images {
+        let res = match reencrypt_file(&conf, &image_path(&conf, img_id, &meta.fmt)).await {
+            Ok(image) => reencrypt_meta(&conf, img_id)
+                .await
+                .map(|meta| image || meta),
+            Err(e) => Err(e),
+        };
+        report.count(img_id, res);
+    }
+    for (name, _) in &derived_files {
+        let res = reencrypt_file(&conf, &std::path::Path::new(&conf.file_path).join(name)).await;
+        report.count(name, res);
+    }
+
+    info!(
+        "reencrypted {} files, {} already current, {} failed",
+        report.rewritten,
+        report.current,
+        report.failed.len()
+    );
+    Ok(Json(report))
+}
+
+// Converts the stored originals matching the request to another format in a
+// background job, polled through GET /api/jobs/{job_id}. Images are handled
+// one at a time so the job never holds more than one decode of the memory
+// budget. Animated GIFs keep their first frame only.
+pub async fn reencode(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(req): Json<ReencodeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    info!("reencode request: {:?}", req);
+
+    let target = format::output_format(&state.conf, &req.target)?;
+    if [req.quality, req.min_quality]
+        .iter()
+        .flatten()
+        .any(|q| !(1..=100).contains(q))
+    {
+        return Err(AppError::BadRequest(
+            "Quality must be between 1 and 100".to_string(),
+        ));
+    }
+    let mut formats = Vec::new();
+    for name in &req.formats {
+        match ImageFormat::from_name(name) {
+            ImageFormat::Unknown => {
+                return Err(AppError::BadRequest(format!("Unknown format {}", name)));
+            }
+            fmt => formats.push(fmt),
+        }
+    }
+    let older_than = match req
+        .older_than
+        .as_deref()
+        .map(|t| OffsetDateTime::parse(t, &Rfc3339))
+    {
+        Some(Ok(t)) => Some(SystemTime::from(t)),
+        Some(Err(_)) => {
+            return Err(AppError::BadRequest(
+                "older_than must be an RFC 3339 time".to_string(),
+            ));
+        }
+        None => None,
+    };
+
+    let conf = tenant.scope(&state.conf);
+    let images = match list_images(&conf).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to list images: {}", e);
+            return Err(AppError::Internal("Failed to list images".to_string()));
+        }
+    };
+    // images already in the target format are only rewritten for a new quality,
+    // held ones not at all
+    let candidates: Vec<(String, ImageFormat)> = images
+        .into_iter()
+        .filter(|(_, meta, _)| meta.legal_hold.is_none())
+        .filter(|(_, _, modified)| older_than.is_none_or(|t| *modified < t))
+        .map(|(img_id, meta, _)| (img_id, ImageFormat::from_fmt(&meta.fmt)))
+        .filter(|(_, fmt)| formats.contains(fmt) && (*fmt != target || req.quality.is_some()))
+        .collect();
+
+    let total = candidates.len();
+    let jobs = state.jobs.clone();
+    let (base, budget) = (state.conf.clone(), state.budget.clone());
+    let job_id = state.jobs.submit(&tenant.id, |id| async move {
+        let mut progress = JobProgress {
+            total,
+            ..Default::default()
+        };
+        jobs.set_progress(&id, progress.clone());
+
+        for (img_id, fmt) in candidates {
+            let res = reencode_image(&conf, &budget, &img_id, fmt, target.as_str(), &req).await;
+            // the re-encoded file is no longer the one of a linked source
+            let res = match res {
+                Ok(true) => unlink_image(&base, &conf, &img_id).await.map(|_| true),
+                res => res,
+            };
+            match res {
+                Ok(true) => progress.converted += 1,
+                Ok(false) => progress.skipped += 1,
+                Err(e) => {
+                    warn!("failed to reencode {}: {}", img_id, e);
+                    progress.failed.push(img_id);
+                }
+            }
+            jobs.set_progress(&id, progress.clone());
+        }
+
+        info!(
+            "reencoded {} images, {} skipped, {} failed",
+            progress.converted,
+            progress.skipped,
+            progress.failed.len()
+        );
+        jobs.finish(&id);
+    })?;
+
+    info!("submitted reencode job {} for {} images", job_id, total);
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/api/jobs/{}", job_id))],
+        Json(BatchJobResponse { job_id, total }),
+    ))
+}
+
+// Re-encodes one image for the reencode job. Returns false when it is
+// skipped for being below min_quality.
+async fn reencode_image(
+    conf: &AppConfig,
+    budget: &budget::MemoryBudget,
+    img_id: &str,
+    fmt: ImageFormat,
+    target: &str,
+    req: &ReencodeRequest,
+) -> anyhow::Result<bool> {
+    let data = read_image_data(conf, &image_path(conf, img_id, fmt.as_str())).await?;
+    if let Some(min_quality) = req.min_quality
+        && fmt == ImageFormat::Jpeg
+        && jpeg_quality(&data).is_none_or(|q| q < min_quality)
+    {
+        return Ok(false);
+    }
+
+    let permit = budget
+        .acquire(budget::estimate(&fmt, &data))
+        .await
+        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    let img = decode::run(&conf.decode, fmt, data, None).await?;
+    let (task_conf, task_target, quality) = (conf.clone(), target.to_string(), req.quality);
+    let encoded =
+        tokio::task::spawn_blocking(move || encode_image(&task_conf, img, &task_target, quality))
+            .await??;
+    drop(permit);
+
+    replace_image(conf, img_id, target, encoded, req.keep_originals).await?;
+    Ok(true)
+}
+
+// Computes the embeddings of images stored before [embeddings] was set up, or
+// with another model, in a job
+pub async fn backfill_embeddings(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Result<impl IntoResponse, AppError> {
+    if state.conf.embeddings.is_none() {
+        return Err(AppError::NotFound(
+            "Similarity search is not configured".to_string(),
+        ));
+    }
+
+    let conf = tenant.scope(&state.conf);
+    let images = match list_images(&conf).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to list images: {}", e);
+            return Err(AppError::Internal("Failed to list images".to_string()));
+        }
+    };
+    let candidates: Vec<String> = images
+        .into_iter()
+        .filter(|(_, meta, _)| ImageFormat::from_fmt(&meta.fmt).is_decodable())
+        .map(|(img_id, _, _)| img_id)
+        .collect();
+
+    let total = candidates.len();
+    let jobs = state.jobs.clone();
+    let job_id = state.jobs.submit(&tenant.id, |id| async move {
+        let mut progress = JobProgress {
+            total,
+            ..Default::default()
+        };
+        jobs.set_progress(&id, progress.clone());
+
+        for img_id in candidates {
+            let stored = tokio::fs::read(embedding::embedding_path(&conf, &img_id)).await;
+            let dimensions = conf.embeddings.as_ref().map_or(0, |e| e.dimensions);
+            if stored.is_ok_and(|v| v.len() == dimensions * 4) {
+                progress.skipped += 1;
+            } else {
+                match embedding::embed_image(&conf, &img_id).await {
+                    Ok(_) => progress.converted += 1,
+                    Err(e) => {
+                        warn!("failed to embed {}: {}", img_id, e);
+                        progress.failed.push(img_id);
+                    }
+                }
+            }
+            jobs.set_progress(&id, progress.clone());
+        }
+
+        info!(
+            "embedded {} images, {} skipped, {} failed",
+            progress.converted,
+            progress.skipped,
+            progress.failed.len()
+        );
+        jobs.finish(&id);
+    })?;
+
+    info!("submitted embedding job {} for {} images", job_id, total);
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/api/jobs/{}", job_id))],
+        Json(BatchJobResponse { job_id, total }),
+    ))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RegeneratePresetQuery {
+    // make the thumbnails of the current definition now instead of on their
+    // first request
+    #[serde(default)]
+    eager: bool,
+}
+
+// Presets being regenerated, by tenant and name
+fn regenerating() -> &'static Mutex<HashSet<String>> {
+    static REGENERATING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    REGENERATING.get_or_init(Default::default)
+}
+
+// Removes the thumbnails of earlier definitions of a thumbnail preset after
+// it was changed, and with `?eager=true` makes the current ones, in a job.
+// Requests in the meantime get the current definition either way. One job
+// per preset runs at a time.
+pub async fn regenerate_preset(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(name): Path<String>,
+    Query(query): Query<RegeneratePresetQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(preset) = state.conf.thumbnail_presets.get(&name).cloned() else {
+        return Err(AppError::NotFound(format!(
+            "Unknown thumbnail preset {:?}",
+            name
+        )));
+    };
+
+    let conf = tenant.scope(&state.conf);
+    let images = match list_images(&conf).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to list images: {}", e);
+            return Err(AppError::Internal("Failed to list images".to_string()));
+        }
+    };
+
+    let key = format!("{}/{}", tenant.id, name);
+    if !regenerating().lock().unwrap().insert(key.clone()) {
+        return Err(AppError::Conflict(format!(
+            "Preset {:?} is already being regenerated",
+            name
+        )));
+    }
+
+    let total = images.len();
+    let task_state = state.clone();
+    let task_key = key.clone();
+    let res = state.jobs.submit(&tenant.id, |id| async move {
+        let jobs = &task_state.jobs;
+        let mut progress = JobProgress {
+            total,
+            ..Default::default()
+        };
+        jobs.set_progress(&id, progress.clone());
+
+        let spec = ThumbnailSpec::preset(&name, &preset);
+        for (img_id, meta, _) in images {
+            let res = regenerate_thumbnail(
+                &task_state,
+                &conf,
+                &img_id,
+                &meta,
+                &name,
+                &spec,
+                query.eager,
+            )
+            .await;
+            match res {
+                Ok(true) => progress.converted += 1,
+                Ok(false) => progress.skipped += 1,
+                Err(e) => {
+                    warn!("failed to regenerate {} of {}: {}", name, img_id, e);
+                    progress.failed.push(img_id);
+                }
+            }
+            jobs.set_progress(&id, progress.clone());
+        }
+
+        info!(
+            "regenerated preset {} of {} images, {} skipped, {} failed",
+            name,
+            progress.converted,
+            progress.skipped,
+            progress.failed.len()
+        );
+        jobs.finish(&id);
+        regenerating().lock().unwrap().remove(&task_key);
+    });
+    let job_id = match res {
+        Ok(v) => v,
+        Err(e) => {
+            regenerating().lock().unwrap().remove(&key);
+            return Err(e.into());
+        }
+    };
+
+    info!("submitted preset job {} for {} images", job_id, total);
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/api/jobs/{}", job_id))],
+        Json(BatchJobResponse { job_id, total }),
+    ))
+}
+
+// Removes the outdated thumbnails of the preset of one image and makes the
+// current one when `eager`. Returns false when there was nothing to do.
+async fn regenerate_thumbnail(
+    state: &AppState,
+    conf: &AppConfig,
+    img_id: &str,
+    meta: &ImgMetadata,
+    name: &str,
+    spec: &ThumbnailSpec,
+    eager: bool,
+) -> anyhow::Result<bool> {
+    let (_, src_fmt) = locate_image(conf, img_id, Some(meta))
+        .await
+        .ok_or_else(|| anyhow::anyhow!("image file not found"))?;
+    let fmt = thumbnail::thumbnail_format(src_fmt);
+    let (variant, focus) = spec.variant(Some(meta));
+    let current = thumbnail_path(conf, img_id, &variant, fmt.as_str());
+
+    let prefix = ThumbnailPreset::cache_prefix(name);
+    let mut changed = remove_thumbnails(conf, img_id, &prefix, &current).await? > 0;
+    if eager && meta.deleted_at.is_none() && !tokio::fs::try_exists(&current).await? {
+        thumbnail::generate(state, conf, img_id, spec, focus, fmt, &current)
+            .await
+            .map_err(|e| anyhow::anyhow!("thumbnail failed: {}", e))?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+// Places a legal hold on an image. Until it is released the image cannot be
+// deleted or replaced (strip, reencode, S3 overwrites) nor soft deleted.
+pub async fn place_hold(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Json(req): Json<HoldRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if req.reason.trim().is_empty() {
+        return Err(AppError::BadRequest("reason must not be empty".to_string()));
+    }
+
+    let conf = tenant.scope(&state.conf);
+    let hold = LegalHold {
+        reason: req.reason.clone(),
+        since: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+    };
+    let meta = update_meta(&conf, &img_id, move |meta| {
+        // the first hold and its time stay, a second one only adds an event
+        meta.legal_hold.get_or_insert(hold);
+        Ok(())
+    })
+    .await
+    .map_err(|e| {
+        warn!("failed to hold {}: {}", img_id, e);
+        AppError::from_read(&e)
+    })?;
+
+    let event = AuditEvent::new("hold_placed", &img_id, Some(req.reason));
+    audit::record(&conf, &event)
+        .await
+        .map_err(|e| AppError::StorageError(format!("Failed to record the hold: {}", e)))?;
+    info!("placed a legal hold on {}", img_id);
+    Ok(Json(meta.legal_hold))
+}
+
+pub async fn release_hold(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+) -> Result<impl IntoResponse, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let mut released = None;
+    update_meta(&conf, &img_id, |meta| {
+        released = meta.legal_hold.take();
+        Ok(())
+    })
+    .await
+    .map_err(|e| {
+        warn!("failed to release the hold of {}: {}", img_id, e);
+        AppError::from_read(&e)
+    })?;
+    let Some(hold) = released else {
+        return Err(AppError::NotFound(format!(
+            "Image {} is not under a legal hold",
+            img_id
+        )));
+    };
+
+    let event = AuditEvent::new("hold_released", &img_id, Some(hold.reason));
+    audit::record(&conf, &event)
+        .await
+        .map_err(|e| AppError::StorageError(format!("Failed to record the release: {}", e)))?;
+    info!("released the legal hold of {}", img_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Audit log of the tenant, oldest first
+pub async fn audit_log(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<AuditQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let conf = tenant.scope(&state.conf);
+    match audit::list(&conf, query.img_id.as_deref(), query.recipe.as_deref()).await {
+        Ok(events) => Ok(Json(events)),
+        Err(e) => {
+            warn!("failed to read the audit log: {}", e);
+            Err(AppError::StorageError(
+                "Failed to read the audit log".to_string(),
+            ))
+        }
+    }
+}
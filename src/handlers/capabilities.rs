@@ -0,0 +1,39 @@
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use serde::Serialize;
+
+use crate::{
+    format::{self, ImageFormat},
+    operations::{OPERATIONS, Operation},
+    state::AppState,
+};
+
+#[derive(Serialize)]
+struct Capabilities {
+    operations: &'static [Operation],
+    input_formats: Vec<&'static str>,
+    // transforms write their output in the format of the source image, these
+    // are the formats it can be converted to
+    output_formats: Vec<&'static str>,
+}
+
+pub async fn capabilities(State(state): State<AppState>) -> impl IntoResponse {
+    let input_formats: Vec<&'static str> = ImageFormat::KNOWN
+        .iter()
+        .filter(|f| format::accepts(&state.conf, **f))
+        .map(|f| f.content_type())
+        .collect();
+    let output_formats: Vec<&'static str> = ImageFormat::KNOWN
+        .iter()
+        .filter(|f| format::produces(&state.conf, **f))
+        .map(|f| f.content_type())
+        .collect();
+
+    (
+        StatusCode::OK,
+        Json(Capabilities {
+            operations: OPERATIONS,
+            input_formats,
+            output_formats,
+        }),
+    )
+}
@@ -0,0 +1,78 @@
+use axum::{Extension, Json, extract::State, http::StatusCode};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    error::AppError,
+    handlers::{Comment, CommentRequest, ImgId, check_region},
+    raster,
+    state::AppState,
+    storage::{image_path, read_meta, update_meta},
+    tenant::Tenant,
+};
+
+pub async fn list_comments(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+) -> Result<Json<Vec<Comment>>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let meta = read_meta(&conf, &img_id).await.map_err(|e| {
+        warn!("failed to read metadata of {}: {}", img_id, e);
+        AppError::from_read(&e)
+    })?;
+    Ok(Json(meta.comments))
+}
+
+pub async fn add_comment(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Json(req): Json<CommentRequest>,
+) -> Result<(StatusCode, Json<Comment>), AppError> {
+    if req.author.trim().is_empty() || req.text.trim().is_empty() {
+        return Err(AppError::BadRequest(
+            "author and text must not be empty".to_string(),
+        ));
+    }
+
+    let conf = tenant.scope(&state.conf);
+    let meta = read_meta(&conf, &img_id).await.map_err(|e| {
+        warn!("failed to read metadata of {}: {}", img_id, e);
+        AppError::from_read(&e)
+    })?;
+
+    if let Some(region) = req.region {
+        let path = image_path(&conf, &img_id, &meta.fmt);
+        let (task_conf, fmt) = (conf.clone(), meta.fmt.clone());
+        let dimensions =
+            tokio::task::spawn_blocking(move || raster::probe_dimensions(&task_conf, &path, &fmt))
+                .await
+                .ok()
+                .and_then(|res| res.ok())
+                .flatten();
+        check_region(region, dimensions).map_err(AppError::BadRequest)?;
+    }
+
+    let comment = Comment {
+        id: Uuid::new_v4().to_string(),
+        author: req.author,
+        text: req.text,
+        created: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+        region: req.region,
+    };
+
+    let added = comment.clone();
+    update_meta(&conf, &img_id, move |meta| {
+        meta.comments.push(added);
+        Ok(())
+    })
+    .await
+    .map_err(|e| AppError::StorageError(e.to_string()))?;
+
+    info!("{} commented on {}", comment.author, img_id);
+    Ok((StatusCode::CREATED, Json(comment)))
+}
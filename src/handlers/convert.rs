@@ -0,0 +1,102 @@
+use axum::{Extension, Json, body::Body, extract::State, http::Response, response::IntoResponse};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    error::AppError,
+    format::{self, ImageFormat},
+    handlers::{FileResponse, ImgId, encode_image, image::read_image},
+    state::AppState,
+    storage::{check_hold, image_path, read_image_data, read_meta, replace_image, unlink_image},
+    tenant::Tenant,
+};
+
+// Body of POST /api/images/{img_id}/convert
+#[derive(Debug, Deserialize)]
+pub struct ConvertImageRequest {
+    // e.g. "webp"
+    format: String,
+    // JPEG quality of the new file
+    quality: Option<u8>,
+}
+
+// Re-encodes the stored file in another format, keeping the id of the
+// image. Its metadata and the extension of the file follow the new format.
+pub async fn convert_image(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Json(req): Json<ConvertImageRequest>,
+) -> Result<Response<Body>, AppError> {
+    info!("convert request: {} {:?}", img_id, req);
+
+    let target = format::output_format(&state.conf, &req.format)?;
+    if let Some(quality) = req.quality {
+        if target != ImageFormat::Jpeg {
+            return Err(AppError::BadRequest(
+                "Quality only applies to JPEG".to_string(),
+            ));
+        }
+        if !(1..=100).contains(&quality) {
+            return Err(AppError::BadRequest(
+                "Quality must be between 1 and 100".to_string(),
+            ));
+        }
+    }
+
+    let conf = tenant.scope(&state.conf);
+    // transform outputs without metadata cannot be replaced
+    let meta = match read_meta(&conf, &img_id).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read metadata of {}: {}", img_id, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+    check_hold(&img_id, &meta)?;
+    let source = ImageFormat::from_fmt(&meta.fmt);
+    if source == target && req.quality.is_none() {
+        return Err(AppError::BadRequest(format!(
+            "Image is already {}",
+            req.format
+        )));
+    }
+    // only the first frame would be converted
+    if source == ImageFormat::Gif {
+        let data = read_image_data(&conf, &image_path(&conf, &img_id, &meta.fmt))
+            .await
+            .map_err(|e| AppError::from_read(&e))?;
+        if source.is_animated(&data) {
+            return Err(AppError::Unprocessable(
+                "Animated GIFs cannot be converted".to_string(),
+            ));
+        }
+    }
+
+    let (img, _, permit) = read_image(&conf, &state.budget, &img_id, None, None).await?;
+    let (task_conf, quality) = (conf.clone(), req.quality);
+    let encoded = tokio::task::spawn_blocking(move || {
+        encode_image(&task_conf, img, target.as_str(), quality)
+    })
+    .await;
+    drop(permit);
+    let encoded = match encoded {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => return Err(AppError::Unprocessable(e.to_string())),
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let res = match replace_image(&conf, &img_id, target.as_str(), encoded, false).await {
+        Ok(_) => unlink_image(&state.conf, &conf, &img_id).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(meta) => Ok(Json(FileResponse { id: img_id, meta }).into_response()),
+        Err(e) => {
+            warn!("failed to convert {}: {}", img_id, e);
+            Err(AppError::StorageError(
+                "Failed to convert image".to_string(),
+            ))
+        }
+    }
+}
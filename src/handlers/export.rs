@@ -0,0 +1,243 @@
+use anyhow::Result;
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{Response, header},
+    response::IntoResponse,
+};
+use photon_rs::PhotonImage;
+use std::io::{Cursor, Write};
+use tracing::{info, warn};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    error::AppError,
+    format::{self, FormatNotAllowed},
+    handlers::{
+        Bundle, ExportQuery, ExportResponse, ExportedImage, ImgId, ImgMetadata, Output,
+        PrintRequest, TransformQuery, cover_exact, encode_image, image::read_image,
+        write_new_image,
+    },
+    print::{self, PrintFormat},
+    resize::Resizer,
+    state::{AppConfig, AppState, ExportOutput, RenderingIntent},
+    storage::is_safe_id,
+    tenant::Tenant,
+    timings::{self, Timings},
+};
+
+// Makes every file of an export profile (e.g. the icon sizes of an app) from
+// one image, decoded once. They are stored as transform outputs named after
+// the "export" operation, or returned in a zip with `?bundle=zip`.
+pub async fn export_image(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path((img_id, profile_name)): Path<(String, String)>,
+    Query(query): Query<TransformQuery>,
+    Query(export): Query<ExportQuery>,
+) -> Result<Response<Body>, AppError> {
+    if !is_safe_id(&img_id) {
+        return Err(AppError::invalid_id());
+    }
+
+    info!("export request: {} {} {:?}", img_id, profile_name, export);
+
+    let Some(profile) = state.conf.export_profiles.get(&profile_name).cloned() else {
+        return Err(AppError::NotFound(format!(
+            "Unknown export profile {:?}",
+            profile_name
+        )));
+    };
+    let conf = tenant.scope(&state.conf);
+
+    // [formats] may have changed since the profile was written
+    for out in &profile.outputs {
+        let fmt = out.format();
+        if !format::produces(&conf, fmt) {
+            let e = FormatNotAllowed::Output(fmt.as_str().trim_start_matches('.').to_string());
+            return Err(e.into());
+        }
+    }
+
+    let (photon_img, img_meta, _permit) =
+        read_image(&conf, &state.budget, &img_id, query.frame, None).await?;
+
+    let output = Output::new(&conf, &query, &img_id, "export");
+    let collector = Timings::current();
+    let task_conf = conf.clone();
+    let bundle = export.bundle;
+    let res = tokio::task::spawn_blocking(move || {
+        timings::within(collector, || match bundle {
+            Bundle::Manifest => store_outputs(
+                &task_conf,
+                &photon_img,
+                &img_meta,
+                &output,
+                &profile_name,
+                &profile.outputs,
+            )
+            .map(|images| {
+                Json(ExportResponse {
+                    profile: profile_name.clone(),
+                    images,
+                })
+                .into_response()
+            }),
+            Bundle::Zip => zip_outputs(&task_conf, &photon_img, &profile.outputs).map(|data| {
+                (
+                    [
+                        (header::CONTENT_TYPE, "application/zip".to_string()),
+                        (
+                            header::CONTENT_DISPOSITION,
+                            format!("attachment; filename=\"{}.zip\"", profile_name),
+                        ),
+                    ],
+                    data,
+                )
+                    .into_response()
+            }),
+        })
+    })
+    .await;
+
+    match res {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(e)) => Err(e.into()),
+        Err(e) => Err(AppError::Internal(format!("Failed to export: {}", e))),
+    }
+}
+
+fn store_outputs(
+    conf: &AppConfig,
+    img: &PhotonImage,
+    img_meta: &ImgMetadata,
+    output: &Output,
+    profile_name: &str,
+    outputs: &[ExportOutput],
+) -> Result<Vec<ExportedImage>> {
+    let mut images = Vec::with_capacity(outputs.len());
+    for out in outputs {
+        let (width, height) = out.size();
+        let resized = timings::time("export", || {
+            cover_exact(img, width, height, None, conf.resizer)
+        });
+        let meta = ImgMetadata {
+            fmt: out.format().as_str().to_string(),
+            ..img_meta.clone()
+        };
+        let output = output.clone().with_params(serde_json::json!({
+            "profile": profile_name,
+            "name": out.name,
+        }));
+        images.push(ExportedImage {
+            name: out.name.clone(),
+            id: write_new_image(conf, &meta, resized, &output)?,
+            width,
+            height,
+        });
+    }
+    Ok(images)
+}
+
+// Images are already compressed, the files are stored as they are
+fn zip_outputs(conf: &AppConfig, img: &PhotonImage, outputs: &[ExportOutput]) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for out in outputs {
+        let (width, height) = out.size();
+        let resized = timings::time("export", || {
+            cover_exact(img, width, height, None, conf.resizer)
+        });
+        let data = timings::time("encode", || {
+            encode_image(conf, resized, out.format().as_str(), None)
+        })?;
+        zip.start_file(out.name.as_str(), options)?;
+        zip.write_all(&data)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+// The image at the trim size of the request with its bleed, converted to CMYK
+// with the configured profile, as a PDF or TIFF download. Nothing is stored.
+pub async fn export_print(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(req): Json<PrintRequest>,
+) -> Result<Response<Body>, AppError> {
+    info!("print request: {} {:?}", img_id, req);
+
+    let Some(print_conf) = state.conf.print.clone() else {
+        return Err(AppError::NotFound(
+            "Print export is not configured".to_string(),
+        ));
+    };
+    if let Err(e) = req.validate() {
+        return Err(AppError::Unprocessable(e.to_string()));
+    }
+    let conf = tenant.scope(&state.conf);
+
+    let profile = match tokio::fs::read(&print_conf.icc_profile).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read {:?}: {}", print_conf.icc_profile, e);
+            return Err(AppError::Internal(
+                "Failed to read the ICC profile".to_string(),
+            ));
+        }
+    };
+
+    let (photon_img, _, _permit) =
+        read_image(&conf, &state.budget, &img_id, query.frame, None).await?;
+
+    let collector = Timings::current();
+    let (resizer, format) = (conf.resizer, req.format);
+    let res = tokio::task::spawn_blocking(move || {
+        timings::within(collector, || {
+            render_print(&photon_img, &req, &profile, print_conf.intent, resizer)
+        })
+    })
+    .await;
+
+    match res {
+        Ok(Ok(data)) => Ok((
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}.{}\"", img_id, format.extension()),
+                ),
+            ],
+            data,
+        )
+            .into_response()),
+        Ok(Err(e)) => Err(e.into()),
+        Err(e) => Err(AppError::Internal(format!(
+            "Failed to export for print: {}",
+            e
+        ))),
+    }
+}
+
+fn render_print(
+    img: &PhotonImage,
+    req: &PrintRequest,
+    profile: &[u8],
+    intent: RenderingIntent,
+    resizer: Resizer,
+) -> Result<Vec<u8>> {
+    let (width, height) = req.trim_size();
+    let bleed = print::to_pixels(req.bleed_mm, req.dpi);
+
+    let trimmed = timings::time("resize", || cover_exact(img, width, height, None, resizer));
+    let cmyk = timings::time("cmyk", || print::to_cmyk(&trimmed, profile, intent))?;
+    let cmyk = timings::time("bleed", || print::extend_bleed(&cmyk, width, height, bleed));
+
+    let (width, height) = (width + 2 * bleed, height + 2 * bleed);
+    timings::time("encode", || match req.format {
+        PrintFormat::Pdf => print::pdf(&cmyk, width, height, req.dpi, bleed, profile),
+        PrintFormat::Tiff => print::tiff(&cmyk, width, height, req.dpi, profile),
+    })
+}
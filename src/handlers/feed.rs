@@ -0,0 +1,241 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, time::SystemTime};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::{info, warn};
+
+use crate::{
+    error::AppError,
+    format::ImageFormat,
+    handlers::{ImgMetadata, xml_escape},
+    public_id, review, signing,
+    state::{AppConfig, AppState},
+    storage::list_images,
+    tenant::Tenant,
+};
+
+const DEFAULT_FEED_LIMIT: usize = 20;
+const MAX_FEED_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct FeedQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct JsonFeed {
+    version: &'static str,
+    title: String,
+    feed_url: String,
+    items: Vec<JsonFeedItem>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem {
+    id: String,
+    url: String,
+    title: String,
+    image: String,
+    date_published: String,
+    attachments: Vec<JsonFeedAttachment>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAttachment {
+    url: String,
+    mime_type: String,
+    size_in_bytes: u32,
+}
+
+struct FeedEntry {
+    img_id: String,
+    // signed with [signed_urls], so feed readers need no API key to follow it
+    url: String,
+    meta: ImgMetadata,
+    modified: SystemTime,
+}
+
+impl FeedEntry {
+    fn title(&self) -> String {
+        self.meta
+            .key
+            .clone()
+            .unwrap_or_else(|| format!("{}{}", self.img_id, self.meta.fmt))
+    }
+
+    fn content_type(&self) -> String {
+        ImageFormat::from_fmt(&self.meta.fmt)
+            .content_type()
+            .to_string()
+    }
+}
+
+pub async fn collection_feed_rss(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(collection_id): Path<String>,
+    Query(query): Query<FeedQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    info!("rss feed request for collection: {}", collection_id);
+
+    let base_url = public_url(&state.conf)?;
+    let entries = recent_images(&state, &tenant, &base_url, &collection_id, query.limit).await?;
+
+    let items: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let url = xml_escape(&entry.url);
+            format!(
+                "<item><title>{}</title><link>{}</link><guid isPermaLink=\"false\">{}</guid><pubDate>{}</pubDate><enclosure url=\"{}\" length=\"{}\" type=\"{}\"/></item>",
+                xml_escape(&entry.title()),
+                url,
+                entry.img_id,
+                httpdate::fmt_http_date(entry.modified),
+                url,
+                entry.meta.size_in_bytes,
+                entry.content_type()
+            )
+        })
+        .collect();
+
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><rss version=\"2.0\"><channel><title>{}</title><link>{}</link><description>Recent images in {}</description>{}</channel></rss>",
+        xml_escape(&collection_id),
+        xml_escape(&format!(
+            "{}/api/collections/{}/feed.rss",
+            base_url, collection_id
+        )),
+        xml_escape(&collection_id),
+        items.concat()
+    );
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")],
+        body,
+    ))
+}
+
+pub async fn collection_feed_json(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(collection_id): Path<String>,
+    Query(query): Query<FeedQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    info!("json feed request for collection: {}", collection_id);
+
+    let base_url = public_url(&state.conf)?;
+    let entries = recent_images(&state, &tenant, &base_url, &collection_id, query.limit).await?;
+
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: collection_id.clone(),
+        feed_url: format!("{}/api/collections/{}/feed.json", base_url, collection_id),
+        items: entries
+            .iter()
+            .map(|entry| JsonFeedItem {
+                id: entry.img_id.clone(),
+                url: entry.url.clone(),
+                title: entry.title(),
+                image: entry.url.clone(),
+                date_published: OffsetDateTime::from(entry.modified)
+                    .format(&Rfc3339)
+                    .unwrap_or_default(),
+                attachments: vec![JsonFeedAttachment {
+                    url: entry.url.clone(),
+                    mime_type: entry.content_type(),
+                    size_in_bytes: entry.meta.size_in_bytes,
+                }],
+            })
+            .collect(),
+    };
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/feed+json")],
+        Json(feed),
+    ))
+}
+
+// Newest images of the tenant's collection first
+async fn recent_images(
+    state: &AppState,
+    tenant: &Tenant,
+    base_url: &str,
+    collection_id: &str,
+    limit: Option<usize>,
+) -> Result<Vec<FeedEntry>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let images = list_images(&conf).await.map_err(|e| {
+        warn!("failed to list images: {}", e);
+        AppError::StorageError("Failed to list images".to_string())
+    })?;
+
+    let mut images: Vec<_> = images
+        .into_iter()
+        .filter(|(_, meta, _)| meta.collection.as_deref() == Some(collection_id))
+        .filter(|(_, meta, _)| meta.deleted_at.is_none())
+        .filter(|(_, meta, _)| review::is_servable(&conf, meta))
+        .collect();
+
+    images.sort_by_key(|(_, _, modified)| Reverse(*modified));
+    images.truncate(limit.unwrap_or(DEFAULT_FEED_LIMIT).clamp(1, MAX_FEED_LIMIT));
+
+    Ok(images
+        .into_iter()
+        .map(|(img_id, meta, modified)| FeedEntry {
+            img_id: public_id::encode(&state.conf, &img_id),
+            url: image_url(&state.conf, tenant, base_url, &img_id),
+            meta,
+            modified,
+        })
+        .collect())
+}
+
+// Link of an item, which without [signed_urls] needs the API key of the feed
+fn image_url(conf: &AppConfig, tenant: &Tenant, base_url: &str, img_id: &str) -> String {
+    match &conf.signed_urls {
+        Some(signed) => {
+            signing::url(
+                conf,
+                signed,
+                base_url,
+                &tenant.id,
+                img_id,
+                signed.default_ttl_secs,
+            )
+            .0
+        }
+        None => format!(
+            "{}/api/images/{}",
+            base_url,
+            public_id::encode(conf, img_id)
+        ),
+    }
+}
+
+// Feeds are read long after the request, their links never come from the
+// Host header
+fn public_url(conf: &AppConfig) -> Result<String, AppError> {
+    conf.public_url
+        .as_ref()
+        .map(|url| url.trim_end_matches('/').to_string())
+        .ok_or_else(|| AppError::NotFound("Feeds need public_url".to_string()))
+}
+
+pub(crate) fn base_url(state: &AppState, headers: &HeaderMap) -> String {
+    if let Some(url) = &state.conf.public_url {
+        return url.trim_end_matches('/').to_string();
+    }
+
+    let host = headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("localhost");
+    format!("http://{}", host)
+}
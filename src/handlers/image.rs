@@ -1,64 +1,89 @@
 use anyhow::{Result, anyhow};
 use axum::{
-    Json,
+    Extension, Json,
     body::Body,
-    extract::{Multipart, Path, State},
-    http::{HeaderMap, HeaderValue, Response, StatusCode},
+    extract::{
+        Multipart, Path, Query, State,
+        multipart::{Field, MultipartError},
+    },
+    http::{HeaderMap, HeaderName, HeaderValue, Response, StatusCode, header},
     response::IntoResponse,
 };
-use photon_rs::{
-    PhotonImage,
-    native::save_image,
-    transform::{compress, crop},
+use photon_rs::{PhotonImage, transform::crop};
+use sha2::{Digest, Sha256};
+use std::{
+    path::PathBuf,
+    time::{Instant, SystemTime},
 };
-use std::{fs::File, io::Write, path::PathBuf};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
 use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::{
+    alpha,
+    budget::{self, BudgetPermit, MemoryBudget},
+    burst::{self, DuplicateAction},
+    cache_control::{self, Served},
+    compare,
+    conditional::Validators,
+    crypt,
+    decode::{self, MinSize},
+    embedding,
+    error::{self, AppError},
+    exif, filter,
+    format::{self, ImageFormat, SNIFF_LEN},
     handlers::{
-        CompressImageRequest, CompressImageResponse, ErrorResponse, FileResponse, ImgMetadata,
-        ResizeImageRequest, ResizeImageResponse, WatermarkRequest, WatermarkResponse,
-        add_watermark_to_image, resize_image, save_new_iamge,
+        AdjustImageRequest, AdjustImageResponse, ApplyRecipeRequest, ApplyRecipeResponse,
+        AsyncTransformResponse, AutoTagResponse, BlurImageRequest, BlurImageResponse, BurstFrame,
+        BurstRequest, BurstResponse, CompareImageRequest, CompareImageResponse,
+        CompressImageRequest, CompressImageResponse, DeliveryQuery, DerivativesResponse,
+        FileResponse, FilterImageRequest, FilterImageResponse, Frame, Gravity, ImageMetaResponse,
+        ImgId, ImgMetadata, InpaintImageRequest, InpaintImageResponse, LineageEntry, ListedImage,
+        MAX_BURST_FRAMES, MAX_SEARCH_RESULTS, OriginResponse, Output, ResizeImageRequest,
+        ResizeImageResponse, ReviewRequest, ReviewResponse, RotateImageRequest,
+        RotateImageResponse, SearchByImageRequest, SearchByTextRequest, SearchHit, SearchResponse,
+        SharpenImageRequest, SharpenImageResponse, StampTooLarge, TooManyTiles, TransformQuery,
+        UploadQuery, WatermarkRequest, WatermarkResponse, adjust_image, blur_image, cover_exact,
+        crop_origin, encode_image, fit_dimensions, fit_image, jpeg_compress, parse_params, policy,
+        preview_dimensions, preview_image,
+        recipe::{Pipeline, StepContext, recipe_error},
+        resize_dimensions, resize_image, rotate_image, save_new_iamge, sharpen_image,
+        stamp_watermark, write_new_image,
+    },
+    inpaint::inpaint_region,
+    jobs::JobOutput,
+    lineage,
+    notify::{Alert, AlertKind},
+    quality, range,
+    raster::{self, OutOfBounds},
+    recipe,
+    report::ErrorEvent,
+    review::{self, InvalidTransition},
+    sigv4,
+    state::{AppConfig, AppState, UploadPolicy},
+    storage::{
+        ImageContent, check_hold, image_path, is_not_found, is_safe_id, list_images_where,
+        locate_image, open_image, read_image_bytes, read_image_data, read_meta, read_version,
+        replace_image, seal_blocking, store_staged_image, unlink_image, update_meta,
+        upload_staging_path,
     },
-    state::AppState,
+    store::{BoxFuture, MetaFilter},
+    strip, tagging,
+    tenant::{QuotaError, Reservation, Tenant},
+    timings::{self, Timings},
 };
 
-#[derive(Debug, PartialEq)]
-enum ImageFormat {
-    Jpeg,
-    Png,
-    Gif,
-    WebP,
-    Unknown,
-}
-
-impl ImageFormat {
-    fn as_str(&self) -> &str {
-        match self {
-            ImageFormat::Jpeg => ".jpeg",
-            ImageFormat::Png => ".png",
-            ImageFormat::Gif => ".gif",
-            ImageFormat::WebP => ".webp",
-            ImageFormat::Unknown => "",
-        }
-    }
-}
-
-fn detect_image_format(content_type: String) -> ImageFormat {
-    match content_type.to_lowercase().as_str() {
-        "image/jpeg" => ImageFormat::Jpeg,
-        "image/png" => ImageFormat::Png,
-        "image/gif" => ImageFormat::Gif,
-        "image/webp" => ImageFormat::WebP,
-        _ => ImageFormat::Unknown,
-    }
-}
-
-pub async fn upload_image(State(state): State<AppState>, mut mp: Multipart) -> impl IntoResponse {
-    let mut file_name = String::new();
-    let mut file_data = Vec::new();
-    let mut image_type = String::new();
+pub async fn upload_image(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<UploadQuery>,
+    mut mp: Multipart,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let mut upload: Option<StagedUpload> = None;
+    let mut meta = ImgMetadata::default();
 
     // Process multipart form data
     while let Some(field) = mp.next_field().await.unwrap_or(None) {
@@ -67,366 +92,2322 @@ pub async fn upload_image(State(state): State<AppState>, mut mp: Multipart) -> i
 
         match field_name.as_deref() {
             Some("file") => {
-                file_name = field
+                meta.original_filename = field
                     .file_name()
                     .map(|s| s.to_string())
-                    .unwrap_or_else(|| format!("images-{}", Uuid::new_v4().to_string()));
-
-                image_type = field.content_type().unwrap().to_string();
+                    .filter(|s| !s.is_empty());
+                let file_name = meta
+                    .original_filename
+                    .clone()
+                    .unwrap_or_else(|| format!("images-{}", Uuid::new_v4()));
                 info!("uploading file: {}", file_name);
 
-                match field.bytes().await {
-                    Ok(data) => file_data = data.to_vec(),
-                    Err(_) => {
-                        return (
-                            StatusCode::BAD_REQUEST,
-                            Json(ErrorResponse {
-                                error: "Failed to read file data".to_string(),
-                            }),
-                        )
-                            .into_response();
+                if let Some(previous) = upload.take() {
+                    previous.discard().await;
+                }
+                match StagedUpload::write(&conf, field).await {
+                    Ok(v) => upload = Some(v),
+                    Err(e) => {
+                        warn!("failed to stage upload {}: {}", file_name, e);
+                        // e.g. 413 when the body limit is hit, other errors are local
+                        return Err(match e.downcast_ref::<MultipartError>() {
+                            Some(e) => AppError::Status(e.status(), e.body_text()),
+                            None => AppError::StorageError("Failed to save file".to_string()),
+                        });
                     }
                 }
             }
+            Some("collection") => match field.text().await {
+                Ok(v) if !v.trim().is_empty() => meta.collection = Some(v.trim().to_string()),
+                Ok(_) => {}
+                Err(_) => {
+                    if let Some(upload) = upload {
+                        upload.discard().await;
+                    }
+                    return Err(AppError::BadRequest(
+                        "Failed to read collection".to_string(),
+                    ));
+                }
+            },
             _ => {} // Ignore other fields
         }
     }
 
-    info!("file_name: {}", file_name);
-    info!("file_data length: {}", file_data.len());
-
-    if file_name.is_empty() || file_data.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Missing file or filename".to_string(),
-            }),
-        )
-            .into_response();
-    }
+    let upload = match upload {
+        Some(upload) if upload.size > 0 => upload,
+        Some(upload) => {
+            upload.discard().await;
+            return Err(AppError::BadRequest("Missing file or filename".to_string()));
+        }
+        None => return Err(AppError::BadRequest("Missing file or filename".to_string())),
+    };
+    info!("file_data length: {}", upload.size);
 
-    write_file(&state, image_type, file_data)
+    let (file_id, meta) = store_upload(&state, &tenant, upload, meta, query.strip_metadata).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(FileResponse { id: file_id, meta }),
+    )
+        .into_response())
 }
 
-fn write_file(state: &AppState, image_type: String, file_data: Vec<u8>) -> Response<Body> {
-    let fp = &state.conf.file_path;
-    let image_format = detect_image_format(image_type);
+// Stores a staged upload into the namespace of `tenant`: within its quota,
+// brought to its upload policy and stripped when `strip` or the config asks
+// for it. The staged file is gone once it returns.
+pub(crate) async fn store_upload(
+    state: &AppState,
+    tenant: &Tenant,
+    upload: StagedUpload,
+    meta: ImgMetadata,
+    strip: bool,
+) -> Result<(String, ImgMetadata), AppError> {
+    let conf = tenant.scope(&state.conf);
+    let reservation = match check_quota(state, &conf, tenant, upload.size).await {
+        Ok(v) => v,
+        Err(e) => {
+            upload.discard().await;
+            return Err(e);
+        }
+    };
 
-    // Generate unique ID and file path
-    let file_id = Uuid::new_v4().to_string();
-    let file_path = PathBuf::from(format!("{}/{}{}", fp, file_id, image_format.as_str()));
+    let mut strip = conf.strip_metadata || strip;
+    let mut upload = upload;
+    if let Some(policy) = policy::for_upload(&conf, tenant, meta.collection.as_deref()) {
+        strip |= policy.strip_metadata;
+        upload = upload.apply_policy(&conf, state, policy, strip).await?;
+    }
+    let stored = write_file(&conf, upload, meta, strip).await?;
+    if let Some(reservation) = reservation {
+        reservation.commit();
+    }
+    Ok(stored)
+}
 
-    // Save file to disk
-    match File::create(&file_path) {
-        Ok(mut file) => {
-            info!("writing data to file: {:?}", file_path);
+// Upload written to a staging file as its chunks arrive, so large files are
+// never held in memory
+pub(crate) struct StagedUpload {
+    path: PathBuf,
+    size: u64,
+    // SHA-256 in hex, of the data as sent
+    hash: String,
+    // start of the data, for ImageFormat::sniff
+    head: Vec<u8>,
+}
 
-            if let Err(_) = file.write_all(&file_data) {
-                return build_err_response(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to save file".to_string(),
-                );
+impl StagedUpload {
+    async fn write(conf: &AppConfig, mut field: Field<'_>) -> Result<Self> {
+        let mut upload = StagedUpload {
+            path: upload_staging_path(conf),
+            size: 0,
+            hash: String::new(),
+            head: Vec::with_capacity(SNIFF_LEN),
+        };
+
+        let res = async {
+            // encrypted as it arrives when encryption at rest is enabled
+            let mut sealer = crypt::keyring(conf)?.map(|k| k.sealer()).transpose()?;
+            let mut file = tokio::fs::File::create(&upload.path).await?;
+            let mut hasher = Sha256::new();
+            while let Some(chunk) = field.chunk().await? {
+                hasher.update(&chunk);
+                let missing = SNIFF_LEN.saturating_sub(upload.head.len());
+                upload
+                    .head
+                    .extend_from_slice(&chunk[..missing.min(chunk.len())]);
+                upload.size += chunk.len() as u64;
+                match &mut sealer {
+                    Some(sealer) => file.write_all(&sealer.update(&chunk)?).await?,
+                    None => file.write_all(&chunk).await?,
+                }
+            }
+            if let Some(sealer) = sealer {
+                file.write_all(&sealer.finish()?).await?;
             }
+            file.sync_all().await?;
+            upload.hash = sigv4::hex(&hasher.finalize());
+            Ok::<_, anyhow::Error>(())
         }
-        Err(e) => {
-            warn!("failed create file: {}", e);
-            return build_err_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to create file".to_string(),
-            );
+        .await;
+
+        match res {
+            Ok(()) => Ok(upload),
+            Err(e) => {
+                upload.discard().await;
+                Err(e)
+            }
         }
     }
 
-    // Save metadata
-    let meta = ImgMetadata {
-        fmt: image_format.as_str().to_string(),
-        size_in_bytes: file_data.len() as u32,
-    };
-    let meta_path = PathBuf::from(format!("{}/{}", &state.conf.meta_path, file_id));
-
-    match File::create(&meta_path) {
-        Ok(mut meta_file) => {
-            let meta_json = serde_json::to_vec(&meta).unwrap();
-            if let Err(_) = meta_file.write_all(meta_json.as_slice()) {
-                return build_err_response(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to save metadata".to_string(),
-                );
+    // `data` received as a whole, e.g. the body of an S3 PUT
+    pub(crate) async fn from_data(conf: &AppConfig, data: Vec<u8>) -> Result<Self> {
+        let upload = StagedUpload {
+            path: upload_staging_path(conf),
+            size: data.len() as u64,
+            hash: sigv4::sha256_hex(&data),
+            head: data[..SNIFF_LEN.min(data.len())].to_vec(),
+        };
+        let written = async {
+            let data = seal_blocking(conf, data).await?;
+            let mut file = tokio::fs::File::create(&upload.path).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        match written {
+            Ok(()) => Ok(upload),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&upload.path).await;
+                Err(e)
             }
         }
-        Err(e) => {
-            warn!("failed create meta file: {}", e);
-            return build_err_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to create metadata file".to_string(),
-            );
+    }
+
+    // The upload brought to `policy`, see policy::apply, unless it leaves
+    // the upload as it is. Formats that are not accepted are left to
+    // write_file to refuse.
+    async fn apply_policy(
+        self,
+        conf: &AppConfig,
+        state: &AppState,
+        policy: &UploadPolicy,
+        strip: bool,
+    ) -> Result<Self, AppError> {
+        let fmt = ImageFormat::sniff(&self.head);
+        if !policy::transforms(policy, &fmt) || !format::accepts(conf, fmt) {
+            return Ok(self);
+        }
+        let data = match read_image_data(conf, &self.path).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("failed to read staged upload {:?}: {}", self.path, e);
+                self.discard().await;
+                return Err(AppError::StorageError("Failed to save file".to_string()));
+            }
+        };
+        let data = match policy::apply(conf, state, policy, fmt, data, strip).await {
+            Ok(Some((_, data))) => data,
+            Ok(None) => return Ok(self),
+            Err(e) => {
+                self.discard().await;
+                return Err(e);
+            }
+        };
+
+        let staged = StagedUpload::from_data(conf, data).await;
+        self.discard().await;
+        staged.map_err(|e| {
+            warn!("failed to stage upload: {}", e);
+            AppError::StorageError("Failed to save file".to_string())
+        })
+    }
+
+    pub(crate) async fn discard(self) {
+        if let Err(e) = tokio::fs::remove_file(&self.path).await {
+            warn!("failed to remove staged upload {:?}: {}", self.path, e);
         }
     }
+}
 
-    info!("success upload file to: {:?}, {}", file_path, file_id);
-    (
-        StatusCode::CREATED,
-        Json(FileResponse {
-            id: file_id,
-            fmt: image_format.as_str().to_string(),
-        }),
+// The format is sniffed from the data, clients often send a generic or wrong
+// Content-Type
+async fn write_file(
+    conf: &AppConfig,
+    upload: StagedUpload,
+    meta: ImgMetadata,
+    strip: bool,
+) -> Result<(String, ImgMetadata), AppError> {
+    let image_format = ImageFormat::sniff(&upload.head);
+    if image_format == ImageFormat::Unknown {
+        upload.discard().await;
+        return Err(AppError::UnsupportedFormat(
+            "File is not a supported image".to_string(),
+        ));
+    }
+    if let Err(e) = format::check_input(conf, image_format) {
+        upload.discard().await;
+        return Err(e.into());
+    }
+
+    match store_staged_image(
+        conf,
+        &image_format,
+        &upload.path,
+        upload.size,
+        upload.hash,
+        meta,
+        strip,
     )
-        .into_response()
+    .await
+    {
+        Ok((file_id, meta)) => {
+            info!("success upload file: {}", file_id);
+            Ok((file_id, meta))
+        }
+        Err(e) => {
+            warn!("failed to store upload: {}", e);
+            Err(AppError::StorageError(e.to_string()))
+        }
+    }
 }
 
 pub async fn get_image(
     headers: HeaderMap,
     State(state): State<AppState>,
-    Path(img_id): Path<String>,
-) -> impl IntoResponse {
-    let file_path = &state.conf.file_path;
-    let default_header = &HeaderValue::from_str("application/octet-stream").unwrap();
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Query(delivery): Query<DeliveryQuery>,
+) -> Result<Response<Body>, AppError> {
+    serve_image(
+        &state,
+        &tenant,
+        &img_id,
+        query.frame,
+        &delivery,
+        &headers,
+        false,
+    )
+    .await
+}
+
+// Body of get_image, also used for share links and WebDAV. The file is
+// streamed, and a Range header is answered with 206 and only the bytes asked
+// for. With `delivery` parameters a resized or converted copy is served
+// instead. `public` reads (without an API key) of images protected by
+// public_reads get the configured watermark and size limit.
+pub(crate) async fn serve_image(
+    state: &AppState,
+    tenant: &Tenant,
+    img_id: &str,
+    frame: Option<Frame>,
+    delivery: &DeliveryQuery,
+    headers: &HeaderMap,
+    public: bool,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+
+    // transform outputs have no metadata and are not reviewed
+    let meta = match read_meta(&conf, img_id).await {
+        Ok(v) => Some(v),
+        Err(e) if is_not_found(&e) => None,
+        Err(e) => {
+            warn!("failed to read metadata of {}: {}", img_id, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+    if meta.as_ref().is_some_and(|m| m.deleted_at.is_some()) {
+        return Err(AppError::image_not_found());
+    }
+    if let Some(meta) = &meta
+        && !review::is_servable(&conf, meta)
+    {
+        return Err(AppError::Forbidden(
+            "Image is not approved for delivery".to_string(),
+        ));
+    }
+
+    let Some((full_path, img_fmt)) = locate_image(&conf, img_id, meta.as_ref()).await else {
+        return Err(AppError::image_not_found());
+    };
+    info!("reading: {:?}", full_path);
+
+    if let Some(policy) = &conf.public_reads
+        && public
+        && policy.protects(meta.as_ref())
+    {
+        // never larger than max_edge, smaller when the client asks for it
+        let edge =
+            |requested: Option<u32>| Some(requested.unwrap_or(u32::MAX).min(policy.max_edge));
+        let delivery = DeliveryQuery {
+            w: edge(delivery.w),
+            h: edge(delivery.h),
+            fmt: delivery.fmt.clone(),
+            ..*delivery
+        };
+        return deliver_variant(
+            state,
+            tenant,
+            &conf,
+            img_id,
+            img_fmt,
+            &delivery,
+            headers,
+            Some(&policy.watermark),
+        )
+        .await;
+    }
+
+    if !delivery.is_empty() {
+        return deliver_variant(
+            state, tenant, &conf, img_id, img_fmt, delivery, headers, None,
+        )
+        .await;
+    }
+
+    // the stored file as it is, which caches may keep and revalidate
+    let first_frame = frame == Some(Frame::First) && img_fmt == ImageFormat::Gif;
+    let validators = match first_frame {
+        true => None,
+        false => Some(file_validators(meta.as_ref(), &full_path).await),
+    };
+    if let Some(validators) = &validators
+        && validators.not_modified(headers)
+    {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_MODIFIED;
+        validators.apply(resp.headers_mut());
+        cache_control::apply(&conf, Served::Original, &mut resp);
+        return Ok(resp);
+    }
 
-    let ct = headers
-        .get("Content-Type")
-        .unwrap_or_else(|| &default_header);
+    let mut ct = img_fmt.content_type();
+    let content_res = if first_frame {
+        ct = ImageFormat::Png.content_type();
+        match read_image_bytes(&conf, &full_path).await {
+            Ok(data) => {
+                let _permit = state
+                    .budget
+                    .acquire(budget::estimate(&img_fmt, &data))
+                    .await?;
+                // the decoder only reads the first frame, re-encoded as a PNG still
+                match decode::run(&conf.decode, img_fmt, data.to_vec(), None).await {
+                    Ok(img) => tokio::task::spawn_blocking(move || {
+                        ImageContent::Bytes(img.get_bytes().into())
+                    })
+                    .await
+                    .map_err(|e| anyhow!("failed to extract first frame: {}", e)),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        open_image(&conf, &full_path).await
+    };
 
-    let ct_value = ct.to_str().unwrap();
+    match content_res {
+        Ok(content) => {
+            state.access.record(&tenant.id, img_id);
+            let mut resp = ranged_response(content, ct, headers.get(header::RANGE)).await?;
+            let served = match validators {
+                Some(validators) => {
+                    validators.apply(resp.headers_mut());
+                    Served::Original
+                }
+                None => Served::Derived,
+            };
+            cache_control::apply(&conf, served, &mut resp);
+            Ok(resp)
+        }
+        // deleted since it was located
+        Err(e) => {
+            warn!("failed to read file: {}", e);
+            Err(AppError::from_read(&e))
+        }
+    }
+}
 
-    let img_fmts: Vec<&str> = ct_value.split("/").collect();
+// ETag from the content_hash of uploads, Last-Modified from the time the
+// file was last replaced or else its modification time
+async fn file_validators(meta: Option<&ImgMetadata>, path: &std::path::Path) -> Validators {
+    let modified = meta
+        .and_then(|m| m.modified_at.as_deref())
+        .and_then(|v| OffsetDateTime::parse(v, &Rfc3339).ok())
+        .map(SystemTime::from);
+    let modified = match modified {
+        Some(v) => v,
+        None => tokio::fs::metadata(path)
+            .await
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH),
+    };
+    Validators::new(meta.and_then(|m| m.content_hash.as_deref()), modified)
+}
 
-    info!("get content type: {:?}", img_fmts);
+// Largest w or h of on-the-fly variants
+const MAX_DELIVERY_EDGE: u32 = 8192;
 
-    let img_fmt = detect_image_format(ct_value.to_string());
-    if img_fmt == ImageFormat::Unknown {
-        return (StatusCode::BAD_REQUEST, format!("unknown image format")).into_response();
+// get_image with ?w=&h=&fit=&fmt=&q=: the variant is made on every request
+// and streamed without being stored, like a CDN's image transforms. Animated
+// GIFs are reduced to their first frame, a PNG unless fmt says otherwise.
+// `watermark` is drawn onto the resized image.
+#[allow(clippy::too_many_arguments)]
+async fn deliver_variant(
+    state: &AppState,
+    tenant: &Tenant,
+    conf: &AppConfig,
+    img_id: &str,
+    src_fmt: ImageFormat,
+    delivery: &DeliveryQuery,
+    headers: &HeaderMap,
+    watermark: Option<&WatermarkRequest>,
+) -> Result<Response<Body>, AppError> {
+    for edge in [delivery.w, delivery.h].into_iter().flatten() {
+        if !(1..=MAX_DELIVERY_EDGE).contains(&edge) {
+            return Err(AppError::BadRequest(format!(
+                "w and h must be between 1 and {}",
+                MAX_DELIVERY_EDGE
+            )));
+        }
     }
+    if let Some(q) = delivery.q
+        && !(1..=100).contains(&q)
+    {
+        return Err(AppError::BadRequest(
+            "q must be between 1 and 100".to_string(),
+        ));
+    }
+    let fmt = match &delivery.fmt {
+        Some(name) => format::output_format(conf, name)?,
+        None if src_fmt == ImageFormat::Gif => ImageFormat::Png,
+        None => src_fmt,
+    };
+
+    // identical requests arriving while the variant is made share it, e.g.
+    // the burst of requests for a newly published image
+    let key = format!(
+        "{}/{}?w={:?}&h={:?}&fit={:?}&gravity={}&fmt={}&q={:?}&watermark={}",
+        conf.file_path,
+        img_id,
+        delivery.w,
+        delivery.h,
+        delivery.fit,
+        delivery.gravity.as_str(),
+        fmt.as_str(),
+        delivery.q,
+        watermark.is_some(),
+    );
+    let data = state
+        .coalescer
+        .run(key, || async {
+            let data = render_variant(state, conf, img_id, fmt, delivery, watermark).await?;
+            Ok(data.into())
+        })
+        .await?;
+
+    state.access.record(&tenant.id, img_id);
+    let mut resp = ranged_response(
+        ImageContent::Bytes(data),
+        fmt.content_type(),
+        headers.get(header::RANGE),
+    )
+    .await?;
+    cache_control::apply(conf, Served::Derived, &mut resp);
+    Ok(resp)
+}
 
-    let full_path = format!("{}/{}{}", file_path, img_id, img_fmt.as_str());
-    info!("reading: {}", full_path);
+// The encoded variant of deliver_variant
+async fn render_variant(
+    state: &AppState,
+    conf: &AppConfig,
+    img_id: &str,
+    fmt: ImageFormat,
+    delivery: &DeliveryQuery,
+    watermark: Option<&WatermarkRequest>,
+) -> Result<Vec<u8>, AppError> {
+    let (box_width, box_height, fit) = (delivery.w, delivery.h, delivery.fit);
+    let min_size = move |width, height| fit_dimensions(width, height, box_width, box_height, fit);
+    let (photon_img, img_meta, permit) = read_image(
+        conf,
+        &state.budget,
+        img_id,
+        Some(Frame::First),
+        Some(&min_size),
+    )
+    .await?;
+    let logo = match watermark.and_then(|w| w.logo.as_ref()) {
+        Some(logo) => {
+            let (logo_img, _, logo_permit) =
+                read_image(conf, &state.budget, &logo.img_id, Some(Frame::First), None).await?;
+            Some((logo_img, logo_permit))
+        }
+        None => None,
+    };
 
-    let img_data_res = get_img_data(&full_path).await;
-    match img_data_res {
-        Ok(data) => {
-            match Response::builder()
-                .header("Content-Type", ct)
-                .body(Body::from(data))
-            {
-                Ok(v) => v,
-                Err(e) => build_err_response(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to build response: {}", e),
-                ),
+    let (task_conf, task_fmt, quality) = (conf.clone(), fmt.as_str().to_string(), delivery.q);
+    let (watermark, fonts) = (watermark.cloned(), state.fonts.clone());
+    let focus = delivery.gravity.focus(&img_meta);
+    let collector = Timings::current();
+    let encoded = tokio::task::spawn_blocking(move || {
+        timings::within(collector, || {
+            let mut img = timings::time("resize", || {
+                fit_image(
+                    photon_img,
+                    box_width,
+                    box_height,
+                    fit,
+                    focus,
+                    task_conf.resizer,
+                )
+            });
+            if let Some(watermark) = &watermark {
+                let (logo, _logo_permit) = logo.unzip();
+                img = timings::time("watermark", || {
+                    stamp_watermark(img, watermark, logo, &fonts, task_conf.resizer)
+                })?;
             }
+            timings::time("encode", || {
+                encode_image(&task_conf, img, &task_fmt, quality)
+            })
+        })
+    })
+    .await;
+    drop(permit);
+
+    match encoded {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(e)) => {
+            warn!("failed to encode variant of {}: {}", img_id, e);
+            Err(AppError::Internal("Failed to encode image".to_string()))
         }
+        Err(e) => Err(AppError::Internal(e.to_string())),
+    }
+}
+
+// The whole content (200), or the part of it a Range header asks for (206)
+pub(crate) async fn ranged_response(
+    content: ImageContent,
+    ct: &str,
+    range: Option<&HeaderValue>,
+) -> Result<Response<Body>, AppError> {
+    build_ranged(content, ct, range)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
+}
+
+async fn build_ranged(
+    content: ImageContent,
+    ct: &str,
+    range: Option<&HeaderValue>,
+) -> Result<Response<Body>> {
+    let len = content.len();
+    let range = match range::parse(range, len) {
+        Ok(v) => v,
         Err(e) => {
-            warn!("failed to read file: {}", e);
-            return build_err_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to read file data".to_string(),
+            let mut resp =
+                error::build_err_response(StatusCode::RANGE_NOT_SATISFIABLE, e.to_string());
+            resp.headers_mut().insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len))?,
             );
+            return Ok(resp);
+        }
+    };
+    let (start, count) = match range {
+        Some((first, last)) => (first, last - first + 1),
+        None => (0, len),
+    };
+
+    let body = match content {
+        ImageContent::File(mut file, _) => {
+            file.seek(std::io::SeekFrom::Start(start)).await?;
+            Body::from_stream(ReaderStream::new(file.take(count)))
+        }
+        ImageContent::Bytes(data) => {
+            Body::from(data.slice(start as usize..(start + count) as usize))
         }
+    };
+
+    let mut builder = Response::builder()
+        .header(header::CONTENT_TYPE, ct)
+        .header(header::CONTENT_LENGTH, count)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some((first, last)) = range {
+        builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", first, last, len),
+        );
     }
+    Ok(builder.body(body)?)
 }
 
 pub async fn watermark_image(
+    headers: HeaderMap,
     State(state): State<AppState>,
-    Path(img_id): Path<String>,
-    Json(watermk_req): Json<WatermarkRequest>,
-) -> impl IntoResponse {
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let watermk_req: WatermarkRequest = parse_params(&params)?;
     info!("watermark request: {:?}", watermk_req);
 
-    let photon_img_res = read_image(&state, &img_id).await;
-    if photon_img_res.is_err() {
-        return photon_img_res.err().unwrap();
+    if let Err(e) = watermk_req.validate() {
+        return Err(AppError::BadRequest(e.to_string()));
     }
 
-    let (mut photon_img, img_meta) = photon_img_res.unwrap();
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "watermark").with_params(params);
 
-    add_watermark_to_image(
-        &mut photon_img,
-        &watermk_req.text,
-        &watermk_req.position,
-        watermk_req.font_size,
-    );
+    let (source, img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
+
+    // the logo is held, and budgeted, until the transform is done
+    let logo = match &watermk_req.logo {
+        Some(logo) => {
+            let (logo_img, _, logo_permit) =
+                read_image(&conf, &state.budget, &logo.img_id, Some(Frame::First), None).await?;
+            Some((logo_img, logo_permit))
+        }
+        None => None,
+    };
+
+    let fonts = state.fonts.clone();
+    let resizer = conf.resizer;
+    let transform = move |img: PhotonImage| {
+        let (logo, _logo_permit) = logo.unzip();
+        stamp_watermark(img, &watermk_req, logo, &fonts, resizer)
+    };
+
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
+
+    let photon_img = match timings::time(output.operation, || transform(photon_img)) {
+        Ok(v) => v,
+        Err(e) if e.is::<TooManyTiles>() || e.is::<StampTooLarge>() => {
+            return Err(AppError::BadRequest(e.to_string()));
+        }
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
 
     // Generate new image ID
-    let file_path = &state.conf.file_path;
-    let new_image_id = save_new_iamge(file_path, &img_meta, photon_img);
-    if new_image_id.is_err() {
-        return build_err_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            new_image_id.err().unwrap().to_string(),
-        );
-    }
+    let new_image_id = save_new_iamge(&conf, &img_meta, photon_img, &output).await?;
 
     // Return response
     let response = WatermarkResponse {
-        new_img_id: new_image_id.unwrap(),
+        new_img_id: new_image_id,
     };
 
-    (StatusCode::OK, Json(response)).into_response()
+    Ok(Json(response).into_response())
 }
 
 pub async fn resize_img(
+    headers: HeaderMap,
     State(state): State<AppState>,
-    Path(img_id): Path<String>,
-    Json(req): Json<ResizeImageRequest>,
-) -> impl IntoResponse {
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: ResizeImageRequest = parse_params(&params)?;
     info!("resize request: {:?}", req);
 
-    let file_path = &state.conf.file_path;
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "resize").with_params(params);
+
+    let file_path = &conf.file_path;
     info!("reading image from: {}", file_path);
 
-    let (mut photon_img, img_meta) = match read_image(&state, &img_id).await {
-        Ok(v) => v,
-        Err(e) => return e,
+    // JPEGs only need to be decoded at about the output size
+    let min_size = |width, height| {
+        resize_dimensions(
+            width,
+            height,
+            Some(req.width),
+            Some(req.height),
+            req.maintain_aspect,
+        )
+        .unwrap_or((width, height))
     };
+    let (source, img_meta) = read_source(
+        &conf,
+        &state.budget,
+        &headers,
+        &img_id,
+        query.frame,
+        Some(&min_size),
+    )
+    .await?;
 
-    let new_image_id = Uuid::new_v4().to_string();
-    let new_img_res = resize_image(
-        &mut photon_img,
-        Some(req.width),
-        Some(req.height),
-        req.maintain_aspect,
-    );
-
-    if new_img_res.is_err() {
-        return build_err_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            new_img_res.err().unwrap().to_string(),
-        );
-    }
+    let resizer = conf.resizer;
+    // the stretch to another aspect ratio becomes a crop around the focal point
+    let focus = match (req.maintain_aspect, req.gravity) {
+        (false, Gravity::Focus) => Some(req.gravity.focus(&img_meta).unwrap_or((0.5, 0.5))),
+        _ => None,
+    };
+    let transform = move |mut img: PhotonImage| match focus {
+        Some(focus) => Ok(cover_exact(
+            &img,
+            req.width,
+            req.height,
+            Some(focus),
+            resizer,
+        )),
+        None => resize_image(
+            &mut img,
+            Some(req.width),
+            Some(req.height),
+            req.maintain_aspect,
+            resizer,
+        ),
+    };
 
-    let output_path = PathBuf::from(format!("{}/{}{}", file_path, new_image_id, img_meta.fmt));
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
 
-    let new_img = new_img_res.unwrap();
-    let save_res = save_image(new_img, output_path);
-    if save_res.is_err() {
-        return build_err_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            save_res.err().unwrap().to_string(),
-        );
-    }
+    let new_img = match timings::time(output.operation, || transform(photon_img)) {
+        Ok(v) => v,
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+    let new_image_id = save_new_iamge(&conf, &img_meta, new_img, &output).await?;
 
     let response = ResizeImageResponse {
-        new_img_id: new_image_id.clone(),
+        new_img_id: new_image_id,
     };
 
-    (StatusCode::OK, Json(response)).into_response()
+    Ok(Json(response).into_response())
 }
 
 pub async fn compress_image(
+    headers: HeaderMap,
     State(state): State<AppState>,
-    Path(img_id): Path<String>,
-    Json(req): Json<CompressImageRequest>,
-) -> impl IntoResponse {
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: CompressImageRequest = parse_params(&params)?;
     info!("compress request: {:?}", req);
 
-    let photon_img_res = read_image(&state, &img_id).await;
-    if photon_img_res.is_err() {
-        return photon_img_res.err().unwrap();
-    }
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "compress").with_params(params);
 
-    let (photon_img, img_meta) = photon_img_res.unwrap();
-    let compressed_image = compress(&photon_img, req.quality);
+    let (source, mut img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
 
-    let file_path = &state.conf.file_path;
-    let new_image_id = save_new_iamge(file_path, &img_meta, compressed_image);
-    if new_image_id.is_err() {
-        return build_err_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            new_image_id.err().unwrap().to_string(),
-        );
-    }
+    // converting to a format without alpha flattens onto the background
+    let flatten = match &req.format {
+        Some(name) => {
+            let target = format::output_format(&conf, name)?;
+            img_meta.fmt = target.as_str().to_string();
+            (!target.has_alpha()).then(|| req.background.unwrap_or(conf.background))
+        }
+        None => None,
+    };
 
-    (
-        StatusCode::OK,
-        Json(CompressImageResponse {
-            new_img_id: new_image_id.unwrap(),
-        }),
-    )
-        .into_response()
+    let transform = move |img: PhotonImage| {
+        Ok(match flatten {
+            Some(background) => jpeg_compress(&alpha::flatten(&img, background), req.quality),
+            None => jpeg_compress(&img, req.quality),
+        })
+    };
+
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
+
+    let compressed_image = match timings::time(output.operation, || transform(photon_img)) {
+        Ok(v) => v,
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let new_img_id = save_new_iamge(&conf, &img_meta, compressed_image, &output).await?;
+    Ok(Json(CompressImageResponse { new_img_id }).into_response())
 }
 
-pub async fn crop_image(
+pub async fn rotate_img(
+    headers: HeaderMap,
     State(state): State<AppState>,
-    Path(img_id): Path<String>,
-    Json(req): Json<super::CorpImageRequest>,
-) -> impl IntoResponse {
-    info!("crop request: {:?}", req);
-
-    let photon_img_res = read_image(&state, &img_id).await;
-    if photon_img_res.is_err() {
-        return photon_img_res.err().unwrap();
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: RotateImageRequest = parse_params(&params)?;
+    info!("rotate request: {:?}", req);
+
+    if let Err(e) = req.validate() {
+        return Err(AppError::BadRequest(e.to_string()));
     }
 
-    let (mut photon_img, img_meta) = photon_img_res.unwrap();
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "rotate").with_params(params);
 
-    let cropped_image = crop(&mut photon_img, req.x, req.y, req.width, req.height);
+    let (source, img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
 
-    let file_path = &state.conf.file_path;
-    let new_image_id = save_new_iamge(file_path, &img_meta, cropped_image);
-    if new_image_id.is_err() {
-        return build_err_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            new_image_id.err().unwrap().to_string(),
-        );
-    }
+    let transform = move |img: PhotonImage| {
+        Ok(rotate_image(
+            img,
+            req.angle,
+            req.flip_horizontal,
+            req.flip_vertical,
+        ))
+    };
 
-    (
-        StatusCode::OK,
-        Json(super::CorpImageResponse {
-            new_img_id: new_image_id.unwrap(),
-        }),
-    )
-        .into_response()
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
+
+    let rotated_image = match timings::time(output.operation, || transform(photon_img)) {
+        Ok(v) => v,
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let new_img_id = save_new_iamge(&conf, &img_meta, rotated_image, &output).await?;
+    Ok(Json(RotateImageResponse { new_img_id }).into_response())
 }
 
-fn build_err_response(code: StatusCode, msg: String) -> Response<Body> {
-    (code, Json(ErrorResponse { error: msg })).into_response()
+pub async fn adjust_img(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: AdjustImageRequest = parse_params(&params)?;
+    info!("adjust request: {:?}", req);
+
+    if let Err(e) = req.validate() {
+        return Err(AppError::BadRequest(e.to_string()));
+    }
+
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "adjust").with_params(params);
+
+    let (source, img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
+
+    let transform = move |mut img: PhotonImage| {
+        adjust_image(&mut img, &req);
+        Ok(img)
+    };
+
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
+
+    let adjusted_image = match timings::time(output.operation, || transform(photon_img)) {
+        Ok(v) => v,
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let new_img_id = save_new_iamge(&conf, &img_meta, adjusted_image, &output).await?;
+    Ok(Json(AdjustImageResponse { new_img_id }).into_response())
 }
 
-async fn read_image(
-    state: &AppState,
-    img_id: &str,
-) -> Result<(PhotonImage, ImgMetadata), Response<Body>> {
-    let img_meta_res = get_meta(&state.conf.meta_path, img_id).await;
+pub async fn blur_img(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: BlurImageRequest = parse_params(&params)?;
+    info!("blur request: {:?}", req);
+
+    if let Err(e) = req.validate() {
+        return Err(AppError::BadRequest(e.to_string()));
+    }
 
-    if img_meta_res.is_err() {
-        return Err(build_err_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to read file meta".to_string(),
-        ));
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "blur").with_params(params);
+
+    let (source, img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
+
+    let transform = move |img: PhotonImage| Ok(blur_image(&img, req.radius));
+
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
+
+    let blurred_image = match timings::time(output.operation, || transform(photon_img)) {
+        Ok(v) => v,
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let new_img_id = save_new_iamge(&conf, &img_meta, blurred_image, &output).await?;
+    Ok(Json(BlurImageResponse { new_img_id }).into_response())
+}
+
+pub async fn sharpen_img(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: SharpenImageRequest = parse_params(&params)?;
+    info!("sharpen request: {:?}", req);
+
+    if let Err(e) = req.validate() {
+        return Err(AppError::BadRequest(e.to_string()));
     }
 
-    let img_meta = img_meta_res.unwrap();
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "sharpen").with_params(params);
 
-    let file_path = &state.conf.file_path;
-    let full_path = format!("{}/{}{}", file_path, img_id, img_meta.fmt);
-    info!("reading: {}", full_path);
+    let (source, img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
 
-    let img_data_res = get_img_data(&full_path).await;
-    if img_data_res.is_err() {
-        return Err(build_err_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to read image".to_string(),
-        ));
+    let transform = move |img: PhotonImage| Ok(sharpen_image(&img, req.radius, req.amount));
+
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
+
+    let sharpened_image = match timings::time(output.operation, || transform(photon_img)) {
+        Ok(v) => v,
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let new_img_id = save_new_iamge(&conf, &img_meta, sharpened_image, &output).await?;
+    Ok(Json(SharpenImageResponse { new_img_id }).into_response())
+}
+
+pub async fn filter_image(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: FilterImageRequest = parse_params(&params)?;
+    info!("filter request: {:?}", req);
+
+    if let Err(e) = req.validate() {
+        return Err(AppError::BadRequest(e.to_string()));
     }
 
-    Ok((
-        PhotonImage::new_from_byteslice(img_data_res.unwrap()),
-        img_meta,
-    ))
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "filter").with_params(params);
+
+    let (source, img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
+
+    let transform = move |mut img: PhotonImage| {
+        filter::apply(&mut img, &req.name);
+        Ok(img)
+    };
+
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
+
+    let filtered_image = match timings::time(output.operation, || transform(photon_img)) {
+        Ok(v) => v,
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let new_img_id = save_new_iamge(&conf, &img_meta, filtered_image, &output).await?;
+    Ok(Json(FilterImageResponse { new_img_id }).into_response())
 }
 
-async fn get_meta(meta_path: &str, img_id: &str) -> Result<ImgMetadata> {
-    let p = format!("{}/{}", meta_path, img_id);
+// Runs a stored recipe, see handlers::recipe. The output's lineage records
+// the version used, so it can be made again after the recipe changed.
+pub async fn apply_recipe(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path((img_id, name)): Path<(String, String)>,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    if !is_safe_id(&img_id) {
+        return Err(AppError::invalid_id());
+    }
 
-    match tokio::fs::read(p).await {
-        Ok(data) => serde_json::from_slice(&data).map_err(|e| anyhow!("{}", e)),
-        Err(e) => Err(anyhow!("{}", e)),
+    let req: ApplyRecipeRequest = parse_params(&params)?;
+    info!("recipe {} request: {:?}", name, req);
+
+    let conf = tenant.scope(&state.conf);
+    let recipe = recipe::get(&conf, &name, req.version)
+        .await
+        .map_err(|e| recipe_error(&name, e))?;
+    let pipeline = recipe
+        .bind(&req.params)
+        .and_then(|r| Pipeline::parse(&r))
+        .map_err(|e| AppError::Unprocessable(e.to_string()))?;
+    pipeline.check_formats(&conf)?;
+
+    let version = recipe.version;
+    let output = Output::new(&conf, &query, &img_id, "recipe").with_params(serde_json::json!({
+        "recipe": name,
+        "version": version,
+        "params": req.params,
+    }));
+
+    let (source, img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
+
+    // logos of watermark steps are held, and budgeted, until it is done
+    let (ctx, logo_permits) = StepContext::load(
+        &conf,
+        &state.budget,
+        state.fonts.clone(),
+        img_meta.clone(),
+        &pipeline,
+    )
+    .await?;
+
+    if !pipeline.has_outputs() {
+        let transform = move |img: PhotonImage| {
+            let _logo_permits = logo_permits;
+            pipeline.run(img, &ctx)
+        };
+        let (photon_img, _permit) = match source {
+            Source::Decoded(img, permit) => (img, permit),
+            Source::Queued(source) => {
+                return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                    .await;
+            }
+        };
+
+        let new_img = match timings::time(output.operation, || transform(photon_img)) {
+            Ok(v) => v,
+            Err(e) => return Err(e.into()),
+        };
+        let new_img_id = save_new_iamge(&conf, &img_meta, new_img, &output).await?;
+        return Ok(Json(ApplyRecipeResponse {
+            new_img_id: Some(new_img_id),
+            outputs: Vec::new(),
+            version,
+        })
+        .into_response());
     }
-}
 
-async fn get_img_data(img_path: &str) -> Result<Vec<u8>> {
-    match tokio::fs::read(img_path).await {
-        Ok(data) => Ok(data),
-        Err(e) => Err(anyhow!("{}", e)),
+    // one decode for every output, the recipe's steps are shared by them
+    let (photon_img, permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            let make = move |conf: &AppConfig, img, _: &ImgMetadata, output: &Output| {
+                let _logo_permits = logo_permits;
+                pipeline.fan_out(conf, img, &ctx, output).map(Made::Outputs)
+            };
+            return submit_image_job(&state, &tenant, source, img_meta, output, make).await;
+        }
+    };
+
+    let (task_conf, collector) = (conf.clone(), Timings::current());
+    let res = tokio::task::spawn_blocking(move || {
+        let _logo_permits = logo_permits;
+        timings::within(collector, || {
+            pipeline.fan_out(&task_conf, photon_img, &ctx, &output)
+        })
+    })
+    .await;
+    drop(permit);
+    let outputs = match res {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => return Err(e.into()),
+        Err(e) => return Err(AppError::Internal(format!("Failed to run recipe: {}", e))),
+    };
+    Ok(Json(ApplyRecipeResponse {
+        new_img_id: None,
+        outputs,
+        version,
+    })
+    .into_response())
+}
+
+pub async fn crop_image(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: super::CorpImageRequest = parse_params(&params)?;
+    info!("crop request: {:?}", req);
+
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "crop").with_params(params);
+
+    let region = match req.gravity {
+        Gravity::Center => (req.x, req.y, req.width, req.height),
+        Gravity::Focus => focused_region(&conf, &img_id, &req).await,
+    };
+    if runs_as_job(&conf, &headers, &img_id).await {
+        let (preview, img_meta) = read_preview(&conf, &state.budget, &img_id, query.frame).await?;
+        let (task_conf, budget, id, frame) = (
+            conf.clone(),
+            state.budget.clone(),
+            img_id.clone(),
+            query.frame,
+        );
+        let load: LoadSource = Box::pin(async move {
+            let (img, _, permit) = read_cropped(&task_conf, &budget, &id, region, frame).await?;
+            Ok((img, permit))
+        });
+        let source = QueuedSource { preview, load };
+        return submit_transform_job(&state, &tenant, source, img_meta, output, Ok).await;
+    }
+
+    let (cropped_image, img_meta, _permit) =
+        read_cropped(&conf, &state.budget, &img_id, region, query.frame).await?;
+    save_cropped(&conf, &img_meta, cropped_image, &output).await
+}
+
+// The (x1, y1)..(x2, y2) region of the image, read through the raster cache
+// when there is one
+async fn read_cropped(
+    conf: &AppConfig,
+    budget: &MemoryBudget,
+    img_id: &str,
+    region: (u32, u32, u32, u32),
+    frame: Option<Frame>,
+) -> Result<(PhotonImage, ImgMetadata, BudgetPermit), AppError> {
+    if let Some(cropped) = crop_cached(conf, budget, img_id, region, frame).await? {
+        return Ok(cropped);
+    }
+
+    let (img, img_meta, permit) = read_image(conf, budget, img_id, frame, None).await?;
+    let (x1, y1, x2, y2) = region;
+    let cropped = timings::time("crop", || crop(&img, x1, y1, x2, y2));
+    Ok((cropped, img_meta, permit))
+}
+
+// The region of a crop request moved to be centred on the focal point of the
+// image, as far as it stays inside the image. Unchanged for images without
+// regions or a recorded size.
+async fn focused_region(
+    conf: &AppConfig,
+    img_id: &str,
+    req: &super::CorpImageRequest,
+) -> (u32, u32, u32, u32) {
+    let region = (req.x, req.y, req.width, req.height);
+    let Ok(meta) = read_meta(conf, img_id).await else {
+        return region;
+    };
+    let (Some(focus), Some(width), Some(height)) = (meta.focal_point(), meta.width, meta.height)
+    else {
+        return region;
+    };
+
+    // x2 and y2 are exclusive
+    let (w, h) = (
+        req.width.saturating_sub(req.x).min(width),
+        req.height.saturating_sub(req.y).min(height),
+    );
+    let focus = (
+        focus.0 as f32 / width as f32,
+        focus.1 as f32 / height as f32,
+    );
+    let (x, y) = crop_origin(width, height, w, h, Some(focus));
+    (x, y, x + w, y + h)
+}
+
+async fn save_cropped(
+    conf: &AppConfig,
+    img_meta: &ImgMetadata,
+    cropped_image: PhotonImage,
+    output: &Output,
+) -> Result<Response<Body>, AppError> {
+    let new_img_id = save_new_iamge(conf, img_meta, cropped_image, output).await?;
+    Ok(Json(super::CorpImageResponse { new_img_id }).into_response())
+}
+
+pub async fn inpaint_image(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: InpaintImageRequest = parse_params(&params)?;
+    info!("inpaint request: {:?}", req);
+
+    let conf = tenant.scope(&state.conf);
+    if req.width as u64 * req.height as u64 > conf.max_inpaint_pixels {
+        return Err(AppError::BadRequest(format!(
+            "Inpaint region must be at most {} pixels",
+            conf.max_inpaint_pixels
+        )));
+    }
+    let output = Output::new(&conf, &query, &img_id, "inpaint").with_params(params);
+
+    let (source, img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
+
+    let transform =
+        move |img: PhotonImage| inpaint_region(&img, req.x, req.y, req.width, req.height);
+
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
+
+    let (collector, operation) = (Timings::current(), output.operation);
+    let inpainted_image = match tokio::task::spawn_blocking(move || {
+        timings::within(collector, || {
+            timings::time(operation, || transform(photon_img))
+        })
+    })
+    .await
+    {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => return Err(AppError::BadRequest(e.to_string())),
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let new_img_id = save_new_iamge(&conf, &img_meta, inpainted_image, &output).await?;
+    Ok(Json(InpaintImageResponse { new_img_id }).into_response())
+}
+
+// Before/after composite of the image and an edited version of it
+pub async fn compare_image(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<TransformQuery>,
+    Json(params): Json<serde_json::Value>,
+) -> Result<Response<Body>, AppError> {
+    let req: CompareImageRequest = parse_params(&params)?;
+    info!("compare request: {:?}", req);
+
+    let conf = tenant.scope(&state.conf);
+    let output = Output::new(&conf, &query, &img_id, "compare").with_params(params);
+
+    let (source, img_meta) =
+        read_source(&conf, &state.budget, &headers, &img_id, query.frame, None).await?;
+    let (edited_img, _, edited_permit) =
+        read_image(&conf, &state.budget, &req.edited_id, query.frame, None).await?;
+
+    let resizer = state.conf.resizer;
+    let transform = move |img: PhotonImage| {
+        let _edited_permit = edited_permit;
+        compare::compose(&img, &edited_img, req.mode, req.split, resizer)
+    };
+
+    let (photon_img, _permit) = match source {
+        Source::Decoded(img, permit) => (img, permit),
+        Source::Queued(source) => {
+            return submit_transform_job(&state, &tenant, source, img_meta, output, transform)
+                .await;
+        }
+    };
+
+    let (collector, operation) = (Timings::current(), output.operation);
+    let composite = match tokio::task::spawn_blocking(move || {
+        timings::within(collector, || {
+            timings::time(operation, || transform(photon_img))
+        })
+    })
+    .await
+    {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => return Err(AppError::BadRequest(e.to_string())),
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let new_img_id = save_new_iamge(&conf, &img_meta, composite, &output).await?;
+    Ok(Json(CompareImageResponse { new_img_id }).into_response())
+}
+
+pub async fn list_image_metas(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(filter): Query<MetaFilter>,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let images = match list_images_where(&conf, &filter).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to list images: {}", e);
+            return Err(AppError::Internal("Failed to list images".to_string()));
+        }
+    };
+
+    let listed: Vec<ListedImage> = images
+        .into_iter()
+        .filter(|(_, meta, _)| meta.deleted_at.is_none())
+        .map(|(id, meta, _)| ListedImage::new(id, meta))
+        .collect();
+
+    Ok(Json(listed).into_response())
+}
+
+// Moves the image to another review state, see ReviewState::can_move_to
+pub async fn review_image(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Json(req): Json<ReviewRequest>,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    read_meta(&conf, &img_id)
+        .await
+        .map_err(|e| AppError::from_read(&e))?;
+
+    let meta = update_meta(&conf, &img_id, |meta| {
+        if !meta.review_state.can_move_to(req.state) {
+            return Err(InvalidTransition {
+                from: meta.review_state,
+                to: req.state,
+            }
+            .into());
+        }
+        meta.review_state = req.state;
+        Ok(())
+    })
+    .await?;
+
+    info!("review state of {} is now {:?}", img_id, meta.review_state);
+    Ok(Json(ReviewResponse {
+        id: img_id,
+        review_state: meta.review_state,
+    })
+    .into_response())
+}
+
+// GET /api/images/{img_id}/origin: the transforms the image was made by, back
+// to the upload they started from
+pub async fn image_origin(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    if locate_image(&conf, &img_id, None).await.is_none() {
+        return Err(AppError::image_not_found());
+    }
+
+    let chain = match lineage::ancestry(&conf, &img_id).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read lineage of {}: {}", img_id, e);
+            return Err(AppError::Internal("Failed to read lineage".to_string()));
+        }
+    };
+    let origin = chain
+        .last()
+        .map(|(_, derivation)| derivation.parent_id.clone())
+        .unwrap_or_else(|| img_id.clone());
+
+    Ok(Json(OriginResponse {
+        id: img_id,
+        origin,
+        chain: chain
+            .into_iter()
+            .map(|(id, derivation)| LineageEntry { id, derivation })
+            .collect(),
+    })
+    .into_response())
+}
+
+// GET /api/images/{img_id}/versions/{n}: the n-th earlier file of the image,
+// counting from 1, see ImgMetadata::versions
+pub async fn image_version(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path((img_id, n)): Path<(String, usize)>,
+) -> Result<Response<Body>, AppError> {
+    if !is_safe_id(&img_id) {
+        return Err(AppError::invalid_id());
+    }
+
+    let conf = tenant.scope(&state.conf);
+    let meta = read_meta(&conf, &img_id).await.map_err(|e| {
+        warn!("failed to read metadata of {}: {}", img_id, e);
+        AppError::from_read(&e)
+    })?;
+    if meta.deleted_at.is_some() {
+        return Err(AppError::image_not_found());
+    }
+    let Some(version) = n.checked_sub(1).and_then(|i| meta.versions.get(i)) else {
+        return Err(AppError::NotFound(format!(
+            "Image {} has no version {}",
+            img_id, n
+        )));
+    };
+
+    let data = read_version(&conf, &img_id, &meta.versions, n - 1)
+        .await
+        .map_err(|e| {
+            warn!("failed to read version {} of {}: {}", n, img_id, e);
+            AppError::StorageError("Failed to read image version".to_string())
+        })?;
+    ranged_response(
+        ImageContent::Bytes(data.into()),
+        ImageFormat::from_fmt(&version.fmt).content_type(),
+        headers.get(header::RANGE),
+    )
+    .await
+}
+
+// GET /api/images/{img_id}/derivatives: the images made from this one
+pub async fn image_derivatives(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    if locate_image(&conf, &img_id, None).await.is_none() {
+        return Err(AppError::image_not_found());
+    }
+
+    match lineage::children(&conf, &img_id).await {
+        Ok(children) => Ok(Json(DerivativesResponse {
+            id: img_id,
+            derivatives: children
+                .into_iter()
+                .map(|(id, derivation)| LineageEntry { id, derivation })
+                .collect(),
+        })
+        .into_response()),
+        Err(e) => {
+            warn!("failed to read derivatives of {}: {}", img_id, e);
+            Err(AppError::Internal("Failed to read lineage".to_string()))
+        }
+    }
+}
+
+pub async fn image_meta(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let mut meta = match read_meta(&conf, &img_id).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read metadata of {}: {}", img_id, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+
+    let path = image_path(&conf, &img_id, &meta.fmt);
+    let created = match tokio::fs::metadata(&path).await.and_then(|m| m.modified()) {
+        Ok(v) => OffsetDateTime::from(v).format(&Rfc3339).unwrap_or_default(),
+        Err(e) => {
+            warn!("failed to stat {:?}: {}", path, e);
+            return Err(AppError::from_read(&e.into()));
+        }
+    };
+
+    // recorded at upload since, older images are probed
+    if meta.width.is_none() || meta.height.is_none() {
+        let (task_conf, fmt) = (conf.clone(), meta.fmt.clone());
+        let dimensions =
+            tokio::task::spawn_blocking(move || raster::probe_dimensions(&task_conf, &path, &fmt))
+                .await
+                .ok()
+                .and_then(|res| res.ok())
+                .flatten();
+        meta.width = dimensions.map(|(w, _)| w);
+        meta.height = dimensions.map(|(_, h)| h);
+    }
+
+    Ok(Json(ImageMetaResponse {
+        id: img_id,
+        meta,
+        created,
+    })
+    .into_response())
+}
+
+pub async fn image_quality(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+) -> Result<Response<Body>, AppError> {
+    info!("quality request: {}", img_id);
+
+    let conf = tenant.scope(&state.conf);
+
+    let (photon_img, _, _permit) = read_image(&conf, &state.budget, &img_id, None, None).await?;
+
+    match tokio::task::spawn_blocking(move || quality::estimate(&photon_img)).await {
+        Ok(score) => Ok(Json(score).into_response()),
+        Err(e) => Err(AppError::Internal(format!(
+            "Failed to estimate quality: {}",
+            e
+        ))),
+    }
+}
+
+// Classifies the image with the [tagging] model and adds the labels to its
+// tags, as done in the background for uploads
+pub async fn autotag_image(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+) -> Result<Response<Body>, AppError> {
+    info!("autotag request: {}", img_id);
+
+    if state.conf.tagging.is_none() {
+        return Err(AppError::NotFound("Tagging is not configured".to_string()));
+    }
+
+    let conf = tenant.scope(&state.conf);
+    read_meta(&conf, &img_id)
+        .await
+        .map_err(|e| AppError::from_read(&e))?;
+
+    match tagging::tag(&conf, &img_id).await {
+        Ok(labels) => Ok(Json(AutoTagResponse { id: img_id, labels }).into_response()),
+        Err(e) => {
+            warn!("failed to tag {}: {}", img_id, e);
+            Err(AppError::Upstream(format!(
+                "Failed to classify the image: {}",
+                e
+            )))
+        }
+    }
+}
+
+// Images that look like the given one, by the cosine similarity of their
+// embeddings. The embedding of the query image is computed when missing.
+pub async fn search_by_image(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(req): Json<SearchByImageRequest>,
+) -> Result<Response<Body>, AppError> {
+    info!("search by image request: {:?}", req);
+
+    if state.conf.embeddings.is_none() {
+        return Err(AppError::NotFound(
+            "Similarity search is not configured".to_string(),
+        ));
+    }
+    if !is_safe_id(&req.id) {
+        return Err(AppError::invalid_id());
+    }
+
+    let conf = tenant.scope(&state.conf);
+    read_meta(&conf, &req.id)
+        .await
+        .map_err(|e| AppError::from_read(&e))?;
+    let query = match embedding::of_image(&conf, &req.id).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to embed {}: {}", req.id, e);
+            return Err(AppError::Internal(format!(
+                "Failed to embed the image: {}",
+                e
+            )));
+        }
+    };
+    similar_images(&conf, &query, req.limit, Some(&req.id)).await
+}
+
+// Images matching a description, through the text encoder of the same model
+pub async fn search_by_text(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(req): Json<SearchByTextRequest>,
+) -> Result<Response<Body>, AppError> {
+    info!("search by text request: {:?}", req);
+
+    if state.conf.embeddings.is_none() {
+        return Err(AppError::NotFound(
+            "Similarity search is not configured".to_string(),
+        ));
+    }
+    if req.text.trim().is_empty() {
+        return Err(AppError::Unprocessable(
+            "text must not be empty".to_string(),
+        ));
+    }
+
+    let conf = tenant.scope(&state.conf);
+    let query = match embedding::embed_text(&conf, &req.text).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to embed {:?}: {}", req.text, e);
+            return Err(AppError::Internal(format!(
+                "Failed to embed the text: {}",
+                e
+            )));
+        }
+    };
+    similar_images(&conf, &query, req.limit, None).await
+}
+
+// Search results without images deleted since they were indexed
+async fn similar_images(
+    conf: &AppConfig,
+    query: &[f32],
+    limit: usize,
+    exclude: Option<&str>,
+) -> Result<Response<Body>, AppError> {
+    if limit == 0 || limit > MAX_SEARCH_RESULTS {
+        return Err(AppError::Unprocessable(format!(
+            "limit must be between 1 and {}",
+            MAX_SEARCH_RESULTS
+        )));
+    }
+
+    // room for the hits dropped below
+    let hits = match embedding::search(conf, query, limit * 2, exclude).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("similarity search failed: {}", e);
+            return Err(AppError::Internal("Similarity search failed".to_string()));
+        }
+    };
+    let mut results = Vec::with_capacity(limit);
+    for (id, score) in hits {
+        if results.len() == limit {
+            break;
+        }
+        match read_meta(conf, &id).await {
+            Ok(meta) if meta.deleted_at.is_none() => results.push(SearchHit { id, score }),
+            _ => {}
+        }
+    }
+    Ok(Json(SearchResponse { results }).into_response())
+}
+
+// Picks the best frame of a burst by quality score and marks the frames that
+// are near identical to it (by fingerprint) as its duplicates, tagging or soft
+// deleting them. Frames are decoded one at a time.
+pub async fn dedupe_burst(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(req): Json<BurstRequest>,
+) -> Result<Response<Body>, AppError> {
+    info!("burst dedupe request: {:?}", req);
+
+    if req.ids.len() < 2 || req.ids.len() > MAX_BURST_FRAMES {
+        return Err(AppError::Unprocessable(format!(
+            "a burst has 2 to {} frames",
+            MAX_BURST_FRAMES
+        )));
+    }
+    let mut unique = req.ids.clone();
+    unique.sort();
+    unique.dedup();
+    if unique.len() != req.ids.len() {
+        return Err(AppError::Unprocessable(
+            "frames must not repeat".to_string(),
+        ));
+    }
+    if req.ids.iter().any(|id| !is_safe_id(id)) {
+        return Err(AppError::invalid_id());
+    }
+
+    let conf = tenant.scope(&state.conf);
+    let mut frames = Vec::with_capacity(req.ids.len());
+    for img_id in &req.ids {
+        // only uploaded images can be marked, transform outputs have no metadata
+        if let Err(e) = read_meta(&conf, img_id).await {
+            return Err(match is_not_found(&e) {
+                true => AppError::NotFound(format!("Image {} not found", img_id)),
+                false => AppError::from_read(&e),
+            });
+        }
+        let (photon_img, _, _permit) = read_image(&conf, &state.budget, img_id, None, None).await?;
+        let analysis = tokio::task::spawn_blocking(move || {
+            (
+                quality::estimate(&photon_img),
+                burst::fingerprint(&photon_img),
+            )
+        })
+        .await;
+        match analysis {
+            Ok((score, fingerprint)) => frames.push(burst::Frame {
+                img_id: img_id.clone(),
+                score: score.score,
+                sharpness: score.sharpness,
+                fingerprint,
+            }),
+            Err(e) => {
+                return Err(AppError::Internal(format!(
+                    "Failed to analyse {}: {}",
+                    img_id, e
+                )));
+            }
+        }
+    }
+
+    let Some(selection) = burst::select(&frames, req.max_distance) else {
+        return Err(AppError::Unprocessable("empty burst".to_string()));
+    };
+    let kept_id = selection.keep.img_id.clone();
+    let now = OffsetDateTime::now_utc().format(&Rfc3339).ok();
+    for (frame, _) in &selection.duplicates {
+        let (kept_id, now) = (kept_id.clone(), now.clone());
+        let res = update_meta(&conf, &frame.img_id, move |meta| {
+            meta.duplicate_of = Some(kept_id);
+            match req.action {
+                DuplicateAction::Tag => {
+                    if !meta.tags.iter().any(|t| t == burst::DUPLICATE_TAG) {
+                        meta.tags.push(burst::DUPLICATE_TAG.to_string());
+                    }
+                }
+                // held frames are only marked
+                DuplicateAction::SoftDelete if meta.legal_hold.is_none() => meta.deleted_at = now,
+                DuplicateAction::SoftDelete => {}
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(e) = res {
+            warn!("failed to mark {} as duplicate: {}", frame.img_id, e);
+            return Err(AppError::Internal(format!(
+                "Failed to mark {} as duplicate",
+                frame.img_id
+            )));
+        }
+    }
+    info!(
+        "burst: kept {}, {} duplicates {:?}",
+        kept_id,
+        selection.duplicates.len(),
+        req.action
+    );
+
+    let burst_frame = |frame: &burst::Frame, distance: Option<u32>| BurstFrame {
+        id: frame.img_id.clone(),
+        score: frame.score,
+        sharpness: frame.sharpness,
+        distance,
+    };
+    let res = BurstResponse {
+        kept: burst_frame(selection.keep, None),
+        duplicates: selection
+            .duplicates
+            .iter()
+            .map(|(f, d)| burst_frame(f, Some(*d)))
+            .collect(),
+        distinct: selection
+            .distinct
+            .iter()
+            .map(|(f, d)| burst_frame(f, Some(*d)))
+            .collect(),
+        action: req.action,
+    };
+    Ok(Json(res).into_response())
+}
+
+// Removes EXIF (with the GPS position), XMP and comments from the stored file,
+// keeping its id. Its cached thumbnails are dropped as on any replacement.
+pub async fn strip_image(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let meta = match read_meta(&conf, &img_id).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read metadata of {}: {}", img_id, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+    check_hold(&img_id, &meta)?;
+
+    let path = image_path(&conf, &img_id, &meta.fmt);
+    let data = match read_image_data(&conf, &path).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read {:?}: {}", path, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+
+    let fmt = ImageFormat::from_fmt(&meta.fmt);
+    let stripped = match tokio::task::spawn_blocking(move || strip::strip(&data, fmt)).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => return Err(AppError::Unprocessable(e.to_string())),
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    let res = match replace_image(&conf, &img_id, &meta.fmt, stripped, false).await {
+        Ok(_) => unlink_image(&state.conf, &conf, &img_id).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(meta) => Ok(Json(FileResponse { id: img_id, meta }).into_response()),
+        Err(e) => {
+            warn!("failed to strip {}: {}", img_id, e);
+            Err(AppError::StorageError(
+                "Failed to strip metadata".to_string(),
+            ))
+        }
+    }
+}
+
+// The EXIF of the stored file, an empty object when it has none
+pub async fn image_exif(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let meta = match read_meta(&conf, &img_id).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read metadata of {}: {}", img_id, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+
+    let path = image_path(&conf, &img_id, &meta.fmt);
+    let data = match read_image_data(&conf, &path).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read {:?}: {}", path, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+
+    match tokio::task::spawn_blocking(move || exif::read(&data)).await {
+        Ok(Ok(exif)) => Ok(Json(exif.unwrap_or_default()).into_response()),
+        Ok(Err(e)) => Err(AppError::Unprocessable(format!("Malformed EXIF: {}", e))),
+        Err(e) => Err(AppError::Internal(format!("Failed to read EXIF: {}", e))),
+    }
+}
+
+// True when the client sent `Prefer: respond-async`
+fn prefers_async(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("Prefer")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|p| p.trim().eq_ignore_ascii_case("respond-async"))
+}
+
+// Whether to answer with a job instead of the result: when the client asks
+// for it, or for sources above jobs.async_above_megapixels, which would hold
+// the request too long. Decided before decoding, from the size recorded at
+// upload or the header of transform outputs.
+async fn runs_as_job(conf: &AppConfig, headers: &HeaderMap, img_id: &str) -> bool {
+    if prefers_async(headers) {
+        return true;
+    }
+    let Some(mp) = conf.jobs.async_above_megapixels else {
+        return false;
+    };
+    source_dimensions(conf, img_id)
+        .await
+        .is_some_and(|(width, height)| width as f64 * height as f64 > mp * 1_000_000.0)
+}
+
+async fn source_dimensions(conf: &AppConfig, img_id: &str) -> Option<(u32, u32)> {
+    let meta = read_meta(conf, img_id).await.ok();
+    if let Some(meta) = &meta
+        && let (Some(width), Some(height)) = (meta.width, meta.height)
+    {
+        return Some((width, height));
+    }
+    let (path, fmt) = locate_image(conf, img_id, meta.as_ref()).await?;
+    let conf = conf.clone();
+    tokio::task::spawn_blocking(move || raster::probe_dimensions(&conf, &path, fmt.as_str()))
+        .await
+        .ok()?
+        .ok()?
+}
+
+// Decodes the source of a job once a worker is free, jobs waiting for one
+// hold neither its pixels nor memory budget
+type LoadSource = BoxFuture<'static, Result<(PhotonImage, BudgetPermit), AppError>>;
+
+// The source of a transform, see read_source
+enum Source {
+    Decoded(PhotonImage, BudgetPermit),
+    Queued(QueuedSource),
+}
+
+struct QueuedSource {
+    preview: PhotonImage,
+    load: LoadSource,
+}
+
+// read_image for transforms, which run as a job when runs_as_job says so.
+// For those only a preview is decoded here.
+async fn read_source(
+    conf: &AppConfig,
+    budget: &MemoryBudget,
+    headers: &HeaderMap,
+    img_id: &str,
+    frame: Option<Frame>,
+    min_size: Option<MinSize<'_>>,
+) -> Result<(Source, ImgMetadata), AppError> {
+    if !runs_as_job(conf, headers, img_id).await {
+        let (img, img_meta, permit) = read_image(conf, budget, img_id, frame, min_size).await?;
+        return Ok((Source::Decoded(img, permit), img_meta));
+    }
+
+    let (preview, img_meta) = read_preview(conf, budget, img_id, frame).await?;
+    let (conf, budget, img_id) = (conf.clone(), budget.clone(), img_id.to_string());
+    let load: LoadSource = Box::pin(async move {
+        let (img, _, permit) = read_image(&conf, &budget, &img_id, frame, None).await?;
+        Ok((img, permit))
+    });
+    Ok((Source::Queued(QueuedSource { preview, load }), img_meta))
+}
+
+// Low-res copy of the source of a job, JPEGs are only decoded at about its size
+async fn read_preview(
+    conf: &AppConfig,
+    budget: &MemoryBudget,
+    img_id: &str,
+    frame: Option<Frame>,
+) -> Result<(PhotonImage, ImgMetadata), AppError> {
+    let (img, img_meta, _permit) =
+        read_image(conf, budget, img_id, frame, Some(&preview_dimensions)).await?;
+    match tokio::task::spawn_blocking(move || preview_image(&img)).await {
+        Ok(preview) => Ok((preview, img_meta)),
+        Err(e) => Err(AppError::Internal(e.to_string())),
+    }
+}
+
+// Runs the transform in the background, see submit_image_job
+async fn submit_transform_job<F>(
+    state: &AppState,
+    tenant: &Tenant,
+    source: QueuedSource,
+    img_meta: ImgMetadata,
+    output: Output,
+    transform: F,
+) -> Result<Response<Body>, AppError>
+where
+    F: FnOnce(PhotonImage) -> Result<PhotonImage> + Send + 'static,
+{
+    let make = move |conf: &AppConfig, img, meta: &ImgMetadata, output: &Output| {
+        let new_img = transform(img)?;
+        write_new_image(conf, meta, new_img, output).map(Made::Image)
+    };
+    submit_image_job(state, tenant, source, img_meta, output, make).await
+}
+
+// What a background transform made, see submit_image_job
+enum Made {
+    Image(String),
+    Outputs(Vec<JobOutput>),
+}
+
+// Runs `make` in the background on a worker of the job queue, which decodes
+// the source first, and answers right away with the job location and the id
+// of a low-res preview of the source
+async fn submit_image_job<F>(
+    state: &AppState,
+    tenant: &Tenant,
+    source: QueuedSource,
+    img_meta: ImgMetadata,
+    output: Output,
+    make: F,
+) -> Result<Response<Body>, AppError>
+where
+    F: FnOnce(&AppConfig, PhotonImage, &ImgMetadata, &Output) -> Result<Made> + Send + 'static,
+{
+    let conf = tenant.scope(&state.conf);
+    let QueuedSource { preview, load } = source;
+    // saved first, a job is never answered without its preview
+    let preview_img_id =
+        save_new_iamge(&conf, &img_meta, preview, &output.with_operation("preview")).await?;
+
+    let jobs = state.jobs.clone();
+    let reporter = state.reporter.clone();
+    let task_conf = conf.clone();
+    let submitted = state.jobs.submit(&tenant.id, |id| async move {
+        let (photon_img, permit) = match load.await {
+            Ok(v) => v,
+            Err(e) => return jobs.fail(&id, e.to_string()),
+        };
+        let res =
+            tokio::task::spawn_blocking(move || make(&task_conf, photon_img, &img_meta, &output))
+                .await;
+        drop(permit);
+
+        match res {
+            Ok(Ok(Made::Image(new_img_id))) => jobs.complete(&id, new_img_id),
+            Ok(Ok(Made::Outputs(outputs))) => jobs.complete_outputs(&id, outputs),
+            Ok(Err(e)) => jobs.fail(&id, e.to_string()),
+            Err(e) => {
+                let message = format!("transform panicked: {}", e);
+                if let Some(reporter) = reporter {
+                    reporter.report(ErrorEvent {
+                        kind: "panic",
+                        message: message.clone(),
+                        status: None,
+                        request_id: None,
+                        route: Some(format!("/api/jobs/{}", id)),
+                        img_id: None,
+                    });
+                }
+                jobs.fail(&id, message)
+            }
+        }
+    });
+    let job_id = match submitted {
+        Ok(v) => v,
+        Err(e) => {
+            // nothing refers to the preview without its job
+            if let Some((path, _)) = locate_image(&conf, &preview_img_id, None).await {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+            return Err(e.into());
+        }
+    };
+
+    info!("submitted transform job: {}", job_id);
+    Ok((
+        StatusCode::ACCEPTED,
+        [
+            (header::LOCATION, format!("/api/jobs/{}", job_id)),
+            (
+                HeaderName::from_static("preference-applied"),
+                "respond-async".to_string(),
+            ),
+        ],
+        Json(AsyncTransformResponse {
+            job_id,
+            preview_img_id,
+        }),
+    )
+        .into_response())
+}
+
+// Decodes the stored image, which for animations is their first frame. With
+// `frame` set the returned metadata makes the output a still, GIFs are
+// saved as PNG instead of a single frame GIF.
+pub(crate) async fn read_image(
+    conf: &AppConfig,
+    budget: &MemoryBudget,
+    img_id: &str,
+    frame: Option<Frame>,
+    min_size: Option<MinSize<'_>>,
+) -> Result<(PhotonImage, ImgMetadata, BudgetPermit), AppError> {
+    if !is_safe_id(img_id) {
+        return Err(AppError::invalid_id());
+    }
+
+    // transform outputs have no metadata, only their format is known
+    let mut img_meta = match read_meta(conf, img_id).await {
+        Ok(v) => v,
+        Err(e) if is_not_found(&e) => match locate_image(conf, img_id, None).await {
+            Some((_, fmt)) => ImgMetadata {
+                fmt: fmt.as_str().to_string(),
+                ..Default::default()
+            },
+            None => return Err(AppError::image_not_found()),
+        },
+        Err(e) => {
+            warn!("failed to read metadata of {}: {}", img_id, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+
+    let full_path = image_path(conf, img_id, &img_meta.fmt);
+    info!("reading: {:?}", full_path);
+
+    // a full decode of the file serves every min_size as well
+    let stamp = match decode_cache::is_enabled() {
+        true => decode_cache::Stamp::of(&full_path).await,
+        false => None,
+    };
+    // the copy of a cached decode is budgeted before it is made
+    if let Some(stamp) = stamp
+        && let Some((width, height)) = decode_cache::dimensions(&full_path, frame, stamp)
+    {
+        let permit = acquire_memory(budget, budget::decode_bytes(width, height)).await?;
+        // gone when acquire_memory had to clear the cache
+        if let Some(img) = decode_cache::get(&full_path, frame, stamp) {
+            still_output(&mut img_meta, frame);
+            return Ok((img, img_meta, permit));
+        }
+    }
+
+    let started = Instant::now();
+    let img_data_res = read_image_data(conf, &full_path).await;
+    timings::record("read", started);
+    let data = match img_data_res {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read {:?}: {}", full_path, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+
+    let fmt = ImageFormat::from_fmt(&img_meta.fmt);
+    if !fmt.is_decodable() {
+        return Err(AppError::UnsupportedFormat(format!(
+            "{} images cannot be transformed",
+            fmt.content_type()
+        )));
+    }
+    still_output(&mut img_meta, frame);
+
+    let estimate = match decode::decoded_size(&fmt, &data, min_size) {
+        Some((width, height)) => budget::decode_bytes(width, height),
+        None => budget::estimate(&fmt, &data),
+    };
+    let permit = acquire_memory(budget, estimate).await?;
+
+    let started = Instant::now();
+    let img = decode::run(&conf.decode, fmt, data, min_size).await;
+    timings::record("decode", started);
+    match img {
+        Ok(img) => {
+            if let (Some(stamp), None) = (stamp, min_size) {
+                decode_cache::insert(budget, &full_path, frame, stamp, &img);
+            }
+            Ok((img, img_meta, permit))
+        }
+        Err(e) => {
+            warn!("failed to decode {}: {}", img_id, e);
+            Err(AppError::DecodeError(
+                "Image could not be decoded".to_string(),
+            ))
+        }
+    }
+}
+
+// Cached decodes give their memory back before operations wait for it
+async fn acquire_memory(budget: &MemoryBudget, bytes: u64) -> Result<BudgetPermit, AppError> {
+    if let Some(permit) = budget.try_acquire(bytes) {
+        return Ok(permit);
+    }
+    decode_cache::clear();
+    Ok(budget.acquire(bytes).await?)
+}
+
+// With `?frame=first` GIF sources are saved as PNG instead of a single frame GIF
+fn still_output(img_meta: &mut ImgMetadata, frame: Option<Frame>) {
+    if frame == Some(Frame::First) && ImageFormat::from_fmt(&img_meta.fmt) == ImageFormat::Gif {
+        img_meta.fmt = ImageFormat::Png.as_str().to_string();
+    }
+}
+
+// Crops large originals through the raster cache instead of decoding them.
+// None when no cache is configured or the image is small.
+// Also None under encryption at rest, the cache would hold plaintext pixels.
+async fn crop_cached(
+    conf: &AppConfig,
+    budget: &MemoryBudget,
+    img_id: &str,
+    region: (u32, u32, u32, u32),
+    frame: Option<Frame>,
+) -> Result<Option<(PhotonImage, ImgMetadata, BudgetPermit)>, AppError> {
+    let Some(cache) = conf.raster_cache.clone() else {
+        return Ok(None);
+    };
+    if conf.encryption.is_some() {
+        return Ok(None);
+    }
+    if !is_safe_id(img_id) {
+        return Err(AppError::invalid_id());
+    }
+
+    let mut img_meta = read_meta(conf, img_id)
+        .await
+        .map_err(|e| AppError::from_read(&e))?;
+
+    // only the region is read from an existing cache, building it decodes
+    // the whole image
+    let (x1, y1, x2, y2) = region;
+    let estimate = if raster::cache_path(&cache, img_id).exists() {
+        budget::decode_bytes(x2.saturating_sub(x1), y2.saturating_sub(y1))
+    } else {
+        let original = image_path(conf, img_id, &img_meta.fmt);
+        match raster::probe_dimensions(conf, &original, &img_meta.fmt) {
+            Ok(Some((width, height))) => budget::decode_bytes(width, height),
+            _ => 0,
+        }
+    };
+    let permit = budget.acquire(estimate).await?;
+
+    let (task_conf, id, fmt) = (conf.clone(), img_id.to_string(), img_meta.fmt.clone());
+    let started = Instant::now();
+    let res =
+        tokio::task::spawn_blocking(move || raster::crop(&task_conf, &cache, &id, &fmt, region))
+            .await;
+    timings::record("raster_crop", started);
+
+    let cropped = match res {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) if e.is::<OutOfBounds>() => return Err(e.into()),
+        Ok(Err(e)) => {
+            warn!("raster crop of {} failed: {}", img_id, e);
+            return Err(AppError::Internal("Failed to crop image".to_string()));
+        }
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    still_output(&mut img_meta, frame);
+    Ok(cropped.map(|img| (img, img_meta, permit)))
+}
+
+// Reserves the space of a write, refusing those that would take the tenant
+// over its storage quota. None when the tenant has no quota.
+async fn check_quota(
+    state: &AppState,
+    conf: &AppConfig,
+    tenant: &Tenant,
+    incoming_bytes: u64,
+) -> Result<Option<Reservation>, AppError> {
+    let Some(quota_mb) = tenant.quota_mb else {
+        return Ok(None);
+    };
+
+    match state
+        .quota_usage
+        .reserve(conf, tenant, quota_mb * 1024 * 1024, incoming_bytes)
+        .await
+    {
+        Ok(reservation) => Ok(Some(reservation)),
+        Err(QuotaError::Exceeded { used }) => {
+            if let Some(notifier) = &state.notifier {
+                notifier.notify(Alert {
+                    kind: AlertKind::QuotaExceeded,
+                    message: format!(
+                        "Upload refused, {} MB of the {} MB quota are used",
+                        used / (1024 * 1024),
+                        quota_mb
+                    ),
+                    tenant: Some(tenant.id.clone()).filter(|t| !t.is_empty()),
+                });
+            }
+            Err(AppError::QuotaExceeded(
+                "Storage quota exceeded".to_string(),
+            ))
+        }
+        Err(QuotaError::Count(e)) => {
+            warn!("failed to compute usage of tenant {}: {}", tenant.id, e);
+            Err(AppError::StorageError(
+                "Failed to compute storage usage".to_string(),
+            ))
+        }
     }
 }
@@ -0,0 +1,20 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+};
+use tracing::info;
+
+use crate::{error::AppError, jobs::Job, state::AppState, tenant::Tenant};
+
+pub async fn get_job(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(job_id): Path<String>,
+) -> Result<Json<Job>, AppError> {
+    info!("job status request: {}", job_id);
+
+    match state.jobs.get(&job_id) {
+        Some(job) if job.tenant == tenant.id => Ok(Json(job)),
+        _ => Err(AppError::NotFound("Job not found".to_string())),
+    }
+}
@@ -0,0 +1,86 @@
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    error::AppError,
+    handlers::{FileResponse, ImgId, LinkSource},
+    state::AppState,
+    storage::{link_image, read_meta},
+    tenant::Tenant,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct LinkRequest {
+    // tenant to add the image to, one of the link_to of the caller's. The
+    // caller's own tenant when absent.
+    pub tenant: Option<String>,
+    pub collection: Option<String>,
+}
+
+// Adds an image to another collection or tenant without copying its file,
+// e.g. a shared brand asset into every product's space
+pub async fn link_image_to(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Json(req): Json<LinkRequest>,
+) -> Result<Response<Body>, AppError> {
+    let target = match req.tenant.as_deref() {
+        None => tenant.clone(),
+        Some(id) if id == tenant.id => tenant.clone(),
+        Some(id) => {
+            let allowed = state
+                .conf
+                .tenants
+                .iter()
+                .find(|t| t.id == tenant.id)
+                .is_some_and(|t| t.link_to.iter().any(|target| target == id));
+            let target = state.conf.tenants.iter().find(|t| t.id == id);
+            match target {
+                Some(target) if allowed => Tenant::from(target),
+                _ => {
+                    return Err(AppError::Forbidden(format!(
+                        "Images cannot be linked to tenant {}",
+                        id
+                    )));
+                }
+            }
+        }
+    };
+
+    let conf = tenant.scope(&state.conf);
+    let meta = read_meta(&conf, &img_id).await.map_err(|e| {
+        warn!("failed to read metadata of {}: {}", img_id, e);
+        AppError::from_read(&e)
+    })?;
+    if meta.deleted_at.is_some() {
+        return Err(AppError::image_not_found());
+    }
+
+    let source = LinkSource {
+        tenant: (!tenant.is_default()).then(|| tenant.id.clone()),
+        img_id: img_id.clone(),
+    };
+    let (id, meta) = link_image(
+        &conf,
+        &img_id,
+        &target.scope(&state.conf),
+        source,
+        req.collection,
+    )
+    .await
+    .map_err(|e| {
+        warn!("failed to link {}: {}", img_id, e);
+        AppError::StorageError("Failed to link image".to_string())
+    })?;
+
+    info!("linked {} as {} for tenant {:?}", img_id, id, target.id);
+    Ok((StatusCode::CREATED, Json(FileResponse { id, meta })).into_response())
+}
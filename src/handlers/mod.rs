@@ -1,33 +1,515 @@
+pub mod admin;
+pub mod capabilities;
+pub mod comment;
+pub mod convert;
+pub mod export;
+pub mod feed;
 pub mod image;
+pub mod jobs;
+pub mod link;
+pub mod policy;
+pub mod proxy;
+pub mod recipe;
+pub mod regions;
+pub mod s3;
+pub mod share;
+pub mod sync;
+pub mod thumbnail;
+pub mod variants;
+pub mod webdav;
 
 use anyhow::{Result, anyhow};
-use photon_rs::{PhotonImage, native::save_image, text::draw_text, transform::resize};
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use uuid::Uuid;
+use axum::{
+    extract::{FromRequestParts, Path},
+    http::request::Parts,
+};
+use photon_rs::{
+    PhotonImage, colour_spaces,
+    conv::gaussian_blur,
+    effects::{adjust_brightness, adjust_contrast},
+    transform::{compress, crop, fliph, flipv, resize, rotate},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::BTreeMap;
+use tracing::warn;
 
-#[derive(Serialize, Deserialize)]
+use crate::{
+    alpha::{self, Color},
+    burst::DuplicateAction,
+    compare::CompareMode,
+    crypt,
+    error::AppError,
+    filter,
+    format::ImageFormat,
+    jobs::JobOutput,
+    lineage::{self, Derivation},
+    placement::Placement,
+    print::{self, PrintFormat},
+    resize::{self, Resizer},
+    review::ReviewState,
+    state::{AppConfig, OutputNaming},
+    storage::{is_safe_id, promote_output, staged_output_path},
+    tagging::Label,
+    text::{FontStack, TextOptions},
+    timings::{self, Timings},
+};
+
+// `{img_id}` of a route with no other parameters. Ids the server does not
+// generate are refused with AppError::invalid_id before the handler runs,
+// see storage::is_safe_id.
+#[derive(Debug, Clone)]
+pub struct ImgId(pub String);
+
+impl<S: Send + Sync> FromRequestParts<S> for ImgId {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(img_id) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| AppError::Status(e.status(), e.body_text()))?;
+        if !is_safe_id(&img_id) {
+            return Err(AppError::invalid_id());
+        }
+        Ok(ImgId(img_id))
+    }
+}
+
+// Query parameters of the transform endpoints
+#[derive(Debug, Default, Deserialize)]
+pub struct TransformQuery {
+    // `?frame=first`: work on a still of animated inputs
+    pub frame: Option<Frame>,
+    // overrides the configured output_naming
+    pub naming: Option<OutputNaming>,
+}
+
+// What a transform output is named after, and recorded as made from
+#[derive(Debug, Clone)]
+pub struct Output {
+    source_id: String,
+    operation: &'static str,
+    naming: OutputNaming,
+    // request body, see lineage::Derivation
+    params: serde_json::Value,
+}
+
+impl Output {
+    fn new(
+        conf: &AppConfig,
+        query: &TransformQuery,
+        source_id: &str,
+        operation: &'static str,
+    ) -> Self {
+        Self {
+            source_id: source_id.to_string(),
+            operation,
+            naming: query.naming.unwrap_or(conf.output_naming),
+            params: serde_json::Value::Null,
+        }
+    }
+
+    fn with_operation(&self, operation: &'static str) -> Self {
+        Self {
+            operation,
+            params: serde_json::Value::Null,
+            ..self.clone()
+        }
+    }
+
+    fn with_params(self, params: serde_json::Value) -> Self {
+        Self { params, ..self }
+    }
+}
+
+// Typed body of a transform request. The handlers take the body as JSON so it
+// can be recorded as the params of the output's lineage.
+fn parse_params<T: DeserializeOwned>(params: &serde_json::Value) -> Result<T, AppError> {
+    T::deserialize(params).map_err(|e| AppError::Unprocessable(e.to_string()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Frame {
+    First,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct ImgMetadata {
     pub fmt: String,
     pub size_in_bytes: u32,
+    // MIME type of the stored file, matches fmt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    // name of the file the client uploaded, or the S3 key, attachment or
+    // watched file name it came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_filename: Option<String>,
+    // RFC 3339 time the image was stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    // RFC 3339 time its file was last replaced, see storage::replace_image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<String>,
+    // SHA-256 (hex) of the unencrypted file, its ETag. Absent for images
+    // stored before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    // read from the image header at upload, absent when it cannot be parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // confidence (0-1) of the tags attached by the classifier, see tagging
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tag_confidence: BTreeMap<String, f32>,
+    // object key when the image was written through the S3 facade
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    // number of reads served, updated in batches by the access tracker
+    #[serde(default)]
+    pub access_count: u64,
+    // RFC 3339 timestamp of the most recent read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_accessed: Option<String>,
+    #[serde(default)]
+    pub review_state: ReviewState,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub comments: Vec<Comment>,
+    // earlier files of the image kept when it was re-encoded, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub versions: Vec<ImageVersion>,
+    // burst frame kept instead of this one, see dedupe_burst
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    // RFC 3339 time the image was soft deleted. It is no longer listed or
+    // served, its file is kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    // named areas of the image, e.g. "focus", "face-1" or "product", set with
+    // PATCH /api/images/{img_id}/meta. Crops with gravity "focus" centre on them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub regions: BTreeMap<String, Region>,
+    // image this one was linked from, sharing its file, see
+    // storage::link_image. Cleared once the image gets a file of its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linked_from: Option<LinkSource>,
+    // number of images linked from this one that still share a file
+    #[serde(default)]
+    pub link_count: u32,
+    // set by an admin, the image cannot be deleted or replaced while held
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub legal_hold: Option<LegalHold>,
+}
+
+// Region whose centre is the focal point of an image
+pub const FOCUS_REGION: &str = "focus";
+
+impl ImgMetadata {
+    // Focal point in pixels of the image: the centre of the "focus" region,
+    // otherwise of the box around all regions
+    pub fn focal_point(&self) -> Option<(u32, u32)> {
+        if let Some(focus) = self.regions.get(FOCUS_REGION) {
+            return Some(focus.centre());
+        }
+        let mut regions = self.regions.values();
+        let first = *regions.next()?;
+        let (x1, y1, x2, y2) = regions.fold(
+            (
+                first.x,
+                first.y,
+                first.x + first.width,
+                first.y + first.height,
+            ),
+            |(x1, y1, x2, y2), r| {
+                (
+                    x1.min(r.x),
+                    y1.min(r.y),
+                    x2.max(r.x + r.width),
+                    y2.max(r.y + r.height),
+                )
+            },
+        );
+        Some(((x1 + x2) / 2, (y1 + y2) / 2))
+    }
+}
+
+// Replaced file of an image, stored under versions/{img_id}/{file}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageVersion {
+    pub file: String,
+    pub fmt: String,
+    // of the version, not of its delta
+    pub size_in_bytes: u32,
+    // RFC 3339 time it was replaced
+    pub replaced: String,
+    // the file is a delta against the version before, see storage::read_version
+    #[serde(default)]
+    pub delta: bool,
+}
+
+// Image a linked image was made from. The tenant is None for the default one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkSource {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub img_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LegalHold {
+    pub reason: String,
+    // RFC 3339 time the hold was placed
+    pub since: String,
+}
+
+// Review note left on an image, optionally pointing at a region of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Comment {
+    pub id: String,
+    pub author: String,
+    pub text: String,
+    // RFC 3339 time the comment was added
+    pub created: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<Region>,
+}
+
+// Rectangle in pixels of the stored image
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn centre(&self) -> (u32, u32) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+}
+
+// The region has to be non-empty and, when the image size is known, inside it
+fn check_region(region: Region, dimensions: Option<(u32, u32)>) -> Result<(), String> {
+    if region.width == 0 || region.height == 0 {
+        return Err("region width and height must be positive".to_string());
+    }
+
+    if let Some((width, height)) = dimensions
+        && (region.x as u64 + region.width as u64 > width as u64
+            || region.y as u64 + region.height as u64 > height as u64)
+    {
+        return Err(format!(
+            "region is outside of the {}x{} image",
+            width, height
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommentRequest {
+    author: String,
+    text: String,
+    region: Option<Region>,
+}
+
+// Changes to the metadata of an image. Regions given as null are removed,
+// the others are added or replaced.
+#[derive(Debug, Deserialize)]
+pub struct MetaPatch {
+    #[serde(default)]
+    regions: BTreeMap<String, Option<Region>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetaPatchResponse {
+    id: String,
+    regions: BTreeMap<String, Region>,
+}
+
+#[derive(Serialize)]
+pub struct ImageMetaResponse {
+    id: String,
+    // width and height are probed for images stored before they were recorded
+    #[serde(flatten)]
+    meta: ImgMetadata,
+    // modification time of the file, created_at of images stored before it
+    // was recorded
+    created: String,
+}
+
+// An image and how it was made from its parent
+#[derive(Serialize)]
+pub struct LineageEntry {
+    id: String,
+    #[serde(flatten)]
+    derivation: Derivation,
+}
+
+#[derive(Serialize)]
+pub struct OriginResponse {
+    id: String,
+    // the upload the chain starts from
+    origin: String,
+    // from the image back to the origin, empty for uploads
+    chain: Vec<LineageEntry>,
 }
 
 #[derive(Serialize)]
-pub struct ErrorResponse {
-    error: String,
+pub struct DerivativesResponse {
+    id: String,
+    // made directly from the image, oldest first
+    derivatives: Vec<LineageEntry>,
 }
 
 #[derive(Serialize)]
 struct FileResponse {
     id: String,
-    fmt: String,
+    #[serde(flatten)]
+    meta: ImgMetadata,
 }
 
-#[derive(Debug, Deserialize)]
+// Also the watermark of public_reads in the config
+#[derive(Debug, Clone, Deserialize)]
 pub struct WatermarkRequest {
+    #[serde(default)]
     text: String,
-    position: String,
+    // `position` and its offsets, unused when tiled
+    #[serde(flatten)]
+    placement: Placement,
+    #[serde(default)]
     font_size: u32,
+    // another stored image placed the same way, under the text
+    logo: Option<WatermarkLogo>,
+    #[serde(flatten)]
+    options: TextOptions,
+    #[serde(default)]
+    mode: WatermarkMode,
+    #[serde(default)]
+    tile: TileOptions,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatermarkMode {
+    // once, at `position`
+    #[default]
+    Single,
+    // repeated across the whole image, the logo above the text
+    Tile,
+}
+
+// Repeats of `mode: "tile"`
+#[derive(Debug, Clone, Deserialize)]
+pub struct TileOptions {
+    // pixels between neighbouring repeats
+    #[serde(default = "default_tile_spacing")]
+    spacing: u32,
+    // degrees, turned the way the rotate endpoint turns images
+    #[serde(default = "default_tile_angle")]
+    angle: f32,
+    #[serde(default = "default_tile_opacity")]
+    opacity: f32,
+}
+
+impl Default for TileOptions {
+    fn default() -> Self {
+        Self {
+            spacing: default_tile_spacing(),
+            angle: default_tile_angle(),
+            opacity: default_tile_opacity(),
+        }
+    }
+}
+
+fn default_tile_spacing() -> u32 {
+    100
+}
+
+fn default_tile_angle() -> f32 {
+    45.0
+}
+
+fn default_tile_opacity() -> f32 {
+    0.3
+}
+
+// Most repeats a tiled watermark may have, each is composited separately
+const MAX_TILES: i64 = 10_000;
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Watermark would be repeated more than {} times, increase the tile spacing",
+    MAX_TILES
+)]
+pub struct TooManyTiles;
+
+// Largest font_size and text of a watermark
+const MAX_FONT_SIZE: u32 = 1000;
+const MAX_WATERMARK_TEXT: usize = 500;
+// Most pixels of the stamp a tiled watermark repeats
+const MAX_STAMP_PIXELS: usize = 16_000_000;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Watermark is too large to tile, use a smaller font_size or a shorter text")]
+pub struct StampTooLarge;
+// Space between the logo and the text of a tile
+const TILE_GAP: u32 = 10;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatermarkLogo {
+    img_id: String,
+    // logo width as a fraction of the image width, its own size when unset
+    scale: Option<f32>,
+    #[serde(default = "default_logo_opacity")]
+    opacity: f32,
+}
+
+fn default_logo_opacity() -> f32 {
+    1.0
+}
+
+impl WatermarkRequest {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.text.is_empty() && self.logo.is_none() {
+            return Err(anyhow!("Watermark needs a text or a logo"));
+        }
+        if !self.text.is_empty() && !(1..=MAX_FONT_SIZE).contains(&self.font_size) {
+            return Err(anyhow!("font_size must be between 1 and {}", MAX_FONT_SIZE));
+        }
+        if self.text.chars().count() > MAX_WATERMARK_TEXT {
+            return Err(anyhow!(
+                "Watermark text must be at most {} characters",
+                MAX_WATERMARK_TEXT
+            ));
+        }
+        if let Some(logo) = &self.logo {
+            if logo.scale.is_some_and(|s| !(s > 0.0 && s <= 1.0)) {
+                return Err(anyhow!("logo scale must be above 0 and at most 1"));
+            }
+            if !(0.0..=1.0).contains(&logo.opacity) {
+                return Err(anyhow!("logo opacity must be between 0 and 1"));
+            }
+        }
+        if self.mode == WatermarkMode::Tile {
+            if !(0.0..=1.0).contains(&self.tile.opacity) {
+                return Err(anyhow!("tile opacity must be between 0 and 1"));
+            }
+            if !self.tile.angle.is_finite() {
+                return Err(anyhow!("Invalid tile angle"));
+            }
+            // measured on the repeated stamp, not on the image under it
+            if self.options.contrast.is_some() {
+                return Err(anyhow!("contrast cannot be used with mode \"tile\""));
+            }
+        }
+        self.options.validate()
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -40,6 +522,10 @@ pub struct ResizeImageRequest {
     width: u32,
     height: u32,
     maintain_aspect: bool,
+    // with "focus" and without maintain_aspect the image is cropped around its
+    // focal point to the new aspect ratio instead of being stretched
+    #[serde(default)]
+    gravity: Gravity,
 }
 
 #[derive(Debug, Serialize)]
@@ -50,6 +536,11 @@ pub struct ResizeImageResponse {
 #[derive(Debug, Deserialize)]
 pub struct CompressImageRequest {
     quality: u8, // 0-100
+    // output format, e.g. "jpeg", that of the source by default
+    format: Option<String>,
+    // behind transparent pixels when `format` has no alpha, overrides the
+    // configured background
+    background: Option<Color>,
 }
 
 #[derive(Debug, Serialize)]
@@ -63,6 +554,10 @@ pub struct CorpImageRequest {
     y: u32,
     width: u32,
     height: u32,
+    // with "focus" the region keeps its size and is moved to be centred on
+    // the focal point of the image
+    #[serde(default)]
+    gravity: Gravity,
 }
 
 #[derive(Debug, Serialize)]
@@ -70,24 +565,794 @@ pub struct CorpImageResponse {
     new_img_id: String,
 }
 
-// Helper function to add watermark
-fn add_watermark_to_image(image: &mut PhotonImage, text: &str, position: &str, font_size: u32) {
-    // Determine position coordinates (simplified for example)
-    let (x, y) = match position {
-        "top-left" => (10, 10),
-        "center" => (image.get_width() / 2 - 50, image.get_height() / 2 - 20),
-        "bottom-right" => (image.get_width() - 100, image.get_height() - 40),
-        _ => (10, 10), // Default to top-left
+#[derive(Debug, Deserialize)]
+pub struct ReviewRequest {
+    state: ReviewState,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReviewResponse {
+    id: String,
+    review_state: ReviewState,
+}
+
+// Most frames of a burst analysed in one request
+pub const MAX_BURST_FRAMES: usize = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct BurstRequest {
+    ids: Vec<String>,
+    #[serde(default)]
+    action: DuplicateAction,
+    // fingerprint bits (of 64) in which a duplicate may differ from the kept frame
+    #[serde(default = "default_burst_distance")]
+    max_distance: u32,
+}
+
+fn default_burst_distance() -> u32 {
+    10
+}
+
+#[derive(Debug, Serialize)]
+pub struct BurstResponse {
+    kept: BurstFrame,
+    duplicates: Vec<BurstFrame>,
+    // frames that differ too much from the kept one, left as they are
+    distinct: Vec<BurstFrame>,
+    action: DuplicateAction,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BurstFrame {
+    id: String,
+    score: f64,
+    sharpness: f64,
+    // fingerprint bits in which it differs from the kept frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance: Option<u32>,
+}
+
+// Most results of a similarity search
+pub const MAX_SEARCH_RESULTS: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchByImageRequest {
+    // image to find similar ones of, it is not in the results
+    id: String,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchByTextRequest {
+    // e.g. "a dog on a beach at sunset"
+    text: String,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    20
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResponse {
+    results: Vec<SearchHit>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    id: String,
+    // cosine similarity, higher is closer
+    score: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AutoTagResponse {
+    id: String,
+    // the labels added, best first
+    labels: Vec<Label>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListedImage {
+    id: String,
+    fmt: String,
+    size_in_bytes: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    review_state: ReviewState,
+}
+
+impl ListedImage {
+    fn new(id: String, meta: ImgMetadata) -> Self {
+        ListedImage {
+            id,
+            fmt: meta.fmt,
+            size_in_bytes: meta.size_in_bytes,
+            original_filename: meta.original_filename,
+            created_at: meta.created_at,
+            width: meta.width,
+            height: meta.height,
+            review_state: meta.review_state,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RotateImageRequest {
+    // clockwise, in degrees
+    angle: f32,
+    #[serde(default)]
+    flip_horizontal: bool,
+    #[serde(default)]
+    flip_vertical: bool,
+}
+
+impl RotateImageRequest {
+    fn validate(&self) -> Result<()> {
+        if !self.angle.is_finite() {
+            return Err(anyhow!("Invalid angle"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RotateImageResponse {
+    new_img_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdjustImageRequest {
+    // added to every channel, -255 to 255
+    #[serde(default)]
+    brightness: i16,
+    // -255 to 255
+    #[serde(default)]
+    contrast: f32,
+    // -1 (grey) to 1
+    #[serde(default)]
+    saturation: f32,
+    // degrees around the colour wheel
+    #[serde(default)]
+    hue_shift: f32,
+}
+
+impl AdjustImageRequest {
+    fn validate(&self) -> Result<()> {
+        if !(-255..=255).contains(&self.brightness) {
+            return Err(anyhow!("brightness must be between -255 and 255"));
+        }
+        if !(-255.0..=255.0).contains(&self.contrast) {
+            return Err(anyhow!("contrast must be between -255 and 255"));
+        }
+        if !(-1.0..=1.0).contains(&self.saturation) {
+            return Err(anyhow!("saturation must be between -1 and 1"));
+        }
+        if !self.hue_shift.is_finite() {
+            return Err(anyhow!("Invalid hue_shift"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdjustImageResponse {
+    new_img_id: String,
+}
+
+// Largest blur radius, also of the blur sharpen subtracts
+const MAX_BLUR_RADIUS: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct BlurImageRequest {
+    // in pixels, 1 to MAX_BLUR_RADIUS
+    radius: u32,
+}
+
+impl BlurImageRequest {
+    fn validate(&self) -> Result<()> {
+        if !(1..=MAX_BLUR_RADIUS).contains(&self.radius) {
+            return Err(anyhow!("radius must be between 1 and {}", MAX_BLUR_RADIUS));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlurImageResponse {
+    new_img_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SharpenImageRequest {
+    // of the blur the image is compared with, 1 to MAX_BLUR_RADIUS
+    #[serde(default = "default_sharpen_radius")]
+    radius: u32,
+    // how much the difference is amplified, 0 to 5
+    #[serde(default = "default_sharpen_amount")]
+    amount: f32,
+}
+
+impl SharpenImageRequest {
+    fn validate(&self) -> Result<()> {
+        if !(1..=MAX_BLUR_RADIUS).contains(&self.radius) {
+            return Err(anyhow!("radius must be between 1 and {}", MAX_BLUR_RADIUS));
+        }
+        if !(0.0..=5.0).contains(&self.amount) {
+            return Err(anyhow!("amount must be between 0 and 5"));
+        }
+        Ok(())
+    }
+}
+
+fn default_sharpen_radius() -> u32 {
+    1
+}
+
+fn default_sharpen_amount() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Serialize)]
+pub struct SharpenImageResponse {
+    new_img_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FilterImageRequest {
+    // one of filter::NAMES
+    name: String,
+}
+
+impl FilterImageRequest {
+    fn validate(&self) -> Result<()> {
+        if !filter::NAMES.contains(&self.name.as_str()) {
+            return Err(anyhow!("Unknown filter {:?}", self.name));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilterImageResponse {
+    new_img_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApplyRecipeRequest {
+    // values of the recipe's parameters, see recipe::Recipe::bind
+    #[serde(default)]
+    params: serde_json::Map<String, serde_json::Value>,
+    // the latest when absent
+    version: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApplyRecipeResponse {
+    // absent for recipes with outputs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_img_id: Option<String>,
+    // the image of every output of the recipe
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    outputs: Vec<JobOutput>,
+    // of the recipe that made them
+    version: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InpaintImageRequest {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InpaintImageResponse {
+    new_img_id: String,
+}
+
+// Compares the image of the path (before) with `edited_id` (after)
+#[derive(Debug, Deserialize)]
+pub struct CompareImageRequest {
+    edited_id: String,
+    #[serde(default)]
+    mode: CompareMode,
+    #[serde(default = "default_split")]
+    split: f32,
+}
+
+fn default_split() -> f32 {
+    0.5
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompareImageResponse {
+    new_img_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShareRequest {
+    // defaults to share::DEFAULT_TTL_SECS
+    ttl_secs: Option<u64>,
+    #[serde(default)]
+    include_variants: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShareResponse {
+    token: String,
+    url: String,
+    // RFC 3339 time the token stops working
+    expires: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignRequest {
+    // defaults to signed_urls.default_ttl_secs
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SignResponse {
+    url: String,
+    // RFC 3339 time the URL stops working
+    expires: String,
+}
+
+// Largest print export in pixels, bleed included
+const MAX_PRINT_PIXELS: u64 = 64_000_000;
+
+#[derive(Debug, Deserialize)]
+pub struct PrintRequest {
+    // trim size, the image is scaled to cover it and cropped
+    width_mm: f64,
+    height_mm: f64,
+    #[serde(default = "default_print_dpi")]
+    dpi: u32,
+    // added on every side by mirroring the edges
+    #[serde(default = "default_bleed_mm")]
+    bleed_mm: f64,
+    #[serde(default)]
+    format: PrintFormat,
+}
+
+fn default_print_dpi() -> u32 {
+    300
+}
+
+fn default_bleed_mm() -> f64 {
+    3.0
+}
+
+impl PrintRequest {
+    fn validate(&self) -> Result<()> {
+        if !(self.width_mm > 0.0 && self.width_mm.is_finite())
+            || !(self.height_mm > 0.0 && self.height_mm.is_finite())
+        {
+            return Err(anyhow!("width_mm and height_mm must be positive"));
+        }
+        if !(72..=1200).contains(&self.dpi) {
+            return Err(anyhow!("dpi must be between 72 and 1200"));
+        }
+        if !(0.0..=25.0).contains(&self.bleed_mm) {
+            return Err(anyhow!("bleed_mm must be between 0 and 25"));
+        }
+        let (width, height) = self.trim_size();
+        let bleed = print::to_pixels(self.bleed_mm, self.dpi) as u64;
+        if width == 0 || height == 0 {
+            return Err(anyhow!(
+                "The trim size is less than a pixel at {} dpi",
+                self.dpi
+            ));
+        }
+        if (width as u64 + 2 * bleed) * (height as u64 + 2 * bleed) > MAX_PRINT_PIXELS {
+            return Err(anyhow!(
+                "The output must be at most {} megapixels",
+                MAX_PRINT_PIXELS / 1_000_000
+            ));
+        }
+        Ok(())
+    }
+
+    // in pixels
+    fn trim_size(&self) -> (u32, u32) {
+        (
+            print::to_pixels(self.width_mm, self.dpi),
+            print::to_pixels(self.height_mm, self.dpi),
+        )
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadQuery {
+    // in addition to the configured strip_metadata
+    #[serde(default)]
+    strip_metadata: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    bundle: Bundle,
+}
+
+// How the files of an export profile are returned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bundle {
+    // stored as new images, their ids are returned
+    #[default]
+    Manifest,
+    // in a zip, nothing is stored
+    Zip,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportResponse {
+    profile: String,
+    images: Vec<ExportedImage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedImage {
+    // as in the profile
+    name: String,
+    id: String,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VariantsResponse {
+    set: String,
+    variants: Vec<ArtDirectedImage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtDirectedImage {
+    // as in the set
+    name: String,
+    id: String,
+    width: u32,
+    height: u32,
+    // media query of a <picture> source, absent for the smallest viewports
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ThumbnailQuery {
+    // one of thumbnail_presets, which replaces the other parameters
+    preset: Option<String>,
+    // longest edge in pixels, thumbnail::DEFAULT_THUMBNAIL_SIZE by default
+    size: Option<u32>,
+    #[serde(default)]
+    fit: Fit,
+    #[serde(default)]
+    gravity: Gravity,
+}
+
+// On-the-fly variant of GET /api/images/{img_id}, streamed without being
+// stored. Absent parameters keep the image as it is.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeliveryQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    #[serde(default)]
+    fit: Fit,
+    #[serde(default)]
+    gravity: Gravity,
+    // output format, e.g. "webp"
+    fmt: Option<String>,
+    // JPEG quality, 1-100
+    q: Option<u8>,
+}
+
+impl DeliveryQuery {
+    fn is_empty(&self) -> bool {
+        self.w.is_none() && self.h.is_none() && self.fmt.is_none() && self.q.is_none()
+    }
+}
+
+// How an image is fitted into a width x height box
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    // the whole image within the box, keeping its aspect ratio
+    #[default]
+    Contain,
+    // the box filled and the overflow cropped from the centre
+    Cover,
+    // stretched to the box
+    Fill,
+}
+
+impl Fit {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+// Where the overflow of Fit::Cover and resizes to another aspect ratio is
+// cropped around
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    // the centre of the image
+    #[default]
+    Center,
+    // the focal point of the image's regions, the centre without regions
+    Focus,
+}
+
+impl Gravity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Gravity::Center => "center",
+            Gravity::Focus => "focus",
+        }
+    }
+
+    // Focal point as fractions of the image width and height, None when the
+    // crop is centred
+    fn focus(self, meta: &ImgMetadata) -> Option<(f32, f32)> {
+        if self != Gravity::Focus {
+            return None;
+        }
+        let (x, y) = meta.focal_point()?;
+        let (width, height) = (meta.width?, meta.height?);
+        Some((x as f32 / width as f32, y as f32 / height as f32))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AsyncTransformResponse {
+    job_id: String,
+    preview_img_id: String,
+}
+
+// Longest edge of the low-res preview returned for async transforms
+const PREVIEW_MAX_EDGE: u32 = 128;
+
+// Draws `text` so the box it covers (see FontStack::measure) is placed in the
+// image
+fn add_watermark_to_image(
+    image: &mut PhotonImage,
+    text: &str,
+    placement: &Placement,
+    font_size: u32,
+    options: &TextOptions,
+    fonts: &FontStack,
+) {
+    let Some(bounds) = fonts.measure(text, font_size as f32, options) else {
+        return;
     };
+    let (x, y) = placement.locate(
+        image.get_width(),
+        image.get_height(),
+        bounds.width,
+        bounds.height,
+    );
 
-    // Apply the watermark (photon-rs draw_text applies text with a default font style)
-    draw_text(
+    fonts.draw(
         image,
         text,
-        x as i32,
-        y as i32,
-        font_size as f32, // photon-rs expects f32 for font size
+        x as i32 - bounds.left,
+        y as i32 - bounds.top,
+        font_size as f32,
+        options,
+    );
+}
+
+// Composites `logo` over the image at `placement`, after scaling it to
+// `scale` of the image width and fading it by `opacity`. Logos larger than
+// the image are cut off at its edges.
+fn add_logo_to_image(
+    image: &mut PhotonImage,
+    logo: PhotonImage,
+    placement: &Placement,
+    scale: Option<f32>,
+    opacity: f32,
+    resizer: Resizer,
+) {
+    let logo = fit_logo(logo, image.get_width(), scale, opacity, resizer);
+    let (x, y) = placement.locate(
+        image.get_width(),
+        image.get_height(),
+        logo.get_width(),
+        logo.get_height(),
+    );
+    photon_rs::multiple::watermark(image, &logo, x as i64, y as i64);
+}
+
+// The logo scaled to `scale` of image_width and faded by `opacity`
+fn fit_logo(
+    mut logo: PhotonImage,
+    image_width: u32,
+    scale: Option<f32>,
+    opacity: f32,
+    resizer: Resizer,
+) -> PhotonImage {
+    if let Some(scale) = scale {
+        let width = ((image_width as f32 * scale).round() as u32).max(1);
+        let height = ((logo.get_height() as f32 * width as f32 / logo.get_width() as f32).round()
+            as u32)
+            .max(1);
+        logo = resize::lanczos3(&logo, width, height, resizer);
+    }
+    fade(logo, opacity)
+}
+
+// Scales the alpha channel by `opacity`
+fn fade(image: PhotonImage, opacity: f32) -> PhotonImage {
+    if opacity >= 1.0 {
+        return image;
+    }
+    let mut pixels = image.get_raw_pixels();
+    for px in pixels.chunks_exact_mut(4) {
+        px[3] = (px[3] as f32 * opacity).round() as u8;
+    }
+    PhotonImage::new(pixels, image.get_width(), image.get_height())
+}
+
+// Transparent RGBA pixels of a width x height stamp
+fn stamp_pixels(width: u32, height: u32) -> Result<Vec<u8>> {
+    let pixels = (width as usize)
+        .checked_mul(height as usize)
+        .filter(|&n| n <= MAX_STAMP_PIXELS)
+        .ok_or(StampTooLarge)?;
+    Ok(vec![0; pixels * 4])
+}
+
+// Watermark text on a transparent image just large enough for it, None for
+// text without visible glyphs
+fn text_stamp(
+    text: &str,
+    font_size: u32,
+    options: &TextOptions,
+    fonts: &FontStack,
+) -> Result<Option<PhotonImage>> {
+    let Some(bounds) = fonts.measure(text, font_size as f32, options) else {
+        return Ok(None);
+    };
+    let (width, height) = (bounds.width.max(1), bounds.height.max(1));
+    let mut stamp = PhotonImage::new(stamp_pixels(width, height)?, width, height);
+    fonts.draw(
+        &mut stamp,
+        text,
+        -bounds.left,
+        -bounds.top,
+        font_size as f32,
+        options,
     );
+    Ok(Some(stamp))
+}
+
+// `top` centred above `bottom`, TILE_GAP apart
+fn stack_stamps(top: &PhotonImage, bottom: &PhotonImage) -> Result<PhotonImage> {
+    let width = top.get_width().max(bottom.get_width());
+    let height = top
+        .get_height()
+        .checked_add(TILE_GAP)
+        .and_then(|h| h.checked_add(bottom.get_height()))
+        .ok_or(StampTooLarge)?;
+    let mut pixels = stamp_pixels(width, height)?;
+    for (part, y) in [(top, 0), (bottom, top.get_height() + TILE_GAP)] {
+        alpha::overlay(
+            &mut pixels,
+            width,
+            &part.get_raw_pixels(),
+            part.get_width(),
+            ((width - part.get_width()) / 2) as i64,
+            y as i64,
+        );
+    }
+    Ok(PhotonImage::new(pixels, width, height))
+}
+
+// Draws the text and logo of `req` onto the image, `logo` being the image
+// req.logo names
+fn stamp_watermark(
+    mut img: PhotonImage,
+    req: &WatermarkRequest,
+    logo: Option<PhotonImage>,
+    fonts: &FontStack,
+    resizer: Resizer,
+) -> Result<PhotonImage> {
+    if req.mode == WatermarkMode::Tile {
+        let logo = match (&req.logo, logo) {
+            (Some(logo_req), Some(logo_img)) => Some(fit_logo(
+                logo_img,
+                img.get_width(),
+                logo_req.scale,
+                logo_req.opacity,
+                resizer,
+            )),
+            _ => None,
+        };
+        let text = match req.text.is_empty() {
+            true => None,
+            false => text_stamp(&req.text, req.font_size, &req.options, fonts)?,
+        };
+        let stamp = match (logo, text) {
+            (Some(logo), Some(text)) => stack_stamps(&logo, &text)?,
+            (Some(stamp), None) | (None, Some(stamp)) => stamp,
+            (None, None) => return Ok(img),
+        };
+        tile_watermark(&mut img, stamp, &req.tile)?;
+        return Ok(img);
+    }
+
+    if let (Some(logo_req), Some(logo_img)) = (&req.logo, logo) {
+        add_logo_to_image(
+            &mut img,
+            logo_img,
+            &req.placement,
+            logo_req.scale,
+            logo_req.opacity,
+            resizer,
+        );
+    }
+    if !req.text.is_empty() {
+        add_watermark_to_image(
+            &mut img,
+            &req.text,
+            &req.placement,
+            req.font_size,
+            &req.options,
+            fonts,
+        );
+    }
+    Ok(img)
+}
+
+// Repeats `stamp` over the whole image, turned by the tile angle and faded by
+// its opacity. Every other row is shifted by half a step, so the repeats run
+// diagonally.
+fn tile_watermark(image: &mut PhotonImage, stamp: PhotonImage, tile: &TileOptions) -> Result<()> {
+    let stamp = match tile.angle.rem_euclid(360.0) {
+        0.0 => stamp,
+        angle => rotate(&stamp, angle),
+    };
+    let stamp = fade(stamp, tile.opacity);
+
+    let (width, height) = (image.get_width() as i64, image.get_height() as i64);
+    let step_x = stamp.get_width() as i64 + tile.spacing as i64;
+    let step_y = stamp.get_height() as i64 + tile.spacing as i64;
+    if (width / step_x + 2) * (height / step_y + 1) > MAX_TILES {
+        return Err(TooManyTiles.into());
+    }
+
+    let mut pixels = image.get_raw_pixels();
+    let stamp_pixels = stamp.get_raw_pixels();
+    for (row, y) in (0..height).step_by(step_y as usize).enumerate() {
+        let shift = if row % 2 == 1 { step_x / 2 } else { 0 };
+        for x in (-shift..width).step_by(step_x as usize) {
+            alpha::overlay(
+                &mut pixels,
+                width as u32,
+                &stamp_pixels,
+                stamp.get_width(),
+                x,
+                y,
+            );
+        }
+    }
+    *image = PhotonImage::new(pixels, width as u32, height as u32);
+    Ok(())
 }
 
 fn resize_image(
@@ -95,13 +1360,29 @@ fn resize_image(
     width: Option<u32>,
     height: Option<u32>,
     maintain_aspect: bool,
+    resizer: Resizer,
 ) -> Result<PhotonImage> {
-    // Get original dimensions
-    let orig_width = image.get_width();
-    let orig_height = image.get_height();
+    let (new_width, new_height) = resize_dimensions(
+        image.get_width(),
+        image.get_height(),
+        width,
+        height,
+        maintain_aspect,
+    )?;
+
+    // Resize the image using Lanczos3 filter for high quality
+    Ok(resize::lanczos3(image, new_width, new_height, resizer))
+}
 
-    // Determine new dimensions
-    let (new_width, new_height) = match (width, height, maintain_aspect) {
+// Output dimensions of `resize_image` for an orig_width x orig_height input
+fn resize_dimensions(
+    orig_width: u32,
+    orig_height: u32,
+    width: Option<u32>,
+    height: Option<u32>,
+    maintain_aspect: bool,
+) -> Result<(u32, u32)> {
+    let dimensions = match (width, height, maintain_aspect) {
         (Some(w), Some(h), false) => (w, h), // Exact dimensions, ignore aspect ratio
         (Some(w), None, _) => {
             // Resize based on width, maintain aspect ratio
@@ -128,28 +1409,311 @@ fn resize_image(
         }
     };
 
-    // Resize the image using Lanczos3 filter for high quality
-    let resized_image = resize(
-        image,
-        new_width,
-        new_height,
-        photon_rs::transform::SamplingFilter::Lanczos3,
+    Ok(dimensions)
+}
+
+// Size the image is scaled to by fit_image, before the crop of Fit::Cover.
+// Images are only ever scaled down.
+fn fit_dimensions(
+    width: u32,
+    height: u32,
+    box_width: Option<u32>,
+    box_height: Option<u32>,
+    fit: Fit,
+) -> (u32, u32) {
+    let ratio = match (box_width, box_height, fit) {
+        (Some(w), Some(h), Fit::Fill) => return (w.min(width), h.min(height)),
+        (Some(w), Some(h), Fit::Contain) => (w as f32 / width as f32).min(h as f32 / height as f32),
+        (Some(w), Some(h), Fit::Cover) => (w as f32 / width as f32).max(h as f32 / height as f32),
+        (Some(w), None, _) => w as f32 / width as f32,
+        (None, Some(h), _) => h as f32 / height as f32,
+        (None, None, _) => 1.0,
+    };
+    if ratio >= 1.0 {
+        return (width, height);
+    }
+
+    (
+        ((width as f32 * ratio).round() as u32).max(1),
+        ((height as f32 * ratio).round() as u32).max(1),
+    )
+}
+
+// Scales the image into the box as `fit` says. The overflow of Fit::Cover is
+// cropped around `focus` (see Gravity::focus), or evenly from both sides.
+fn fit_image(
+    img: PhotonImage,
+    box_width: Option<u32>,
+    box_height: Option<u32>,
+    fit: Fit,
+    focus: Option<(f32, f32)>,
+    resizer: Resizer,
+) -> PhotonImage {
+    let (width, height) = fit_dimensions(
+        img.get_width(),
+        img.get_height(),
+        box_width,
+        box_height,
+        fit,
     );
+    let img = if (width, height) == (img.get_width(), img.get_height()) {
+        img
+    } else {
+        resize::lanczos3(&img, width, height, resizer)
+    };
 
-    Ok(resized_image)
+    match (fit, box_width, box_height) {
+        (Fit::Cover, Some(box_width), Some(box_height)) => {
+            let (w, h) = (box_width.min(width), box_height.min(height));
+            let (x, y) = crop_origin(width, height, w, h, focus);
+            crop(&img, x, y, x + w, y + h)
+        }
+        _ => img,
+    }
 }
 
-fn save_new_iamge(
-    file_path: &str,
+// Scaled to cover the box and cropped to it around `focus` or from the
+// centre. Unlike Fit::Cover smaller images are scaled up, the result has the
+// exact size.
+fn cover_exact(
+    img: &PhotonImage,
+    width: u32,
+    height: u32,
+    focus: Option<(f32, f32)>,
+    resizer: Resizer,
+) -> PhotonImage {
+    let (orig_width, orig_height) = (img.get_width(), img.get_height());
+    let ratio = (width as f32 / orig_width as f32).max(height as f32 / orig_height as f32);
+    let scaled_width = ((orig_width as f32 * ratio).round() as u32).max(width);
+    let scaled_height = ((orig_height as f32 * ratio).round() as u32).max(height);
+
+    let scaled = resize::lanczos3(img, scaled_width, scaled_height, resizer);
+    let (x, y) = crop_origin(scaled_width, scaled_height, width, height, focus);
+    crop(&scaled, x, y, x + width, y + height)
+}
+
+// Top left corner of a width x height window of the image, centred on `focus`
+// as far as the window stays inside the image
+fn crop_origin(
+    img_width: u32,
+    img_height: u32,
+    width: u32,
+    height: u32,
+    focus: Option<(f32, f32)>,
+) -> (u32, u32) {
+    let Some((fx, fy)) = focus else {
+        return ((img_width - width) / 2, (img_height - height) / 2);
+    };
+    let centred = |len: u32, window: u32, at: f32| {
+        let start = (at * len as f32).round() as i64 - window as i64 / 2;
+        start.clamp(0, (len - window) as i64) as u32
+    };
+    (
+        centred(img_width, width, fx),
+        centred(img_height, height, fy),
+    )
+}
+
+// Rotates clockwise by `angle` degrees, then applies the flips. Multiples of 90
+// are exact, other angles grow the canvas to fit the rotated image.
+fn rotate_image(
+    image: PhotonImage,
+    angle: f32,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+) -> PhotonImage {
+    let mut image = match angle.rem_euclid(360.0) {
+        0.0 => image,
+        angle => rotate(&image, angle),
+    };
+    if flip_horizontal {
+        fliph(&mut image);
+    }
+    if flip_vertical {
+        flipv(&mut image);
+    }
+    image
+}
+
+// Applies the non-zero adjustments of the request, in the order they are
+// listed
+fn adjust_image(image: &mut PhotonImage, req: &AdjustImageRequest) {
+    if req.brightness != 0 {
+        adjust_brightness(image, req.brightness);
+    }
+    if req.contrast != 0.0 {
+        // photon makes every pixel opaque
+        let original = alpha::has_transparency(image).then(|| image.clone());
+        adjust_contrast(image, req.contrast);
+        if let Some(original) = original {
+            *image = alpha::restore(image, &original);
+        }
+    }
+    if req.saturation != 0.0 {
+        // negative factors desaturate
+        colour_spaces::saturate_hsl(image, req.saturation);
+    }
+    if req.hue_shift != 0.0 {
+        // photon takes fractions of a turn, despite the parameter's name
+        colour_spaces::hue_rotate_hsl(image, req.hue_shift / 360.0);
+    }
+}
+
+// Gaussian blur, premultiplied like resizes so transparent pixels do not
+// darken the edges. Photon limits the radius to half the smaller side.
+fn blur_image(image: &PhotonImage, radius: u32) -> PhotonImage {
+    let radius = (radius as i32)
+        .min(image.get_width() as i32 / 2 - 1)
+        .min(image.get_height() as i32 / 2 - 1);
+    if radius < 1 {
+        return image.clone();
+    }
+
+    if !alpha::has_transparency(image) {
+        let mut blurred = image.clone();
+        gaussian_blur(&mut blurred, radius);
+        return blurred;
+    }
+    let mut blurred = alpha::premultiply(image);
+    gaussian_blur(&mut blurred, radius);
+    alpha::unpremultiply(&blurred)
+}
+
+// Unsharp mask: the difference to a blur of `radius`, times `amount`, is
+// added to the image
+fn sharpen_image(image: &PhotonImage, radius: u32, amount: f32) -> PhotonImage {
+    let blurred = blur_image(image, radius).get_raw_pixels();
+    let mut pixels = image.get_raw_pixels();
+    for (px, blurred) in pixels.chunks_exact_mut(4).zip(blurred.chunks_exact(4)) {
+        for (c, b) in px[..3].iter_mut().zip(&blurred[..3]) {
+            let sharpened = *c as f32 + amount * (*c as f32 - *b as f32);
+            *c = sharpened.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    PhotonImage::new(pixels, image.get_width(), image.get_height())
+}
+
+// Lossy JPEG round trip at `quality` (0-100). JPEG has no alpha channel, so
+// the alpha of transparent images is carried over from the input instead of
+// being dropped.
+fn jpeg_compress(image: &PhotonImage, quality: u8) -> PhotonImage {
+    let compressed = compress(image, quality);
+    if alpha::has_transparency(image) {
+        return alpha::restore(&compressed, image);
+    }
+    compressed
+}
+
+// Cheap nearest-neighbour downscale, used as a stand-in while a job runs
+// Dimensions of the preview of a width x height image
+fn preview_dimensions(width: u32, height: u32) -> (u32, u32) {
+    let ratio = (PREVIEW_MAX_EDGE as f32 / width.max(height) as f32).min(1.0);
+    (
+        ((width as f32 * ratio).round() as u32).max(1),
+        ((height as f32 * ratio).round() as u32).max(1),
+    )
+}
+
+fn preview_image(image: &PhotonImage) -> PhotonImage {
+    let (width, height) = preview_dimensions(image.get_width(), image.get_height());
+    resize(
+        image,
+        width,
+        height,
+        photon_rs::transform::SamplingFilter::Nearest,
+    )
+}
+
+// Encodes and writes the image on the blocking thread pool, returning the id
+// it was saved under
+async fn save_new_iamge(
+    conf: &AppConfig,
+    img_meta: &ImgMetadata,
+    image: PhotonImage,
+    output: &Output,
+) -> Result<String> {
+    let (conf, img_meta, output) = (conf.clone(), img_meta.clone(), output.clone());
+    let collector = Timings::current();
+    tokio::task::spawn_blocking(move || {
+        timings::within(collector, || {
+            write_new_image(&conf, &img_meta, image, &output)
+        })
+    })
+    .await?
+}
+
+fn write_new_image(
+    conf: &AppConfig,
     img_meta: &ImgMetadata,
     compressed_image: PhotonImage,
+    output: &Output,
 ) -> Result<String> {
-    let new_image_id = Uuid::new_v4().to_string();
-    let output_path = PathBuf::from(format!("{}/{}{}", file_path, new_image_id, img_meta.fmt));
+    let staged = staged_output_path(conf, &img_meta.fmt);
 
     // Save the modified image
-    match save_image(compressed_image, output_path.to_str().unwrap()) {
-        Err(e) => return Err(anyhow!("Failed to save image: {}", e)),
-        Ok(_) => Ok(new_image_id),
+    let saved = timings::time("encode", || {
+        encode_image(conf, compressed_image, &img_meta.fmt, None)
+            .and_then(|data| crypt::seal(conf, data))
+    })
+    .and_then(|data| timings::time("write", || Ok(std::fs::write(&staged, data)?)));
+    if let Err(e) = saved {
+        let _ = std::fs::remove_file(&staged);
+        return Err(anyhow!("Failed to save image: {}", e));
     }
+
+    let img_id = timings::time("promote", || {
+        promote_output(
+            conf,
+            &staged,
+            &img_meta.fmt,
+            output.naming,
+            &output.source_id,
+            output.operation,
+        )
+    })?;
+
+    let derivation = Derivation::new(&output.source_id, output.operation, output.params.clone());
+    if let Err(e) = lineage::record(conf, &img_id, &derivation) {
+        warn!("failed to record lineage of {}: {}", img_id, e);
+    }
+    Ok(img_id)
+}
+
+// Encodes like photon's save_image, in memory so it can be encrypted before
+// it is written. Transforms can make pixels of a JPEG transparent, e.g. the
+// corners of a rotation, those are flattened onto the configured background
+// instead of turning black. `quality` applies to JPEG only, the image crate
+// writes the other formats losslessly.
+pub(crate) fn encode_image(
+    conf: &AppConfig,
+    mut image: PhotonImage,
+    fmt: &str,
+    quality: Option<u8>,
+) -> Result<Vec<u8>> {
+    if !ImageFormat::from_fmt(fmt).has_alpha() && alpha::has_transparency(&image) {
+        image = alpha::flatten(&image, conf.background);
+    }
+
+    let format = ::image::ImageFormat::from_extension(fmt.trim_start_matches('.'))
+        .ok_or_else(|| anyhow!("cannot encode {} images", fmt))?;
+    let (width, height) = (image.get_width(), image.get_height());
+    let buffer = ::image::RgbaImage::from_vec(width, height, image.get_raw_pixels())
+        .ok_or_else(|| anyhow!("pixel buffer does not match {}x{}", width, height))?;
+
+    let format = match (format, quality) {
+        (::image::ImageFormat::Jpeg, Some(quality)) => ::image::ImageOutputFormat::Jpeg(quality),
+        (format, _) => format.into(),
+    };
+    let mut out = std::io::Cursor::new(Vec::new());
+    ::image::DynamicImage::ImageRgba8(buffer).write_to(&mut out, format)?;
+    Ok(out.into_inner())
+}
+
+pub(crate) fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
 }
@@ -0,0 +1,131 @@
+use tracing::{info, warn};
+
+use crate::{
+    budget, decode,
+    error::AppError,
+    exif,
+    format::{self, ImageFormat},
+    handlers::{encode_image, recipe::process_image},
+    resize,
+    state::{AppConfig, AppState, UploadPolicy},
+    strip,
+    tenant::Tenant,
+};
+
+// The policy an upload into `collection` with the key of `tenant` is held
+// to: that of the collection, otherwise that of the key. Only uploads to
+// /api/images/upload have either, S3 PUTs and watch and email ingest are not
+// held to a policy, their `recipe` options take its place.
+pub(crate) fn for_upload<'a>(
+    conf: &'a AppConfig,
+    tenant: &'a Tenant,
+    collection: Option<&str>,
+) -> Option<&'a UploadPolicy> {
+    collection
+        .and_then(|c| conf.collection_policies.get(c))
+        .or(tenant.upload_policy.as_ref())
+}
+
+// Whether uploads in `fmt` are transformed by the policy. GIFs are stored as
+// sent, a transform would keep only the first frame of animations, and so
+// are formats photon cannot decode.
+pub(crate) fn transforms(policy: &UploadPolicy, fmt: &ImageFormat) -> bool {
+    policy.transforms() && *fmt != ImageFormat::Gif && fmt.is_decodable()
+}
+
+// `data` brought to the policy: run through its recipe, scaled down to
+// max_dimension and encoded as convert_to. Unless `strip` is set the EXIF of
+// the upload is copied to the new encoding, which would leave it behind.
+// Returns the format and data to store, None when the upload is stored as
+// sent: the policy does not transform uploads in `fmt`, or there is no
+// recipe and the upload already fits max_dimension in the convert_to format.
+pub(crate) async fn apply(
+    conf: &AppConfig,
+    state: &AppState,
+    policy: &UploadPolicy,
+    fmt: ImageFormat,
+    data: Vec<u8>,
+    strip: bool,
+) -> Result<Option<(ImageFormat, Vec<u8>)>, AppError> {
+    if !transforms(policy, &fmt) {
+        return Ok(None);
+    }
+    let target = match &policy.convert_to {
+        Some(name) => format::output_format(conf, name)?,
+        None => fmt,
+    };
+    let fits = policy.max_dimension.is_none_or(|edge| {
+        fmt.dimensions(&data)
+            .is_some_and(|(width, height)| width.max(height) <= edge)
+    });
+    if policy.recipe.is_none() && target == fmt && fits {
+        return Ok(None);
+    }
+    // the decode turns the pixels by the EXIF orientation, the copy says upright
+    let metadata = (!strip).then(|| exif::raw(&data)).flatten().map(|tiff| {
+        let turned = decode::orientation(&conf.decode, &data).is_some();
+        (tiff, turned)
+    });
+
+    let _permit = state.budget.acquire(budget::estimate(&fmt, &data)).await?;
+    let img = match decode::run(&conf.decode, fmt, data, None).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to decode upload: {}", e);
+            return Err(AppError::DecodeError(
+                "Image could not be decoded".to_string(),
+            ));
+        }
+    };
+    let img = match &policy.recipe {
+        Some(name) => match process_image(conf, &state.budget, &state.fonts, name, img).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("failed to run recipe {} of upload policy: {}", name, e);
+                return Err(AppError::Internal(format!(
+                    "Failed to apply recipe {} of the upload policy",
+                    name
+                )));
+            }
+        },
+        None => img,
+    };
+    let (task_conf, max_dimension) = (conf.clone(), policy.max_dimension);
+    let res = tokio::task::spawn_blocking(move || {
+        let img = match max_dimension {
+            Some(edge) if img.get_width().max(img.get_height()) > edge => {
+                let ratio = edge as f32 / img.get_width().max(img.get_height()) as f32;
+                let width = ((img.get_width() as f32 * ratio).round() as u32).max(1);
+                let height = ((img.get_height() as f32 * ratio).round() as u32).max(1);
+                resize::lanczos3(&img, width, height, task_conf.resizer)
+            }
+            _ => img,
+        };
+        let data = encode_image(&task_conf, img, target.as_str(), None)?;
+        let data = match metadata {
+            Some((tiff, upright)) => strip::insert_exif(data, target, &tiff, upright)?,
+            None => data,
+        };
+        Ok::<_, anyhow::Error>((target, data))
+    })
+    .await;
+
+    match res {
+        Ok(Ok((target, data))) => {
+            info!(
+                "upload policy: {} -> {}, {} bytes",
+                fmt.as_str(),
+                target.as_str(),
+                data.len()
+            );
+            Ok(Some((target, data)))
+        }
+        Ok(Err(e)) => {
+            warn!("failed to apply upload policy: {}", e);
+            Err(AppError::Unprocessable(
+                "Image could not be brought to the upload policy".to_string(),
+            ))
+        }
+        Err(e) => Err(AppError::Internal(e.to_string())),
+    }
+}
@@ -0,0 +1,193 @@
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{Response, header},
+};
+use bytes::Bytes;
+use photon_rs::PhotonImage;
+use reqwest::Url;
+use serde::Deserialize;
+use std::{
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+use crate::{
+    budget, decode,
+    error::AppError,
+    format::{self, ImageFormat},
+    handlers::{resize_dimensions, resize_image},
+    sigv4,
+    state::{AppConfig, AppState, ProxyConfig},
+    storage::{read_image_bytes, write_cached_file},
+};
+
+const JPEG_QUALITY: u8 = 85;
+
+// `?url=` of the remote image, optionally resized to fit `w` x `h`, which
+// are capped at its own size
+#[derive(Debug, Deserialize)]
+pub struct ProxyQuery {
+    url: String,
+    w: Option<u32>,
+    h: Option<u32>,
+}
+
+// Fetches an image from an allowed remote host, resizes it when asked to and
+// keeps the result in the proxy cache for max_age_secs
+pub async fn proxy_image(
+    State(state): State<AppState>,
+    Query(query): Query<ProxyQuery>,
+) -> Result<Response<Body>, AppError> {
+    let Some(proxy) = &state.conf.proxy else {
+        return Err(AppError::NotFound("Proxy is disabled".to_string()));
+    };
+
+    let url = match state.egress.check(&query.url) {
+        Ok(v) => v,
+        Err(e) => return Err(AppError::BadRequest(e.to_string())),
+    };
+    if !is_allowed_host(proxy, &url) {
+        return Err(AppError::Forbidden(format!(
+            "{} is not an allowed host",
+            url.host_str().unwrap_or_default()
+        )));
+    }
+    if query.w == Some(0) || query.h == Some(0) {
+        return Err(AppError::BadRequest("w and h must be positive".to_string()));
+    }
+
+    let cache_path = Path::new(&proxy.cache_dir).join(sigv4::sha256_hex(
+        format!("{}|{:?}|{:?}", url, query.w, query.h).as_bytes(),
+    ));
+    if let Some(data) = read_cached(&state.conf, proxy, &cache_path).await {
+        return image_response(proxy, data);
+    }
+
+    let fetched = match state.egress.fetch(url.as_str()).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("proxy fetch of {} failed: {}", url, e);
+            return Err(AppError::Upstream(format!("Failed to fetch {}", url)));
+        }
+    };
+    // redirects may lead anywhere the egress policy allows
+    if !is_allowed_host(proxy, &fetched.url) {
+        return Err(AppError::Forbidden(format!(
+            "{} redirected to a host that is not allowed",
+            url
+        )));
+    }
+
+    let fmt = ImageFormat::sniff(&fetched.body);
+    if fmt == ImageFormat::Unknown {
+        return Err(AppError::Upstream(format!(
+            "{} is not a supported image",
+            url
+        )));
+    }
+    format::check_input(&state.conf, fmt)?;
+
+    let data = if query.w.is_none() && query.h.is_none() {
+        fetched.body
+    } else if !fmt.is_decodable() {
+        return Err(AppError::Upstream(format!("{} cannot be resized", url)));
+    } else {
+        let (w, h) = (query.w, query.h);
+        let min_size = move |width, height| {
+            let (w, h) = (w.map(|w| w.min(width)), h.map(|h| h.min(height)));
+            resize_dimensions(width, height, w, h, true).unwrap_or((width, height))
+        };
+        let estimate = match decode::decoded_size(&fmt, &fetched.body, Some(&min_size)) {
+            Some((width, height)) => budget::decode_bytes(width, height),
+            None => budget::estimate(&fmt, &fetched.body),
+        };
+        let _permit = state.budget.acquire(estimate).await?;
+
+        let mut img = match decode::run(
+            &state.conf.decode,
+            fmt,
+            fetched.body.to_vec(),
+            Some(&min_size),
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(AppError::Upstream(format!(
+                    "{} could not be decoded: {}",
+                    url, e
+                )));
+            }
+        };
+
+        let resizer = state.conf.resizer;
+        let res = tokio::task::spawn_blocking(move || {
+            // never scaled up, the budget only covers the decoded image
+            let w = w.map(|w| w.min(img.get_width()));
+            let h = h.map(|h| h.min(img.get_height()));
+            let resized = resize_image(&mut img, w, h, true, resizer)?;
+            Ok::<_, anyhow::Error>(encode(&fmt, &resized))
+        })
+        .await;
+
+        match res {
+            Ok(Ok(v)) => Bytes::from(v),
+            Ok(Err(e)) => return Err(AppError::BadRequest(e.to_string())),
+            Err(e) => return Err(AppError::Internal(e.to_string())),
+        }
+    };
+
+    if let Err(e) = write_cached_file(&state.conf, &cache_path, data.to_vec()).await {
+        warn!("failed to cache proxied {}: {}", url, e);
+    }
+    info!("proxied {} ({} bytes)", url, data.len());
+    image_response(proxy, data)
+}
+
+fn is_allowed_host(proxy: &ProxyConfig, url: &Url) -> bool {
+    let Some(host) = url.host_str().map(|h| h.to_ascii_lowercase()) else {
+        return false;
+    };
+
+    proxy.allowed_hosts.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        match allowed.strip_prefix("*.") {
+            Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+            None => host == allowed,
+        }
+    })
+}
+
+// Same format as the source where photon can encode it, PNG otherwise
+fn encode(fmt: &ImageFormat, img: &PhotonImage) -> Vec<u8> {
+    match fmt {
+        ImageFormat::Jpeg => img.get_bytes_jpeg(JPEG_QUALITY),
+        ImageFormat::WebP => img.get_bytes_webp(),
+        _ => img.get_bytes(),
+    }
+}
+
+async fn read_cached(conf: &AppConfig, proxy: &ProxyConfig, path: &Path) -> Option<Bytes> {
+    let modified = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    if age > Duration::from_secs(proxy.max_age_secs) {
+        return None;
+    }
+    read_image_bytes(conf, path).await.ok()
+}
+
+fn image_response(proxy: &ProxyConfig, data: Bytes) -> Result<Response<Body>, AppError> {
+    let content_type = ImageFormat::sniff(&data).content_type();
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", proxy.max_age_secs),
+        )
+        .body(Body::from(data))
+        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
+}
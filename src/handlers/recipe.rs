@@ -0,0 +1,526 @@
+use anyhow::{Result, anyhow, bail};
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use photon_rs::PhotonImage;
+use serde::Deserialize;
+use serde_json::Map;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{self, AuditEvent},
+    budget::{self, BudgetPermit, MemoryBudget},
+    decode,
+    error::AppError,
+    filter,
+    format::{self, FormatNotAllowed, ImageFormat},
+    handlers::{
+        AdjustImageRequest, BlurImageRequest, FilterImageRequest, Frame, Gravity, ImgMetadata,
+        Output, ResizeImageRequest, RotateImageRequest, SharpenImageRequest, WatermarkRequest,
+        adjust_image, blur_image, cover_exact, encode_image, image::read_image, resize_image,
+        rotate_image, sharpen_image, stamp_watermark, write_new_image,
+    },
+    jobs::JobOutput,
+    recipe::{
+        self, Recipe, RecipeDefinition, RecipeExists, RecipeStep, UnknownRecipe, UnknownVersion,
+    },
+    resize::Resizer,
+    state::{AppConfig, AppState},
+    tenant::Tenant,
+    text::FontStack,
+    timings,
+};
+
+// Transforms a recipe step can be, those from one image to another of the
+// same format
+pub const RECIPE_OPS: &[&str] = &[
+    "resize",
+    "rotate",
+    "adjust",
+    "blur",
+    "sharpen",
+    "filter",
+    "watermark",
+];
+
+// Most outputs a recipe fans out into, each is encoded and written
+pub const MAX_RECIPE_OUTPUTS: usize = 16;
+
+// Parsed and checked RecipeStep
+pub(crate) enum Step {
+    Resize(ResizeImageRequest),
+    Rotate(RotateImageRequest),
+    Adjust(AdjustImageRequest),
+    Blur(BlurImageRequest),
+    Sharpen(SharpenImageRequest),
+    Filter(FilterImageRequest),
+    Watermark(WatermarkRequest),
+}
+
+// A bound recipe (see Recipe::bind) with the checks of the routes of its
+// steps
+pub(crate) struct Pipeline {
+    steps: Vec<Step>,
+    outputs: Vec<Branch>,
+}
+
+// One of the outputs of a Pipeline
+struct Branch {
+    name: String,
+    steps: Vec<Step>,
+    // that of the source when None
+    fmt: Option<ImageFormat>,
+}
+
+// What steps need besides the image
+pub(crate) struct StepContext {
+    // of the source image, for resizes with gravity "focus"
+    meta: ImgMetadata,
+    resizer: Resizer,
+    fonts: Arc<FontStack>,
+    // decoded logos of the watermark steps, by image id
+    logos: HashMap<String, PhotonImage>,
+}
+
+impl Pipeline {
+    pub(crate) fn parse(recipe: &Recipe) -> Result<Self> {
+        if recipe.outputs.len() > MAX_RECIPE_OUTPUTS {
+            bail!("a recipe has at most {} outputs", MAX_RECIPE_OUTPUTS);
+        }
+        let mut names = HashSet::new();
+        let mut outputs = Vec::with_capacity(recipe.outputs.len());
+        for out in &recipe.outputs {
+            if out.name.is_empty() || !names.insert(out.name.as_str()) {
+                bail!("output names must be set and unique, {:?} is not", out.name);
+            }
+            let fmt = match out.format.as_deref().map(ImageFormat::from_name) {
+                Some(fmt) if !fmt.is_decodable() => {
+                    bail!("output {}: unknown format {:?}", out.name, out.format)
+                }
+                fmt => fmt,
+            };
+            outputs.push(Branch {
+                name: out.name.clone(),
+                steps: parse_steps(&out.steps)
+                    .map_err(|e| anyhow!("output {}: {}", out.name, e))?,
+                fmt,
+            });
+        }
+        Ok(Pipeline {
+            steps: parse_steps(&recipe.steps)?,
+            outputs,
+        })
+    }
+
+    // Whether it fans out into several images, see fan_out
+    pub(crate) fn has_outputs(&self) -> bool {
+        !self.outputs.is_empty()
+    }
+
+    // [formats] may have changed since the recipe was stored
+    pub(crate) fn check_formats(&self, conf: &AppConfig) -> Result<(), FormatNotAllowed> {
+        for fmt in self.outputs.iter().filter_map(|out| out.fmt) {
+            if !format::produces(conf, fmt) {
+                let name = fmt.as_str().trim_start_matches('.').to_string();
+                return Err(FormatNotAllowed::Output(name));
+            }
+        }
+        Ok(())
+    }
+
+    fn all_steps(&self) -> impl Iterator<Item = &Step> {
+        self.steps
+            .iter()
+            .chain(self.outputs.iter().flat_map(|out| &out.steps))
+    }
+
+    // The recipe's steps on `img`, without its outputs
+    pub(crate) fn run(self, img: PhotonImage, ctx: &StepContext) -> Result<PhotonImage> {
+        apply_steps(img, self.steps, ctx)
+    }
+
+    // Runs the recipe's steps once and the steps of every output on a copy of
+    // their result, saving each output like a transform's. Returns the ids by
+    // output name.
+    pub(crate) fn fan_out(
+        self,
+        conf: &AppConfig,
+        img: PhotonImage,
+        ctx: &StepContext,
+        output: &Output,
+    ) -> Result<Vec<JobOutput>> {
+        let mut shared = Some(timings::time("recipe", || {
+            apply_steps(img, self.steps, ctx)
+        })?);
+        let mut made = Vec::with_capacity(self.outputs.len());
+        let last = self.outputs.len().saturating_sub(1);
+        for (i, out) in self.outputs.into_iter().enumerate() {
+            // the last output gets the shared result itself
+            let img = match i == last {
+                true => shared.take(),
+                false => shared.clone(),
+            }
+            .expect("only the last output takes the shared result");
+            let img = timings::time("recipe", || apply_steps(img, out.steps, ctx))?;
+            let meta = ImgMetadata {
+                fmt: out
+                    .fmt
+                    .map_or(ctx.meta.fmt.clone(), |f| f.as_str().to_string()),
+                ..ctx.meta.clone()
+            };
+            let mut params = output.params.clone();
+            params["output"] = serde_json::Value::String(out.name.clone());
+            let output = output.clone().with_params(params);
+            made.push(JobOutput {
+                id: write_new_image(conf, &meta, img, &output)?,
+                name: out.name,
+            });
+        }
+        Ok(made)
+    }
+}
+
+impl StepContext {
+    // Decodes the logos of the watermark steps, which stay budgeted while the
+    // returned permits are held
+    pub(crate) async fn load(
+        conf: &AppConfig,
+        budget: &MemoryBudget,
+        fonts: Arc<FontStack>,
+        meta: ImgMetadata,
+        pipeline: &Pipeline,
+    ) -> Result<(Self, Vec<BudgetPermit>), AppError> {
+        let mut logos = HashMap::new();
+        let mut permits = Vec::new();
+        for step in pipeline.all_steps() {
+            let Step::Watermark(WatermarkRequest {
+                logo: Some(logo), ..
+            }) = step
+            else {
+                continue;
+            };
+            if logos.contains_key(&logo.img_id) {
+                continue;
+            }
+            let (logo_img, _, permit) =
+                read_image(conf, budget, &logo.img_id, Some(Frame::First), None).await?;
+            logos.insert(logo.img_id.clone(), logo_img);
+            permits.push(permit);
+        }
+        let ctx = StepContext {
+            meta,
+            resizer: conf.resizer,
+            fonts,
+            logos,
+        };
+        Ok((ctx, permits))
+    }
+}
+
+fn parse_steps(steps: &[RecipeStep]) -> Result<Vec<Step>> {
+    steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            parse_step(step).map_err(|e| anyhow!("step {} ({}): {}", i + 1, step.op, e))
+        })
+        .collect()
+}
+
+fn parse_step(step: &RecipeStep) -> Result<Step> {
+    let params = &step.params;
+    Ok(match step.op.as_str() {
+        "resize" => Step::Resize(ResizeImageRequest::deserialize(params)?),
+        "rotate" => {
+            let req = RotateImageRequest::deserialize(params)?;
+            req.validate()?;
+            Step::Rotate(req)
+        }
+        "adjust" => {
+            let req = AdjustImageRequest::deserialize(params)?;
+            req.validate()?;
+            Step::Adjust(req)
+        }
+        "blur" => {
+            let req = BlurImageRequest::deserialize(params)?;
+            req.validate()?;
+            Step::Blur(req)
+        }
+        "sharpen" => {
+            let req = SharpenImageRequest::deserialize(params)?;
+            req.validate()?;
+            Step::Sharpen(req)
+        }
+        "filter" => {
+            let req = FilterImageRequest::deserialize(params)?;
+            req.validate()?;
+            Step::Filter(req)
+        }
+        "watermark" => {
+            let req = WatermarkRequest::deserialize(params)?;
+            req.validate()?;
+            Step::Watermark(req)
+        }
+        _ => bail!(
+            "not a recipe operation, use one of {}",
+            RECIPE_OPS.join(", ")
+        ),
+    })
+}
+
+// Runs `steps` on `img` in order, like the routes of the operations would
+fn apply_steps(mut img: PhotonImage, steps: Vec<Step>, ctx: &StepContext) -> Result<PhotonImage> {
+    let resizer = ctx.resizer;
+    for step in steps {
+        img = match step {
+            Step::Resize(req) if !req.maintain_aspect && req.gravity == Gravity::Focus => {
+                let focus = req.gravity.focus(&ctx.meta).unwrap_or((0.5, 0.5));
+                cover_exact(&img, req.width, req.height, Some(focus), resizer)
+            }
+            Step::Resize(req) => resize_image(
+                &mut img,
+                Some(req.width),
+                Some(req.height),
+                req.maintain_aspect,
+                resizer,
+            )?,
+            Step::Rotate(req) => {
+                rotate_image(img, req.angle, req.flip_horizontal, req.flip_vertical)
+            }
+            Step::Adjust(req) => {
+                adjust_image(&mut img, &req);
+                img
+            }
+            Step::Blur(req) => blur_image(&img, req.radius),
+            Step::Sharpen(req) => sharpen_image(&img, req.radius, req.amount),
+            Step::Filter(req) => {
+                filter::apply(&mut img, &req.name);
+                img
+            }
+            Step::Watermark(req) => {
+                let logo = req
+                    .logo
+                    .as_ref()
+                    .and_then(|logo| ctx.logos.get(&logo.img_id))
+                    .cloned();
+                stamp_watermark(img, &req, logo, &ctx.fonts, resizer)?
+            }
+        };
+    }
+    Ok(img)
+}
+
+// `data` of an ingested image run through the latest version of recipe
+// `name` with its default parameters, in the same format. Ingest stores one
+// image, recipes with outputs are refused.
+pub async fn process(
+    state: &AppState,
+    name: &str,
+    fmt: ImageFormat,
+    data: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let _permit = state.budget.acquire(budget::estimate(&fmt, &data)).await?;
+    let img = decode::run(&state.conf.decode, fmt, data, None).await?;
+    let img = process_image(&state.conf, &state.budget, &state.fonts, name, img).await?;
+    let conf = state.conf.clone();
+    tokio::task::spawn_blocking(move || encode_image(&conf, img, fmt.as_str(), None)).await?
+}
+
+// Like process for an image already decoded, see handlers::policy
+pub(crate) async fn process_image(
+    conf: &AppConfig,
+    budget: &MemoryBudget,
+    fonts: &Arc<FontStack>,
+    name: &str,
+    img: PhotonImage,
+) -> Result<PhotonImage> {
+    let recipe = recipe::get(conf, name, None).await?;
+    let pipeline = Pipeline::parse(&recipe.bind(&Map::new())?)?;
+    if pipeline.has_outputs() {
+        bail!(
+            "recipe {} makes several outputs, only one can be stored",
+            name
+        );
+    }
+    let (ctx, _permits) = StepContext::load(
+        conf,
+        budget,
+        fonts.clone(),
+        ImgMetadata::default(),
+        &pipeline,
+    )
+    .await?;
+
+    tokio::task::spawn_blocking(move || pipeline.run(img, &ctx)).await?
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRecipeRequest {
+    name: String,
+    #[serde(flatten)]
+    definition: RecipeDefinition,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecipeQuery {
+    // an earlier version instead of the latest
+    version: Option<u32>,
+}
+
+pub async fn list_recipes(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Result<impl IntoResponse, AppError> {
+    let conf = tenant.scope(&state.conf);
+    match recipe::list(&conf).await {
+        Ok(recipes) => Ok(Json(recipes)),
+        Err(e) => {
+            warn!("failed to list recipes: {}", e);
+            Err(AppError::StorageError("Failed to list recipes".to_string()))
+        }
+    }
+}
+
+pub async fn create_recipe(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(req): Json<CreateRecipeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let recipe = save(&conf, &req.name, req.definition, true).await?;
+    Ok((StatusCode::CREATED, Json(recipe)))
+}
+
+// Stores a new version of the recipe, the earlier ones stay readable
+pub async fn update_recipe(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(name): Path<String>,
+    Json(def): Json<RecipeDefinition>,
+) -> Result<impl IntoResponse, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let recipe = save(&conf, &name, def, false).await?;
+    Ok(Json(recipe))
+}
+
+pub async fn get_recipe(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(name): Path<String>,
+    Query(query): Query<RecipeQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let conf = tenant.scope(&state.conf);
+    match recipe::get(&conf, &name, query.version).await {
+        Ok(recipe) => Ok(Json(recipe)),
+        Err(e) => Err(recipe_error(&name, e)),
+    }
+}
+
+// Every version of the recipe, oldest first
+pub async fn recipe_versions(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let conf = tenant.scope(&state.conf);
+    match recipe::versions(&conf, &name).await {
+        Ok(versions) => Ok(Json(versions)),
+        Err(e) => Err(recipe_error(&name, e)),
+    }
+}
+
+pub async fn delete_recipe(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let conf = tenant.scope(&state.conf);
+    let recipe = recipe::delete(&conf, &name)
+        .await
+        .map_err(|e| recipe_error(&name, e))?;
+
+    let event = AuditEvent::for_recipe(
+        "recipe_deleted",
+        &name,
+        Some(format!("version {}", recipe.version)),
+    );
+    record(&conf, &event).await;
+    info!("deleted recipe {}", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Checks `def` and stores it, see recipe::save
+async fn save(
+    conf: &AppConfig,
+    name: &str,
+    def: RecipeDefinition,
+    create: bool,
+) -> Result<Recipe, AppError> {
+    if !recipe::is_valid_name(name) {
+        return Err(AppError::BadRequest(
+            "Recipe names are 1 to 64 of a-z, 0-9, - and _".to_string(),
+        ));
+    }
+    if def.steps.is_empty() && def.outputs.is_empty() {
+        return Err(AppError::BadRequest(
+            "a recipe needs at least one step or output".to_string(),
+        ));
+    }
+    // with its defaults the recipe must be usable as it is
+    let draft = Recipe {
+        name: name.to_string(),
+        version: 0,
+        description: None,
+        params: def.params.clone(),
+        steps: def.steps.clone(),
+        outputs: def.outputs.clone(),
+        updated_at: String::new(),
+    };
+    if let Err(e) = draft.bind(&Map::new()).and_then(|r| Pipeline::parse(&r)) {
+        return Err(AppError::Unprocessable(e.to_string()));
+    }
+
+    let recipe = recipe::save(conf, name, def, create)
+        .await
+        .map_err(|e| recipe_error(name, e))?;
+
+    let action = match create {
+        true => "recipe_created",
+        false => "recipe_updated",
+    };
+    let event = AuditEvent::for_recipe(action, name, Some(format!("version {}", recipe.version)));
+    record(conf, &event).await;
+    info!("stored version {} of recipe {}", recipe.version, name);
+    Ok(recipe)
+}
+
+// The change is stored already, a failure to record it is only logged
+async fn record(conf: &AppConfig, event: &AuditEvent) {
+    if let Err(e) = audit::record(conf, event).await {
+        warn!(
+            "failed to record {} of recipe {:?}: {}",
+            event.action, event.recipe, e
+        );
+    }
+}
+
+pub(crate) fn recipe_error(name: &str, e: anyhow::Error) -> AppError {
+    if e.is::<UnknownRecipe>() {
+        return AppError::NotFound(format!("Recipe {} not found", name));
+    }
+    if let Some(e) = e.downcast_ref::<UnknownVersion>() {
+        return AppError::NotFound(format!("Recipe {} has no version {}", name, e.version));
+    }
+    if e.is::<RecipeExists>() {
+        return AppError::Conflict(format!("Recipe {} already exists", name));
+    }
+    warn!("failed to access recipe {}: {}", name, e);
+    AppError::StorageError(format!("Failed to access recipe {}", name))
+}
@@ -0,0 +1,96 @@
+use axum::{Extension, Json, extract::State};
+use std::collections::BTreeSet;
+use tracing::{info, warn};
+
+use crate::{
+    error::AppError,
+    handlers::{ImgId, MetaPatch, MetaPatchResponse, check_region},
+    raster,
+    state::AppState,
+    storage::{image_path, read_meta, update_meta},
+    tenant::Tenant,
+};
+
+// Named regions an image may have
+const MAX_REGIONS: usize = 50;
+const MAX_REGION_NAME_LEN: usize = 64;
+
+// Sets or removes named regions of an image, e.g. its focal point as
+// {"regions": {"focus": {"x": 410, "y": 120, "width": 200, "height": 200}}}
+pub async fn patch_image_meta(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Json(patch): Json<MetaPatch>,
+) -> Result<Json<MetaPatchResponse>, AppError> {
+    for name in patch.regions.keys() {
+        if name.trim().is_empty() || name.len() > MAX_REGION_NAME_LEN {
+            return Err(AppError::BadRequest(format!(
+                "region names must have 1 to {} characters",
+                MAX_REGION_NAME_LEN
+            )));
+        }
+    }
+
+    let conf = tenant.scope(&state.conf);
+    let meta = read_meta(&conf, &img_id).await.map_err(|e| {
+        warn!("failed to read metadata of {}: {}", img_id, e);
+        AppError::from_read(&e)
+    })?;
+
+    // focal points are placed relative to the size, which older images lack
+    let dimensions = match (meta.width, meta.height) {
+        (Some(width), Some(height)) => Some((width, height)),
+        _ => {
+            let path = image_path(&conf, &img_id, &meta.fmt);
+            let (task_conf, fmt) = (conf.clone(), meta.fmt.clone());
+            tokio::task::spawn_blocking(move || raster::probe_dimensions(&task_conf, &path, &fmt))
+                .await
+                .ok()
+                .and_then(|res| res.ok())
+                .flatten()
+        }
+    };
+    for (name, region) in &patch.regions {
+        if let Some(region) = region {
+            check_region(*region, dimensions)
+                .map_err(|msg| AppError::BadRequest(format!("{}: {}", name, msg)))?;
+        }
+    }
+    let mut names: BTreeSet<&String> = meta.regions.keys().collect();
+    for (name, region) in &patch.regions {
+        match region {
+            Some(_) => names.insert(name),
+            None => names.remove(name),
+        };
+    }
+    if names.len() > MAX_REGIONS {
+        return Err(AppError::BadRequest(format!(
+            "an image can have at most {} regions",
+            MAX_REGIONS
+        )));
+    }
+
+    let regions = patch.regions;
+    let meta = update_meta(&conf, &img_id, move |meta| {
+        for (name, region) in regions {
+            match region {
+                Some(region) => meta.regions.insert(name, region),
+                None => meta.regions.remove(&name),
+            };
+        }
+        if let Some((width, height)) = dimensions {
+            meta.width.get_or_insert(width);
+            meta.height.get_or_insert(height);
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|e| AppError::StorageError(e.to_string()))?;
+
+    info!("updated the regions of {}", img_id);
+    Ok(Json(MetaPatchResponse {
+        id: img_id,
+        regions: meta.regions,
+    }))
+}
@@ -0,0 +1,621 @@
+use anyhow::{Result, anyhow};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, Response, StatusCode, header},
+    middleware::Next,
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::{
+    format::{self, ImageFormat},
+    handlers::{
+        ImgMetadata,
+        image::{StagedUpload, store_upload},
+        xml_escape,
+    },
+    sigv4::{self, Credentials},
+    state::{AppState, S3Config},
+    storage::{
+        Held, check_hold, delete_image, image_path, is_not_found, is_safe_id, list_images,
+        read_image_data, read_meta, unlink_image,
+    },
+    tenant::Tenant,
+};
+
+const DEFAULT_MAX_KEYS: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct ListObjectsQuery {
+    #[serde(rename = "list-type")]
+    list_type: Option<u8>,
+    prefix: Option<String>,
+    delimiter: Option<String>,
+    #[serde(rename = "max-keys")]
+    max_keys: Option<usize>,
+    #[serde(rename = "continuation-token")]
+    continuation_token: Option<String>,
+    #[serde(rename = "start-after")]
+    start_after: Option<String>,
+    marker: Option<String>,
+    location: Option<String>,
+}
+
+struct S3Object {
+    img_id: String,
+    fmt: String,
+    size_in_bytes: u32,
+    last_modified: SystemTime,
+}
+
+// Image ids by S3 key, so reading or writing one object does not list every
+// image. Filled from the metadata on first use and kept up to date by the
+// facade. The metadata stays the truth, find_object checks entries against
+// it: images can be deleted through the REST API.
+#[derive(Debug, Clone, Default)]
+pub struct KeyIndex {
+    keys: Arc<OnceCell<Mutex<HashMap<String, String>>>>,
+}
+
+impl KeyIndex {
+    async fn keys(&self, state: &AppState) -> Result<&Mutex<HashMap<String, String>>> {
+        self.keys
+            .get_or_try_init(|| async {
+                let mut keys = HashMap::new();
+                for (img_id, meta, _) in list_images(&state.conf).await? {
+                    if let Some(key) = meta.key {
+                        keys.insert(key, img_id);
+                    }
+                }
+                Ok::<_, anyhow::Error>(Mutex::new(keys))
+            })
+            .await
+    }
+
+    async fn get(&self, state: &AppState, key: &str) -> Result<Option<String>> {
+        Ok(self.keys(state).await?.lock().unwrap().get(key).cloned())
+    }
+
+    async fn insert(&self, state: &AppState, key: &str, img_id: &str) -> Result<()> {
+        self.keys(state)
+            .await?
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), img_id.to_string());
+        Ok(())
+    }
+
+    async fn remove(&self, state: &AppState, key: &str) -> Result<()> {
+        self.keys(state).await?.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+fn credentials(s3: &S3Config) -> Credentials<'_> {
+    Credentials {
+        access_key: &s3.access_key,
+        secret_key: &s3.secret_key,
+        region: &s3.region,
+    }
+}
+
+// Rejects requests that don't carry a valid SigV4 signature for the configured keys
+pub async fn authorize(State(state): State<AppState>, req: Request, next: Next) -> Response<Body> {
+    let Some(s3) = &state.conf.s3 else {
+        return build_s3_error(StatusCode::NOT_FOUND, "NoSuchBucket", "");
+    };
+
+    if let Err(e) = sigv4::verify(&credentials(s3), req.method(), req.uri(), req.headers()) {
+        warn!("rejected s3 request {}: {}", req.uri().path(), e);
+        return build_s3_error(StatusCode::FORBIDDEN, "AccessDenied", &e.to_string());
+    }
+
+    next.run(req).await
+}
+
+pub async fn list_objects(
+    State(state): State<AppState>,
+    Query(query): Query<ListObjectsQuery>,
+) -> impl IntoResponse {
+    let bucket = bucket_name(&state);
+
+    if query.location.is_some() {
+        let region = state.conf.s3.as_ref().map(|s| s.region.as_str());
+        return xml_response(
+            StatusCode::OK,
+            format!(
+                "<LocationConstraint xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">{}</LocationConstraint>",
+                xml_escape(region.unwrap_or_default())
+            ),
+        );
+    }
+
+    let objects = match load_objects(&state).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to list objects: {}", e);
+            return build_s3_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InternalError",
+                &e.to_string(),
+            );
+        }
+    };
+
+    let v2 = query.list_type == Some(2);
+    let prefix = query.prefix.clone().unwrap_or_default();
+    let max_keys = query
+        .max_keys
+        .unwrap_or(DEFAULT_MAX_KEYS)
+        .min(DEFAULT_MAX_KEYS);
+    let start_after = if v2 {
+        query
+            .continuation_token
+            .clone()
+            .or_else(|| query.start_after.clone())
+    } else {
+        query.marker.clone()
+    }
+    .unwrap_or_default();
+
+    let mut contents = Vec::new();
+    let mut common_prefixes: Vec<String> = Vec::new();
+    let mut last_key = None;
+    let mut truncated = false;
+
+    for (key, obj) in objects
+        .iter()
+        .filter(|(k, _)| k.starts_with(&prefix) && k.as_str() > start_after.as_str())
+    {
+        // Keys sharing everything up to the delimiter are rolled up into one prefix
+        let rolled_up = query
+            .delimiter
+            .as_deref()
+            .filter(|d| !d.is_empty())
+            .and_then(|d| {
+                key[prefix.len()..]
+                    .find(d)
+                    .map(|idx| key[..prefix.len() + idx + d.len()].to_string())
+            });
+        if let Some(p) = &rolled_up
+            && (common_prefixes.last() == Some(p) || *p == start_after)
+        {
+            continue;
+        }
+
+        if contents.len() + common_prefixes.len() == max_keys {
+            truncated = true;
+            break;
+        }
+
+        match rolled_up {
+            Some(p) => {
+                last_key = Some(p.clone());
+                common_prefixes.push(p);
+            }
+            None => {
+                last_key = Some(key.clone());
+                contents.push(format!(
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                    xml_escape(key),
+                    iso8601(obj.last_modified),
+                    obj.size_in_bytes
+                ));
+            }
+        }
+    }
+
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">",
+    );
+    body.push_str(&format!(
+        "<Name>{}</Name><Prefix>{}</Prefix><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+        xml_escape(&bucket),
+        xml_escape(&prefix),
+        max_keys,
+        truncated
+    ));
+    if let Some(d) = &query.delimiter {
+        body.push_str(&format!("<Delimiter>{}</Delimiter>", xml_escape(d)));
+    }
+
+    let next = last_key.filter(|_| truncated).map(|k| xml_escape(&k));
+    if v2 {
+        body.push_str(&format!(
+            "<KeyCount>{}</KeyCount>",
+            contents.len() + common_prefixes.len()
+        ));
+        if let Some(token) = &query.continuation_token {
+            body.push_str(&format!(
+                "<ContinuationToken>{}</ContinuationToken>",
+                xml_escape(token)
+            ));
+        }
+        if let Some(next) = next {
+            body.push_str(&format!(
+                "<NextContinuationToken>{}</NextContinuationToken>",
+                next
+            ));
+        }
+    } else {
+        body.push_str(&format!(
+            "<Marker>{}</Marker>",
+            xml_escape(query.marker.as_deref().unwrap_or_default())
+        ));
+        if let Some(next) = next {
+            body.push_str(&format!("<NextMarker>{}</NextMarker>", next));
+        }
+    }
+
+    body.push_str(&contents.concat());
+    for p in common_prefixes {
+        body.push_str(&format!(
+            "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+            xml_escape(&p)
+        ));
+    }
+    body.push_str("</ListBucketResult>");
+
+    xml_response(StatusCode::OK, body)
+}
+
+pub async fn get_object(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> impl IntoResponse {
+    let obj = match find_object(&state, &key).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return build_s3_error(StatusCode::NOT_FOUND, "NoSuchKey", &key),
+        Err(e) => {
+            return build_s3_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InternalError",
+                &e.to_string(),
+            );
+        }
+    };
+
+    let full_path = image_path(&state.conf, &obj.img_id, &obj.fmt);
+    let data = match read_image_data(&state.conf, &full_path).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read {:?}: {}", full_path, e);
+            if !is_not_found(&e) {
+                return build_s3_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "InternalError",
+                    &e.to_string(),
+                );
+            }
+            return build_s3_error(StatusCode::NOT_FOUND, "NoSuchKey", &key);
+        }
+    };
+    state.access.record("", &obj.img_id);
+
+    match Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            ImageFormat::from_fmt(&obj.fmt).content_type(),
+        )
+        .header(header::CONTENT_LENGTH, data.len())
+        .header(
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(obj.last_modified),
+        )
+        .body(Body::from(data))
+    {
+        Ok(v) => v,
+        Err(e) => build_s3_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "InternalError",
+            &e.to_string(),
+        ),
+    }
+}
+
+pub async fn put_object(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let data = match decode_body(&state, &headers, body) {
+        Ok(v) => v,
+        Err(e) => {
+            return build_s3_error(StatusCode::BAD_REQUEST, "InvalidRequest", &e.to_string());
+        }
+    };
+
+    if let Some(expected) = headers
+        .get("x-amz-content-sha256")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.len() == 64 && v.chars().all(|c| c.is_ascii_hexdigit()))
+        && !expected.eq_ignore_ascii_case(&sigv4::sha256_hex(&data))
+    {
+        return build_s3_error(
+            StatusCode::BAD_REQUEST,
+            "XAmzContentSHA256Mismatch",
+            "payload hash does not match",
+        );
+    }
+
+    // Content-Type and key of the PUT may be anything, the data decides
+    let image_format = ImageFormat::sniff(&data);
+    if image_format == ImageFormat::Unknown {
+        return build_s3_error(
+            StatusCode::BAD_REQUEST,
+            "InvalidArgument",
+            "object is not a supported image type",
+        );
+    }
+    if let Err(e) = format::check_input(&state.conf, image_format) {
+        return build_s3_error(e.status(), "InvalidArgument", &e.to_string());
+    }
+
+    // Overwriting a key replaces the previous image, unless it is held
+    let existing = match find_object(&state, &key).await {
+        Ok(v) => v,
+        Err(e) => return internal_error(&e),
+    };
+    if let Some(existing) = &existing
+        && let Err(e) = read_meta(&state.conf, &existing.img_id)
+            .await
+            .and_then(|meta| check_hold(&existing.img_id, &meta))
+    {
+        if e.is::<Held>() {
+            return build_s3_error(StatusCode::FORBIDDEN, "AccessDenied", &e.to_string());
+        }
+        return internal_error(&e);
+    }
+
+    // stored like an upload to /api/images/upload, see store_upload
+    let upload = match StagedUpload::from_data(&state.conf, data).await {
+        Ok(v) => v,
+        Err(e) => return internal_error(&e),
+    };
+    let meta = ImgMetadata {
+        key: Some(key.clone()),
+        original_filename: key.rsplit('/').next().map(|s| s.to_string()),
+        ..Default::default()
+    };
+    let img_id = match store_upload(&state, &Tenant::default(), upload, meta, false).await {
+        Ok((img_id, _)) => img_id,
+        Err(e) => {
+            let code = match e.status().is_server_error() {
+                true => "InternalError",
+                false => "InvalidArgument",
+            };
+            return build_s3_error(e.status(), code, &e.to_string());
+        }
+    };
+
+    // The key moves to the new image before the old one goes. When either
+    // fails the PUT is undone, the key keeps its old object.
+    let replaced = match state.s3_keys.insert(&state, &key, &img_id).await {
+        Ok(()) => match &existing {
+            Some(existing) => remove_object(&state, &existing.img_id).await,
+            None => Ok(()),
+        },
+        Err(e) => Err(e),
+    };
+    if let Err(e) = replaced {
+        warn!("failed to replace s3 object {}: {}", key, e);
+        let restored = match &existing {
+            Some(existing) => state.s3_keys.insert(&state, &key, &existing.img_id).await,
+            None => state.s3_keys.remove(&state, &key).await,
+        };
+        if let Err(e) = restored.and(remove_object(&state, &img_id).await) {
+            warn!("failed to undo the put of s3 object {}: {}", key, e);
+        }
+        return internal_error(&e);
+    }
+
+    info!("stored s3 object {} as {}", key, img_id);
+    (StatusCode::OK, [("x-amz-meta-brushbloom-id", img_id)]).into_response()
+}
+
+fn internal_error(e: &anyhow::Error) -> Response<Body> {
+    build_s3_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "InternalError",
+        &e.to_string(),
+    )
+}
+
+pub async fn delete_object(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> impl IntoResponse {
+    match find_object(&state, &key).await {
+        Ok(Some(obj)) => {
+            let removed = match remove_object(&state, &obj.img_id).await {
+                Ok(()) => state.s3_keys.remove(&state, &key).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = removed {
+                if e.is::<Held>() {
+                    return build_s3_error(StatusCode::FORBIDDEN, "AccessDenied", &e.to_string());
+                }
+                return internal_error(&e);
+            }
+            info!("deleted s3 object {} ({})", key, obj.img_id);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            return build_s3_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InternalError",
+                &e.to_string(),
+            );
+        }
+    }
+
+    // S3 answers 204 whether or not the key existed
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// Every stored image keyed by its S3 key, images not written through the
+// facade are exposed as "{img_id}{fmt}"
+async fn load_objects(state: &AppState) -> Result<BTreeMap<String, S3Object>> {
+    let mut objects = BTreeMap::new();
+
+    for (img_id, meta, last_modified) in list_images(&state.conf).await? {
+        let key = meta
+            .key
+            .clone()
+            .unwrap_or_else(|| format!("{}{}", img_id, meta.fmt));
+        objects.insert(
+            key,
+            S3Object {
+                img_id,
+                fmt: meta.fmt,
+                size_in_bytes: meta.size_in_bytes,
+                last_modified,
+            },
+        );
+    }
+
+    Ok(objects)
+}
+
+// The object of one key, through the KeyIndex or, for images not written
+// through the facade, the id in their "{img_id}{fmt}" key
+async fn find_object(state: &AppState, key: &str) -> Result<Option<S3Object>> {
+    let img_id = match state.s3_keys.get(state, key).await? {
+        Some(img_id) => img_id,
+        None => match key.rsplit_once('.') {
+            Some((img_id, _)) if is_safe_id(img_id) => img_id.to_string(),
+            _ => return Ok(None),
+        },
+    };
+    let meta = match read_meta(&state.conf, &img_id).await {
+        Ok(v) => v,
+        Err(e) if is_not_found(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let object_key = meta
+        .key
+        .clone()
+        .unwrap_or_else(|| format!("{}{}", img_id, meta.fmt));
+    if object_key != key {
+        return Ok(None);
+    }
+
+    let last_modified = tokio::fs::metadata(image_path(&state.conf, &img_id, &meta.fmt))
+        .await
+        .and_then(|m| m.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    Ok(Some(S3Object {
+        img_id,
+        fmt: meta.fmt,
+        size_in_bytes: meta.size_in_bytes,
+        last_modified,
+    }))
+}
+
+// delete_image, dropping the image from the link count of its source first.
+// The KeyIndex is left to the caller.
+async fn remove_object(state: &AppState, img_id: &str) -> Result<()> {
+    check_hold(img_id, &read_meta(&state.conf, img_id).await?)?;
+    unlink_image(&state.conf, &state.conf, img_id).await?;
+    delete_image(&state.conf, img_id).await
+}
+
+// Strips the aws-chunked framing newer SDKs use for streaming uploads. The
+// signature of every chunk is checked, unless the payload is declared
+// unsigned.
+fn decode_body(state: &AppState, headers: &HeaderMap, body: Bytes) -> Result<Vec<u8>> {
+    let content_sha256 = headers
+        .get("x-amz-content-sha256")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let mut chunks = match content_sha256 {
+        "STREAMING-AWS4-HMAC-SHA256-PAYLOAD" => {
+            let s3 = state
+                .conf
+                .s3
+                .as_ref()
+                .ok_or_else(|| anyhow!("s3 is not configured"))?;
+            Some(sigv4::ChunkVerifier::new(&credentials(s3), headers)?)
+        }
+        "STREAMING-UNSIGNED-PAYLOAD-TRAILER" => None,
+        v if v.starts_with("STREAMING-") => {
+            return Err(anyhow!("unsupported x-amz-content-sha256 {}", v));
+        }
+        _ => return Ok(body.to_vec()),
+    };
+
+    let mut data = Vec::with_capacity(body.len());
+    let mut rest = &body[..];
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| anyhow!("malformed aws-chunked body"))?;
+        let line = std::str::from_utf8(&rest[..line_end])?;
+        let (size_hex, extensions) = line.split_once(';').unwrap_or((line, ""));
+        let size = usize::from_str_radix(size_hex.trim(), 16)?;
+        rest = &rest[line_end + 2..];
+
+        if rest.len() < size + if size == 0 { 0 } else { 2 } {
+            return Err(anyhow!("truncated aws-chunked body"));
+        }
+        // the final, empty chunk is signed as well
+        if let Some(chunks) = &mut chunks {
+            let signature = extensions
+                .split(';')
+                .find_map(|e| e.trim().strip_prefix("chunk-signature="))
+                .ok_or_else(|| anyhow!("chunk without a signature"))?;
+            chunks.verify(signature, &rest[..size])?;
+        }
+
+        if size == 0 {
+            break;
+        }
+        data.extend_from_slice(&rest[..size]);
+        rest = &rest[size + 2..];
+    }
+
+    Ok(data)
+}
+
+fn bucket_name(state: &AppState) -> String {
+    state
+        .conf
+        .s3
+        .as_ref()
+        .map(|s| s.bucket.clone())
+        .unwrap_or_default()
+}
+
+fn xml_response(code: StatusCode, body: String) -> Response<Body> {
+    (code, [(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+fn build_s3_error(code: StatusCode, s3_code: &str, msg: &str) -> Response<Body> {
+    xml_response(
+        code,
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{}</Code><Message>{}</Message></Error>",
+            s3_code,
+            xml_escape(msg)
+        ),
+    )
+}
+
+fn iso8601(t: SystemTime) -> String {
+    OffsetDateTime::from(t)
+        .replace_nanosecond(0)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
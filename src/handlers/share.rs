@@ -0,0 +1,172 @@
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, Response, StatusCode},
+};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::{info, warn};
+
+use crate::{
+    error::AppError,
+    handlers::{
+        DeliveryQuery, ImgId, ShareRequest, ShareResponse, SignRequest, SignResponse,
+        TransformQuery, feed::base_url, image::serve_image,
+    },
+    share::{self, DEFAULT_TTL_SECS},
+    signing,
+    state::AppState,
+    storage::{is_safe_id, read_meta},
+    tenant::Tenant,
+};
+
+// Mints a token granting read access to the image without an API key
+pub async fn share_image(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Json(req): Json<ShareRequest>,
+) -> Result<(StatusCode, Json<ShareResponse>), AppError> {
+    let conf = tenant.scope(&state.conf);
+    read_meta(&conf, &img_id)
+        .await
+        .map_err(|e| AppError::from_read(&e))?;
+
+    let ttl_secs = req.ttl_secs.unwrap_or(DEFAULT_TTL_SECS);
+    let share = share::create(
+        &state.conf,
+        &tenant.id,
+        &img_id,
+        ttl_secs,
+        req.include_variants,
+    )
+    .await
+    .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    info!("shared {} for {}s", img_id, ttl_secs);
+
+    let expires = OffsetDateTime::from_unix_timestamp(share.expires as i64)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_default();
+    let url = format!("{}/share/{}", base_url(&state, &headers), share.token);
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ShareResponse {
+            token: share.token,
+            url,
+            expires,
+        }),
+    ))
+}
+
+// Signed URL reading the image without an API key until it expires, see
+// signing. Unlike a share it cannot be revoked, only expire.
+pub async fn sign_image(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Json(req): Json<SignRequest>,
+) -> Result<(StatusCode, Json<SignResponse>), AppError> {
+    let Some(signed) = &state.conf.signed_urls else {
+        return Err(AppError::NotFound(
+            "Signed URLs are not configured".to_string(),
+        ));
+    };
+
+    let ttl_secs = req.ttl_secs.unwrap_or(signed.default_ttl_secs);
+    if ttl_secs == 0 || ttl_secs > signed.max_ttl_secs {
+        return Err(AppError::BadRequest(format!(
+            "ttl_secs must be between 1 and {}",
+            signed.max_ttl_secs
+        )));
+    }
+
+    let conf = tenant.scope(&state.conf);
+    read_meta(&conf, &img_id)
+        .await
+        .map_err(|e| AppError::from_read(&e))?;
+
+    let (url, expires) = signing::url(
+        &state.conf,
+        signed,
+        &base_url(&state, &headers),
+        &tenant.id,
+        &img_id,
+        ttl_secs,
+    );
+    info!("signed {} for {}s", img_id, ttl_secs);
+
+    let expires = OffsetDateTime::from_unix_timestamp(expires as i64)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_default();
+    Ok((StatusCode::CREATED, Json(SignResponse { url, expires })))
+}
+
+pub async fn revoke_share(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(token): Path<String>,
+) -> Result<StatusCode, AppError> {
+    match share::revoke(&state.conf, &tenant.id, &token).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(AppError::NotFound("Share not found".to_string())),
+        Err(e) => {
+            warn!("failed to revoke share: {}", e);
+            Err(AppError::StorageError(e.to_string()))
+        }
+    }
+}
+
+// GET /share/{token}: the shared image
+pub async fn get_shared(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Query(query): Query<TransformQuery>,
+    Query(delivery): Query<DeliveryQuery>,
+) -> Result<Response<Body>, AppError> {
+    serve_shared(&state, &token, None, &query, &delivery, &headers).await
+}
+
+// GET /share/{token}/{img_id}: a variant of the shared image
+pub async fn get_shared_variant(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Path((token, img_id)): Path<(String, String)>,
+    Query(query): Query<TransformQuery>,
+    Query(delivery): Query<DeliveryQuery>,
+) -> Result<Response<Body>, AppError> {
+    serve_shared(&state, &token, Some(&img_id), &query, &delivery, &headers).await
+}
+
+async fn serve_shared(
+    state: &AppState,
+    token: &str,
+    img_id: Option<&str>,
+    query: &TransformQuery,
+    delivery: &DeliveryQuery,
+    headers: &HeaderMap,
+) -> Result<Response<Body>, AppError> {
+    // unknown, expired and revoked tokens all look the same
+    let Some(share) = share::find(&state.conf, token).await else {
+        return Err(AppError::NotFound("Share not found".to_string()));
+    };
+    let img_id = img_id.unwrap_or(&share.img_id);
+    if !is_safe_id(img_id) || !share.grants(img_id) {
+        return Err(AppError::image_not_found());
+    }
+
+    // the tenant may have been removed since the image was shared
+    let tenant = match state.conf.tenants.iter().find(|t| t.id == share.tenant_id) {
+        Some(t) => Tenant::from(t),
+        None if share.tenant_id.is_empty() => Tenant::default(),
+        None => return Err(AppError::NotFound("Share not found".to_string())),
+    };
+
+    // share links are read without an API key
+    serve_image(state, &tenant, img_id, query.frame, delivery, headers, true).await
+}
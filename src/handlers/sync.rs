@@ -0,0 +1,145 @@
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::{Query, State},
+    http::Response,
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    changes::{self, ChangeKind, InvalidCursor},
+    error::AppError,
+    handlers::ListedImage,
+    state::AppState,
+    storage::{is_not_found, list_images, read_meta},
+    tenant::Tenant,
+};
+
+const DEFAULT_SYNC_LIMIT: usize = 1000;
+const MAX_SYNC_LIMIT: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+pub struct SyncQuery {
+    // cursor of the previous response. Without it every image is returned as
+    // created.
+    since: Option<u64>,
+    // how many changes to read from the log
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SyncResponse {
+    changes: Vec<SyncChange>,
+    // `since` of the next request
+    cursor: u64,
+    // whether there are more changes after the cursor already
+    more: bool,
+}
+
+#[derive(Serialize)]
+struct SyncChange {
+    id: String,
+    change: ChangeKind,
+    // what the listing shows of it, unless deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<ListedImage>,
+}
+
+// Changefeed for keeping a copy of the listing in sync, e.g. a mobile app's:
+// the images created, updated or deleted since a cursor, one entry per image
+pub async fn sync_changes(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<SyncQuery>,
+) -> Result<Response<Body>, AppError> {
+    let conf = tenant.scope(&state.conf);
+
+    let Some(since) = query.since else {
+        // the cursor is taken first, so what changes while listing is
+        // returned again by the next request rather than missed
+        let res = async {
+            let cursor = changes::cursor(&conf).await?;
+            anyhow::Ok((cursor, list_images(&conf).await?))
+        }
+        .await;
+        let (cursor, images) = res.map_err(|e| {
+            warn!("failed to list images: {}", e);
+            AppError::Internal("Failed to list images".to_string())
+        })?;
+
+        let changes = images
+            .into_iter()
+            .filter(|(_, meta, _)| meta.deleted_at.is_none())
+            .map(|(id, meta, _)| SyncChange {
+                id: id.clone(),
+                change: ChangeKind::Created,
+                image: Some(ListedImage::new(id, meta)),
+            })
+            .collect();
+        return Ok(Json(SyncResponse {
+            changes,
+            cursor,
+            more: false,
+        })
+        .into_response());
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SYNC_LIMIT)
+        .clamp(1, MAX_SYNC_LIMIT);
+    let (logged, cursor, more) = changes::since(&conf, since, limit).await.map_err(|e| {
+        match e.downcast_ref::<InvalidCursor>() {
+            Some(e) => AppError::BadRequest(e.to_string()),
+            None => {
+                warn!("failed to read changes: {}", e);
+                AppError::Internal("Failed to read changes".to_string())
+            }
+        }
+    })?;
+
+    let mut changes = Vec::with_capacity(logged.len());
+    for (id, kind) in logged {
+        if kind == ChangeKind::Deleted {
+            changes.push(SyncChange {
+                id,
+                change: kind,
+                image: None,
+            });
+            continue;
+        }
+        // the metadata now, which may be newer than the change
+        let change = match read_meta(&conf, &id).await {
+            Ok(meta) if meta.deleted_at.is_none() => SyncChange {
+                id: id.clone(),
+                change: kind,
+                image: Some(ListedImage::new(id, meta)),
+            },
+            // soft deleted, or deleted after the last change read
+            Ok(_) => SyncChange {
+                id,
+                change: ChangeKind::Deleted,
+                image: None,
+            },
+            Err(e) if is_not_found(&e) => SyncChange {
+                id,
+                change: ChangeKind::Deleted,
+                image: None,
+            },
+            Err(e) => {
+                warn!("failed to read metadata of {}: {}", id, e);
+                return Err(AppError::from_read(&e));
+            }
+        };
+        changes.push(change);
+    }
+
+    Ok(Json(SyncResponse {
+        changes,
+        cursor,
+        more,
+    })
+    .into_response())
+}
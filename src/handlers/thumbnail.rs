@@ -0,0 +1,207 @@
+use axum::{
+    Extension,
+    body::Body,
+    extract::{Query, State},
+    http::{HeaderMap, Response, header},
+};
+use tracing::{info, warn};
+
+use crate::{
+    cache_control::{self, Served},
+    error::AppError,
+    format::ImageFormat,
+    handlers::{
+        Fit, Frame, Gravity, ImgId, ImgMetadata, ThumbnailQuery, encode_image, fit_dimensions,
+        fit_image,
+        image::{ranged_response, read_image},
+    },
+    review,
+    state::{AppConfig, AppState, ThumbnailPreset},
+    storage::{
+        ImageContent, is_not_found, locate_image, open_image, read_meta, thumbnail_path,
+        write_cached_file,
+    },
+    tenant::Tenant,
+};
+
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+const MIN_THUMBNAIL_SIZE: u32 = 16;
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+// Thumbnail of at most `size` pixels per edge, generated on the first request
+// and served from thumbs/ afterwards. Animated GIFs get a PNG of their first
+// frame. Smaller images are not upscaled.
+pub async fn get_thumbnail(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    ImgId(img_id): ImgId,
+    Query(query): Query<ThumbnailQuery>,
+) -> Result<Response<Body>, AppError> {
+    let spec = match &query.preset {
+        Some(name) => match state.conf.thumbnail_presets.get(name) {
+            Some(preset) => ThumbnailSpec::preset(name, preset),
+            None => {
+                return Err(AppError::NotFound(format!(
+                    "Unknown thumbnail preset {:?}",
+                    name
+                )));
+            }
+        },
+        None => {
+            let size = query.size.unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+            ThumbnailSpec {
+                size,
+                fit: query.fit,
+                gravity: query.gravity,
+                cache_name: format!("{}_{}", size, query.fit.as_str()),
+            }
+        }
+    };
+    if !(MIN_THUMBNAIL_SIZE..=MAX_THUMBNAIL_SIZE).contains(&spec.size) {
+        return Err(AppError::BadRequest(format!(
+            "size must be between {} and {}",
+            MIN_THUMBNAIL_SIZE, MAX_THUMBNAIL_SIZE
+        )));
+    }
+
+    let conf = tenant.scope(&state.conf);
+
+    // thumbnails are deliveries like get_image, subject to review
+    let meta = match read_meta(&conf, &img_id).await {
+        Ok(v) => Some(v),
+        Err(e) if is_not_found(&e) => None,
+        Err(e) => {
+            warn!("failed to read metadata of {}: {}", img_id, e);
+            return Err(AppError::from_read(&e));
+        }
+    };
+    if meta.as_ref().is_some_and(|m| m.deleted_at.is_some()) {
+        return Err(AppError::image_not_found());
+    }
+    if let Some(meta) = &meta
+        && !review::is_servable(&conf, meta)
+    {
+        return Err(AppError::Forbidden(
+            "Image is not approved for delivery".to_string(),
+        ));
+    }
+    let Some((_, src_fmt)) = locate_image(&conf, &img_id, meta.as_ref()).await else {
+        return Err(AppError::image_not_found());
+    };
+
+    let fmt = thumbnail_format(src_fmt);
+    let (variant, focus) = spec.variant(meta.as_ref());
+    let path = thumbnail_path(&conf, &img_id, &variant, fmt.as_str());
+    let range = headers.get(header::RANGE);
+
+    if let Ok(content) = open_image(&conf, &path).await {
+        let mut resp = ranged_response(content, fmt.content_type(), range).await?;
+        cache_control::apply(&conf, Served::Derived, &mut resp);
+        return Ok(resp);
+    }
+
+    // concurrent requests for a thumbnail that is not cached yet wait for the
+    // first one to make it
+    let key = path.display().to_string();
+    let data = state
+        .coalescer
+        .run(key, || async {
+            info!("generating {} thumbnail of {}", variant, img_id);
+            let data = generate(&state, &conf, &img_id, &spec, focus, fmt, &path).await?;
+            Ok(data.into())
+        })
+        .await?;
+
+    let mut resp = ranged_response(ImageContent::Bytes(data), fmt.content_type(), range).await?;
+    cache_control::apply(&conf, Served::Derived, &mut resp);
+    Ok(resp)
+}
+
+// Size, fit and gravity of a thumbnail and the name it is cached under
+pub(crate) struct ThumbnailSpec {
+    pub size: u32,
+    pub fit: Fit,
+    pub gravity: Gravity,
+    cache_name: String,
+}
+
+impl ThumbnailSpec {
+    pub fn preset(name: &str, preset: &ThumbnailPreset) -> Self {
+        Self {
+            size: preset.size,
+            fit: preset.fit,
+            gravity: preset.gravity,
+            cache_name: preset.cache_name(name),
+        }
+    }
+
+    // Cache name of the thumbnail of the image and its focal point. Thumbnails
+    // around a focal point are cached per focal point, moving it makes new
+    // ones.
+    pub fn variant(&self, meta: Option<&ImgMetadata>) -> (String, Option<(f32, f32)>) {
+        let focus = meta.and_then(|m| self.gravity.focus(m));
+        let variant = match meta.and_then(|m| m.focal_point()) {
+            Some((x, y)) if focus.is_some() && self.fit == Fit::Cover => {
+                format!("{}_focus-{}-{}", self.cache_name, x, y)
+            }
+            _ => self.cache_name.clone(),
+        };
+        (variant, focus)
+    }
+}
+
+// GIFs get a PNG of their first frame
+pub(crate) fn thumbnail_format(src_fmt: ImageFormat) -> ImageFormat {
+    match src_fmt {
+        ImageFormat::Gif => ImageFormat::Png,
+        fmt => fmt,
+    }
+}
+
+// Makes the thumbnail and caches it at `path`
+pub(crate) async fn generate(
+    state: &AppState,
+    conf: &AppConfig,
+    img_id: &str,
+    spec: &ThumbnailSpec,
+    focus: Option<(f32, f32)>,
+    fmt: ImageFormat,
+    path: &std::path::Path,
+) -> Result<Vec<u8>, AppError> {
+    let (size, fit) = (spec.size, spec.fit);
+    let min_size = move |width, height| fit_dimensions(width, height, Some(size), Some(size), fit);
+    let (photon_img, _, permit) = read_image(
+        conf,
+        &state.budget,
+        img_id,
+        Some(Frame::First),
+        Some(&min_size),
+    )
+    .await?;
+
+    let (task_conf, task_fmt, resizer) = (conf.clone(), fmt.as_str().to_string(), conf.resizer);
+    let encoded = tokio::task::spawn_blocking(move || {
+        let thumb = fit_image(photon_img, Some(size), Some(size), fit, focus, resizer);
+        encode_image(&task_conf, thumb, &task_fmt, None)
+    })
+    .await;
+    drop(permit);
+
+    let data = match encoded {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            warn!("failed to encode thumbnail of {}: {}", img_id, e);
+            return Err(AppError::Internal(
+                "Failed to generate thumbnail".to_string(),
+            ));
+        }
+        Err(e) => return Err(AppError::Internal(e.to_string())),
+    };
+
+    // a failed write only costs regenerating it next time
+    if let Err(e) = write_cached_file(conf, path, data.clone()).await {
+        warn!("failed to cache thumbnail {:?}: {}", path, e);
+    }
+    Ok(data)
+}
@@ -0,0 +1,117 @@
+use anyhow::Result;
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+};
+use photon_rs::{PhotonImage, transform::crop};
+use tracing::info;
+
+use crate::{
+    art_direction,
+    error::AppError,
+    handlers::{
+        ArtDirectedImage, ImgMetadata, Output, TransformQuery, VariantsResponse, image::read_image,
+        write_new_image,
+    },
+    resize,
+    state::{AppConfig, AppState, Breakpoint},
+    storage::is_safe_id,
+    tenant::Tenant,
+    timings::{self, Timings},
+};
+
+// Makes the composition of every breakpoint of an art-direction set from one
+// image, decoded once, cropped around the image's regions. They are stored as
+// transform outputs named after the "variants" operation.
+pub async fn art_directed_variants(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path((img_id, set_name)): Path<(String, String)>,
+    Query(query): Query<TransformQuery>,
+) -> Result<Json<VariantsResponse>, AppError> {
+    if !is_safe_id(&img_id) {
+        return Err(AppError::invalid_id());
+    }
+
+    info!("variants request: {} {}", img_id, set_name);
+
+    let Some(set) = state.conf.art_direction.get(&set_name).cloned() else {
+        return Err(AppError::NotFound(format!(
+            "Unknown art-direction set {:?}",
+            set_name
+        )));
+    };
+    let conf = tenant.scope(&state.conf);
+
+    let (photon_img, img_meta, _permit) =
+        read_image(&conf, &state.budget, &img_id, query.frame, None).await?;
+
+    let output = Output::new(&conf, &query, &img_id, "variants");
+    let collector = Timings::current();
+    let task_conf = conf.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        timings::within(collector, || {
+            store_variants(
+                &task_conf,
+                &photon_img,
+                &img_meta,
+                &output,
+                &set_name,
+                &set.breakpoints,
+            )
+            .map(|variants| {
+                Json(VariantsResponse {
+                    set: set_name.clone(),
+                    variants,
+                })
+            })
+        })
+    })
+    .await;
+
+    match res {
+        Ok(res) => Ok(res?),
+        Err(e) => Err(AppError::Internal(format!(
+            "Failed to make variants: {}",
+            e
+        ))),
+    }
+}
+
+fn store_variants(
+    conf: &AppConfig,
+    img: &PhotonImage,
+    img_meta: &ImgMetadata,
+    output: &Output,
+    set_name: &str,
+    breakpoints: &[Breakpoint],
+) -> Result<Vec<ArtDirectedImage>> {
+    let mut variants = Vec::with_capacity(breakpoints.len());
+    for bp in breakpoints {
+        let (region, width, height) =
+            art_direction::compose(img.get_width(), img.get_height(), bp, img_meta);
+        let composed = timings::time("variants", || {
+            let cropped = crop(
+                img,
+                region.x,
+                region.y,
+                region.x + region.width,
+                region.y + region.height,
+            );
+            resize::lanczos3(&cropped, width, height, conf.resizer)
+        });
+        let output = output.clone().with_params(serde_json::json!({
+            "set": set_name,
+            "name": bp.name,
+            "crop": region,
+        }));
+        variants.push(ArtDirectedImage {
+            name: bp.name.clone(),
+            id: write_new_image(conf, img_meta, composed, &output)?,
+            width,
+            height,
+            media: (bp.min_viewport > 0).then(|| format!("(min-width: {}px)", bp.min_viewport)),
+        });
+    }
+    Ok(variants)
+}
@@ -0,0 +1,357 @@
+use axum::{
+    Extension,
+    body::Body,
+    extract::{Path, Request, State},
+    http::{HeaderMap, Method, Response, StatusCode, header},
+    middleware::Next,
+    response::IntoResponse,
+};
+use std::{collections::BTreeSet, time::SystemTime};
+use tracing::{info, warn};
+
+use crate::{
+    format::ImageFormat,
+    handlers::{DeliveryQuery, ImgMetadata, image::serve_image, xml_escape},
+    public_id, review,
+    sigv4::uri_encode,
+    state::{AppConfig, AppState},
+    storage::{image_path, is_not_found, list_images, read_image_data},
+    tenant::{self, Tenant},
+};
+
+pub const DAV_ROOT: &str = "/dav";
+const ALLOWED_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND";
+
+// Read-only view of the library:
+//   /dav/all/{file}         every image
+//   /dav/tags/{tag}/{file}  images carrying that tag
+enum DavNode {
+    Root,
+    All,
+    Tags,
+    Tag(String),
+    File(DavFile),
+}
+
+#[derive(Clone)]
+struct DavFile {
+    img_id: String,
+    // in file names, see public_id
+    public_id: String,
+    meta: ImgMetadata,
+    modified: SystemTime,
+}
+
+impl DavFile {
+    fn name(&self) -> String {
+        format!("{}{}", self.public_id, self.meta.fmt)
+    }
+}
+
+// WebDAV clients cannot send an API key header, once tenants are configured
+// they log in with Basic credentials: the tenant id and its API key
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response<Body> {
+    let tenant = match state.conf.tenants.is_empty() {
+        true => Some(Tenant::default()),
+        false => tenant::basic_credentials(req.headers()).and_then(|(id, key)| {
+            state
+                .conf
+                .tenants
+                .iter()
+                .find(|t| t.id == id && t.api_key == key)
+                .map(Tenant::from)
+        }),
+    };
+    let Some(tenant) = tenant else {
+        warn!("rejected webdav request without valid credentials");
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"brushbloom\"")],
+        )
+            .into_response();
+    };
+
+    req.extensions_mut().insert(tenant);
+    next.run(req).await
+}
+
+pub async fn webdav_root(
+    method: Method,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> impl IntoResponse {
+    handle(&state, &tenant, method, &headers, "").await
+}
+
+pub async fn webdav(
+    method: Method,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(path): Path<String>,
+) -> impl IntoResponse {
+    handle(&state, &tenant, method, &headers, &path).await
+}
+
+async fn handle(
+    state: &AppState,
+    tenant: &Tenant,
+    method: Method,
+    headers: &HeaderMap,
+    path: &str,
+) -> Response<Body> {
+    info!("webdav {} /{}", method, path);
+
+    match method.as_str() {
+        "OPTIONS" => (
+            StatusCode::OK,
+            [
+                ("DAV", "1"),
+                ("Allow", ALLOWED_METHODS),
+                ("MS-Author-Via", "DAV"),
+            ],
+        )
+            .into_response(),
+        "GET" | "HEAD" | "PROPFIND" => {
+            let conf = tenant.scope(&state.conf);
+            let images = match load_files(&conf).await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("failed to list images for webdav: {}", e);
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            };
+
+            let Some(node) = resolve(path, &images) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+
+            if method.as_str() == "PROPFIND" {
+                propfind(&node, path, headers, &images)
+            } else {
+                get(state, tenant, &conf, node, headers).await
+            }
+        }
+        _ => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, ALLOWED_METHODS)],
+        )
+            .into_response(),
+    }
+}
+
+async fn load_files(conf: &AppConfig) -> anyhow::Result<Vec<DavFile>> {
+    Ok(list_images(conf)
+        .await?
+        .into_iter()
+        // as for GET /api/images/{img_id}, pending and rejected images are hidden
+        // unless serve_unapproved is set
+        .filter(|(_, meta, _)| meta.deleted_at.is_none() && review::is_servable(conf, meta))
+        .map(|(img_id, meta, modified)| DavFile {
+            public_id: public_id::encode(conf, &img_id),
+            img_id,
+            meta,
+            modified,
+        })
+        .collect())
+}
+
+fn resolve(path: &str, images: &[DavFile]) -> Option<DavNode> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let find = |name: &str, tag: Option<&str>| {
+        images
+            .iter()
+            .find(|f| f.name() == name && tag.is_none_or(|t| has_tag(f, t)))
+            .cloned()
+            .map(DavNode::File)
+    };
+
+    match segments.as_slice() {
+        [] => Some(DavNode::Root),
+        ["all"] => Some(DavNode::All),
+        ["all", name] => find(name, None),
+        ["tags"] => Some(DavNode::Tags),
+        ["tags", tag] => images
+            .iter()
+            .any(|f| has_tag(f, tag))
+            .then(|| DavNode::Tag(tag.to_string())),
+        ["tags", tag, name] => find(name, Some(tag)),
+        _ => None,
+    }
+}
+
+fn has_tag(file: &DavFile, tag: &str) -> bool {
+    file.meta.tags.iter().any(|t| t == tag)
+}
+
+fn propfind(node: &DavNode, path: &str, headers: &HeaderMap, images: &[DavFile]) -> Response<Body> {
+    let depth = headers
+        .get("Depth")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("1");
+
+    let href = href_for(path, matches!(node, DavNode::File(_)));
+    let name = path.rsplit('/').find(|s| !s.is_empty()).unwrap_or("dav");
+    let mut responses = vec![prop_response(&href, name, node)];
+
+    if depth != "0" {
+        for (name, child) in children(node, images) {
+            let child_href = format!(
+                "{}{}{}",
+                href,
+                uri_encode(&name),
+                if matches!(child, DavNode::File(_)) {
+                    ""
+                } else {
+                    "/"
+                }
+            );
+            responses.push(prop_response(&child_href, &name, &child));
+        }
+    }
+
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+        responses.concat()
+    );
+
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+fn children(node: &DavNode, images: &[DavFile]) -> Vec<(String, DavNode)> {
+    let files = |tag: Option<&str>| {
+        images
+            .iter()
+            .filter(|f| tag.is_none_or(|t| has_tag(f, t)))
+            .map(|f| (f.name(), DavNode::File(f.clone())))
+            .collect()
+    };
+
+    match node {
+        DavNode::Root => vec![
+            ("all".to_string(), DavNode::All),
+            ("tags".to_string(), DavNode::Tags),
+        ],
+        DavNode::All => files(None),
+        DavNode::Tags => images
+            .iter()
+            .flat_map(|f| f.meta.tags.iter().cloned())
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .map(|t| (t.clone(), DavNode::Tag(t)))
+            .collect(),
+        DavNode::Tag(tag) => files(Some(tag)),
+        DavNode::File(_) => vec![],
+    }
+}
+
+fn prop_response(href: &str, name: &str, node: &DavNode) -> String {
+    let props = match node {
+        DavNode::File(f) => format!(
+            "<D:displayname>{}</D:displayname><D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>{}</D:getcontenttype><D:getlastmodified>{}</D:getlastmodified>",
+            xml_escape(name),
+            f.meta.size_in_bytes,
+            ImageFormat::from_fmt(&f.meta.fmt).content_type(),
+            httpdate::fmt_http_date(f.modified)
+        ),
+        _ => format!(
+            "<D:displayname>{}</D:displayname><D:resourcetype><D:collection/></D:resourcetype>",
+            xml_escape(name)
+        ),
+    };
+
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        xml_escape(href),
+        props
+    )
+}
+
+async fn get(
+    state: &AppState,
+    tenant: &Tenant,
+    conf: &AppConfig,
+    node: DavNode,
+    headers: &HeaderMap,
+) -> Response<Body> {
+    let DavNode::File(f) = node else {
+        // Collections have no content, clients are expected to PROPFIND them
+        return (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            "read-only WebDAV collection\n",
+        )
+            .into_response();
+    };
+
+    // protected images get the public_reads treatment
+    if conf
+        .public_reads
+        .as_ref()
+        .is_some_and(|policy| policy.protects(Some(&f.meta)))
+    {
+        return serve_image(
+            state,
+            tenant,
+            &f.img_id,
+            None,
+            &DeliveryQuery::default(),
+            headers,
+            true,
+        )
+        .await
+        .into_response();
+    }
+
+    let full_path = image_path(conf, &f.img_id, &f.meta.fmt);
+    match read_image_data(conf, &full_path).await {
+        Ok(data) => {
+            state.access.record(&tenant.id, &f.img_id);
+            (
+                StatusCode::OK,
+                [
+                    (
+                        header::CONTENT_TYPE,
+                        ImageFormat::from_fmt(&f.meta.fmt)
+                            .content_type()
+                            .to_string(),
+                    ),
+                    (header::LAST_MODIFIED, httpdate::fmt_http_date(f.modified)),
+                ],
+                data,
+            )
+                .into_response()
+        }
+        Err(e) => {
+            warn!("failed to read {:?}: {}", full_path, e);
+            match is_not_found(&e) {
+                true => StatusCode::NOT_FOUND.into_response(),
+                false => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        }
+    }
+}
+
+fn href_for(path: &str, is_file: bool) -> String {
+    let encoded: Vec<String> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(uri_encode)
+        .collect();
+
+    let mut href = format!("{}/{}", DAV_ROOT, encoded.join("/"));
+    if !is_file && !href.ends_with('/') {
+        href.push('/');
+    }
+    href
+}
@@ -0,0 +1,345 @@
+use anyhow::{Result, anyhow};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use mail_parser::{MessageParser, MimeHeaders};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+use crate::{
+    format::{self, ImageFormat},
+    handlers::{ImgMetadata, recipe},
+    state::{AppState, EmailConfig},
+    storage::store_image,
+};
+
+struct IngestedMail {
+    sender: String,
+    subject: String,
+    img_ids: Vec<String>,
+}
+
+// Minimal SMTP listener which stores the image attachments of every accepted message
+pub async fn run(state: AppState, email: EmailConfig) {
+    let listener = match TcpListener::bind(&email.listen).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to bind email listener on {}: {}", email.listen, e);
+            return;
+        }
+    };
+    info!("accepting mail for {} on {}", email.mailbox, email.listen);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                info!("smtp connection from: {}", addr);
+                let state = state.clone();
+                let email = email.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_session(&state, &email, stream).await {
+                        warn!("smtp session from {} failed: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => warn!("failed to accept smtp connection: {}", e),
+        }
+    }
+}
+
+// Longest command or text line, RFC 5321 allows 1000 octets with the CRLF
+const MAX_LINE: u64 = 1000;
+
+async fn handle_session(state: &AppState, email: &EmailConfig, stream: TcpStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    writer.write_all(b"220 brushbloom ESMTP\r\n").await?;
+
+    let max_size = (email.max_message_size * 1024 * 1024) as usize;
+    // envelope sender of the current transaction, once accepted
+    let mut sender: Option<String> = None;
+    let mut has_recipient = false;
+    let mut line = Vec::new();
+
+    loop {
+        if read_line(&mut reader, &mut line).await? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with(b"\n") {
+            writer.write_all(b"500 Line too long\r\n").await?;
+            return Ok(());
+        }
+
+        let cmd = String::from_utf8_lossy(&line).trim_end().to_string();
+        let verb = cmd
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+
+        let reply = match verb.as_str() {
+            "HELO" => "250 brushbloom\r\n".to_string(),
+            "EHLO" => format!("250-brushbloom\r\n250 SIZE {}\r\n", max_size),
+            "MAIL" => {
+                let addr = envelope_address(&cmd);
+                has_recipient = false;
+                if sender_allowed(email, &addr) {
+                    sender = Some(addr);
+                    "250 OK\r\n".to_string()
+                } else {
+                    info!("rejected mail from {:?}", addr);
+                    sender = None;
+                    "550 Sender not allowed\r\n".to_string()
+                }
+            }
+            "RCPT" if sender.is_none() => "503 Need MAIL first\r\n".to_string(),
+            "RCPT" => {
+                if recipient_allowed(email, &envelope_address(&cmd)) {
+                    has_recipient = true;
+                    "250 OK\r\n".to_string()
+                } else {
+                    "550 No such mailbox\r\n".to_string()
+                }
+            }
+            "DATA" if !has_recipient => "503 Need RCPT first\r\n".to_string(),
+            "DATA" => {
+                writer
+                    .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                    .await?;
+
+                let Some(data) = read_data(&mut reader, max_size).await? else {
+                    // the rest of the message is not read, the session ends here
+                    writer
+                        .write_all(b"552 Message size exceeds fixed limit\r\n")
+                        .await?;
+                    return Ok(());
+                };
+                let sender = sender.take().unwrap_or_default();
+                has_recipient = false;
+                match ingest_message(state, email, &sender, &data).await {
+                    Ok(mail) => {
+                        info!("stored {} images from {}", mail.img_ids.len(), sender);
+                        send_reply(email, mail);
+                        "250 OK\r\n".to_string()
+                    }
+                    Err(e) => {
+                        warn!("failed to ingest message from {}: {}", sender, e);
+                        "554 Transaction failed\r\n".to_string()
+                    }
+                }
+            }
+            "RSET" => {
+                sender = None;
+                has_recipient = false;
+                "250 OK\r\n".to_string()
+            }
+            "NOOP" => "250 OK\r\n".to_string(),
+            "QUIT" => {
+                writer.write_all(b"221 Bye\r\n").await?;
+                return Ok(());
+            }
+            _ => "502 Command not implemented\r\n".to_string(),
+        };
+
+        writer.write_all(reply.as_bytes()).await?;
+    }
+}
+
+// Whether the envelope sender is on allowed_senders, any sender is when only
+// the secret address is configured
+fn sender_allowed(email: &EmailConfig, addr: &str) -> bool {
+    if email.allowed_senders.is_empty() {
+        return email.secret.is_some();
+    }
+    let domain = addr.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+    email
+        .allowed_senders
+        .iter()
+        .any(|allowed| match allowed.strip_prefix('@') {
+            Some(allowed) => !domain.is_empty() && domain.eq_ignore_ascii_case(allowed),
+            None => addr.eq_ignore_ascii_case(allowed),
+        })
+}
+
+// The mailbox, with "+secret" in its local part when a secret is configured
+fn recipient_allowed(email: &EmailConfig, addr: &str) -> bool {
+    let Some(secret) = &email.secret else {
+        return addr.eq_ignore_ascii_case(&email.mailbox);
+    };
+    let (Some((local, domain)), Some((mailbox_local, mailbox_domain))) =
+        (addr.rsplit_once('@'), email.mailbox.rsplit_once('@'))
+    else {
+        return false;
+    };
+    local
+        .strip_prefix(mailbox_local)
+        .and_then(|l| l.strip_prefix('+'))
+        == Some(secret.as_str())
+        && domain.eq_ignore_ascii_case(mailbox_domain)
+}
+
+// Reads a line of at most MAX_LINE bytes, one without the newline at the end
+// was cut there
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut Vec<u8>) -> Result<usize> {
+    line.clear();
+    Ok((&mut *reader)
+        .take(MAX_LINE)
+        .read_until(b'\n', line)
+        .await?)
+}
+
+// Reads the DATA section up to the terminating dot. Returns None as soon as
+// it grows past max_size, without reading the rest of it.
+async fn read_data<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    let mut line = Vec::new();
+    // false while in the middle of a line longer than MAX_LINE
+    let mut line_start = true;
+
+    loop {
+        if read_line(reader, &mut line).await? == 0 {
+            return Err(anyhow!("connection closed during DATA"));
+        }
+
+        if line_start && (line == b".\r\n" || line == b".\n") {
+            break;
+        }
+
+        // Undo dot-stuffing
+        let content = if line_start && line.starts_with(b"..") {
+            &line[1..]
+        } else {
+            &line[..]
+        };
+        line_start = line.ends_with(b"\n");
+
+        if data.len() + content.len() > max_size {
+            return Ok(None);
+        }
+        data.extend_from_slice(content);
+    }
+
+    Ok(Some(data))
+}
+
+async fn ingest_message(
+    state: &AppState,
+    email: &EmailConfig,
+    envelope_sender: &str,
+    data: &[u8],
+) -> Result<IngestedMail> {
+    let conf = &state.conf;
+    let message = MessageParser::default()
+        .parse(data)
+        .ok_or_else(|| anyhow!("failed to parse message"))?;
+
+    // only tags the images, replies go to the envelope sender that was checked
+    let from = message
+        .from()
+        .and_then(|f| f.first())
+        .and_then(|a| a.address())
+        .unwrap_or(envelope_sender)
+        .to_string();
+    let subject = message.subject().unwrap_or_default().to_string();
+
+    let mut img_ids = Vec::new();
+    for attachment in message.attachments() {
+        // by the magic bytes, the MIME type of attachments is often wrong
+        let image_format = ImageFormat::sniff(attachment.contents());
+        if image_format == ImageFormat::Unknown || !format::accepts(conf, image_format) {
+            continue;
+        }
+
+        let mut tags = vec![format!("from:{}", from)];
+        if !subject.is_empty() {
+            tags.push(format!("subject:{}", subject));
+        }
+
+        let meta = ImgMetadata {
+            tags,
+            original_filename: attachment.attachment_name().map(|s| s.to_string()),
+            ..Default::default()
+        };
+        let data = match &email.recipe {
+            Some(name) => {
+                recipe::process(state, name, image_format, attachment.contents().to_vec()).await?
+            }
+            None => attachment.contents().to_vec(),
+        };
+        img_ids.push(store_image(conf, &image_format, &data, meta).await?);
+    }
+
+    Ok(IngestedMail {
+        sender: envelope_sender.to_string(),
+        subject,
+        img_ids,
+    })
+}
+
+// Replies only go to senders on allowed_senders, with just the secret
+// address the envelope sender is unchecked and may be forged (backscatter).
+// Bounces, with an empty sender, get none either.
+fn send_reply(email: &EmailConfig, mail: IngestedMail) {
+    let (Some(relay), Some(from)) = (email.reply_relay.clone(), email.reply_from.clone()) else {
+        return;
+    };
+    if email.allowed_senders.is_empty() || mail.sender.is_empty() {
+        return;
+    }
+    let public_url = email.public_url.clone().unwrap_or_default();
+
+    tokio::spawn(async move {
+        if let Err(e) = deliver_reply(&relay, &from, &public_url, &mail).await {
+            warn!("failed to send reply to {}: {}", mail.sender, e);
+        }
+    });
+}
+
+async fn deliver_reply(
+    relay: &str,
+    from: &str,
+    public_url: &str,
+    mail: &IngestedMail,
+) -> Result<()> {
+    let body = if mail.img_ids.is_empty() {
+        "No image attachments were found in your message.".to_string()
+    } else {
+        let links: Vec<String> = mail
+            .img_ids
+            .iter()
+            .map(|id| format!("{}/api/images/{}", public_url, id))
+            .collect();
+        format!("Your images were stored:\n\n{}\n", links.join("\n"))
+    };
+
+    let message = Message::builder()
+        .from(from.parse()?)
+        .to(mail.sender.parse()?)
+        .subject(format!("Re: {}", mail.subject))
+        .body(body)?;
+
+    let (host, port) = match relay.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse()?),
+        None => (relay, 25),
+    };
+    let mailer = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        .port(port)
+        .build();
+    mailer.send(message).await?;
+
+    Ok(())
+}
+
+fn envelope_address(cmd: &str) -> String {
+    match (cmd.find('<'), cmd.rfind('>')) {
+        (Some(start), Some(end)) if start < end => cmd[start + 1..end].trim().to_string(),
+        _ => cmd
+            .split_once(':')
+            .map(|(_, addr)| addr.trim().to_string())
+            .unwrap_or_default(),
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::Result;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+use crate::{
+    format::{self, ImageFormat, SNIFF_LEN},
+    handlers::{ImgMetadata, recipe},
+    state::{AfterIngest, AppState, WatchConfig},
+    storage::store_image,
+};
+
+// Polls the configured directory and ingests every image that lands in it
+pub async fn run(state: AppState, watch: WatchConfig) {
+    info!("watching {} for new images", watch.dir);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(watch.interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        if let Err(e) = scan_once(&state, &watch).await {
+            warn!("watch folder scan failed: {}", e);
+        }
+    }
+}
+
+async fn scan_once(state: &AppState, watch: &WatchConfig) -> Result<()> {
+    let mut entries = tokio::fs::read_dir(&watch.dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        // Skip files which are probably still being copied into the folder
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|m| SystemTime::now().duration_since(m).ok())
+            .unwrap_or_default();
+        if age < Duration::from_secs(watch.settle_secs) {
+            continue;
+        }
+
+        // by the magic bytes, whatever the extension says. Files that are no
+        // image or of formats the deployment does not accept are left alone.
+        let path = entry.path();
+        let image_format = match sniff_file(&path).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("failed to read {:?}: {}", path, e);
+                continue;
+            }
+        };
+        if image_format == ImageFormat::Unknown || !format::accepts(&state.conf, image_format) {
+            continue;
+        }
+
+        if let Err(e) = ingest_file(state, watch, &path, &image_format).await {
+            warn!("failed to ingest {:?}: {}", path, e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn sniff_file(path: &Path) -> Result<ImageFormat> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    tokio::fs::File::open(path)
+        .await?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .await?;
+    Ok(ImageFormat::sniff(&head))
+}
+
+async fn ingest_file(
+    state: &AppState,
+    watch: &WatchConfig,
+    path: &Path,
+    image_format: &ImageFormat,
+) -> Result<()> {
+    let mut data = tokio::fs::read(path).await?;
+    if let Some(name) = &watch.recipe {
+        data = recipe::process(state, name, *image_format, data).await?;
+    }
+    let meta = ImgMetadata {
+        original_filename: path.file_name().map(|s| s.to_string_lossy().into_owned()),
+        ..Default::default()
+    };
+    let img_id = store_image(&state.conf, image_format, &data, meta).await?;
+    info!("ingested {:?} as {}", path, img_id);
+
+    match watch.after_ingest {
+        AfterIngest::Delete => tokio::fs::remove_file(path).await?,
+        AfterIngest::Move => {
+            let processed_dir = watch
+                .processed_dir
+                .as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| Path::new(&watch.dir).join("processed"));
+            tokio::fs::create_dir_all(&processed_dir).await?;
+
+            let file_name = path.file_name().unwrap_or_default();
+            let target = processed_dir.join(file_name);
+            tokio::fs::rename(path, target).await?;
+        }
+    }
+
+    Ok(())
+}
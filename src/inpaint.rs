@@ -0,0 +1,126 @@
+use anyhow::{Result, anyhow};
+use photon_rs::PhotonImage;
+
+// Jacobi passes run over the filled region to soften the fill's streaks
+const SMOOTHING_PASSES: usize = 20;
+
+// Fills the rectangle from its border inwards ("onion peel"): each pass sets
+// the unknown pixels touching known ones to the weighted mean of their known
+// neighbours, then the filled region is smoothed. Good for small overlays such
+// as timestamps or stickers; large regions come out blurry.
+pub fn inpaint_region(
+    image: &PhotonImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> Result<PhotonImage> {
+    let (img_w, img_h) = (image.get_width() as usize, image.get_height() as usize);
+    let (x, y, width, height) = (x as usize, y as usize, width as usize, height as usize);
+
+    if width == 0 || height == 0 || x + width > img_w || y + height > img_h {
+        return Err(anyhow!("Inpaint region must lie within the image"));
+    }
+    if width == img_w && height == img_h {
+        return Err(anyhow!("Inpaint region cannot cover the whole image"));
+    }
+
+    let mut pixels: Vec<[f32; 4]> = image
+        .get_raw_pixels()
+        .chunks_exact(4)
+        .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32, p[3] as f32])
+        .collect();
+
+    let mut known = vec![true; img_w * img_h];
+    let mut region = Vec::with_capacity(width * height);
+    for row in y..y + height {
+        for col in x..x + width {
+            known[row * img_w + col] = false;
+            region.push(row * img_w + col);
+        }
+    }
+
+    let mut remaining = region.clone();
+    while !remaining.is_empty() {
+        let mut filled = Vec::new();
+        for &i in &remaining {
+            let (col, row) = (i % img_w, i / img_w);
+            let mut acc = [0.0f32; 4];
+            let mut weight = 0.0;
+
+            let (near, len) = neighbours(col, row, img_w, img_h);
+            for &(n, w) in &near[..len] {
+                if known[n] {
+                    for c in 0..4 {
+                        acc[c] += pixels[n][c] * w;
+                    }
+                    weight += w;
+                }
+            }
+
+            if weight > 0.0 {
+                filled.push((i, acc.map(|v| v / weight)));
+            }
+        }
+
+        // Only mark pixels known after the pass so each ring sees the same state
+        for (i, value) in &filled {
+            pixels[*i] = *value;
+            known[*i] = true;
+        }
+        remaining.retain(|i| !known[*i]);
+    }
+
+    for _ in 0..SMOOTHING_PASSES {
+        let snapshot = pixels.clone();
+        for &i in &region {
+            let (col, row) = (i % img_w, i / img_w);
+            let mut acc = [0.0f32; 4];
+            let mut weight = 0.0;
+
+            let (near, len) = neighbours(col, row, img_w, img_h);
+            for &(n, w) in &near[..len] {
+                for c in 0..4 {
+                    acc[c] += snapshot[n][c] * w;
+                }
+                weight += w;
+            }
+            pixels[i] = acc.map(|v| v / weight);
+        }
+    }
+
+    let raw: Vec<u8> = pixels
+        .iter()
+        .flat_map(|p| p.map(|v| v.round().clamp(0.0, 255.0) as u8))
+        .collect();
+
+    Ok(PhotonImage::new(raw, img_w as u32, img_h as u32))
+}
+
+// 8-connected neighbours, diagonals weighted by 1/sqrt(2). Only the first
+// `len` are set, pixels at the edge of the image have fewer.
+fn neighbours(col: usize, row: usize, width: usize, height: usize) -> ([(usize, f32); 8], usize) {
+    let mut out = [(0, 0.0); 8];
+    let mut len = 0;
+    for dy in -1i64..=1 {
+        for dx in -1i64..=1 {
+            if dx == 0 && dy == 0 {
+                continue;
+            }
+
+            let (nx, ny) = (col as i64 + dx, row as i64 + dy);
+            if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                continue;
+            }
+
+            let w = if dx != 0 && dy != 0 {
+                std::f32::consts::FRAC_1_SQRT_2
+            } else {
+                1.0
+            };
+            out[len] = (ny as usize * width + nx as usize, w);
+            len += 1;
+        }
+    }
+    (out, len)
+}
@@ -0,0 +1,333 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    notify::{Alert, AlertKind, Notifier},
+    state::AppConfig,
+};
+
+pub mod queue;
+
+use queue::{JobQueue, QueueFull};
+
+// Finished jobs are kept around this long so clients can still poll them
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Journal of the jobs in meta_path, see JobStore::recover
+const JOURNAL_DIR: &str = ".jobs";
+
+// Error of the jobs a crash or restart interrupted
+const INTERRUPTED: &str = "Interrupted by a server restart, submit the job again";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_img_id: Option<String>,
+    // images of a job making several, instead of new_img_id
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<JobOutput>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<JobProgress>,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+    // tenant that submitted the job, only it may see the job
+    #[serde(skip)]
+    pub tenant: String,
+}
+
+// Counts of a job that works through many images, e.g. an archive re-encode
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobProgress {
+    pub total: usize,
+    pub converted: usize,
+    pub skipped: usize,
+    // ids of the images that could not be processed
+    pub failed: Vec<String>,
+}
+
+// Named image a job made, e.g. one of the outputs of a recipe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobOutput {
+    pub name: String,
+    pub id: String,
+}
+
+// A job as written to the journal
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    tenant: String,
+    #[serde(flatten)]
+    job: Job,
+}
+
+// Registry of background transform and admin jobs. They run in memory on
+// the workers of the queue, the journal only keeps their state across
+// restarts.
+#[derive(Debug, Clone, Default)]
+pub struct JobStore {
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+    journal: Option<PathBuf>,
+    // held by a journal write from reading the job to renaming its file
+    journal_lock: Arc<Mutex<()>>,
+    // told about every failed job
+    notifier: Option<Notifier>,
+    queue: JobQueue,
+}
+
+impl JobStore {
+    pub fn new(conf: &AppConfig, notifier: Option<Notifier>) -> Self {
+        Self {
+            jobs: Arc::default(),
+            journal: Some(Path::new(&conf.meta_path).join(JOURNAL_DIR)),
+            journal_lock: Arc::default(),
+            notifier,
+            queue: JobQueue::new(&conf.jobs),
+        }
+    }
+
+    // Creates a job of `tenant` and runs `work` with its id once a worker is
+    // free. The job is pending until then and running after, `work` has to
+    // complete, finish or fail it. Returns the id.
+    pub fn submit<F, Fut>(&self, tenant: &str, work: F) -> Result<String, QueueFull>
+    where
+        F: FnOnce(String) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let slot = self.queue.reserve()?;
+        let id = self.create(tenant);
+        let (jobs, job_id) = (self.clone(), id.clone());
+        tokio::spawn(async move {
+            let _worker = slot.start().await;
+            jobs.set_running(&job_id);
+            work(job_id).await;
+        });
+        Ok(id)
+    }
+
+    fn create(&self, tenant: &str) -> String {
+        let id = Uuid::new_v4().to_string();
+        let job = Job {
+            id: id.clone(),
+            status: JobStatus::Pending,
+            new_img_id: None,
+            outputs: Vec::new(),
+            error: None,
+            progress: None,
+            finished_at: None,
+            tenant: tenant.to_string(),
+        };
+
+        let mut expired = Vec::new();
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.retain(|id, job| {
+                let keep = job
+                    .finished_at
+                    .is_none_or(|t| t.elapsed() < FINISHED_JOB_TTL);
+                if !keep {
+                    expired.push(id.clone());
+                }
+                keep
+            });
+            jobs.insert(id.clone(), job.clone());
+        }
+
+        self.persist(&id);
+        if let Some(dir) = self.journal.clone()
+            && !expired.is_empty()
+        {
+            tokio::task::spawn_blocking(move || {
+                for id in expired {
+                    let _ = fs::remove_file(dir.join(id));
+                }
+            });
+        }
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    fn set_running(&self, id: &str) {
+        self.update(id, |job| job.status = JobStatus::Running);
+    }
+
+    pub fn complete(&self, id: &str, new_img_id: String) {
+        self.update(id, |job| {
+            job.status = JobStatus::Done;
+            job.new_img_id = Some(new_img_id);
+            job.finished_at = Some(Instant::now());
+        });
+    }
+
+    pub fn complete_outputs(&self, id: &str, outputs: Vec<JobOutput>) {
+        self.update(id, |job| {
+            job.status = JobStatus::Done;
+            job.outputs = outputs;
+            job.finished_at = Some(Instant::now());
+        });
+    }
+
+    // Not journaled, it changes with every image. The journal has the last
+    // progress once the job is finished.
+    pub fn set_progress(&self, id: &str, progress: JobProgress) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            job.progress = Some(progress);
+        }
+    }
+
+    // Done without an output image, for jobs that report progress instead
+    pub fn finish(&self, id: &str) {
+        self.update(id, |job| {
+            job.status = JobStatus::Done;
+            job.finished_at = Some(Instant::now());
+        });
+    }
+
+    pub fn fail(&self, id: &str, error: String) {
+        let message = format!("Job {} failed: {}", id, error);
+        let job = self.update(id, |job| {
+            job.status = JobStatus::Failed;
+            job.error = Some(error);
+            job.finished_at = Some(Instant::now());
+        });
+        if let (Some(notifier), Some(job)) = (&self.notifier, job) {
+            notifier.notify(Alert {
+                kind: AlertKind::JobFailed,
+                message,
+                tenant: Some(job.tenant).filter(|t| !t.is_empty()),
+            });
+        }
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) -> Option<Job> {
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = jobs.get_mut(id)?;
+            f(job);
+            job.clone()
+        };
+        self.persist(id);
+        Some(job)
+    }
+
+    // Journals the job on the blocking pool. Every write takes the state the
+    // job has by then, so writes running out of order still leave the latest
+    // behind. A failed write only loses the job's state on a restart.
+    fn persist(&self, id: &str) {
+        let Some(dir) = self.journal.clone() else {
+            return;
+        };
+        let (jobs, id) = (self.clone(), id.to_string());
+        tokio::task::spawn_blocking(move || jobs.write_journal(&dir, &id));
+    }
+
+    fn write_journal(&self, dir: &Path, id: &str) {
+        let _guard = self.journal_lock.lock().unwrap();
+        let Some(job) = self.get(id) else {
+            return;
+        };
+        let entry = JournalEntry {
+            tenant: job.tenant.clone(),
+            job,
+        };
+        let staged = dir.join(format!(".{}.tmp", Uuid::new_v4()));
+        let res = fs::create_dir_all(dir)
+            .and_then(|_| serde_json::to_vec(&entry).map_err(io::Error::other))
+            .and_then(|data| fs::write(&staged, data))
+            .and_then(|_| fs::rename(&staged, dir.join(id)));
+        if let Err(e) = res {
+            let _ = fs::remove_file(&staged);
+            warn!("failed to journal job {}: {}", id, e);
+        }
+    }
+
+    // Loads the jobs of the previous run from the journal. Those it left
+    // pending or running were interrupted by a crash or restart and are
+    // failed, so clients polling them stop waiting. Returns how many were.
+    pub fn recover(&self) -> io::Result<usize> {
+        let Some(dir) = &self.journal else {
+            return Ok(0);
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let mut interrupted = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            // staging files of writes the crash cut short
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            let age = entry
+                .metadata()?
+                .modified()
+                .ok()
+                .and_then(|t| SystemTime::now().duration_since(t).ok())
+                .unwrap_or_default();
+            let JournalEntry { tenant, mut job } =
+                match fs::read(&path).map(|data| serde_json::from_slice(&data)) {
+                    Ok(Ok(v)) => v,
+                    Ok(Err(e)) => {
+                        warn!("removing unreadable job journal {:?}: {}", path, e);
+                        let _ = fs::remove_file(&path);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+            job.tenant = tenant;
+
+            match job.status {
+                JobStatus::Pending | JobStatus::Running => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(INTERRUPTED.to_string());
+                    job.finished_at = Some(Instant::now());
+                    interrupted.push(job.clone());
+                }
+                JobStatus::Done | JobStatus::Failed if age >= FINISHED_JOB_TTL => {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                JobStatus::Done | JobStatus::Failed => {
+                    job.finished_at =
+                        Some(Instant::now().checked_sub(age).unwrap_or_else(Instant::now));
+                }
+            }
+            self.jobs.lock().unwrap().insert(job.id.clone(), job);
+        }
+
+        for job in &interrupted {
+            self.persist(&job.id);
+        }
+        Ok(interrupted.len())
+    }
+}
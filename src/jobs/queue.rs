@@ -0,0 +1,79 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::state::JobsConfig;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Too many jobs are waiting, retry later")]
+pub struct QueueFull;
+
+// Bounded worker pool of the jobs: at most `workers` of them run at once, up
+// to max_queued wait for a worker and further ones are refused
+#[derive(Debug, Clone)]
+pub struct JobQueue {
+    inner: Arc<QueueInner>,
+}
+
+#[derive(Debug)]
+struct QueueInner {
+    workers: Arc<Semaphore>,
+    max_queued: usize,
+    queued: AtomicUsize,
+}
+
+// Place of a job in the queue, given up once it gets a worker or is dropped
+#[derive(Debug)]
+pub struct Slot {
+    inner: Arc<QueueInner>,
+}
+
+impl JobQueue {
+    pub fn new(conf: &JobsConfig) -> Self {
+        Self {
+            inner: Arc::new(QueueInner {
+                workers: Arc::new(Semaphore::new(conf.workers)),
+                max_queued: conf.max_queued,
+                queued: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    // Queues a job, which is refused when max_queued are waiting already
+    pub fn reserve(&self) -> Result<Slot, QueueFull> {
+        let inner = &self.inner;
+        if inner.queued.fetch_add(1, Ordering::SeqCst) >= inner.max_queued {
+            inner.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(QueueFull);
+        }
+        Ok(Slot {
+            inner: inner.clone(),
+        })
+    }
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self::new(&JobsConfig::default())
+    }
+}
+
+impl Slot {
+    // Waits for a free worker, which is the job's until the permit is dropped
+    pub async fn start(self) -> OwnedSemaphorePermit {
+        self.inner
+            .workers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the worker semaphore is never closed")
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.inner.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,3 +1,64 @@
+pub mod access;
+pub mod alpha;
+pub mod ann;
+pub mod art_direction;
+pub mod audit;
+pub mod bidi;
+pub mod blobs;
+pub mod budget;
+pub mod burst;
+pub mod cache_control;
+pub mod changes;
+pub mod cli;
+pub mod coalesce;
+pub mod compare;
+pub mod conditional;
+pub mod config_check;
+pub mod crypt;
+pub mod decode;
+pub mod decode_cache;
+pub mod delta;
+pub mod disk;
+pub mod egress;
+pub mod embedding;
+pub mod error;
+pub mod exif;
+pub mod filter;
+pub mod format;
 pub mod handlers;
+pub mod ingest;
+pub mod inpaint;
+pub mod jobs;
+pub mod limits;
+pub mod lineage;
+pub mod logging;
+pub mod notify;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+pub mod operations;
+pub mod placement;
+pub mod print;
+pub mod public_id;
+pub mod quality;
+pub mod range;
+pub mod raster;
+pub mod recipe;
+pub mod recovery;
+pub mod report;
+pub mod resize;
+pub mod review;
 pub mod router;
+pub mod server;
+pub mod share;
+pub mod signing;
+pub mod sigv4;
+pub mod spool;
 pub mod state;
+pub mod storage;
+pub mod store;
+pub mod strip;
+pub mod tagging;
+pub mod tenant;
+pub mod text;
+pub mod timings;
+pub mod tls;
@@ -0,0 +1,227 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{HeaderMap, HeaderValue, Response, StatusCode, header},
+    middleware::Next,
+};
+use http_body_util::Limited;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::build_err_response,
+    state::{AppState, ClientRateConfig},
+    tenant,
+};
+
+// Buckets kept before idle ones are dropped
+const MAX_CLIENTS: usize = 10_000;
+
+// Rejects request bodies above the limit of the matched route, from
+// limits.body_mb or max_file_size. Bodies without a Content-Length are cut
+// off while they are read.
+pub async fn limit_body(State(state): State<AppState>, req: Request, next: Next) -> Response<Body> {
+    let Some(limits) = &state.conf.limits else {
+        return next.run(req).await;
+    };
+
+    let limit_mb = req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|route| limits.body_mb.get(route.as_str()))
+        .copied()
+        .unwrap_or(state.conf.max_file_size);
+    let limit = limit_mb * 1024 * 1024;
+
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > limit) {
+        return build_err_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Request body is larger than {} MB", limit_mb),
+        );
+    }
+
+    next.run(req.map(|body| Body::new(Limited::new(body, limit as usize))))
+        .await
+}
+
+// Size of the header block as sent on the wire
+pub fn header_bytes(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+// Token bucket allowing `rate` requests per second with bursts of `burst`
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated_at: Instant,
+    // of the last request, allowed or not
+    requested_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        Self::with_burst(rate, rate)
+    }
+
+    pub fn with_burst(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated_at: Instant::now(),
+            requested_at: Instant::now(),
+        }
+    }
+
+    pub fn allow(&mut self) -> bool {
+        self.requested_at = Instant::now();
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    // Time until the next request is allowed
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.rate).max(0.0))
+    }
+
+    // Refilled completely, the bucket behaves like a new one
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated_at = now;
+    }
+}
+
+// Token buckets of API keys and IP addresses across connections
+#[derive(Debug, Clone, Default)]
+pub struct ClientLimits {
+    buckets: Arc<Mutex<HashMap<String, RateLimiter>>>,
+}
+
+impl ClientLimits {
+    // None when the client may go ahead, otherwise how long it has to wait
+    fn check(&self, client: String, conf: &ClientRateConfig) -> Option<Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| !bucket.is_full());
+            // all of them busy, the one that waited longest makes room
+            if buckets.len() >= MAX_CLIENTS
+                && let Some(oldest) = buckets
+                    .iter()
+                    .min_by_key(|(_, bucket)| bucket.requested_at)
+                    .map(|(client, _)| client.clone())
+            {
+                buckets.remove(&oldest);
+            }
+        }
+        let bucket = buckets.entry(client).or_insert_with(|| {
+            RateLimiter::with_burst(
+                conf.requests_per_sec,
+                conf.burst.unwrap_or(conf.requests_per_sec),
+            )
+        });
+        if bucket.allow() {
+            None
+        } else {
+            Some(bucket.retry_after())
+        }
+    }
+}
+
+// Limits the requests of a tenant's API key with limits.per_key and those of
+// other clients by IP address with limits.per_ip, across connections. Unknown
+// keys count against the address, rotating keys gets around nothing.
+pub async fn limit_clients(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    let Some(limits) = &state.conf.limits else {
+        return next.run(req).await;
+    };
+
+    let known_key = tenant::api_key(req.headers())
+        .map(str::trim)
+        .filter(|key| state.conf.tenants.iter().any(|t| t.api_key == *key));
+    let client = match (known_key, &limits.per_key, &limits.per_ip) {
+        (Some(key), Some(conf), _) => Some((format!("key:{}", key), conf)),
+        // known keys are not limited by address
+        (Some(_), None, _) => None,
+        (None, _, Some(conf)) => {
+            client_ip(&req, &limits.trusted_proxies).map(|ip| (format!("ip:{}", ip), conf))
+        }
+        (None, _, None) => None,
+    };
+
+    if let Some((client, conf)) = client
+        && let Some(wait) = state.clients.check(client, conf)
+    {
+        let mut res = build_err_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many requests, slow down".to_string(),
+        );
+        res.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(wait.as_secs_f64().ceil().max(1.0) as u64),
+        );
+        return res;
+    }
+    next.run(req).await
+}
+
+// Address of the client. Behind trusted proxies it is the last
+// X-Forwarded-For entry they did not add, the entries before it are sent by
+// the client and can be anything.
+fn client_ip(req: &Request, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+    let mut client = peer;
+    for entry in forwarded.iter().rev() {
+        // anything before an entry that is no address is not to be trusted
+        let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = ip;
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+    }
+    Some(client)
+}
@@ -0,0 +1,117 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{crypt, state::AppConfig};
+
+// Next to the metadata of the tenant, one file per transform output named
+// after it. The leading dot keeps it out of listings.
+const LINEAGE_DIR: &str = ".lineage";
+
+// How an image was made from another one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Derivation {
+    pub parent_id: String,
+    pub operation: String,
+    // body of the transform request
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub params: serde_json::Value,
+    // RFC 3339
+    pub created_at: String,
+}
+
+impl Derivation {
+    pub fn new(parent_id: &str, operation: &str, params: serde_json::Value) -> Self {
+        Derivation {
+            parent_id: parent_id.to_string(),
+            operation: operation.to_string(),
+            params,
+            created_at: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// Records how `img_id` was made, run on the blocking pool by the transforms.
+// An output written again (content-hash or suffixed naming) keeps the latest.
+pub fn record(conf: &AppConfig, img_id: &str, derivation: &Derivation) -> Result<()> {
+    let dir = lineage_dir(conf);
+    fs::create_dir_all(&dir)?;
+
+    let data = crypt::seal(conf, serde_json::to_vec(derivation)?)?;
+    let staged = dir.join(format!(".{}.tmp", Uuid::new_v4()));
+    let res = fs::write(&staged, data).and_then(|_| fs::rename(&staged, dir.join(img_id)));
+    if res.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    Ok(res?)
+}
+
+// How `img_id` was made, None for uploads
+pub async fn find(conf: &AppConfig, img_id: &str) -> Result<Option<Derivation>> {
+    match tokio::fs::read(lineage_dir(conf).join(img_id)).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(&crypt::open(conf, data)?)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The derivations from `img_id` back to the upload it started from, nearest
+// first. Empty for uploads.
+pub async fn ancestry(conf: &AppConfig, img_id: &str) -> Result<Vec<(String, Derivation)>> {
+    let mut chain = Vec::new();
+    let mut seen = HashSet::new();
+    let mut current = img_id.to_string();
+
+    // content-hash naming can make an output its own ancestor
+    while seen.insert(current.clone()) {
+        let Some(derivation) = find(conf, &current).await? else {
+            break;
+        };
+        let parent_id = derivation.parent_id.clone();
+        chain.push((current, derivation));
+        current = parent_id;
+    }
+    Ok(chain)
+}
+
+// Images made directly from `img_id`, oldest first
+pub async fn children(conf: &AppConfig, img_id: &str) -> Result<Vec<(String, Derivation)>> {
+    let mut children = Vec::new();
+    let mut entries = match tokio::fs::read_dir(lineage_dir(conf)).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(children),
+        Err(e) => return Err(e.into()),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        match find(conf, &name).await {
+            Ok(Some(derivation)) if derivation.parent_id == img_id => {
+                children.push((name, derivation))
+            }
+            Ok(_) => {}
+            Err(e) => warn!("skipping lineage of {}: {}", name, e),
+        }
+    }
+
+    children.sort_by(|a, b| a.1.created_at.cmp(&b.1.created_at));
+    Ok(children)
+}
+
+fn lineage_dir(conf: &AppConfig) -> PathBuf {
+    Path::new(&conf.meta_path).join(LINEAGE_DIR)
+}
@@ -0,0 +1,162 @@
+use anyhow::{Result, anyhow};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+use time::{Date, OffsetDateTime};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    Layer as _,
+    filter::Targets,
+    fmt::{self, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
+
+use crate::state::{LogFileConfig, LogFormat, LogRotation, LoggingConfig};
+
+// Installs the global subscriber. Without a [logging] section this is the
+// plain INFO output on stdout.
+pub fn init(conf: Option<&LoggingConfig>) -> Result<()> {
+    let default_conf = LoggingConfig::default();
+    let conf = conf.unwrap_or(&default_conf);
+
+    let mut filter = Targets::new().with_default(parse_level(&conf.level)?);
+    for (module, level) in &conf.modules {
+        filter = filter.with_target(module.clone(), parse_level(level)?);
+    }
+
+    let (writer, ansi) = match &conf.file {
+        Some(file) => (
+            BoxMakeWriter::new(Mutex::new(RotatingFile::open(file)?)),
+            false,
+        ),
+        None => (BoxMakeWriter::new(io::stdout), true),
+    };
+
+    let layer = match conf.format {
+        LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+        LogFormat::Pretty => fmt::layer().with_ansi(ansi).with_writer(writer).boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()
+        .map_err(|e| anyhow!("failed to install logger: {}", e))
+}
+
+pub(crate) fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level).map_err(|_| anyhow!("invalid log level: {:?}", level))
+}
+
+// Log file that is renamed to "{prefix}.{suffix}" and replaced by a fresh one
+// when the day changes or it reaches the size limit
+struct RotatingFile {
+    dir: PathBuf,
+    prefix: String,
+    rotation: LogRotation,
+    max_bytes: u64,
+    max_files: Option<usize>,
+    file: File,
+    written: u64,
+    opened_on: Date,
+}
+
+impl RotatingFile {
+    fn open(conf: &LogFileConfig) -> Result<Self> {
+        fs::create_dir_all(&conf.dir)?;
+        let dir = PathBuf::from(&conf.dir);
+        let file = open_append(&dir.join(&conf.prefix))?;
+        let written = file.metadata()?.len();
+
+        Ok(Self {
+            dir,
+            prefix: conf.prefix.clone(),
+            rotation: conf.rotation.clone(),
+            max_bytes: conf.max_size_mb * 1024 * 1024,
+            max_files: conf.max_files,
+            file,
+            written,
+            opened_on: OffsetDateTime::now_utc().date(),
+        })
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        match self.rotation {
+            LogRotation::Daily => OffsetDateTime::now_utc().date() != self.opened_on,
+            LogRotation::Size => {
+                self.written > 0 && self.written + incoming as u64 > self.max_bytes
+            }
+            LogRotation::Never => false,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let suffix = match self.rotation {
+            LogRotation::Daily => self.opened_on.to_string(),
+            _ => OffsetDateTime::now_utc().unix_timestamp().to_string(),
+        };
+        let mut rotated = self.dir.join(format!("{}.{}", self.prefix, suffix));
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = self.dir.join(format!("{}.{}.{}", self.prefix, suffix, n));
+            n += 1;
+        }
+
+        let current = self.dir.join(&self.prefix);
+        fs::rename(&current, &rotated)?;
+        self.file = open_append(&current)?;
+        self.written = 0;
+        self.opened_on = OffsetDateTime::now_utc().date();
+
+        self.prune()
+    }
+
+    // Removes the oldest rotated files beyond max_files
+    fn prune(&self) -> io::Result<()> {
+        let Some(max_files) = self.max_files else {
+            return Ok(());
+        };
+
+        let rotated_prefix = format!("{}.", self.prefix);
+        let mut rotated: Vec<(std::time::SystemTime, PathBuf)> = fs::read_dir(&self.dir)?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().starts_with(&rotated_prefix))
+            .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+            .collect();
+
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(max_files);
+        for (_, path) in rotated.into_iter().take(excess) {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
@@ -1,36 +1,90 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use brushbloom::{
-    router,
+    access,
+    cli::{Cli, Command},
+    config_check, decode, disk, ingest, logging, recovery, router,
+    server::Server,
+    spool,
     state::{AppConfig, AppState},
+    tenant::Tenant,
+    tls,
 };
-use std::path::Path;
+use clap::Parser;
 use tokio::net::TcpListener;
-use tracing::{info, level_filters::LevelFilter};
-use tracing_subscriber::{Layer as _, fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
+    // run by decode::run for isolated decodes, not part of the command line
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|s| s.as_str()) == Some(decode::WORKER_COMMAND) {
+        return decode::worker_main(&args[2..]);
+    }
+
+    let cli = Cli::parse();
+    if let Some(Command::CheckConfig { path }) = &cli.command {
+        let path = path.as_deref().or(cli.config.as_deref());
+        let problems = config_check::check(&AppConfig::new(path, &cli.overrides)?);
+        println!("{}", config_check::report(&problems));
+        std::process::exit(if problems.is_empty() { 0 } else { 1 });
+    }
+
+    let app_conf = AppConfig::new(cli.config.as_deref(), &cli.overrides)?;
+    let problems = config_check::check(&app_conf);
+    if !problems.is_empty() {
+        return Err(anyhow!("{}", config_check::report(&problems)));
+    }
+    logging::init(app_conf.logging.as_ref())?;
 
-    let app_conf = AppConfig::new("config.toml")?;
+    tokio::fs::create_dir_all(&app_conf.file_path).await?;
+    tokio::fs::create_dir_all(&app_conf.meta_path).await?;
 
-    let upload_dir = app_conf.file_path.clone();
-    if !Path::new(&upload_dir).exists() {
-        tokio::fs::create_dir(upload_dir).await?;
+    if let Some(spool) = app_conf.spool.clone() {
+        tokio::fs::create_dir_all(&spool.dir).await?;
+        tokio::spawn(spool::run(spool));
     }
 
-    let meta_path = app_conf.meta_path.clone();
-    if !Path::new(&meta_path).exists() {
-        tokio::fs::create_dir(meta_path).await?;
+    for tenant in &app_conf.tenants {
+        let scoped = Tenant::from(tenant).scope(&app_conf);
+        tokio::fs::create_dir_all(&scoped.file_path).await?;
+        tokio::fs::create_dir_all(&scoped.meta_path).await?;
     }
 
-    let app_state = AppState::new(app_conf);
+    let app_state = AppState::new(app_conf)?;
     info!("app_state: {:?}", app_state);
+    recovery::run(&app_state);
+
+    if let Some(watch) = app_state.conf.watch.clone() {
+        tokio::spawn(ingest::watch::run(app_state.clone(), watch));
+    }
 
+    if let Some(email) = app_state.conf.email.clone() {
+        tokio::spawn(ingest::email::run(app_state.clone(), email));
+    }
+
+    tokio::spawn(access::run(
+        app_state.conf.clone(),
+        app_state.access.clone(),
+    ));
+
+    if let Some(disk_space) = app_state.conf.disk_space.clone() {
+        tokio::spawn(disk::run(app_state.clone(), disk_space));
+    }
+
+    let tls_conf = app_state.conf.tls.clone();
+    let limits = app_state.conf.limits.clone();
     let app = router::routers(app_state)?;
+    let server = Server::new(app.clone(), limits);
+
+    if let Some(tls_conf) = tls_conf {
+        #[cfg(feature = "http3")]
+        if let Some(listen) = tls_conf.http3_listen.clone() {
+            tokio::spawn(tls::http3::run(tls_conf.clone(), listen, app.clone()));
+        }
+        tokio::spawn(tls::run(tls_conf, server.clone()));
+    }
+
     let listener = TcpListener::bind("0.0.0.0:8080").await?;
 
-    axum::serve(listener, app).await?;
-    Ok(())
+    server.serve(listener).await
 }
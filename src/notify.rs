@@ -0,0 +1,144 @@
+use anyhow::Result;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::warn;
+
+use crate::state::{NotificationChannel, NotificationTarget, NotificationsConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    // an upload was refused for the tenant's storage quota
+    QuotaExceeded,
+    // read-only mode was entered, see disk::run
+    DiskSpaceLow,
+    // read-only mode was left again
+    DiskSpaceRecovered,
+    // a background job failed, jobs are not retried
+    JobFailed,
+}
+
+impl AlertKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertKind::QuotaExceeded => "quota_exceeded",
+            AlertKind::DiskSpaceLow => "disk_space_low",
+            AlertKind::DiskSpaceRecovered => "disk_space_recovered",
+            AlertKind::JobFailed => "job_failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+// Sends alerts to the channels of [notifications]. Like ErrorReporter,
+// delivery happens in the background and failures are only logged.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    channels: Arc<Vec<NotificationChannel>>,
+    repeat_after: Duration,
+    // when each alert (kind and tenant) was last sent
+    sent: Arc<Mutex<HashMap<(AlertKind, Option<String>), Instant>>>,
+}
+
+impl Notifier {
+    pub fn new(conf: &NotificationsConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            channels: Arc::new(conf.channels.clone()),
+            repeat_after: Duration::from_secs(conf.repeat_after_secs),
+            sent: Arc::default(),
+        }
+    }
+
+    pub fn notify(&self, alert: Alert) {
+        // e.g. every upload of a tenant over its quota, or a batch of jobs
+        // failing the same way
+        {
+            let mut sent = self.sent.lock().unwrap();
+            let key = (alert.kind, alert.tenant.clone());
+            if sent
+                .get(&key)
+                .is_some_and(|t| t.elapsed() < self.repeat_after)
+            {
+                return;
+            }
+            sent.insert(key, Instant::now());
+        }
+
+        for channel in self.channels.iter() {
+            if !channel.events.is_empty() && !channel.events.contains(&alert.kind) {
+                continue;
+            }
+            let (client, target, alert) =
+                (self.client.clone(), channel.target.clone(), alert.clone());
+            tokio::spawn(async move {
+                if let Err(e) = send(&client, &target, &alert).await {
+                    warn!("failed to send {} alert: {}", alert.kind.as_str(), e);
+                }
+            });
+        }
+    }
+}
+
+async fn send(client: &reqwest::Client, target: &NotificationTarget, alert: &Alert) -> Result<()> {
+    let text = match &alert.tenant {
+        Some(tenant) => format!("{} (tenant {})", alert.message, tenant),
+        None => alert.message.clone(),
+    };
+
+    match target {
+        NotificationTarget::Slack { webhook_url } => {
+            let body = json!({ "text": format!("brushbloom {}: {}", alert.kind.as_str(), text) });
+            client
+                .post(webhook_url)
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        NotificationTarget::Webhook { url } => {
+            let mut body = serde_json::to_value(alert)?;
+            body["timestamp"] = json!(OffsetDateTime::now_utc().format(&Rfc3339)?);
+            client
+                .post(url)
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        NotificationTarget::Email { relay, from, to } => {
+            let mut builder = Message::builder()
+                .from(from.parse()?)
+                .subject(format!("[brushbloom] {}", alert.kind.as_str()));
+            for addr in to {
+                builder = builder.to(addr.parse()?);
+            }
+            let message = builder.body(text)?;
+
+            let (host, port) = match relay.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse()?),
+                None => (relay.as_str(), 25),
+            };
+            let mailer = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+                .port(port)
+                .build();
+            mailer.send(message).await?;
+        }
+    }
+    Ok(())
+}
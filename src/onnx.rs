@@ -0,0 +1,52 @@
+use anyhow::Result;
+use ort::value::Tensor;
+use photon_rs::{PhotonImage, transform::crop};
+
+use crate::resize::{self, Resizer};
+
+// Per channel normalisation a vision model was trained with
+pub struct Normalization {
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+}
+
+pub const IMAGENET: Normalization = Normalization {
+    mean: [0.485, 0.456, 0.406],
+    std: [0.229, 0.224, 0.225],
+};
+
+pub const CLIP: Normalization = Normalization {
+    mean: [0.481_454_66, 0.457_827_5, 0.408_210_73],
+    std: [0.268_629_54, 0.261_302_58, 0.275_777_1],
+};
+
+// The image as the single NCHW float input of a vision model: scaled to
+// cover size x size, cropped to it from the centre and normalised
+pub fn image_input(
+    img: &PhotonImage,
+    size: u32,
+    norm: &Normalization,
+    resizer: Resizer,
+) -> Result<Tensor<f32>> {
+    let (width, height) = (img.get_width(), img.get_height());
+    let ratio = size as f32 / width.min(height) as f32;
+    let (scaled_width, scaled_height) = (
+        ((width as f32 * ratio).round() as u32).max(size),
+        ((height as f32 * ratio).round() as u32).max(size),
+    );
+    let scaled = resize::lanczos3(img, scaled_width, scaled_height, resizer);
+    let (x, y) = ((scaled_width - size) / 2, (scaled_height - size) / 2);
+    let square = crop(&scaled, x, y, x + size, y + size);
+
+    let plane = (size * size) as usize;
+    let mut input = vec![0f32; 3 * plane];
+    for (i, p) in square.get_raw_pixels().chunks_exact(4).enumerate() {
+        for c in 0..3 {
+            input[c * plane + i] = (p[c] as f32 / 255.0 - norm.mean[c]) / norm.std[c];
+        }
+    }
+    Ok(Tensor::from_array((
+        [1usize, 3, size as usize, size as usize],
+        input,
+    ))?)
+}
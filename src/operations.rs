@@ -0,0 +1,726 @@
+use serde::Serialize;
+
+use crate::filter;
+
+// Transform operations exposed under /api/images/{img_id}/{name}. Clients use
+// this (through GET /api/capabilities) to build their forms, so every new
+// operation gets an entry here.
+#[derive(Debug, Serialize)]
+pub struct Operation {
+    pub name: &'static str,
+    pub method: &'static str,
+    pub path: &'static str,
+    pub description: &'static str,
+    // accepts `Prefer: respond-async` and answers with a job
+    pub supports_async: bool,
+    pub params: &'static [Param],
+}
+
+#[derive(Debug, Serialize)]
+pub struct Param {
+    pub name: &'static str,
+    // JSON type: "integer", "number", "string", "boolean", "array" or "object"
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub required: bool,
+    pub description: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<i64>,
+    #[serde(rename = "enum", skip_serializing_if = "<[_]>::is_empty")]
+    pub allowed: &'static [&'static str],
+}
+
+const fn param(name: &'static str, kind: &'static str, description: &'static str) -> Param {
+    Param {
+        name,
+        kind,
+        required: true,
+        description,
+        minimum: None,
+        maximum: None,
+        allowed: &[],
+    }
+}
+
+const fn pixels(name: &'static str, description: &'static str) -> Param {
+    Param {
+        minimum: Some(0),
+        ..param(name, "integer", description)
+    }
+}
+
+const GRAVITY: Param = Param {
+    required: false,
+    allowed: &["center", "focus"],
+    ..param(
+        "gravity",
+        "string",
+        "what crops keep: the centre, or the focal point of the image's regions",
+    )
+};
+
+const REGION: &[Param] = &[
+    pixels("x", "left edge of the region"),
+    pixels("y", "top edge of the region"),
+    pixels("width", "width of the region"),
+    pixels("height", "height of the region"),
+];
+
+pub const OPERATIONS: &[Operation] = &[
+    Operation {
+        name: "watermark",
+        method: "POST",
+        path: "/api/images/{img_id}/watermark",
+        description: "Draw a text or logo watermark",
+        supports_async: true,
+        params: &[
+            Param {
+                required: false,
+                ..param(
+                    "text",
+                    "string",
+                    "watermark text, needed unless a logo is given",
+                )
+            },
+            Param {
+                allowed: &[
+                    "top-left",
+                    "top",
+                    "top-right",
+                    "left",
+                    "center",
+                    "right",
+                    "bottom-left",
+                    "bottom",
+                    "bottom-right",
+                ],
+                required: false,
+                ..param(
+                    "position",
+                    "string",
+                    "where the text and logo are anchored, top-left by default",
+                )
+            },
+            Param {
+                required: false,
+                ..param(
+                    "offset_x",
+                    "string",
+                    "distance from the anchored edge (or right of the centre) in pixels, 12 or \"12px\", or of the image width, \"5%\"; 10 pixels from edges by default",
+                )
+            },
+            Param {
+                required: false,
+                ..param(
+                    "offset_y",
+                    "string",
+                    "distance from the anchored edge (or below the centre) in pixels, 12 or \"12px\", or of the image height, \"5%\"; 10 pixels from edges by default",
+                )
+            },
+            Param {
+                required: false,
+                minimum: Some(1),
+                ..param(
+                    "font_size",
+                    "integer",
+                    "font size in pixels, needed with a text",
+                )
+            },
+            Param {
+                required: false,
+                ..param(
+                    "logo",
+                    "object",
+                    "stored image drawn under the text, {\"img_id\": \"...\", \"scale\": 0.2, \"opacity\": 1.0}, scale is a fraction of the image width",
+                )
+            },
+            Param {
+                required: false,
+                allowed: &["single", "tile"],
+                ..param(
+                    "mode",
+                    "string",
+                    "single (default) places the watermark once, tile repeats it diagonally across the image",
+                )
+            },
+            Param {
+                required: false,
+                ..param(
+                    "tile",
+                    "object",
+                    "repeats of mode tile, {\"spacing\": 100, \"angle\": 45, \"opacity\": 0.3}, spacing in pixels, angle in degrees",
+                )
+            },
+            Param {
+                required: false,
+                allowed: &["auto", "ltr", "rtl", "vertical"],
+                ..param(
+                    "direction",
+                    "string",
+                    "auto is right to left for Arabic or Hebrew text, vertical stacks the characters",
+                )
+            },
+            Param {
+                required: false,
+                allowed: &["color", "scrim"],
+                ..param(
+                    "contrast",
+                    "string",
+                    "from the luminance under the text: color picks black or white text, scrim adds a black box as dark as needed",
+                )
+            },
+            Param {
+                required: false,
+                ..param(
+                    "stroke",
+                    "object",
+                    "outline, {\"color\": \"#000000\", \"width\": 2} (1-16 pixels)",
+                )
+            },
+            Param {
+                required: false,
+                ..param(
+                    "shadow",
+                    "object",
+                    "drop shadow, {\"color\": \"#000000\", \"offset_x\": 2, \"offset_y\": 2, \"opacity\": 0.5}",
+                )
+            },
+            Param {
+                required: false,
+                ..param(
+                    "background",
+                    "object",
+                    "box behind the text, {\"color\": \"#000000\", \"opacity\": 0.5, \"padding\": 8}",
+                )
+            },
+        ],
+    },
+    Operation {
+        name: "resize",
+        method: "POST",
+        path: "/api/images/{img_id}/resize",
+        description: "Resize to the given dimensions",
+        supports_async: true,
+        params: &[
+            Param {
+                minimum: Some(1),
+                ..param("width", "integer", "target width")
+            },
+            Param {
+                minimum: Some(1),
+                ..param("height", "integer", "target height")
+            },
+            param(
+                "maintain_aspect",
+                "boolean",
+                "fit within width x height keeping the aspect ratio",
+            ),
+            GRAVITY,
+        ],
+    },
+    Operation {
+        name: "compress",
+        method: "POST",
+        path: "/api/images/{img_id}/compress",
+        description: "Re-encode at a lower quality, optionally converting the format",
+        supports_async: true,
+        params: &[
+            Param {
+                minimum: Some(0),
+                maximum: Some(100),
+                ..param("quality", "integer", "encoder quality")
+            },
+            Param {
+                required: false,
+                allowed: &["jpeg", "png", "gif", "webp"],
+                ..param(
+                    "format",
+                    "string",
+                    "output format, that of the source by default",
+                )
+            },
+            Param {
+                required: false,
+                ..param(
+                    "background",
+                    "string",
+                    "\"#rrggbb\" behind transparent pixels when format has no alpha, the configured background (white) by default",
+                )
+            },
+        ],
+    },
+    Operation {
+        name: "rotate",
+        method: "POST",
+        path: "/api/images/{img_id}/rotate",
+        description: "Rotate clockwise, then optionally flip",
+        supports_async: true,
+        params: &[
+            param(
+                "angle",
+                "number",
+                "degrees, multiples of 90 keep the dimensions exact",
+            ),
+            Param {
+                required: false,
+                ..param("flip_horizontal", "boolean", "mirror left to right")
+            },
+            Param {
+                required: false,
+                ..param("flip_vertical", "boolean", "mirror top to bottom")
+            },
+        ],
+    },
+    Operation {
+        name: "filter",
+        method: "POST",
+        path: "/api/images/{img_id}/filter",
+        description: "Apply one of photon's filters or monochrome effects",
+        supports_async: true,
+        params: &[Param {
+            allowed: filter::NAMES,
+            ..param("name", "string", "filter to apply")
+        }],
+    },
+    Operation {
+        name: "adjust",
+        method: "POST",
+        path: "/api/images/{img_id}/adjust",
+        description: "Adjust brightness, contrast, saturation and hue",
+        supports_async: true,
+        params: &[
+            Param {
+                required: false,
+                minimum: Some(-255),
+                maximum: Some(255),
+                ..param("brightness", "integer", "added to every channel")
+            },
+            Param {
+                required: false,
+                minimum: Some(-255),
+                maximum: Some(255),
+                ..param(
+                    "contrast",
+                    "number",
+                    "negative values flatten, positive ones stretch",
+                )
+            },
+            Param {
+                required: false,
+                minimum: Some(-1),
+                maximum: Some(1),
+                ..param(
+                    "saturation",
+                    "number",
+                    "-1 is greyscale, 0 leaves it as it is",
+                )
+            },
+            Param {
+                required: false,
+                ..param("hue_shift", "number", "degrees around the colour wheel")
+            },
+        ],
+    },
+    Operation {
+        name: "blur",
+        method: "POST",
+        path: "/api/images/{img_id}/blur",
+        description: "Gaussian blur",
+        supports_async: true,
+        params: &[Param {
+            minimum: Some(1),
+            maximum: Some(100),
+            ..param("radius", "integer", "in pixels")
+        }],
+    },
+    Operation {
+        name: "sharpen",
+        method: "POST",
+        path: "/api/images/{img_id}/sharpen",
+        description: "Unsharp mask",
+        supports_async: true,
+        params: &[
+            Param {
+                required: false,
+                minimum: Some(1),
+                maximum: Some(100),
+                ..param(
+                    "radius",
+                    "integer",
+                    "of the blur compared with, 1 by default",
+                )
+            },
+            Param {
+                required: false,
+                minimum: Some(0),
+                maximum: Some(5),
+                ..param("amount", "number", "strength, 1 by default")
+            },
+        ],
+    },
+    Operation {
+        name: "crop",
+        method: "POST",
+        path: "/api/images/{img_id}/crop",
+        description: "Crop to a region",
+        supports_async: true,
+        // forwarded to photon's crop(x1, y1, x2, y2) as-is
+        params: &[
+            pixels("x", "left edge of the region"),
+            pixels("y", "top edge of the region"),
+            pixels("width", "right edge (exclusive) of the region"),
+            pixels("height", "bottom edge (exclusive) of the region"),
+            GRAVITY,
+        ],
+    },
+    Operation {
+        name: "inpaint",
+        method: "POST",
+        path: "/api/images/{img_id}/inpaint",
+        description: "Fill a region from its surroundings, e.g. to remove a timestamp",
+        supports_async: true,
+        params: REGION,
+    },
+    Operation {
+        name: "compare",
+        method: "POST",
+        path: "/api/images/{img_id}/compare",
+        description: "Before/after composite with an edited version of the image",
+        supports_async: true,
+        params: &[
+            param("edited_id", "string", "id of the edited (after) image"),
+            Param {
+                required: false,
+                ..param("mode", "string", "side_by_side (default) or wipe")
+            },
+            Param {
+                required: false,
+                ..param(
+                    "split",
+                    "number",
+                    "wipe position as a fraction of the width, 0.5 by default",
+                )
+            },
+        ],
+    },
+    Operation {
+        name: "quality",
+        method: "GET",
+        path: "/api/images/{img_id}/quality",
+        description: "Estimate sharpness and exposure",
+        supports_async: false,
+        params: &[],
+    },
+    Operation {
+        name: "convert",
+        method: "POST",
+        path: "/api/images/{img_id}/convert",
+        description: "Re-encode the stored file in another format, keeping the id of the image",
+        supports_async: false,
+        params: &[
+            param("format", "string", "jpeg, png, webp or gif"),
+            Param {
+                required: false,
+                minimum: Some(1),
+                maximum: Some(100),
+                ..param("quality", "integer", "JPEG quality")
+            },
+        ],
+    },
+    Operation {
+        name: "dedupe_burst",
+        method: "POST",
+        path: "/api/bursts/dedupe",
+        description: "Keep the best scored frame of a burst, marking near identical frames as its duplicates",
+        supports_async: false,
+        params: &[
+            param("ids", "array", "ids of the 2 to 50 frames"),
+            Param {
+                required: false,
+                allowed: &["tag", "soft_delete"],
+                ..param(
+                    "action",
+                    "string",
+                    "tag the duplicates \"duplicate\" (default) or hide them",
+                )
+            },
+            Param {
+                required: false,
+                minimum: Some(0),
+                maximum: Some(64),
+                ..param(
+                    "max_distance",
+                    "integer",
+                    "fingerprint bits a duplicate may differ in, 10 by default",
+                )
+            },
+        ],
+    },
+    Operation {
+        name: "meta",
+        method: "GET",
+        path: "/api/images/{img_id}/meta",
+        description: "Stored metadata, dimensions and creation time",
+        supports_async: false,
+        params: &[],
+    },
+    Operation {
+        name: "update_meta",
+        method: "PATCH",
+        path: "/api/images/{img_id}/meta",
+        description: "Set or remove named regions, \"focus\" is the focal point of crops",
+        supports_async: false,
+        params: &[param(
+            "regions",
+            "object",
+            "regions by name as {x, y, width, height}, null removes one",
+        )],
+    },
+    Operation {
+        name: "exif",
+        method: "GET",
+        path: "/api/images/{img_id}/exif",
+        description: "Camera, lens, exposure, GPS, orientation and capture time from EXIF",
+        supports_async: false,
+        params: &[],
+    },
+    Operation {
+        name: "autotag",
+        method: "POST",
+        path: "/api/images/{img_id}/autotag",
+        description: "Add descriptive tags from the configured vision model, with their confidence",
+        supports_async: false,
+        params: &[],
+    },
+    Operation {
+        name: "search_by_image",
+        method: "POST",
+        path: "/api/images/search/by-image",
+        description: "Images that look like the given one, by embedding similarity",
+        supports_async: false,
+        params: &[
+            param("id", "string", "image to find similar ones of"),
+            Param {
+                required: false,
+                minimum: Some(1),
+                maximum: Some(100),
+                ..param("limit", "integer", "20 by default")
+            },
+        ],
+    },
+    Operation {
+        name: "search_by_text",
+        method: "POST",
+        path: "/api/images/search/by-text",
+        description: "Images matching a description, by embedding similarity",
+        supports_async: false,
+        params: &[
+            param("text", "string", "e.g. \"a dog on a beach\""),
+            Param {
+                required: false,
+                minimum: Some(1),
+                maximum: Some(100),
+                ..param("limit", "integer", "20 by default")
+            },
+        ],
+    },
+    Operation {
+        name: "strip",
+        method: "POST",
+        path: "/api/images/{img_id}/strip",
+        description: "Remove EXIF (with the GPS position), XMP and comments, keeping the orientation",
+        supports_async: false,
+        params: &[],
+    },
+    Operation {
+        name: "print",
+        method: "POST",
+        path: "/api/images/{img_id}/print",
+        description: "CMYK PDF or TIFF for print with the configured ICC profile, cover-fit to the trim size plus bleed",
+        supports_async: false,
+        params: &[
+            Param {
+                minimum: Some(1),
+                ..param("width_mm", "number", "trim width in millimetres")
+            },
+            Param {
+                minimum: Some(1),
+                ..param("height_mm", "number", "trim height in millimetres")
+            },
+            Param {
+                required: false,
+                minimum: Some(72),
+                maximum: Some(1200),
+                ..param("dpi", "integer", "300 by default")
+            },
+            Param {
+                required: false,
+                minimum: Some(0),
+                maximum: Some(25),
+                ..param(
+                    "bleed_mm",
+                    "number",
+                    "added on every side by mirroring the edges, 3 by default",
+                )
+            },
+            Param {
+                required: false,
+                allowed: &["pdf", "tiff"],
+                ..param("format", "string", "pdf (default) or tiff")
+            },
+        ],
+    },
+    Operation {
+        name: "export",
+        method: "POST",
+        path: "/api/images/{img_id}/export/{profile}",
+        description: "Every file of a configured export profile, e.g. the icon sizes of an app",
+        supports_async: false,
+        params: &[Param {
+            required: false,
+            allowed: &["manifest", "zip"],
+            ..param(
+                "bundle",
+                "string",
+                "manifest (default) stores the files and returns their ids, zip returns them in a zip",
+            )
+        }],
+    },
+    Operation {
+        name: "variants",
+        method: "POST",
+        path: "/api/images/{img_id}/variants/{set}",
+        description: "A composition per breakpoint of a configured art-direction set, cropped to the image's regions",
+        supports_async: false,
+        params: &[],
+    },
+    Operation {
+        name: "thumbnail",
+        method: "GET",
+        path: "/api/images/{img_id}/thumbnail",
+        description: "Cached thumbnail, a PNG still for GIFs",
+        supports_async: false,
+        params: &[
+            Param {
+                required: false,
+                minimum: Some(16),
+                maximum: Some(1024),
+                ..param("size", "integer", "longest edge in pixels, 256 by default")
+            },
+            Param {
+                required: false,
+                allowed: &["contain", "cover", "fill"],
+                ..param(
+                    "fit",
+                    "string",
+                    "contain keeps the aspect ratio, cover crops a square, fill stretches",
+                )
+            },
+            GRAVITY,
+            Param {
+                required: false,
+                ..param(
+                    "preset",
+                    "string",
+                    "one of the configured thumbnail_presets, replaces size, fit and gravity",
+                )
+            },
+        ],
+    },
+    Operation {
+        name: "review",
+        method: "POST",
+        path: "/api/images/{img_id}/review",
+        description: "Move through uploaded -> pending_review -> approved / rejected",
+        supports_async: false,
+        params: &[param(
+            "state",
+            "string",
+            "pending_review, approved or rejected",
+        )],
+    },
+    Operation {
+        name: "sign",
+        method: "POST",
+        path: "/api/images/{img_id}/sign",
+        description: "Time-limited URL reading the image without an API key",
+        supports_async: false,
+        params: &[Param {
+            required: false,
+            minimum: Some(1),
+            ..param(
+                "ttl_secs",
+                "integer",
+                "seconds the URL is valid, signed_urls.default_ttl_secs by default",
+            )
+        }],
+    },
+    Operation {
+        name: "share",
+        method: "POST",
+        path: "/api/images/{img_id}/share",
+        description: "Token for /share/{token}, read access without an API key until it expires or DELETE /api/shares/{token}",
+        supports_async: false,
+        params: &[
+            Param {
+                required: false,
+                ..param(
+                    "ttl_secs",
+                    "number",
+                    "lifetime, one day by default and at most 30 days",
+                )
+            },
+            Param {
+                required: false,
+                ..param(
+                    "include_variants",
+                    "boolean",
+                    "also share outputs named {img_id}_..., see output_naming suffixed",
+                )
+            },
+        ],
+    },
+    Operation {
+        name: "comment",
+        method: "POST",
+        path: "/api/images/{img_id}/comments",
+        description: "Attach a review note, listed by GET on the same path",
+        supports_async: false,
+        params: &[
+            param("author", "string", "who left the note"),
+            param("text", "string", "the note"),
+            Param {
+                required: false,
+                ..param(
+                    "region",
+                    "object",
+                    "x, y, width and height of the area the note refers to",
+                )
+            },
+        ],
+    },
+    Operation {
+        name: "recipe",
+        method: "POST",
+        path: "/api/images/{img_id}/recipes/{name}",
+        description: "Run the latest version of a recipe, a named pipeline stored under /api/recipes. Recipes with outputs make several images from one decode.",
+        supports_async: true,
+        params: &[
+            Param {
+                required: false,
+                ..param(
+                    "params",
+                    "object",
+                    "values of the recipe's parameters, its defaults otherwise",
+                )
+            },
+            Param {
+                required: false,
+                minimum: Some(1),
+                ..param("version", "integer", "an earlier version of the recipe")
+            },
+        ],
+    },
+];
@@ -0,0 +1,139 @@
+use serde::Deserialize;
+
+// Space between a watermark and the edges it is anchored to, when no offset
+// is given
+const DEFAULT_MARGIN: i64 = 10;
+
+// Point of the image a watermark is aligned to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Align {
+    Start,
+    Center,
+    End,
+}
+
+impl Anchor {
+    // horizontal and vertical alignment
+    fn align(self) -> (Align, Align) {
+        match self {
+            Anchor::TopLeft => (Align::Start, Align::Start),
+            Anchor::Top => (Align::Center, Align::Start),
+            Anchor::TopRight => (Align::End, Align::Start),
+            Anchor::Left => (Align::Start, Align::Center),
+            Anchor::Center => (Align::Center, Align::Center),
+            Anchor::Right => (Align::End, Align::Center),
+            Anchor::BottomLeft => (Align::Start, Align::End),
+            Anchor::Bottom => (Align::Center, Align::End),
+            Anchor::BottomRight => (Align::End, Align::End),
+        }
+    }
+}
+
+// Distance of a watermark from the edge it is anchored to: pixels as a
+// number, "12" or "12px", or a percentage of the image width or height, "5%"
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "OffsetValue")]
+pub enum Offset {
+    Pixels(i32),
+    Percent(f32),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OffsetValue {
+    Number(i32),
+    Text(String),
+}
+
+impl TryFrom<OffsetValue> for Offset {
+    type Error = String;
+
+    fn try_from(value: OffsetValue) -> Result<Self, Self::Error> {
+        let text = match value {
+            OffsetValue::Number(px) => return Ok(Offset::Pixels(px)),
+            OffsetValue::Text(text) => text,
+        };
+        let trimmed = text.trim();
+        if let Some(percent) = trimmed.strip_suffix('%') {
+            return match percent.trim().parse::<f32>() {
+                Ok(p) if (-100.0..=100.0).contains(&p) => Ok(Offset::Percent(p)),
+                _ => Err(format!(
+                    "{:?} is not a percentage between -100% and 100%",
+                    text
+                )),
+            };
+        }
+        let px = trimmed.strip_suffix("px").unwrap_or(trimmed).trim();
+        px.parse()
+            .map(Offset::Pixels)
+            .map_err(|_| format!("{:?} is not an offset, e.g. 12, \"12px\" or \"5%\"", text))
+    }
+}
+
+impl Offset {
+    fn pixels(self, extent: u32) -> i64 {
+        match self {
+            Offset::Pixels(px) => px as i64,
+            Offset::Percent(p) => (extent as f32 * p / 100.0).round() as i64,
+        }
+    }
+}
+
+// Where a watermark goes: the anchor, moved by the offsets away from the
+// edges it is anchored to, or right and down from the centre
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Placement {
+    #[serde(rename = "position", default)]
+    pub anchor: Anchor,
+    pub offset_x: Option<Offset>,
+    pub offset_y: Option<Offset>,
+}
+
+impl Placement {
+    // Top left corner of a width x height box in an image of image_width x
+    // image_height. The box is kept inside the image, boxes larger than the
+    // image start at its top left corner.
+    pub fn locate(
+        &self,
+        image_width: u32,
+        image_height: u32,
+        width: u32,
+        height: u32,
+    ) -> (u32, u32) {
+        let (align_x, align_y) = self.anchor.align();
+        (
+            axis(align_x, self.offset_x, image_width, width),
+            axis(align_y, self.offset_y, image_height, height),
+        )
+    }
+}
+
+fn axis(align: Align, offset: Option<Offset>, extent: u32, len: u32) -> u32 {
+    let free = extent.saturating_sub(len) as i64;
+    let offset = match (offset, align) {
+        (Some(offset), _) => offset.pixels(extent),
+        (None, Align::Center) => 0,
+        (None, _) => DEFAULT_MARGIN,
+    };
+    let start = match align {
+        Align::Start => offset,
+        Align::Center => free / 2 + offset,
+        Align::End => free - offset,
+    };
+    start.clamp(0, free) as u32
+}
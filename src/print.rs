@@ -0,0 +1,204 @@
+use anyhow::{Result, anyhow};
+use flate2::{Compression, write::ZlibEncoder};
+use lcms2::{Intent, PixelFormat, Profile, Transform};
+use photon_rs::PhotonImage;
+use serde::Deserialize;
+use std::io::{Cursor, Write};
+use tiff::{
+    encoder::{Rational, TiffEncoder, colortype::CMYK8, compression::Lzw},
+    tags::{ResolutionUnit, Tag},
+};
+
+use crate::{
+    alpha::{self, Color},
+    state::RenderingIntent,
+};
+
+// TIFF tag of an embedded ICC profile
+const ICC_PROFILE_TAG: u16 = 34675;
+const MM_PER_INCH: f64 = 25.4;
+const POINTS_PER_INCH: f64 = 72.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrintFormat {
+    #[default]
+    Pdf,
+    Tiff,
+}
+
+impl PrintFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            PrintFormat::Pdf => "application/pdf",
+            PrintFormat::Tiff => "image/tiff",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            PrintFormat::Pdf => "pdf",
+            PrintFormat::Tiff => "tiff",
+        }
+    }
+}
+
+// Pixels of `mm` printed at `dpi`
+pub fn to_pixels(mm: f64, dpi: u32) -> u32 {
+    (mm / MM_PER_INCH * dpi as f64).round() as u32
+}
+
+// CMYK pixels, 4 bytes each, converted with the profile of the press.
+// Transparency is flattened onto white, the paper.
+pub fn to_cmyk(img: &PhotonImage, profile: &[u8], intent: RenderingIntent) -> Result<Vec<u8>> {
+    let img = alpha::flatten(img, Color([255, 255, 255]));
+    let srgb = Profile::new_srgb();
+    let cmyk = Profile::new_icc(profile).map_err(|e| anyhow!("invalid ICC profile: {}", e))?;
+    let transform = Transform::<[u8; 4], [u8; 4]>::new(
+        &srgb,
+        PixelFormat::RGBA_8,
+        &cmyk,
+        PixelFormat::CMYK_8,
+        intent.into(),
+    )
+    .map_err(|e| anyhow!("cannot convert to the ICC profile: {}", e))?;
+
+    let rgba: Vec<[u8; 4]> = img
+        .get_raw_pixels()
+        .chunks_exact(4)
+        .map(|p| [p[0], p[1], p[2], p[3]])
+        .collect();
+    let mut cmyk = vec![[0u8; 4]; rgba.len()];
+    transform.transform_pixels(&rgba, &mut cmyk);
+    Ok(cmyk.into_iter().flatten().collect())
+}
+
+// Grows a 4 bytes per pixel image by `bleed` pixels on every side, mirroring
+// its edges so the artwork runs past the trim line
+pub fn extend_bleed(pixels: &[u8], width: u32, height: u32, bleed: u32) -> Vec<u8> {
+    let mirror = |i: i64, len: i64| -> usize {
+        let i = if i < 0 { -i - 1 } else { i };
+        let i = if i >= len { 2 * len - i - 1 } else { i };
+        i.clamp(0, len - 1) as usize
+    };
+
+    let (new_width, new_height) = (width + 2 * bleed, height + 2 * bleed);
+    let mut out = Vec::with_capacity(new_width as usize * new_height as usize * 4);
+    for y in 0..new_height as i64 {
+        let src_y = mirror(y - bleed as i64, height as i64);
+        for x in 0..new_width as i64 {
+            let src_x = mirror(x - bleed as i64, width as i64);
+            let at = (src_y * width as usize + src_x) * 4;
+            out.extend_from_slice(&pixels[at..at + 4]);
+        }
+    }
+    out
+}
+
+// LZW compressed CMYK TIFF with the resolution and the profile embedded
+pub fn tiff(cmyk: &[u8], width: u32, height: u32, dpi: u32, profile: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    {
+        let mut encoder = TiffEncoder::new(&mut out)?;
+        let mut image = encoder.new_image_with_compression::<CMYK8, _>(width, height, Lzw)?;
+        image.resolution(ResolutionUnit::Inch, Rational { n: dpi, d: 1 });
+        image
+            .encoder()
+            .write_tag(Tag::Unknown(ICC_PROFILE_TAG), profile)?;
+        image.write_data(cmyk)?;
+    }
+    Ok(out.into_inner())
+}
+
+// Single page PDF of the image, with the bleed area as MediaBox and
+// BleedBox, the trim size as TrimBox and the profile as output intent
+pub fn pdf(
+    cmyk: &[u8],
+    width: u32,
+    height: u32,
+    dpi: u32,
+    bleed: u32,
+    profile: &[u8],
+) -> Result<Vec<u8>> {
+    let points = |px: u32| px as f64 / dpi as f64 * POINTS_PER_INCH;
+    let (page_width, page_height, bleed_points) = (points(width), points(height), points(bleed));
+
+    let content = format!(
+        "q {:.3} 0 0 {:.3} 0 0 cm /Im0 Do Q",
+        page_width, page_height
+    );
+    let page = format!(
+        "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {w:.3} {h:.3}] /BleedBox [0 0 {w:.3} {h:.3}] /TrimBox [{b:.3} {b:.3} {tw:.3} {th:.3}] /Resources << /XObject << /Im0 5 0 R >> >> /Contents 4 0 R >>",
+        w = page_width,
+        h = page_height,
+        b = bleed_points,
+        tw = page_width - bleed_points,
+        th = page_height - bleed_points,
+    );
+    let objects: Vec<(String, Option<Vec<u8>>)> = vec![
+        (
+            "<< /Type /Catalog /Pages 2 0 R /OutputIntents [<< /Type /OutputIntent /S /GTS_PDFX /OutputConditionIdentifier (Custom) /DestOutputProfile 6 0 R >>] >>".to_string(),
+            None,
+        ),
+        ("<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(), None),
+        (page, None),
+        (String::new(), Some(content.into_bytes())),
+        (
+            format!(
+                "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace [/ICCBased 6 0 R] /BitsPerComponent 8 /Filter /FlateDecode",
+                width, height
+            ),
+            Some(deflate(cmyk)?),
+        ),
+        (
+            "/N 4 /Alternate /DeviceCMYK /Filter /FlateDecode".to_string(),
+            Some(deflate(profile)?),
+        ),
+    ];
+
+    let mut out = b"%PDF-1.6\n%\xE2\xE3\xCF\xD3\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, (dict, stream)) in objects.into_iter().enumerate() {
+        offsets.push(out.len());
+        writeln!(out, "{} 0 obj", i + 1)?;
+        match stream {
+            Some(stream) => {
+                writeln!(out, "<< {} /Length {} >>\nstream", dict, stream.len())?;
+                out.extend(stream);
+                out.extend_from_slice(b"\nendstream");
+            }
+            None => out.extend_from_slice(dict.as_bytes()),
+        }
+        out.extend_from_slice(b"\nendobj\n");
+    }
+
+    let xref = out.len();
+    writeln!(out, "xref\n0 {}\n0000000000 65535 f ", offsets.len() + 1)?;
+    for offset in &offsets {
+        writeln!(out, "{:010} 00000 n ", offset)?;
+    }
+    writeln!(
+        out,
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF",
+        offsets.len() + 1,
+        xref
+    )?;
+    Ok(out)
+}
+
+fn deflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+impl From<RenderingIntent> for Intent {
+    fn from(intent: RenderingIntent) -> Self {
+        match intent {
+            RenderingIntent::Perceptual => Intent::Perceptual,
+            RenderingIntent::RelativeColorimetric => Intent::RelativeColorimetric,
+            RenderingIntent::Saturation => Intent::Saturation,
+            RenderingIntent::AbsoluteColorimetric => Intent::AbsoluteColorimetric,
+        }
+    }
+}
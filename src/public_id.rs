@@ -0,0 +1,237 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{StatusCode, Uri, uri::PathAndQuery},
+    middleware::Next,
+    response::Response,
+};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    error::build_err_response,
+    state::{AppConfig, AppState},
+    storage::is_safe_id,
+};
+
+// Public identifiers replace the image ids on routes read without an API key
+// (share links, feeds, WebDAV), so their URLs cannot be enumerated. They are
+// the id run through a keyed permutation and base58 encoded: only the secret
+// maps between the two, and nothing is stored.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PublicIdConfig {
+    // changing it changes every public id, links handed out become invalid
+    pub secret: String,
+}
+
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const ROUNDS: u8 = 4;
+// zero bytes inside the permutation, any other string decodes to something
+// without them
+const CHECK_LEN: usize = 2;
+
+// how the id is packed before the permutation
+const KIND_UUID: u8 = 0;
+const KIND_HASH: u8 = 1;
+const KIND_RAW: u8 = 2;
+
+// The public id of `img_id`, the id itself when public ids are off
+pub fn encode(conf: &AppConfig, img_id: &str) -> String {
+    match &conf.public_ids {
+        Some(public_ids) => encode_with(public_ids.secret.as_bytes(), img_id),
+        None => img_id.to_string(),
+    }
+}
+
+// The image id behind a public id, None when it was not made with the secret
+pub fn decode(conf: &AppConfig, public_id: &str) -> Option<String> {
+    let secret = conf.public_ids.as_ref()?.secret.as_bytes();
+    let mut data = base58_decode(public_id)?;
+    if data.len() <= CHECK_LEN + 1 {
+        return None;
+    }
+    for round in (0..ROUNDS).rev() {
+        feistel_unround(secret, round, &mut data);
+    }
+    unpack(&data).filter(|img_id| is_safe_id(img_id))
+}
+
+fn encode_with(secret: &[u8], img_id: &str) -> String {
+    let mut data = pack(img_id);
+    for round in 0..ROUNDS {
+        feistel_round(secret, round, &mut data);
+    }
+    base58_encode(&data)
+}
+
+// Maps public ids in request paths back to image ids before routing. Share
+// links only take public ids, the API takes both so feed links resolve.
+pub async fn translate(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response<Body> {
+    let mut segments: Vec<String> = req.uri().path().split('/').map(|s| s.to_string()).collect();
+
+    let decoded = match segments
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["", "api", "images", id, ..] if !matches!(*id, "upload" | "search") => {
+            decode(&state.conf, id)
+        }
+        ["", "share", _, id] => match decode(&state.conf, id) {
+            Some(img_id) => Some(img_id),
+            None => {
+                return build_err_response(StatusCode::NOT_FOUND, "Image not found".to_string());
+            }
+        },
+        _ => None,
+    };
+
+    if let Some(img_id) = decoded {
+        segments[3] = img_id;
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{}", segments.join("/"), query),
+            None => segments.join("/"),
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = path_and_query.parse::<PathAndQuery>().ok();
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
+    }
+    next.run(req).await
+}
+
+// UUIDs and content hashes as their 16 bytes, keeping public ids short
+fn pack(img_id: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(img_id.len() + CHECK_LEN + 1);
+    match Uuid::parse_str(img_id) {
+        Ok(uuid) if uuid.hyphenated().to_string() == img_id => {
+            data.push(KIND_UUID);
+            data.extend_from_slice(uuid.as_bytes());
+        }
+        _ => match hex_decode(img_id) {
+            Some(bytes) if bytes.len() == 16 => {
+                data.push(KIND_HASH);
+                data.extend(bytes);
+            }
+            _ => {
+                data.push(KIND_RAW);
+                data.extend_from_slice(img_id.as_bytes());
+            }
+        },
+    }
+    data.extend([0; CHECK_LEN]);
+    data
+}
+
+fn unpack(data: &[u8]) -> Option<String> {
+    let (data, check) = data.split_at(data.len() - CHECK_LEN);
+    if check.iter().any(|b| *b != 0) {
+        return None;
+    }
+    let (kind, body) = data.split_first()?;
+    match *kind {
+        KIND_UUID => Some(Uuid::from_slice(body).ok()?.hyphenated().to_string()),
+        KIND_HASH if body.len() == 16 => Some(body.iter().map(|b| format!("{:02x}", b)).collect()),
+        KIND_RAW => String::from_utf8(body.to_vec()).ok(),
+        _ => None,
+    }
+}
+
+// One Feistel round over the whole buffer: (L, R) -> (R, L ^ F(R)). The
+// halves swap lengths when the buffer is odd, an even number of rounds
+// restores them.
+fn feistel_round(secret: &[u8], round: u8, data: &mut Vec<u8>) {
+    let split = data.len() / 2;
+    let (left, right) = data.split_at(split);
+    let mut left = left.to_vec();
+    xor_keystream(secret, round, right, &mut left);
+    let mut out = right.to_vec();
+    out.extend(left);
+    *data = out;
+}
+
+// Inverse of feistel_round: (L', R') -> (R' ^ F(L'), L')
+fn feistel_unround(secret: &[u8], round: u8, data: &mut Vec<u8>) {
+    let split = data.len() - data.len() / 2;
+    let (left, right) = data.split_at(split);
+    let mut right = right.to_vec();
+    xor_keystream(secret, round, left, &mut right);
+    right.extend_from_slice(left);
+    *data = right;
+}
+
+// HMAC-SHA256 of the round number and input in counter mode
+fn xor_keystream(secret: &[u8], round: u8, input: &[u8], target: &mut [u8]) {
+    for (counter, chunk) in target.chunks_mut(32).enumerate() {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+        mac.update(&[round, counter as u8]);
+        mac.update(input);
+        let stream = mac.finalize().into_bytes();
+        for (b, s) in chunk.iter_mut().zip(stream.iter()) {
+            *b ^= s;
+        }
+    }
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn base58_encode(data: &[u8]) -> String {
+    // base 58 digits, least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in data {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let zeros = data.iter().take_while(|b| **b == 0).count();
+    std::iter::repeat_n(ALPHABET[0], zeros)
+        .chain(digits.iter().rev().map(|d| ALPHABET[*d as usize]))
+        .map(|b| b as char)
+        .collect()
+}
+
+fn base58_decode(s: &str) -> Option<Vec<u8>> {
+    // bytes, least significant first
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.bytes() {
+        let mut carry = ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+
+    let zeros = s.bytes().take_while(|c| *c == ALPHABET[0]).count();
+    let mut data = vec![0; zeros];
+    data.extend(bytes.iter().rev());
+    Some(data)
+}
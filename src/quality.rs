@@ -0,0 +1,90 @@
+use photon_rs::{
+    PhotonImage,
+    transform::{SamplingFilter, resize},
+};
+use serde::Serialize;
+
+// Images are normalised to this longest edge so scores are comparable
+// across resolutions
+const ANALYSIS_MAX_EDGE: u32 = 1024;
+// Laplacian variance at which an image is considered fully sharp
+const SHARPNESS_REFERENCE: f64 = 300.0;
+const SHADOW_CLIP_LUMA: f64 = 5.0;
+const HIGHLIGHT_CLIP_LUMA: f64 = 250.0;
+// Fraction of clipped pixels at which the exposure score reaches zero
+const MAX_CLIPPED_FRACTION: f64 = 0.5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QualityScore {
+    // variance of the Laplacian of the luma channel, higher is sharper
+    pub sharpness: f64,
+    pub underexposed_pct: f64,
+    pub overexposed_pct: f64,
+    pub mean_luma: f64,
+    // 0-100 combination of sharpness and exposure
+    pub score: f64,
+}
+
+pub fn estimate(image: &PhotonImage) -> QualityScore {
+    let (width, height) = (image.get_width(), image.get_height());
+    let ratio = (ANALYSIS_MAX_EDGE as f32 / width.max(height) as f32).min(1.0);
+    let normalized;
+    let image = if ratio < 1.0 {
+        normalized = resize(
+            image,
+            ((width as f32 * ratio).round() as u32).max(1),
+            ((height as f32 * ratio).round() as u32).max(1),
+            SamplingFilter::Triangle,
+        );
+        &normalized
+    } else {
+        image
+    };
+
+    let (width, height) = (image.get_width() as usize, image.get_height() as usize);
+    let luma: Vec<f64> = image
+        .get_raw_pixels()
+        .chunks_exact(4)
+        .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+        .collect();
+
+    let sharpness = laplacian_variance(&luma, width, height);
+
+    let total = luma.len().max(1) as f64;
+    let under = luma.iter().filter(|&&l| l <= SHADOW_CLIP_LUMA).count() as f64 / total;
+    let over = luma.iter().filter(|&&l| l >= HIGHLIGHT_CLIP_LUMA).count() as f64 / total;
+    let mean_luma = luma.iter().sum::<f64>() / total;
+
+    let sharpness_score = (sharpness / SHARPNESS_REFERENCE).min(1.0);
+    let exposure_score = (1.0 - (under + over) / MAX_CLIPPED_FRACTION).clamp(0.0, 1.0);
+
+    QualityScore {
+        sharpness,
+        underexposed_pct: under * 100.0,
+        overexposed_pct: over * 100.0,
+        mean_luma,
+        score: (sharpness_score * exposure_score * 100.0).round(),
+    }
+}
+
+// Variance of the 4-neighbour Laplacian over the interior pixels
+fn laplacian_variance(luma: &[f64], width: usize, height: usize) -> f64 {
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let mut sum = 0.0;
+    let mut sum_sq = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let i = y * width + x;
+            let lap = luma[i - width] + luma[i + width] + luma[i - 1] + luma[i + 1] - 4.0 * luma[i];
+            sum += lap;
+            sum_sq += lap * lap;
+        }
+    }
+
+    let n = ((width - 2) * (height - 2)) as f64;
+    let mean = sum / n;
+    sum_sq / n - mean * mean
+}
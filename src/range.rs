@@ -0,0 +1,46 @@
+use axum::http::HeaderValue;
+
+// Range header that cannot be served, answered with 416
+#[derive(Debug, thiserror::Error)]
+#[error("range is outside the {len} bytes of the content")]
+pub struct Unsatisfiable {
+    pub len: u64,
+}
+
+// First and last byte (inclusive) a Range header asks for out of `len` bytes.
+// None when the whole content should be sent: no header, another unit than
+// bytes, several ranges or a malformed value, all of which a server may
+// ignore (RFC 9110, section 14.2).
+pub fn parse(header: Option<&HeaderValue>, len: u64) -> Result<Option<(u64, u64)>, Unsatisfiable> {
+    let Some(spec) = header
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("bytes="))
+    else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let range = match (first.parse::<u64>().ok(), last.parse::<u64>().ok()) {
+        // "-n", the last n bytes
+        (None, Some(suffix)) if first.is_empty() => {
+            if suffix == 0 || len == 0 {
+                return Err(Unsatisfiable { len });
+            }
+            (len - suffix.min(len), len - 1)
+        }
+        // "a-", from a to the end
+        (Some(start), None) if last.is_empty() => (start, len.saturating_sub(1)),
+        (Some(start), Some(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return Ok(None),
+    };
+
+    if range.0 >= len {
+        return Err(Unsatisfiable { len });
+    }
+    Ok(Some(range))
+}
@@ -0,0 +1,158 @@
+use anyhow::{Result, anyhow};
+use photon_rs::PhotonImage;
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    crypt, decode,
+    format::ImageFormat,
+    state::{AppConfig, RasterCacheConfig},
+    storage::image_path,
+};
+
+const MAGIC: &[u8; 8] = b"BBRGBA1\0";
+const HEADER_LEN: u64 = 16;
+const BYTES_PER_PIXEL: u64 = 4;
+// enough for the dimensions of every supported format, incl. JPEGs with EXIF
+const PROBE_LEN: u64 = 256 * 1024;
+
+// Crop region that does not overlap the image
+#[derive(Debug, thiserror::Error)]
+#[error("region is outside the {width}x{height} image")]
+pub struct OutOfBounds {
+    pub width: u32,
+    pub height: u32,
+}
+
+// Uncompressed RGBA copy of a large original. Crops read only the rows of
+// their region from it instead of decoding the whole image again.
+struct Raster {
+    file: File,
+    width: u32,
+    height: u32,
+}
+
+impl Raster {
+    fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(anyhow!("{:?} is not a raster cache file", path));
+        }
+
+        Ok(Self {
+            file,
+            width: u32::from_le_bytes(header[8..12].try_into()?),
+            height: u32::from_le_bytes(header[12..16].try_into()?),
+        })
+    }
+
+    // Writes the decoded image, via a temporary file so readers never see a
+    // partial raster
+    fn create(path: &Path, img: &PhotonImage) -> Result<()> {
+        let dir = path
+            .parent()
+            .ok_or_else(|| anyhow!("{:?} has no parent directory", path))?;
+        fs::create_dir_all(dir)?;
+
+        let tmp = dir.join(format!(".{}.tmp", Uuid::new_v4()));
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&img.get_width().to_le_bytes())?;
+        out.write_all(&img.get_height().to_le_bytes())?;
+        out.write_all(&img.get_raw_pixels())?;
+        out.into_inner()?.sync_all()?;
+
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn read_region(&mut self, x1: u32, y1: u32, x2: u32, y2: u32) -> Result<PhotonImage> {
+        let x2 = x2.min(self.width);
+        let y2 = y2.min(self.height);
+        if x1 >= x2 || y1 >= y2 {
+            return Err(OutOfBounds {
+                width: self.width,
+                height: self.height,
+            }
+            .into());
+        }
+
+        let row_len = (x2 - x1) as usize * BYTES_PER_PIXEL as usize;
+        let mut pixels = vec![0u8; row_len * (y2 - y1) as usize];
+        for (row, buf) in (y1..y2).zip(pixels.chunks_exact_mut(row_len)) {
+            let offset = (row as u64 * self.width as u64 + x1 as u64) * BYTES_PER_PIXEL;
+            self.file.seek(SeekFrom::Start(HEADER_LEN + offset))?;
+            self.file.read_exact(buf)?;
+        }
+
+        Ok(PhotonImage::new(pixels, x2 - x1, y2 - y1))
+    }
+}
+
+// Dimensions of a stored image, read from its header. Encrypted images have
+// to be decrypted as a whole first.
+pub fn probe_dimensions(conf: &AppConfig, path: &Path, fmt: &str) -> Result<Option<(u32, u32)>> {
+    let mut probe = Vec::new();
+    File::open(path)?.take(PROBE_LEN).read_to_end(&mut probe)?;
+    if crypt::key_id(&probe).is_some() {
+        probe = crypt::open(conf, fs::read(path)?)?;
+    }
+    // as displayed, the way decode::run returns the image
+    let dimensions = ImageFormat::from_fmt(fmt).dimensions(&probe);
+    Ok(match decode::orientation(&conf.decode, &probe) {
+        Some(orientation) if decode::swaps_axes(orientation) => dimensions.map(|(w, h)| (h, w)),
+        _ => dimensions,
+    })
+}
+
+pub fn cache_path(cache: &RasterCacheConfig, img_id: &str) -> PathBuf {
+    Path::new(&cache.dir).join(format!("{}.rgba", img_id))
+}
+
+// Crops (x1, y1)..(x2, y2) of a large image through its raster cache, building
+// the cache on first use. Returns None for images below min_megapixels, which
+// are cheaper to decode directly.
+pub fn crop(
+    conf: &AppConfig,
+    cache: &RasterCacheConfig,
+    img_id: &str,
+    fmt: &str,
+    (x1, y1, x2, y2): (u32, u32, u32, u32),
+) -> Result<Option<PhotonImage>> {
+    let raster_path = cache_path(cache, img_id);
+    if let Ok(mut raster) = Raster::open(&raster_path) {
+        return raster.read_region(x1, y1, x2, y2).map(Some);
+    }
+
+    let original = image_path(conf, img_id, fmt);
+    let Some((width, height)) = probe_dimensions(conf, &original, fmt)? else {
+        return Ok(None);
+    };
+    if (width as u64 * height as u64) < cache.min_megapixels * 1_000_000 {
+        return Ok(None);
+    }
+
+    info!(
+        "building raster cache for {} ({}x{})",
+        img_id, width, height
+    );
+    let data = crypt::open(conf, fs::read(&original)?)?;
+    let orientation = decode::orientation(&conf.decode, &data);
+    let mut img = decode::decode(&ImageFormat::from_fmt(fmt), data, None)?;
+    if let Some(orientation) = orientation {
+        img = decode::orient(&img, orientation);
+    }
+    Raster::create(&raster_path, &img)?;
+    drop(img);
+
+    Raster::open(&raster_path)?
+        .read_region(x1, y1, x2, y2)
+        .map(Some)
+}
@@ -0,0 +1,290 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::Mutex;
+
+use crate::state::AppConfig;
+
+// Every version of a tenant's recipes, one JSON file per recipe in this
+// directory of its meta_path. The leading dot keeps it out of listings.
+const RECIPE_DIR: &str = ".recipes";
+
+// Named pipeline of transforms, e.g. "standard-product", applied with
+// POST /api/images/{img_id}/recipes/{name} and by ingest. Changing it stores
+// a new version, later uses get the latest. With outputs the result of the
+// steps fans out into several images, made from one decode of the source.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recipe {
+    pub name: String,
+    // 1 for the first definition, one more for every change
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // parameters the steps refer to as "$name", with their defaults. Uses
+    // of the recipe may override them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub steps: Vec<RecipeStep>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<RecipeOutput>,
+    // RFC 3339 time this version was stored
+    pub updated_at: String,
+}
+
+// One transform, the op is the name of its route (e.g. "resize") and the
+// params its request body
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipeStep {
+    pub op: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+// One of the images a recipe makes, e.g. a watermarked JPEG next to a clean
+// WebP. Its steps continue from the result of the recipe's steps, which are
+// only run once for all outputs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipeOutput {
+    pub name: String,
+    #[serde(default)]
+    pub steps: Vec<RecipeStep>,
+    // e.g. "webp", the format of the source when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+// Body of creating or changing a recipe
+#[derive(Debug, Deserialize)]
+pub struct RecipeDefinition {
+    pub description: Option<String>,
+    #[serde(default)]
+    pub params: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub steps: Vec<RecipeStep>,
+    #[serde(default)]
+    pub outputs: Vec<RecipeOutput>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("no recipe {name}")]
+pub struct UnknownRecipe {
+    pub name: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("recipe {name} has no version {version}")]
+pub struct UnknownVersion {
+    pub name: String,
+    pub version: u32,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("recipe {name} already exists")]
+pub struct RecipeExists {
+    pub name: String,
+}
+
+// Recipe names are used as file names
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+impl Recipe {
+    // The recipe with every "$param" of its steps (and those of its outputs)
+    // replaced by its value in `overrides`, or else its default. Overrides of
+    // parameters the recipe does not declare are refused.
+    pub fn bind(&self, overrides: &Map<String, Value>) -> Result<Recipe> {
+        if let Some(name) = overrides.keys().find(|k| !self.params.contains_key(*k)) {
+            return Err(anyhow!("recipe {} has no parameter {:?}", self.name, name));
+        }
+        let lookup = |name: &str| overrides.get(name).or_else(|| self.params.get(name));
+        let bind_steps = |steps: &[RecipeStep]| {
+            steps
+                .iter()
+                .map(|step| {
+                    Ok(RecipeStep {
+                        op: step.op.clone(),
+                        params: substitute(&step.params, &lookup)?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Recipe {
+            steps: bind_steps(&self.steps)?,
+            outputs: self
+                .outputs
+                .iter()
+                .map(|out| {
+                    Ok(RecipeOutput {
+                        steps: bind_steps(&out.steps)?,
+                        ..out.clone()
+                    })
+                })
+                .collect::<Result<_>>()?,
+            ..self.clone()
+        })
+    }
+}
+
+fn substitute<'a>(value: &Value, lookup: &impl Fn(&str) -> Option<&'a Value>) -> Result<Value> {
+    Ok(match value {
+        Value::String(s) => match s.strip_prefix('$') {
+            Some(name) => lookup(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("unknown parameter ${}", name))?,
+            None => value.clone(),
+        },
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|v| substitute(v, lookup))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), substitute(v, lookup)?)))
+                .collect::<Result<_>>()?,
+        ),
+        _ => value.clone(),
+    })
+}
+
+// changes of concurrent requests must not interleave
+static WRITES: Mutex<()> = Mutex::const_new(());
+
+// Latest version of every recipe of the tenant, by name
+pub async fn list(conf: &AppConfig) -> Result<Vec<Recipe>> {
+    let mut entries = match tokio::fs::read_dir(recipe_dir(conf)).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut recipes = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            recipes.extend(read_versions(&path).await?.pop());
+        }
+    }
+    recipes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(recipes)
+}
+
+// Every version of a recipe, oldest first
+pub async fn versions(conf: &AppConfig, name: &str) -> Result<Vec<Recipe>> {
+    let versions = match is_valid_name(name) {
+        true => read_versions(&recipe_path(conf, name)).await?,
+        false => Vec::new(),
+    };
+    match versions.is_empty() {
+        true => Err(UnknownRecipe {
+            name: name.to_string(),
+        }
+        .into()),
+        false => Ok(versions),
+    }
+}
+
+// `version` of a recipe, the latest when None
+pub async fn get(conf: &AppConfig, name: &str, version: Option<u32>) -> Result<Recipe> {
+    let mut versions = versions(conf, name).await?;
+    match version {
+        None => Ok(versions.pop().expect("versions are not empty")),
+        Some(n) => versions
+            .into_iter()
+            .find(|r| r.version == n)
+            .ok_or_else(|| {
+                UnknownVersion {
+                    name: name.to_string(),
+                    version: n,
+                }
+                .into()
+            }),
+    }
+}
+
+// Stores `def` as the first version of a new recipe `name` with `create`,
+// otherwise as the next version of an existing one. Returns the stored
+// version.
+pub async fn save(
+    conf: &AppConfig,
+    name: &str,
+    def: RecipeDefinition,
+    create: bool,
+) -> Result<Recipe> {
+    if !is_valid_name(name) {
+        return Err(anyhow!("invalid recipe name {:?}", name));
+    }
+    let _guard = WRITES.lock().await;
+    let path = recipe_path(conf, name);
+    let mut versions = read_versions(&path).await?;
+    match (create, versions.is_empty()) {
+        (true, false) => {
+            return Err(RecipeExists {
+                name: name.to_string(),
+            }
+            .into());
+        }
+        (false, true) => {
+            return Err(UnknownRecipe {
+                name: name.to_string(),
+            }
+            .into());
+        }
+        _ => {}
+    }
+
+    let recipe = Recipe {
+        name: name.to_string(),
+        version: versions.last().map_or(1, |r| r.version + 1),
+        description: def.description,
+        params: def.params,
+        steps: def.steps,
+        outputs: def.outputs,
+        updated_at: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+    };
+    versions.push(recipe.clone());
+
+    tokio::fs::create_dir_all(recipe_dir(conf)).await?;
+    let staged = path.with_extension("json.tmp");
+    tokio::fs::write(&staged, serde_json::to_vec_pretty(&versions)?).await?;
+    tokio::fs::rename(&staged, &path).await?;
+    Ok(recipe)
+}
+
+// Removes a recipe with all its versions. Returns the latest.
+pub async fn delete(conf: &AppConfig, name: &str) -> Result<Recipe> {
+    let _guard = WRITES.lock().await;
+    let recipe = get(conf, name, None).await?;
+    tokio::fs::remove_file(recipe_path(conf, name)).await?;
+    Ok(recipe)
+}
+
+async fn read_versions(path: &Path) -> Result<Vec<Recipe>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn recipe_dir(conf: &AppConfig) -> PathBuf {
+    Path::new(&conf.meta_path).join(RECIPE_DIR)
+}
+
+fn recipe_path(conf: &AppConfig, name: &str) -> PathBuf {
+    recipe_dir(conf).join(format!("{}.json", name))
+}
@@ -0,0 +1,99 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::{info, warn};
+
+use crate::{
+    notify::{Alert, AlertKind},
+    state::{AppConfig, AppState},
+};
+
+// Cleans up after a crash on startup: the jobs the previous run did not
+// finish are failed, and the staging files of writes it was in the middle of
+// are removed in the background
+pub fn run(state: &AppState) {
+    match state.jobs.recover() {
+        Ok(0) => {}
+        Ok(n) => {
+            warn!("failed {} jobs interrupted by the last shutdown", n);
+            if let Some(notifier) = &state.notifier {
+                notifier.notify(Alert {
+                    kind: AlertKind::JobFailed,
+                    message: format!("{} jobs were interrupted by the last shutdown", n),
+                    tenant: None,
+                });
+            }
+        }
+        Err(e) => warn!("failed to recover jobs: {}", e),
+    }
+
+    // files staged by this run are newer, they are left alone
+    let started = SystemTime::now();
+    let conf = state.conf.clone();
+    tokio::spawn(async move {
+        match tokio::task::spawn_blocking(move || remove_staged_files(&conf, started)).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(n)) => info!("removed {} partially written files", n),
+            Ok(Err(e)) => warn!("failed to remove partially written files: {}", e),
+            Err(e) => warn!("failed to remove partially written files: {}", e),
+        }
+    });
+}
+
+// Removes the ".{uuid}.tmp" files older than `before` under the directories
+// the server writes to, each is a write that never completed
+fn remove_staged_files(conf: &AppConfig, before: SystemTime) -> io::Result<usize> {
+    let mut dirs: Vec<PathBuf> = [Some(&conf.file_path), Some(&conf.meta_path)]
+        .into_iter()
+        .chain([
+            conf.spool.as_ref().map(|s| &s.dir),
+            conf.raster_cache.as_ref().map(|c| &c.dir),
+            conf.proxy.as_ref().map(|p| &p.cache_dir),
+        ])
+        .flatten()
+        .map(PathBuf::from)
+        .collect();
+    // meta_path is usually inside file_path, walk it once
+    dirs.sort();
+    dirs.dedup_by(|dir, parent| dir.starts_with(parent));
+
+    let mut removed = 0;
+    for dir in dirs {
+        removed += remove_staged_in(&dir, before)?;
+    }
+    Ok(removed)
+}
+
+fn remove_staged_in(dir: &Path, before: SystemTime) -> io::Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            removed += remove_staged_in(&entry.path(), before)?;
+            continue;
+        }
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !file_type.is_file() || !name.starts_with('.') || !name.ends_with(".tmp") {
+            continue;
+        }
+        if entry.metadata()?.modified()? >= before {
+            continue;
+        }
+        match fs::remove_file(entry.path()) {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("failed to remove {:?}: {}", entry.path(), e),
+        }
+    }
+    Ok(removed)
+}
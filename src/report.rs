@@ -0,0 +1,256 @@
+use anyhow::{Result, anyhow};
+use axum::{
+    body::{Body, to_bytes},
+    extract::{MatchedPath, Request, State},
+    http::{HeaderValue, Response, StatusCode},
+    middleware::Next,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{any::Any, collections::BTreeMap};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::{
+    error::build_err_response,
+    state::{AppState, ErrorReportingConfig},
+};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+// Error bodies larger than this are not read for the report message
+const MAX_REPORTED_BODY: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorEvent {
+    // "panic", "server_error" or "disk_space"
+    pub kind: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub img_id: Option<String>,
+}
+
+// Parsed "https://{public_key}@{host}/{project_id}"
+#[derive(Debug, Clone)]
+struct SentryDsn {
+    store_url: String,
+    public_key: String,
+}
+
+impl SentryDsn {
+    fn parse(dsn: &str) -> Result<Self> {
+        let (scheme, rest) = dsn
+            .split_once("://")
+            .ok_or_else(|| anyhow!("sentry_dsn is missing the scheme"))?;
+        let (public_key, rest) = rest
+            .split_once('@')
+            .ok_or_else(|| anyhow!("sentry_dsn is missing the public key"))?;
+        let (host, project_id) = rest
+            .rsplit_once('/')
+            .ok_or_else(|| anyhow!("sentry_dsn is missing the project id"))?;
+
+        Ok(Self {
+            store_url: format!("{}://{}/api/{}/store/", scheme, host, project_id),
+            public_key: public_key.split(':').next().unwrap_or_default().to_string(),
+        })
+    }
+}
+
+// Sends panics and 5xx responses to Sentry and/or a generic webhook.
+// Delivery happens in the background and failures are only logged.
+#[derive(Debug, Clone)]
+pub struct ErrorReporter {
+    client: reqwest::Client,
+    sentry: Option<SentryDsn>,
+    webhook_url: Option<String>,
+    environment: Option<String>,
+}
+
+impl ErrorReporter {
+    pub fn new(conf: &ErrorReportingConfig) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::new(),
+            sentry: conf
+                .sentry_dsn
+                .as_deref()
+                .map(SentryDsn::parse)
+                .transpose()?,
+            webhook_url: conf.webhook_url.clone(),
+            environment: conf.environment.clone(),
+        })
+    }
+
+    pub fn report(&self, event: ErrorEvent) {
+        let reporter = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reporter.send(&event).await {
+                warn!("failed to report error event: {}", e);
+            }
+        });
+    }
+
+    async fn send(&self, event: &ErrorEvent) -> Result<()> {
+        let timestamp = OffsetDateTime::now_utc().format(&Rfc3339)?;
+
+        if let Some(url) = &self.webhook_url {
+            let mut body = serde_json::to_value(event)?;
+            body["timestamp"] = json!(timestamp);
+            body["environment"] = json!(self.environment);
+            self.client
+                .post(url)
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+
+        if let Some(dsn) = &self.sentry {
+            let mut tags = BTreeMap::new();
+            tags.insert("kind", event.kind.to_string());
+            if let Some(v) = event.status {
+                tags.insert("status", v.to_string());
+            }
+            if let Some(v) = &event.request_id {
+                tags.insert("request_id", v.clone());
+            }
+            if let Some(v) = &event.route {
+                tags.insert("route", v.clone());
+            }
+            if let Some(v) = &event.img_id {
+                tags.insert("img_id", v.clone());
+            }
+
+            let body = json!({
+                "event_id": Uuid::new_v4().simple().to_string(),
+                "timestamp": timestamp,
+                "platform": "other",
+                "level": if event.kind == "panic" { "fatal" } else { "error" },
+                "logger": "brushbloom",
+                "environment": self.environment,
+                "message": { "formatted": event.message },
+                "tags": tags,
+            });
+
+            self.client
+                .post(&dsn.store_url)
+                .header(
+                    "X-Sentry-Auth",
+                    format!(
+                        "Sentry sentry_version=7, sentry_client=brushbloom/{}, sentry_key={}",
+                        env!("CARGO_PKG_VERSION"),
+                        dsn.public_key
+                    ),
+                )
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Attached by the panic handler so the reporter can include the message
+#[derive(Debug, Clone)]
+struct PanicMessage(String);
+
+// Turns a panic inside a handler into a 500 instead of a dropped connection
+pub fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response<Body> {
+    let message = if let Some(s) = err.downcast_ref::<String>() {
+        s.clone()
+    } else if let Some(s) = err.downcast_ref::<&str>() {
+        s.to_string()
+    } else {
+        "unknown panic".to_string()
+    };
+    error!("handler panicked: {}", message);
+
+    let mut res = build_err_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Internal server error".to_string(),
+    );
+    res.extensions_mut().insert(PanicMessage(message));
+    res
+}
+
+// Tags every response with an x-request-id and reports 5xx responses
+pub async fn report_errors(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string());
+    let img_id = route
+        .as_deref()
+        .and_then(|r| path_param(r, req.uri().path(), "img_id"));
+
+    let mut res = next.run(req).await;
+    if let Ok(v) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, v);
+    }
+
+    let Some(reporter) = &state.reporter else {
+        return res;
+    };
+    if !res.status().is_server_error() {
+        return res;
+    }
+    // reported once by disk::run, not for every refused request
+    if res.status() == StatusCode::INSUFFICIENT_STORAGE && state.disk.is_read_only() {
+        return res;
+    }
+
+    let panic = res.extensions().get::<PanicMessage>().cloned();
+    let kind = if panic.is_some() {
+        "panic"
+    } else {
+        "server_error"
+    };
+    let (parts, body) = res.into_parts();
+    let bytes = to_bytes(body, MAX_REPORTED_BODY).await.unwrap_or_default();
+
+    let message = match panic {
+        Some(PanicMessage(msg)) => msg,
+        None => serde_json::from_slice::<serde_json::Value>(&bytes)
+            .ok()
+            .and_then(|v| v["error"].as_str().map(|s| s.to_string()))
+            .unwrap_or_else(|| String::from_utf8_lossy(&bytes).into_owned()),
+    };
+
+    reporter.report(ErrorEvent {
+        kind,
+        message,
+        status: Some(parts.status.as_u16()),
+        request_id: Some(request_id),
+        route,
+        img_id,
+    });
+
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+// Value of `{name}` in `route` for the concrete request path
+fn path_param(route: &str, path: &str, name: &str) -> Option<String> {
+    let placeholder = format!("{{{}}}", name);
+    route
+        .split('/')
+        .zip(path.split('/'))
+        .find(|(r, _)| *r == placeholder)
+        .map(|(_, p)| p.to_string())
+}
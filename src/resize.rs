@@ -0,0 +1,62 @@
+use anyhow::Result;
+use fast_image_resize::{
+    FilterType, PixelType, ResizeAlg, ResizeOptions, Resizer as FastResizer, images::Image,
+};
+use photon_rs::{
+    PhotonImage,
+    transform::{SamplingFilter, resize},
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::alpha;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resizer {
+    #[default]
+    Photon,
+    // SIMD convolution, rows split across the rayon thread pool
+    Fast,
+}
+
+// Lanczos3 resize to width x height. The fast resizer falls back to photon
+// when it fails. Both filter premultiplied colours, fast_image_resize does so
+// by itself.
+pub fn lanczos3(img: &PhotonImage, width: u32, height: u32, resizer: Resizer) -> PhotonImage {
+    if resizer == Resizer::Fast {
+        match fast_lanczos3(img, width, height) {
+            Ok(resized) => return resized,
+            Err(e) => warn!("fast resize failed, using photon: {}", e),
+        }
+    }
+
+    if alpha::has_transparency(img) {
+        let resized = resize(
+            &alpha::premultiply(img),
+            width,
+            height,
+            SamplingFilter::Lanczos3,
+        );
+        return alpha::unpremultiply(&resized);
+    }
+    resize(img, width, height, SamplingFilter::Lanczos3)
+}
+
+fn fast_lanczos3(img: &PhotonImage, width: u32, height: u32) -> Result<PhotonImage> {
+    let src = Image::from_vec_u8(
+        img.get_width(),
+        img.get_height(),
+        img.get_raw_pixels(),
+        PixelType::U8x4,
+    )?;
+    let mut dst = Image::new(width, height, PixelType::U8x4);
+
+    FastResizer::new().resize(
+        &src,
+        &mut dst,
+        &ResizeOptions::new().resize_alg(ResizeAlg::Convolution(FilterType::Lanczos3)),
+    )?;
+
+    Ok(PhotonImage::new(dst.into_vec(), width, height))
+}
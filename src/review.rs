@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{handlers::ImgMetadata, state::AppConfig};
+
+// Editorial lifecycle of an image:
+// uploaded -> pending_review -> approved / rejected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewState {
+    #[default]
+    Uploaded,
+    PendingReview,
+    Approved,
+    Rejected,
+}
+
+impl ReviewState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReviewState::Uploaded => "uploaded",
+            ReviewState::PendingReview => "pending_review",
+            ReviewState::Approved => "approved",
+            ReviewState::Rejected => "rejected",
+        }
+    }
+
+    // Rejected and approved images can be sent back for another review
+    pub fn can_move_to(self, next: ReviewState) -> bool {
+        use ReviewState::*;
+        matches!(
+            (self, next),
+            (Uploaded, PendingReview)
+                | (PendingReview, Approved)
+                | (PendingReview, Rejected)
+                | (Rejected, PendingReview)
+                | (Approved, PendingReview)
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("cannot move an image from {} to {}", from.as_str(), to.as_str())]
+pub struct InvalidTransition {
+    pub from: ReviewState,
+    pub to: ReviewState,
+}
+
+// Whether the image may be delivered, see [review] serve_unapproved
+pub fn is_servable(conf: &AppConfig, meta: &ImgMetadata) -> bool {
+    match &conf.review {
+        Some(review) if !review.serve_unapproved => meta.review_state == ReviewState::Approved,
+        _ => true,
+    }
+}
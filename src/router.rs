@@ -1,25 +1,270 @@
 use anyhow::Result;
 use axum::{
     Router,
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    http::{HeaderMap, StatusCode, Version, header},
+    middleware,
+    routing::{any, delete, get, post, put},
+};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    compression::{
+        CompressionLayer,
+        predicate::{Predicate, SizeAbove},
+    },
 };
 
 use crate::{
-    handlers::image::{
-        compress_image, crop_image, get_image, resize_img, upload_image, watermark_image,
+    disk,
+    handlers::{
+        admin::{
+            self, audit_log, backfill_embeddings, dedup_report, place_hold, reencode, reencrypt,
+            regenerate_preset, release_hold, space_report, top_images,
+        },
+        capabilities::capabilities,
+        comment::{add_comment, list_comments},
+        convert::convert_image,
+        export::{export_image, export_print},
+        feed::{collection_feed_json, collection_feed_rss},
+        image::{
+            adjust_img, apply_recipe, autotag_image, blur_img, compare_image, compress_image,
+            crop_image, dedupe_burst, filter_image, get_image, image_derivatives, image_exif,
+            image_meta, image_origin, image_quality, image_version, inpaint_image,
+            list_image_metas, resize_img, review_image, rotate_img, search_by_image,
+            search_by_text, sharpen_img, strip_image, upload_image, watermark_image,
+        },
+        jobs::get_job,
+        link::link_image_to,
+        proxy,
+        recipe::{
+            create_recipe, delete_recipe, get_recipe, list_recipes, recipe_versions, update_recipe,
+        },
+        regions::patch_image_meta,
+        s3,
+        share::{get_shared, get_shared_variant, revoke_share, share_image, sign_image},
+        sync::sync_changes,
+        thumbnail::get_thumbnail,
+        variants::art_directed_variants,
+        webdav::{self, DAV_ROOT, webdav_root},
     },
-    state::AppState,
+    limits, public_id, report,
+    state::{AppState, CompressionConfig},
+    tenant, timings,
 };
 
 pub fn routers(app_state: AppState) -> Result<Router> {
-    let router = Router::new()
-        .route("/api/images/upload", post(upload_image))
+    // uploads are streamed to disk, so they may be as large as max_file_size
+    // instead of the default 2MB (limits::limit_body takes over when set)
+    let mut upload = post(upload_image);
+    if app_state.conf.limits.is_none() {
+        upload = upload.layer(DefaultBodyLimit::max(
+            (app_state.conf.max_file_size * 1024 * 1024) as usize,
+        ));
+    }
+
+    // Routes acting on a tenant's images, see tenant::authenticate
+    let mut api = Router::new()
+        .route("/api/images", get(list_image_metas))
+        .route("/api/sync", get(sync_changes))
+        .route("/api/recipes", get(list_recipes).post(create_recipe))
+        .route(
+            "/api/recipes/{name}",
+            get(get_recipe).put(update_recipe).delete(delete_recipe),
+        )
+        .route("/api/recipes/{name}/versions", get(recipe_versions))
+        .route("/api/images/upload", upload)
+        .route("/api/images/search/by-image", post(search_by_image))
+        .route("/api/images/search/by-text", post(search_by_text))
+        .route("/api/bursts/dedupe", post(dedupe_burst))
         .route("/api/images/{img_id}", get(get_image))
         .route("/api/images/{img_id}/watermark", post(watermark_image))
         .route("/api/images/{img_id}/resize", post(resize_img))
         .route("/api/images/{img_id}/compress", post(compress_image))
+        .route("/api/images/{img_id}/rotate", post(rotate_img))
+        .route("/api/images/{img_id}/filter", post(filter_image))
+        .route("/api/images/{img_id}/recipes/{name}", post(apply_recipe))
+        .route("/api/images/{img_id}/adjust", post(adjust_img))
+        .route("/api/images/{img_id}/blur", post(blur_img))
+        .route("/api/images/{img_id}/sharpen", post(sharpen_img))
         .route("/api/images/{img_id}/crop", post(crop_image))
-        .with_state(app_state);
+        .route("/api/images/{img_id}/inpaint", post(inpaint_image))
+        .route("/api/images/{img_id}/quality", get(image_quality))
+        .route("/api/images/{img_id}/convert", post(convert_image))
+        .route("/api/images/{img_id}/exif", get(image_exif))
+        .route("/api/images/{img_id}/autotag", post(autotag_image))
+        .route("/api/images/{img_id}/strip", post(strip_image))
+        .route("/api/images/{img_id}/export/{profile}", post(export_image))
+        .route("/api/images/{img_id}/print", post(export_print))
+        .route(
+            "/api/images/{img_id}/variants/{set}",
+            post(art_directed_variants),
+        )
+        .route("/api/images/{img_id}/compare", post(compare_image))
+        .route(
+            "/api/images/{img_id}/meta",
+            get(image_meta).patch(patch_image_meta),
+        )
+        .route("/api/images/{img_id}/origin", get(image_origin))
+        .route("/api/images/{img_id}/derivatives", get(image_derivatives))
+        .route("/api/images/{img_id}/versions/{n}", get(image_version))
+        .route("/api/images/{img_id}/thumbnail", get(get_thumbnail))
+        .route("/api/images/{img_id}/review", post(review_image))
+        .route(
+            "/api/images/{img_id}/comments",
+            get(list_comments).post(add_comment),
+        )
+        .route("/api/images/{img_id}/share", post(share_image))
+        .route("/api/images/{img_id}/sign", post(sign_image))
+        .route("/api/images/{img_id}/link", post(link_image_to))
+        .route("/api/shares/{token}", delete(revoke_share))
+        .route("/api/jobs/{job_id}", get(get_job));
+    if app_state.conf.proxy.is_some() {
+        api = api.route("/api/proxy", get(proxy::proxy_image));
+    }
+    // feeds need the API key like any other read, and public_url for their
+    // links, see feed::public_url
+    if app_state.conf.public_url.is_some() {
+        api = api
+            .route(
+                "/api/collections/{collection_id}/feed.rss",
+                get(collection_feed_rss),
+            )
+            .route(
+                "/api/collections/{collection_id}/feed.json",
+                get(collection_feed_json),
+            );
+    }
+
+    // Admin routes, for the tenant of the API key, see admin::authorize
+    let admin = Router::new()
+        .route("/api/admin/space", get(space_report))
+        .route("/api/admin/dedup", get(dedup_report))
+        .route("/api/admin/top-images", get(top_images))
+        .route("/api/admin/reencrypt", post(reencrypt))
+        .route("/api/admin/reencode", post(reencode))
+        .route("/api/admin/embeddings", post(backfill_embeddings))
+        .route(
+            "/api/admin/images/{img_id}/hold",
+            put(place_hold).delete(release_hold),
+        )
+        .route("/api/admin/audit", get(audit_log))
+        .route(
+            "/api/admin/presets/{name}/regenerate",
+            post(regenerate_preset),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            admin::authorize,
+        ));
+
+    let mut api = api.merge(admin).route_layer(middleware::from_fn_with_state(
+        app_state.clone(),
+        tenant::authenticate,
+    ));
+    if app_state.conf.debug_timings {
+        api = api.route_layer(middleware::from_fn(timings::collect));
+    }
+
+    let mut router = api
+        .route("/api/capabilities", get(capabilities))
+        // share links carry their own token instead of an API key
+        .route("/share/{token}", get(get_shared))
+        .route("/share/{token}/{img_id}", get(get_shared_variant))
+        .merge(
+            Router::new()
+                .route(DAV_ROOT, any(webdav_root))
+                .route(&format!("{}/", DAV_ROOT), any(webdav_root))
+                .route(&format!("{}/{{*path}}", DAV_ROOT), any(webdav::webdav))
+                .route_layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    webdav::authenticate,
+                )),
+        );
+
+    if let Some(s3_conf) = &app_state.conf.s3 {
+        let bucket_path = format!("/{}", s3_conf.bucket);
+        let s3_router = Router::new()
+            .route(&bucket_path, get(s3::list_objects))
+            .route(
+                &format!("{}/{{*key}}", bucket_path),
+                get(s3::get_object)
+                    .put(s3::put_object)
+                    .delete(s3::delete_object),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                s3::authorize,
+            ))
+            .layer(DefaultBodyLimit::max(
+                (app_state.conf.max_file_size * 1024 * 1024) as usize,
+            ));
+        router = router.merge(s3_router);
+    }
+
+    if app_state.conf.disk_space.is_some() {
+        router = router.layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            disk::reject_writes,
+        ));
+    }
+
+    if app_state.conf.limits.is_some() {
+        // limits::limit_body replaces the default 2MB limit of the extractors
+        router = router
+            .layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                limits::limit_body,
+            ))
+            .layer(DefaultBodyLimit::disable());
+    }
+    if app_state
+        .conf
+        .limits
+        .as_ref()
+        .is_some_and(|l| l.per_key.is_some() || l.per_ip.is_some())
+    {
+        router = router.layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            limits::limit_clients,
+        ));
+    }
+
+    router = router.layer(CatchPanicLayer::custom(report::handle_panic));
+    if app_state.reporter.is_some() {
+        router = router.layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            report::report_errors,
+        ));
+    }
+
+    if let Some(compression) = &app_state.conf.compression {
+        router = router.layer(compression_layer(compression));
+    }
+
+    if app_state.conf.public_ids.is_some() {
+        // routing has already matched by the time a layer of the router runs,
+        // so the public ids are translated by an outer one
+        let translate = middleware::from_fn_with_state(app_state.clone(), public_id::translate);
+        return Ok(Router::new()
+            .fallback_service(router.with_state(app_state))
+            .layer(translate));
+    }
+
+    Ok(router.with_state(app_state))
+}
+
+fn compression_layer(conf: &CompressionConfig) -> CompressionLayer<impl Predicate + use<>> {
+    // Only JSON bodies are worth it, the images are already compressed
+    let is_json = |_: StatusCode, _: Version, headers: &HeaderMap, _: &axum::http::Extensions| {
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .is_some_and(|v| v.trim() == "application/json" || v.trim().ends_with("+json"))
+    };
 
-    Ok(router)
+    CompressionLayer::new()
+        .gzip(conf.gzip)
+        .br(conf.br)
+        .compress_when(SizeAbove::new(conf.min_size).and(is_json))
 }
@@ -0,0 +1,175 @@
+use anyhow::Result;
+use axum::{
+    Router,
+    body::Body,
+    extract::ConnectInfo,
+    http::{HeaderValue, Request, Response, StatusCode, header},
+};
+use hyper::{body::Incoming, service::service_fn};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::Semaphore,
+};
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+use crate::{
+    error::build_err_response,
+    limits::{RateLimiter, header_bytes},
+    state::LimitsConfig,
+};
+
+// How long a connection rejected for the connection limit may take to read
+// its 503
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Serves the router on accepted connections for the plain and the TLS
+// listener, enforcing the connection level parts of [limits]
+#[derive(Clone)]
+pub struct Server {
+    app: Router,
+    limits: Option<Arc<LimitsConfig>>,
+    connections: Option<Arc<Semaphore>>,
+}
+
+impl Server {
+    pub fn new(app: Router, limits: Option<LimitsConfig>) -> Self {
+        Self {
+            app,
+            connections: limits
+                .as_ref()
+                .and_then(|l| l.max_connections)
+                .map(|n| Arc::new(Semaphore::new(n))),
+            limits: limits.map(Arc::new),
+        }
+    }
+
+    pub fn map_app(self, f: impl FnOnce(Router) -> Router) -> Self {
+        Self {
+            app: f(self.app),
+            ..self
+        }
+    }
+
+    pub fn header_timeout(&self) -> Option<Duration> {
+        self.limits
+            .as_ref()
+            .map(|l| Duration::from_secs(l.header_timeout_secs))
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let server = self.clone();
+            tokio::spawn(async move { server.serve_connection(stream, addr).await });
+        }
+    }
+
+    pub async fn serve_connection<I>(&self, io: I, addr: SocketAddr)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // released when the connection closes
+        let _permit = match &self.connections {
+            Some(connections) => match connections.clone().try_acquire_owned() {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    warn!("rejecting connection from {}: too many connections", addr);
+                    let _ = tokio::time::timeout(REJECT_TIMEOUT, reject(io)).await;
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let app = self.app.clone();
+        let max_header_bytes = self.limits.as_ref().map(|l| l.max_header_bytes);
+        let rate = self
+            .limits
+            .as_ref()
+            .and_then(|l| l.requests_per_sec)
+            .map(|r| Arc::new(Mutex::new(RateLimiter::new(r))));
+
+        let service = service_fn(move |req: Request<Incoming>| {
+            let app = app.clone();
+            let rate = rate.clone();
+            async move {
+                if let Some(rate) = &rate
+                    && !rate.lock().unwrap().allow()
+                {
+                    return Ok(build_err_response(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "Too many requests on this connection".to_string(),
+                    ));
+                }
+                if let Some(max) = max_header_bytes
+                    && header_bytes(req.headers()) > max
+                {
+                    return Ok(build_err_response(
+                        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                        format!("Request headers are larger than {} bytes", max),
+                    ));
+                }
+
+                let mut req = req.map(Body::new);
+                // for limits::limit_clients
+                req.extensions_mut().insert(ConnectInfo(addr));
+                app.oneshot(req).await
+            }
+        });
+
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        if let Some(limits) = &self.limits {
+            builder
+                .http1()
+                .timer(TokioTimer::new())
+                .header_read_timeout(Duration::from_secs(limits.header_timeout_secs));
+            builder
+                .http2()
+                .timer(TokioTimer::new())
+                .max_header_list_size(limits.max_header_bytes as u32);
+        }
+
+        if let Err(e) = builder.serve_connection(TokioIo::new(io), service).await {
+            debug!("connection from {} closed: {}", addr, e);
+        }
+    }
+}
+
+// Answers every request on the connection with a 503 and closes it
+async fn reject<I>(io: I)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(|_: Request<Incoming>| async {
+        let mut res: Response<Body> = build_err_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many connections".to_string(),
+        );
+        res.headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+        Ok::<_, Infallible>(res)
+    });
+
+    let _ = auto::Builder::new(TokioExecutor::new())
+        .serve_connection(TokioIo::new(io), service)
+        .await;
+}
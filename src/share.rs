@@ -0,0 +1,121 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::state::AppConfig;
+
+// Shares are kept next to the metadata of the default tenant, so a token can
+// be resolved before its tenant is known
+const SHARES_DIR: &str = ".shares";
+const TOKEN_LEN: usize = 64;
+
+pub const DEFAULT_TTL_SECS: u64 = 24 * 60 * 60;
+pub const MAX_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+// Read access to one image of a tenant until `expires`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Share {
+    pub token: String,
+    pub tenant_id: String,
+    pub img_id: String,
+    // also grants the outputs named "{img_id}_..." (suffixed output naming)
+    pub include_variants: bool,
+    // seconds since the unix epoch
+    pub expires: u64,
+}
+
+impl Share {
+    pub fn grants(&self, img_id: &str) -> bool {
+        img_id == self.img_id
+            || (self.include_variants
+                && img_id
+                    .strip_prefix(self.img_id.as_str())
+                    .is_some_and(|rest| rest.starts_with('_')))
+    }
+
+    fn is_expired(&self) -> bool {
+        unix_now() >= self.expires
+    }
+}
+
+pub async fn create(
+    conf: &AppConfig,
+    tenant_id: &str,
+    img_id: &str,
+    ttl_secs: u64,
+    include_variants: bool,
+) -> Result<Share> {
+    if ttl_secs == 0 || ttl_secs > MAX_TTL_SECS {
+        return Err(anyhow!("ttl_secs must be between 1 and {}", MAX_TTL_SECS));
+    }
+
+    let share = Share {
+        // 244 random bits
+        token: format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+        tenant_id: tenant_id.to_string(),
+        img_id: img_id.to_string(),
+        include_variants,
+        expires: unix_now() + ttl_secs,
+    };
+
+    let dir = shares_dir(conf);
+    tokio::fs::create_dir_all(&dir).await?;
+    tokio::fs::write(dir.join(&share.token), serde_json::to_vec(&share)?).await?;
+    Ok(share)
+}
+
+// The share of `token`, None when it is unknown, revoked or expired
+pub async fn find(conf: &AppConfig, token: &str) -> Option<Share> {
+    if !is_valid_token(token) {
+        return None;
+    }
+
+    let path = shares_dir(conf).join(token);
+    let data = tokio::fs::read(&path).await.ok()?;
+    let share: Share = match serde_json::from_slice(&data) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("invalid share {:?}: {}", path, e);
+            return None;
+        }
+    };
+
+    if share.is_expired() {
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            warn!("failed to remove expired share {:?}: {}", path, e);
+        }
+        return None;
+    }
+    Some(share)
+}
+
+// Revokes a share of the tenant, false when it has no such share
+pub async fn revoke(conf: &AppConfig, tenant_id: &str, token: &str) -> Result<bool> {
+    match find(conf, token).await {
+        Some(share) if share.tenant_id == tenant_id => {
+            tokio::fs::remove_file(shares_dir(conf).join(token)).await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn shares_dir(conf: &AppConfig) -> PathBuf {
+    Path::new(&conf.meta_path).join(SHARES_DIR)
+}
+
+fn is_valid_token(token: &str) -> bool {
+    token.len() == TOKEN_LEN && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
@@ -0,0 +1,130 @@
+use axum::{extract::Request, http::Method};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{public_id, state::AppConfig};
+
+// Signed URLs let GET /api/images/{img_id} be read without an API key until
+// they expire, e.g. to embed private images in web pages. The signature is an
+// HMAC of the tenant, image id and expiry, nothing is stored. With
+// [signed_urls] configured reads without an API key need one.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignedUrlConfig {
+    // changing it invalidates every signed URL handed out
+    pub secret: String,
+    #[serde(default = "default_ttl_secs")]
+    pub default_ttl_secs: u64,
+    #[serde(default = "default_max_ttl_secs")]
+    pub max_ttl_secs: u64,
+}
+
+fn default_ttl_secs() -> u64 {
+    60 * 60
+}
+
+fn default_max_ttl_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+// Query parameters of a signed URL, delivery parameters may be added to it
+#[derive(Debug, Default, Deserialize)]
+pub struct SignedQuery {
+    // seconds since the unix epoch
+    pub expires: Option<u64>,
+    // absent for the default tenant
+    pub tenant: Option<String>,
+    // hex
+    pub signature: Option<String>,
+}
+
+// Signed URL of the image valid for `ttl_secs`, and when it expires
+pub fn url(
+    conf: &AppConfig,
+    signed: &SignedUrlConfig,
+    base_url: &str,
+    tenant_id: &str,
+    img_id: &str,
+    ttl_secs: u64,
+) -> (String, u64) {
+    let expires = unix_now() + ttl_secs;
+    let signature = hex(&mac(signed, tenant_id, img_id, expires)
+        .finalize()
+        .into_bytes());
+    let tenant = if tenant_id.is_empty() {
+        String::new()
+    } else {
+        format!("&tenant={}", tenant_id)
+    };
+    let url = format!(
+        "{}/api/images/{}?expires={}{}&signature={}",
+        base_url,
+        public_id::encode(conf, img_id),
+        expires,
+        tenant,
+        signature
+    );
+    (url, expires)
+}
+
+// The tenant id the signed URL was made for, or why it is not valid
+pub fn verify(
+    signed: &SignedUrlConfig,
+    img_id: &str,
+    query: &SignedQuery,
+) -> Result<String, &'static str> {
+    let (Some(expires), Some(signature)) = (query.expires, &query.signature) else {
+        return Err("Missing signature");
+    };
+    if unix_now() >= expires {
+        return Err("Signed URL expired");
+    }
+    let tenant_id = query.tenant.clone().unwrap_or_default();
+    let signature = hex_decode(signature).ok_or("Invalid signature")?;
+    mac(signed, &tenant_id, img_id, expires)
+        .verify_slice(&signature)
+        .map_err(|_| "Invalid signature")?;
+    Ok(tenant_id)
+}
+
+// The image id of a GET /api/images/{img_id}, the only route signed URLs are
+// valid for
+pub fn image_read(req: &Request) -> Option<&str> {
+    if req.method() != Method::GET {
+        return None;
+    }
+    req.uri()
+        .path()
+        .strip_prefix("/api/images/")
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+}
+
+fn mac(signed: &SignedUrlConfig, tenant_id: &str, img_id: &str, expires: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(signed.secret.as_bytes())
+        .expect("hmac accepts any key length");
+    // ids cannot contain newlines, the fields cannot run into each other
+    mac.update(format!("{}\n{}\n{}", tenant_id, img_id, expires).as_bytes());
+    mac
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
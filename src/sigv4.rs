@@ -0,0 +1,315 @@
+use anyhow::{Result, anyhow};
+use axum::http::{HeaderMap, Method, Uri};
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::{Digest, Sha256};
+use time::{OffsetDateTime, PrimitiveDateTime, format_description};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const MAX_CLOCK_SKEW_SECS: i64 = 15 * 60;
+const MAX_PRESIGN_EXPIRES_SECS: i64 = 7 * 24 * 60 * 60;
+
+pub struct Credentials<'a> {
+    pub access_key: &'a str,
+    pub secret_key: &'a str,
+    pub region: &'a str,
+}
+
+struct SignedRequest {
+    access_key: String,
+    date: String,
+    region: String,
+    service: String,
+    amz_date: String,
+    signed_headers: Vec<String>,
+    signature: String,
+    payload_hash: String,
+}
+
+// Verifies an AWS Signature Version 4 request, either from the Authorization
+// header or from the query string of a presigned URL
+pub fn verify(creds: &Credentials, method: &Method, uri: &Uri, headers: &HeaderMap) -> Result<()> {
+    let query = parse_query(uri.query().unwrap_or_default());
+    let presigned = query.iter().any(|(k, _)| k == "X-Amz-Signature");
+
+    let req = if presigned {
+        parse_presigned(&query)?
+    } else {
+        parse_authorization(headers)?
+    };
+
+    if req.access_key != creds.access_key {
+        return Err(anyhow!("unknown access key"));
+    }
+    if req.region != creds.region || req.service != "s3" {
+        return Err(anyhow!("credential scope mismatch"));
+    }
+    if !req.amz_date.starts_with(&req.date) {
+        return Err(anyhow!("request date does not match credential scope"));
+    }
+
+    let signed_at = parse_amz_date(&req.amz_date)?;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    if presigned {
+        let expires: i64 = query_value(&query, "X-Amz-Expires")
+            .ok_or_else(|| anyhow!("missing X-Amz-Expires"))?
+            .parse()?;
+        if !(1..=MAX_PRESIGN_EXPIRES_SECS).contains(&expires) {
+            return Err(anyhow!("invalid X-Amz-Expires"));
+        }
+        if now > signed_at + expires {
+            return Err(anyhow!("presigned url expired"));
+        }
+    } else if (now - signed_at).abs() > MAX_CLOCK_SKEW_SECS {
+        return Err(anyhow!("request time too skewed"));
+    }
+
+    let canonical_request = canonical_request(method, uri, headers, &query, &req, presigned)?;
+    let string_to_sign = format!(
+        "{}\n{}\n{}/{}/{}/aws4_request\n{}",
+        ALGORITHM,
+        req.amz_date,
+        req.date,
+        req.region,
+        req.service,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = signing_key(creds.secret_key, &req.date, &req.region, &req.service);
+    let expected = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    if !constant_time_eq(expected.as_bytes(), req.signature.as_bytes()) {
+        return Err(anyhow!("signature does not match"));
+    }
+
+    Ok(())
+}
+
+// Checks the chunk signatures of a STREAMING-AWS4-HMAC-SHA256-PAYLOAD body.
+// Each chunk is signed over the signature before it, starting from the seed
+// signature of the Authorization header, which verify has checked.
+pub struct ChunkVerifier {
+    key: Vec<u8>,
+    amz_date: String,
+    scope: String,
+    previous: String,
+}
+
+impl ChunkVerifier {
+    pub fn new(creds: &Credentials, headers: &HeaderMap) -> Result<Self> {
+        let req = parse_authorization(headers)?;
+        Ok(Self {
+            key: signing_key(creds.secret_key, &req.date, &req.region, &req.service),
+            scope: format!("{}/{}/{}/aws4_request", req.date, req.region, req.service),
+            amz_date: req.amz_date,
+            previous: req.signature,
+        })
+    }
+
+    // Chunks must be passed in order, the final empty one included
+    pub fn verify(&mut self, signature: &str, data: &[u8]) -> Result<()> {
+        let string_to_sign = format!(
+            "{}-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+            ALGORITHM,
+            self.amz_date,
+            self.scope,
+            self.previous,
+            sha256_hex(b""),
+            sha256_hex(data)
+        );
+        let expected = hex(&hmac_sha256(&self.key, string_to_sign.as_bytes()));
+        if !constant_time_eq(expected.as_bytes(), signature.as_bytes()) {
+            return Err(anyhow!("chunk signature does not match"));
+        }
+        self.previous = expected;
+        Ok(())
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn parse_authorization(headers: &HeaderMap) -> Result<SignedRequest> {
+    let auth = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| anyhow!("missing authorization"))?;
+
+    let params = auth
+        .strip_prefix(ALGORITHM)
+        .ok_or_else(|| anyhow!("unsupported authorization algorithm"))?;
+
+    let mut credential = None;
+    let mut signed_headers = None;
+    let mut signature = None;
+    for part in params.split(',') {
+        match part.trim().split_once('=') {
+            Some(("Credential", v)) => credential = Some(v),
+            Some(("SignedHeaders", v)) => signed_headers = Some(v),
+            Some(("Signature", v)) => signature = Some(v),
+            _ => {}
+        }
+    }
+
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+
+    build_signed_request(
+        credential.ok_or_else(|| anyhow!("missing Credential"))?,
+        signed_headers.ok_or_else(|| anyhow!("missing SignedHeaders"))?,
+        signature.ok_or_else(|| anyhow!("missing Signature"))?,
+        header("x-amz-date").ok_or_else(|| anyhow!("missing x-amz-date"))?,
+        header("x-amz-content-sha256").ok_or_else(|| anyhow!("missing x-amz-content-sha256"))?,
+    )
+}
+
+fn parse_presigned(query: &[(String, String)]) -> Result<SignedRequest> {
+    let value = |name: &str| query_value(query, name).ok_or_else(|| anyhow!("missing {}", name));
+
+    if value("X-Amz-Algorithm")? != ALGORITHM {
+        return Err(anyhow!("unsupported X-Amz-Algorithm"));
+    }
+
+    build_signed_request(
+        value("X-Amz-Credential")?,
+        value("X-Amz-SignedHeaders")?,
+        value("X-Amz-Signature")?,
+        value("X-Amz-Date")?.to_string(),
+        "UNSIGNED-PAYLOAD".to_string(),
+    )
+}
+
+fn build_signed_request(
+    credential: &str,
+    signed_headers: &str,
+    signature: &str,
+    amz_date: String,
+    payload_hash: String,
+) -> Result<SignedRequest> {
+    // Credential=AKID/20240101/us-east-1/s3/aws4_request
+    let scope: Vec<&str> = credential.split('/').collect();
+    if scope.len() != 5 || scope[4] != "aws4_request" {
+        return Err(anyhow!("malformed credential scope"));
+    }
+
+    Ok(SignedRequest {
+        access_key: scope[0].to_string(),
+        date: scope[1].to_string(),
+        region: scope[2].to_string(),
+        service: scope[3].to_string(),
+        amz_date,
+        signed_headers: signed_headers.split(';').map(|h| h.to_string()).collect(),
+        signature: signature.to_string(),
+        payload_hash,
+    })
+}
+
+fn canonical_request(
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    query: &[(String, String)],
+    req: &SignedRequest,
+    presigned: bool,
+) -> Result<String> {
+    let mut params: Vec<(String, String)> = query
+        .iter()
+        .filter(|(k, _)| !(presigned && k == "X-Amz-Signature"))
+        .map(|(k, v)| (uri_encode(k), uri_encode(v)))
+        .collect();
+    params.sort();
+    let canonical_query = params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut canonical_headers = String::new();
+    for name in &req.signed_headers {
+        let values: Vec<&str> = headers
+            .get_all(name.as_str())
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        if values.is_empty() {
+            return Err(anyhow!("signed header {} missing", name));
+        }
+
+        let value = values
+            .iter()
+            .map(|v| v.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>()
+            .join(",");
+        canonical_headers.push_str(&format!("{}:{}\n", name, value));
+    }
+
+    Ok(format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method.as_str(),
+        uri.path(),
+        canonical_query,
+        canonical_headers,
+        req.signed_headers.join(";"),
+        req.payload_hash
+    ))
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let k_date = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let k_region = hmac_sha256(&k_date, region.as_bytes());
+    let k_service = hmac_sha256(&k_region, service.as_bytes());
+    hmac_sha256(&k_service, b"aws4_request")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    form_urlencoded::parse(query.as_bytes())
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect()
+}
+
+fn query_value<'a>(query: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    query
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_str())
+}
+
+// Percent-encodes everything except the unreserved characters, as SigV4 requires
+pub fn uri_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+// Parses the basic ISO8601 form used by SigV4 (20240101T000000Z) into unix seconds
+fn parse_amz_date(value: &str) -> Result<i64> {
+    let format = format_description::parse("[year][month][day]T[hour][minute][second]Z")?;
+    let date = PrimitiveDateTime::parse(value, &format)
+        .map_err(|e| anyhow!("invalid amz date {}: {}", value, e))?;
+    Ok(date.assume_utc().unix_timestamp())
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
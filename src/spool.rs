@@ -0,0 +1,101 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::state::{AppConfig, SpoolConfig};
+
+// How often the spool directory is swept for stale files
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Temporary file for data that ends up at `dest` once complete: in the spool
+// directory when one is configured, otherwise next to `dest`. The leading dot
+// keeps it out of listings.
+pub fn staging_path(conf: &AppConfig, dest: &Path) -> PathBuf {
+    let name = format!(".{}.tmp", Uuid::new_v4());
+    match &conf.spool {
+        Some(spool) => Path::new(&spool.dir).join(name),
+        None => dest.with_file_name(name),
+    }
+}
+
+// Moves a completely written file to `dest`. Renames cannot cross file
+// systems, so a spool on another disk is copied next to `dest` first and then
+// renamed into place, readers never see a partial file either way.
+pub fn persist(staged: &Path, dest: &Path) -> io::Result<()> {
+    match fs::rename(staged, dest) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        res => return res,
+    }
+
+    let copy = dest.with_file_name(format!(".{}.tmp", Uuid::new_v4()));
+    let res = fs::copy(staged, &copy)
+        .and_then(|_| File::open(&copy)?.sync_all())
+        .and_then(|_| fs::rename(&copy, dest));
+    if let Err(e) = res {
+        let _ = fs::remove_file(&copy);
+        return Err(e);
+    }
+    fs::remove_file(staged)
+}
+
+// persist on the blocking thread pool, copies can be large
+pub async fn persist_blocking(staged: &Path, dest: &Path) -> io::Result<()> {
+    let (staged, dest) = (staged.to_path_buf(), dest.to_path_buf());
+    tokio::task::spawn_blocking(move || persist(&staged, &dest))
+        .await
+        .map_err(io::Error::other)?
+}
+
+// Removes files older than max_age_secs, which are leftovers of aborted
+// uploads or a crash. Every staged file is written and moved within a
+// request, so nothing that old is still in use.
+pub async fn sweep(spool: &SpoolConfig) -> io::Result<usize> {
+    let max_age = Duration::from_secs(spool.max_age_secs);
+    let mut removed = 0;
+    let mut entries = tokio::fs::read_dir(&spool.dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+
+        match tokio::fs::remove_file(entry.path()).await {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!(
+                    "failed to remove stale spool file {:?}: {}",
+                    entry.path(),
+                    e
+                )
+            }
+            _ => {}
+        }
+    }
+    Ok(removed)
+}
+
+pub async fn run(spool: SpoolConfig) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match sweep(&spool).await {
+            Ok(0) => {}
+            Ok(n) => info!("removed {} stale files from spool {}", n, spool.dir),
+            Err(e) => warn!("failed to sweep spool {}: {}", spool.dir, e),
+        }
+    }
+}
@@ -1,7 +1,31 @@
 use anyhow::{Result, anyhow};
 use bytes::BytesMut;
 use serde::Deserialize;
-use std::{fs::File, io::Read, ops::Deref, sync::Arc};
+use std::{
+    collections::BTreeMap, fs::File, io::Read, net::IpAddr, ops::Deref, path::Path, sync::Arc,
+};
+
+use crate::{
+    access::AccessTracker,
+    alpha::Color,
+    budget::MemoryBudget,
+    coalesce::Coalescer,
+    decode_cache,
+    disk::DiskStatus,
+    egress::EgressPolicy,
+    format::ImageFormat,
+    handlers::{Fit, Gravity, ImgMetadata, WatermarkRequest, s3::KeyIndex},
+    jobs::JobStore,
+    limits::ClientLimits,
+    notify::{AlertKind, Notifier},
+    public_id::PublicIdConfig,
+    report::ErrorReporter,
+    resize::Resizer,
+    signing::SignedUrlConfig,
+    sigv4,
+    tenant::QuotaUsage,
+    text::FontStack,
+};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
@@ -11,6 +35,25 @@ pub struct AppState {
 #[derive(Debug, Clone)]
 pub struct AppStateInner {
     pub conf: AppConfig,
+    pub jobs: JobStore,
+    pub access: AccessTracker,
+    pub reporter: Option<ErrorReporter>,
+    pub notifier: Option<Notifier>,
+    // client for URLs supplied by clients
+    pub egress: EgressPolicy,
+    pub budget: MemoryBudget,
+    // from [text], the built-in Roboto without it
+    pub fonts: Arc<FontStack>,
+    // rate limits of limits.per_key and limits.per_ip
+    pub clients: ClientLimits,
+    // variants being rendered, see coalesce::Coalescer
+    pub coalescer: Coalescer,
+    // free space and read-only mode, see disk::run
+    pub disk: DiskStatus,
+    // image ids of the keys of the S3 facade
+    pub s3_keys: KeyIndex,
+    // stored bytes of the namespaces with a quota
+    pub quota_usage: QuotaUsage,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -18,26 +61,1180 @@ pub struct AppConfig {
     pub max_file_size: u64,
     pub file_path: String,
     pub meta_path: String,
+    // externally visible base url, e.g. "https://images.example.com"
+    #[serde(default)]
+    pub public_url: Option<String>,
+    // version of newly generated image ids, ids of either version are readable
+    #[serde(default)]
+    pub id_version: IdVersion,
+    #[serde(default)]
+    pub resizer: Resizer,
+    // names of transform outputs, overridable per request with `?naming=`
+    #[serde(default)]
+    pub output_naming: OutputNaming,
+    // standards uploads into a collection are brought to, by collection
+    // name, see handlers::policy
+    #[serde(default)]
+    pub collection_policies: BTreeMap<String, UploadPolicy>,
+    // behind transparent images written in a format without alpha (JPEG)
+    #[serde(default)]
+    pub background: Color,
+    // largest region /inpaint fills, in pixels, its time grows faster than
+    // the area
+    #[serde(default = "default_max_inpaint_pixels")]
+    pub max_inpaint_pixels: u64,
+    #[serde(default)]
+    pub watch: Option<WatchConfig>,
+    #[serde(default)]
+    pub email: Option<EmailConfig>,
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+    // API keys of the REST API, each owning an isolated storage namespace
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+    // key of the /api/admin routes, which are refused without it
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    // caching headers of image responses, none are sent when unset
+    #[serde(default)]
+    pub cache_control: Option<CacheControlConfig>,
+    #[serde(default)]
+    pub logging: Option<LoggingConfig>,
+    #[serde(default)]
+    pub error_reporting: Option<ErrorReportingConfig>,
+    // operational alerts to Slack, mail or a webhook, see notify
+    #[serde(default)]
+    pub notifications: Option<NotificationsConfig>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub limits: Option<LimitsConfig>,
+    #[serde(default)]
+    pub raster_cache: Option<RasterCacheConfig>,
+    // versions kept by re-encodes stored as deltas, see storage::replace_image
+    #[serde(default)]
+    pub version_deltas: Option<VersionDeltasConfig>,
+    // temporary files of uploads and transforms, file_path when unset
+    #[serde(default)]
+    pub spool: Option<SpoolConfig>,
+    // read-only mode when the storage volume runs out of space, see disk
+    #[serde(default)]
+    pub disk_space: Option<DiskSpaceConfig>,
+    #[serde(default)]
+    pub memory_budget: Option<MemoryBudgetConfig>,
+    // decoded originals kept in memory for repeated transforms
+    #[serde(default)]
+    pub decode_cache: Option<DecodeCacheConfig>,
+    #[serde(default)]
+    pub decode: DecodeConfig,
+    #[serde(default)]
+    pub jobs: JobsConfig,
+    // applies to every URL fetched on behalf of a client
+    #[serde(default)]
+    pub egress: EgressConfig,
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    #[serde(default)]
+    pub review: Option<ReviewConfig>,
+    // AES-GCM encryption of stored images and metadata, see crypt
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+    #[serde(default)]
+    pub text: Option<TextConfig>,
+    // `?debug=timings` adds the duration of every pipeline stage to the
+    // response, for development deployments
+    #[serde(default)]
+    pub debug_timings: bool,
+    // where image metadata is kept, see store::open
+    #[serde(default)]
+    pub metadata_store: MetadataBackend,
+    #[serde(default)]
+    pub formats: FormatsConfig,
+    #[serde(default)]
+    pub public_reads: Option<PublicReadsConfig>,
+    // obfuscated image ids on share links, feeds and WebDAV, see public_id
+    #[serde(default)]
+    pub public_ids: Option<PublicIdConfig>,
+    // time-limited image URLs that need no API key, see signing
+    #[serde(default)]
+    pub signed_urls: Option<SignedUrlConfig>,
+    // removes EXIF (with GPS positions), XMP and comments from every stored
+    // upload, see strip::strip. Uploads can ask for it with
+    // `?strip_metadata=true`.
+    #[serde(default)]
+    pub strip_metadata: bool,
+    // stores the file of an upload once per content, later uploads of the
+    // same bytes get their own id sharing it, see blobs
+    #[serde(default)]
+    pub dedup_uploads: bool,
+    // named sets of sizes made from one image in a single call, see
+    // handlers::export
+    #[serde(default)]
+    pub export_profiles: BTreeMap<String, ExportProfile>,
+    // named thumbnails, `?preset=card` on the thumbnail route
+    #[serde(default)]
+    pub thumbnail_presets: BTreeMap<String, ThumbnailPreset>,
+    // compositions per breakpoint, see handlers::variants
+    #[serde(default)]
+    pub art_direction: BTreeMap<String, ArtDirectionSet>,
+    // CMYK exports for print, see handlers::export::export_print
+    #[serde(default)]
+    pub print: Option<PrintConfig>,
+    // descriptive tags for uploads from a vision model, see tagging
+    #[serde(default)]
+    pub tagging: Option<TaggingConfig>,
+    // image and text embeddings for similarity search, see embedding
+    #[serde(default)]
+    pub embeddings: Option<EmbeddingConfig>,
+}
+
+// The ONNX encoders of a CLIP-style model, needs the embeddings feature
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmbeddingConfig {
+    pub image_model: String,
+    pub text_model: String,
+    // tokenizer.json of the text model
+    pub tokenizer_path: String,
+    // length of the embeddings both encoders return
+    #[serde(default = "default_embedding_dimensions")]
+    pub dimensions: usize,
+    // edge in pixels of the image the image encoder sees
+    #[serde(default = "default_tagging_input_size")]
+    pub input_size: u32,
+}
+
+// Either an HTTP vision API (url) or a local ONNX model (model_path)
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaggingConfig {
+    #[serde(default)]
+    pub url: Option<String>,
+    // sent as a bearer token to url
+    #[serde(default)]
+    pub api_key: Option<String>,
+    // image classification model, needs the onnx feature
+    #[serde(default)]
+    pub model_path: Option<String>,
+    // names of the model's outputs, one per line in output order
+    #[serde(default)]
+    pub labels_path: Option<String>,
+    // labels below it are not attached
+    #[serde(default = "default_tagging_min_confidence")]
+    pub min_confidence: f32,
+    #[serde(default = "default_tagging_max_tags")]
+    pub max_tags: usize,
+    // edge in pixels of the image the model sees
+    #[serde(default = "default_tagging_input_size")]
+    pub input_size: u32,
+    #[serde(default = "default_tagging_timeout")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrintConfig {
+    // CMYK ICC profile of the press, e.g. ISO Coated v2
+    pub icc_profile: String,
+    #[serde(default)]
+    pub intent: RenderingIntent,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderingIntent {
+    #[default]
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    AbsoluteColorimetric,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportProfile {
+    pub outputs: Vec<ExportOutput>,
+}
+
+// One file of a profile, scaled to cover width x height and cropped to it
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportOutput {
+    // path in the zip, its extension is the format, e.g.
+    // "mipmap-xhdpi/ic_launcher.png"
+    pub name: String,
+    pub width: u32,
+    // square when absent
+    #[serde(default)]
+    pub height: Option<u32>,
+}
+
+impl ExportOutput {
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height.unwrap_or(self.width))
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        match self.name.rsplit_once('.') {
+            Some((_, ext)) => ImageFormat::from_name(ext),
+            None => ImageFormat::Unknown,
+        }
+    }
+}
+
+// Size, fit and gravity of a named thumbnail. Its cached files are named after
+// the definition, so a changed preset is never served at the old size;
+// POST /api/admin/presets/{name}/regenerate removes the old files.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThumbnailPreset {
+    pub size: u32,
+    #[serde(default)]
+    pub fit: Fit,
+    #[serde(default)]
+    pub gravity: Gravity,
+}
+
+impl ThumbnailPreset {
+    // Start of the cache names of every version of the preset. Preset names
+    // cannot contain dots, one cannot be the prefix of another.
+    pub fn cache_prefix(name: &str) -> String {
+        format!("preset.{}.", name)
+    }
+
+    // Cache name of the current version, e.g. "preset.card.1a2b3c4d"
+    pub fn cache_name(&self, name: &str) -> String {
+        let definition = format!(
+            "{}:{}:{}",
+            self.size,
+            self.fit.as_str(),
+            self.gravity.as_str()
+        );
+        format!(
+            "{}{}",
+            Self::cache_prefix(name),
+            &sigv4::sha256_hex(definition.as_bytes())[..8]
+        )
+    }
+}
+
+// Differently cropped compositions of an image for responsive layouts, e.g.
+// a tight crop of the product on phones and the whole scene on desktops
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArtDirectionSet {
+    pub breakpoints: Vec<Breakpoint>,
+}
+
+// One composition of a set, see art_direction::compose
+#[derive(Debug, Clone, Deserialize)]
+pub struct Breakpoint {
+    // e.g. "mobile"
+    pub name: String,
+    // smallest viewport width in CSS pixels the composition is meant for
+    #[serde(default)]
+    pub min_viewport: u32,
+    pub width: u32,
+    // the aspect ratio of the composition when absent
+    #[serde(default)]
+    pub height: Option<u32>,
+    // named region of the image the composition is cropped to, e.g.
+    // "product". Images without it are cropped around their focal point.
+    #[serde(default)]
+    pub region: Option<String>,
+    // room around the region, in percent of its size on each side
+    #[serde(default)]
+    pub padding_pct: u32,
+}
+
+// Images read without an API key (share links, WebDAV) are served scaled down
+// with a watermark, authenticated reads get the originals
+#[derive(Debug, Clone, Deserialize)]
+pub struct PublicReadsConfig {
+    // as in a watermark request
+    pub watermark: WatermarkRequest,
+    // longest edge in pixels
+    #[serde(default = "default_public_max_edge")]
+    pub max_edge: u32,
+    // the images it applies to, every image when both are empty
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub collections: Vec<String>,
+}
+
+impl PublicReadsConfig {
+    // Transform outputs have no metadata and are always protected, they may
+    // be made from a protected image
+    pub fn protects(&self, meta: Option<&ImgMetadata>) -> bool {
+        if self.tags.is_empty() && self.collections.is_empty() {
+            return true;
+        }
+        let Some(meta) = meta else {
+            return true;
+        };
+        meta.tags.iter().any(|t| self.tags.contains(t))
+            || meta
+                .collection
+                .as_ref()
+                .is_some_and(|c| self.collections.contains(c))
+    }
+}
+
+// Formats a deployment works with, every known one when a list is absent.
+// Names as in requests, e.g. "jpeg" or "webp".
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FormatsConfig {
+    // stored by uploads, the S3 facade, ingestion and the proxy
+    #[serde(default)]
+    pub input: Option<Vec<String>>,
+    // clients can convert to, see format::output_format
+    #[serde(default)]
+    pub output: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataBackend {
+    // a JSON file per image in meta_path
+    #[default]
+    File,
+    // a database in meta_path, listings filter in SQL
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdVersion {
+    V4,
+    // time ordered, so ids sort by creation time
+    #[default]
+    V7,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputNaming {
+    // a new image id
+    #[default]
+    Uuid,
+    // hash of the encoded output, identical outputs share one file
+    ContentHash,
+    // source id and operation, e.g. "{img_id}_resize"
+    Suffixed,
+    // a new image id stored in a YYYY/MM/DD folder
+    Dated,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatchConfig {
+    pub dir: String,
+    #[serde(default = "default_watch_interval")]
+    pub interval_secs: u64,
+    // files modified more recently than this are assumed to still be copying
+    #[serde(default = "default_settle_secs")]
+    pub settle_secs: u64,
+    #[serde(default)]
+    pub after_ingest: AfterIngest,
+    pub processed_dir: Option<String>,
+    // recipe of the default tenant every ingested file is run through
+    #[serde(default)]
+    pub recipe: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AfterIngest {
+    #[default]
+    Move,
+    Delete,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct EmailConfig {
+    // address the built-in SMTP listener binds to, e.g. "0.0.0.0:2525"
+    pub listen: String,
+    // only mail addressed to this recipient is accepted
+    pub mailbox: String,
+    // envelope senders mail is accepted from, addresses or "@domain" for a
+    // whole domain
+    #[serde(default)]
+    pub allowed_senders: Vec<String>,
+    // when set, mail is only accepted for the mailbox with "+secret" added to
+    // its local part, e.g. photos+secret@example.com
+    pub secret: Option<String>,
+    // message size in MegaBytes
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: u64,
+    // base url used for the links in reply emails
+    pub public_url: Option<String>,
+    // "host:port" of the relay used to send replies to allowed_senders,
+    // replies are skipped if unset
+    pub reply_relay: Option<String>,
+    pub reply_from: Option<String>,
+    // recipe of the default tenant every attachment is run through
+    #[serde(default)]
+    pub recipe: Option<String>,
+}
+
+// Keep the secret address out of the startup log
+impl std::fmt::Debug for EmailConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmailConfig")
+            .field("listen", &self.listen)
+            .field("mailbox", &self.mailbox)
+            .field("allowed_senders", &self.allowed_senders)
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .field("max_message_size", &self.max_message_size)
+            .field("public_url", &self.public_url)
+            .field("reply_relay", &self.reply_relay)
+            .field("reply_from", &self.reply_from)
+            .field("recipe", &self.recipe)
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct S3Config {
+    // the single bucket exposed by the S3 facade, served at /{bucket}
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+// Keep the secret key out of the startup log
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .field("secret_key", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TenantConfig {
+    // used as a directory name, so limited to ASCII letters, digits, '-' and '_'
+    pub id: String,
+    pub api_key: String,
+    // storage quota in MegaBytes
+    pub quota_mb: Option<u64>,
+    // applied to uploads with the key that no collection policy covers
+    #[serde(default)]
+    pub upload_policy: Option<UploadPolicy>,
+    // tenants this one may link its images into, see storage::link_image
+    #[serde(default)]
+    pub link_to: Vec<String>,
+}
+
+impl std::fmt::Debug for TenantConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TenantConfig")
+            .field("id", &self.id)
+            .field("api_key", &"<redacted>")
+            .field("quota_mb", &self.quota_mb)
+            .field("upload_policy", &self.upload_policy)
+            .field("link_to", &self.link_to)
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct AdminConfig {
+    // sent as X-Admin-Key, besides the API key of the tenant acted on
+    pub api_key: String,
+}
+
+impl std::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("api_key", &"<redacted>")
+            .finish()
+    }
+}
+
+// Publishing standards enforced on uploads instead of trusting clients to
+// follow them, see handlers::policy. They apply to /api/images/upload only,
+// not to S3 PUTs or watch and email ingest.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UploadPolicy {
+    // recipe of the tenant every upload is run through
+    #[serde(default)]
+    pub recipe: Option<String>,
+    // remove EXIF, XMP and comments, as with `?strip_metadata=true`
+    #[serde(default)]
+    pub strip_metadata: bool,
+    // longest edge in pixels, larger uploads are scaled down
+    #[serde(default)]
+    pub max_dimension: Option<u32>,
+    // e.g. "webp", the format uploads are stored in
+    #[serde(default)]
+    pub convert_to: Option<String>,
+}
+
+impl UploadPolicy {
+    // Whether uploads have to be decoded and encoded again
+    pub fn transforms(&self) -> bool {
+        self.recipe.is_some() || self.max_dimension.is_some() || self.convert_to.is_some()
+    }
+}
+
+// HTTPS listener next to the plain one on port 8080
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    // "ip:port" of the listener, it serves HTTP/2 and HTTP/1.1
+    pub listen: String,
+    // PEM encoded certificate chain and private key
+    pub cert_path: String,
+    pub key_path: String,
+    // "ip:port" of the experimental QUIC listener, needs the http3 feature
+    pub http3_listen: Option<String>,
+}
+
+// Protection against clients that hold or flood connections
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitsConfig {
+    // open connections across all listeners, further ones get a 503
+    pub max_connections: Option<usize>,
+    // sustained requests per second on one connection, above it requests get a 429
+    pub requests_per_sec: Option<u32>,
+    // total size of the request headers, above it requests get a 431
+    #[serde(default = "default_max_header_bytes")]
+    pub max_header_bytes: usize,
+    // connections that take longer to send the request headers are closed
+    #[serde(default = "default_header_timeout_secs")]
+    pub header_timeout_secs: u64,
+    // request body limit in MegaBytes by route, e.g. "/api/images/upload" = 20.
+    // Other routes are limited to max_file_size, larger bodies get a 413.
+    #[serde(default)]
+    pub body_mb: BTreeMap<String, u64>,
+    // requests of one tenant's API key across connections, see
+    // limits::limit_clients
+    #[serde(default)]
+    pub per_key: Option<ClientRateConfig>,
+    // requests of one IP address, for requests without a known API key
+    #[serde(default)]
+    pub per_ip: Option<ClientRateConfig>,
+    // addresses of the reverse proxies in front of the server. Requests from
+    // them are counted against the last X-Forwarded-For entry that is not
+    // one of them, other requests against the peer.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+// Token bucket of a client, above it requests get a 429 with Retry-After
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientRateConfig {
+    // sustained requests per second
+    pub requests_per_sec: u32,
+    // requests that may arrive at once, requests_per_sec when unset
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+// Limit for the estimated memory of images being decoded and transformed
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemoryBudgetConfig {
+    pub max_mb: u64,
+    // operations waiting for memory, further ones get a 429
+    #[serde(default = "default_budget_max_queued")]
+    pub max_queued: usize,
+    // waiting longer than this gives a 503
+    #[serde(default = "default_budget_queue_timeout")]
+    pub queue_timeout_secs: u64,
+}
+
+// Decoded sources kept for their next transform, see decode_cache. Counts
+// against the memory_budget.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DecodeCacheConfig {
+    pub max_mb: u64,
+    // entries not used for this long are dropped
+    #[serde(default = "default_decode_cache_ttl")]
+    pub ttl_secs: u64,
+}
+
+// Editorial review of uploads, see review::ReviewState
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReviewConfig {
+    // deliver images that are not approved (yet)
+    #[serde(default)]
+    pub serve_unapproved: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EncryptionConfig {
+    // the first key encrypts new files, the others are still used to decrypt
+    // files written before a rotation
+    pub keys: Vec<EncryptionKey>,
+}
+
+// A 256 bit key, hex encoded, either inline or from an environment variable
+// (e.g. filled in by a KMS or secrets manager)
+#[derive(Clone, Deserialize)]
+pub struct EncryptionKey {
+    // stored in the header of every file the key encrypts
+    pub id: String,
+    pub key: Option<String>,
+    pub key_env: Option<String>,
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .field("key_env", &self.key_env)
+            .finish()
+    }
+}
+
+// Fonts for watermark text, see text::FontStack
+#[derive(Debug, Clone, Deserialize)]
+pub struct TextConfig {
+    // paths of TrueType/OpenType fonts, in fallback order
+    pub fonts: Vec<String>,
+}
+
+// Uncompressed copies of large originals that crops read regions from
+#[derive(Debug, Clone, Deserialize)]
+pub struct RasterCacheConfig {
+    pub dir: String,
+    // smaller images are decoded directly
+    #[serde(default = "default_raster_min_megapixels")]
+    pub min_megapixels: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VersionDeltasConfig {
+    // every this many versions one is stored whole, the others as deltas
+    // against the version before them
+    #[serde(default = "default_snapshot_every")]
+    pub snapshot_every: u32,
+}
+
+// Local directory for files that are still being written, moved into
+// file_path once complete
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpoolConfig {
+    pub dir: String,
+    // older files are leftovers of aborted uploads or crashes and removed
+    #[serde(default = "default_spool_max_age")]
+    pub max_age_secs: u64,
+}
+
+// Free space kept on the volumes of file_path and meta_path. Below
+// min_free_mb the server stops storing anything until space is freed.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiskSpaceConfig {
+    pub min_free_mb: u64,
+    #[serde(default = "default_disk_check_interval")]
+    pub check_interval_secs: u64,
+    // remove cached thumbnails and raster_cache files on entering read-only
+    // mode, they are made again on demand
+    #[serde(default)]
+    pub evict_caches: bool,
+}
+
+// Limits of image decoding, see decode::run
+#[derive(Debug, Clone, Deserialize)]
+pub struct DecodeConfig {
+    #[serde(default = "default_decode_timeout")]
+    pub timeout_secs: u64,
+    // decode in child processes, for untrusted uploads
+    #[serde(default)]
+    pub isolate: bool,
+    // child processes decoding at the same time
+    #[serde(default = "default_decode_workers")]
+    pub workers: usize,
+    // apply the EXIF orientation, transforms work on the displayed image
+    #[serde(default = "default_auto_orient")]
+    pub auto_orient: bool,
+}
+
+impl Default for DecodeConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_decode_timeout(),
+            isolate: false,
+            workers: default_decode_workers(),
+            auto_orient: default_auto_orient(),
+        }
+    }
+}
+
+// Worker pool of background jobs, see jobs::queue
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobsConfig {
+    // jobs running at the same time
+    #[serde(default = "default_job_workers")]
+    pub workers: usize,
+    // jobs waiting for a worker, further submissions get a 429
+    #[serde(default = "default_jobs_max_queued")]
+    pub max_queued: usize,
+    // transforms of larger images run as jobs even without
+    // `Prefer: respond-async`, instead of holding the request
+    #[serde(default)]
+    pub async_above_megapixels: Option<f64>,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            workers: default_job_workers(),
+            max_queued: default_jobs_max_queued(),
+            async_above_megapixels: None,
+        }
+    }
+}
+
+// GET /api/proxy, serving (resized) images of remote hosts
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProxyConfig {
+    // hosts images may be proxied from, "*.example.com" also matches subdomains
+    pub allowed_hosts: Vec<String>,
+    pub cache_dir: String,
+    // how long a fetched image is served from the cache
+    #[serde(default = "default_proxy_max_age")]
+    pub max_age_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EgressConfig {
+    #[serde(default = "default_egress_schemes")]
+    pub allowed_schemes: Vec<String>,
+    #[serde(default = "default_egress_ports")]
+    pub allowed_ports: Vec<u16>,
+    // also connect to loopback, private and link-local addresses
+    #[serde(default)]
+    pub allow_private: bool,
+    #[serde(default = "default_egress_max_redirects")]
+    pub max_redirects: usize,
+    // response size in MegaBytes
+    #[serde(default = "default_egress_max_response")]
+    pub max_response_mb: u64,
+    #[serde(default = "default_egress_timeout")]
+    pub timeout_secs: u64,
 }
 
+impl Default for EgressConfig {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: default_egress_schemes(),
+            allowed_ports: default_egress_ports(),
+            allow_private: false,
+            max_redirects: default_egress_max_redirects(),
+            max_response_mb: default_egress_max_response(),
+            timeout_secs: default_egress_timeout(),
+        }
+    }
+}
+
+// Compression of JSON responses, image bodies are always sent as stored
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default = "default_true")]
+    pub gzip: bool,
+    #[serde(default = "default_true")]
+    pub br: bool,
+    // responses smaller than this many bytes are sent uncompressed
+    #[serde(default = "default_compression_min_size")]
+    pub min_size: u64,
+}
+
+// Cache-Control of images served as stored and of the copies made from them
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheControlConfig {
+    // GET /api/images/{img_id} without transform parameters, share links and
+    // WebDAV
+    #[serde(default)]
+    pub originals: Option<CachePolicy>,
+    // thumbnails, variants made on request (?w=, ?fmt=, ...) and the copies
+    // public_reads serves
+    #[serde(default)]
+    pub derived: Option<CachePolicy>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CachePolicy {
+    // the Cache-Control value, e.g. "public, max-age=86400"
+    pub value: String,
+    // also send an Expires header this many seconds ahead, for caches that
+    // predate Cache-Control
+    #[serde(default)]
+    pub expires_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    // default level, e.g. "info"
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    // per-module overrides, e.g. { "brushbloom::ingest" = "debug" }
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+    // logs go to stdout when unset
+    pub file: Option<LogFileConfig>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            level: default_log_level(),
+            modules: BTreeMap::new(),
+            file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogFileConfig {
+    pub dir: String,
+    // name of the active log file, rotated files get a suffix appended
+    #[serde(default = "default_log_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    // size limit in MegaBytes for "size" rotation
+    #[serde(default = "default_log_max_size")]
+    pub max_size_mb: u64,
+    // rotated files to keep, all are kept when unset
+    pub max_files: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    #[default]
+    Daily,
+    Size,
+    Never,
+}
+
+// Where panics and 5xx responses are reported, either or both may be set
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErrorReportingConfig {
+    pub sentry_dsn: Option<String>,
+    // receives each event as a JSON POST
+    pub webhook_url: Option<String>,
+    // e.g. "production", attached to every event
+    pub environment: Option<String>,
+}
+
+// Where operational alerts go. Each channel gets the kinds in its `events`,
+// all of them when it has none.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotificationsConfig {
+    pub channels: Vec<NotificationChannel>,
+    // the same alert (kind and tenant) is not sent again for this long
+    #[serde(default = "default_repeat_after")]
+    pub repeat_after_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotificationChannel {
+    #[serde(flatten)]
+    pub target: NotificationTarget,
+    #[serde(default)]
+    pub events: Vec<AlertKind>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NotificationTarget {
+    // an incoming webhook of a Slack app
+    Slack {
+        webhook_url: String,
+    },
+    // "host:port" of the relay, like email.reply_relay
+    Email {
+        relay: String,
+        from: String,
+        to: Vec<String>,
+    },
+    // receives each alert as a JSON POST
+    Webhook {
+        url: String,
+    },
+}
+
+fn default_watch_interval() -> u64 {
+    5
+}
+
+fn default_settle_secs() -> u64 {
+    2
+}
+
+fn default_max_inpaint_pixels() -> u64 {
+    250_000
+}
+
+fn default_max_message_size() -> u64 {
+    25
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_log_prefix() -> String {
+    "brushbloom.log".to_string()
+}
+
+fn default_log_max_size() -> u64 {
+    100
+}
+
+fn default_max_header_bytes() -> usize {
+    16 * 1024
+}
+
+fn default_header_timeout_secs() -> u64 {
+    10
+}
+
+fn default_budget_max_queued() -> usize {
+    16
+}
+
+fn default_budget_queue_timeout() -> u64 {
+    30
+}
+
+fn default_decode_cache_ttl() -> u64 {
+    60
+}
+
+fn default_raster_min_megapixels() -> u64 {
+    40
+}
+
+fn default_public_max_edge() -> u32 {
+    1024
+}
+
+fn default_spool_max_age() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_disk_check_interval() -> u64 {
+    30
+}
+
+fn default_repeat_after() -> u64 {
+    60 * 60
+}
+
+fn default_decode_timeout() -> u64 {
+    30
+}
+
+fn default_tagging_min_confidence() -> f32 {
+    0.5
+}
+
+fn default_tagging_max_tags() -> usize {
+    5
+}
+
+fn default_tagging_input_size() -> u32 {
+    224
+}
+
+fn default_tagging_timeout() -> u64 {
+    10
+}
+
+fn default_embedding_dimensions() -> usize {
+    512
+}
+
+fn default_decode_workers() -> usize {
+    4
+}
+
+fn default_job_workers() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+fn default_jobs_max_queued() -> usize {
+    100
+}
+
+fn default_auto_orient() -> bool {
+    true
+}
+
+fn default_proxy_max_age() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_egress_schemes() -> Vec<String> {
+    vec!["https".to_string(), "http".to_string()]
+}
+
+fn default_egress_ports() -> Vec<u16> {
+    vec![80, 443]
+}
+
+fn default_egress_max_redirects() -> usize {
+    3
+}
+
+fn default_egress_max_response() -> u64 {
+    20
+}
+
+fn default_egress_timeout() -> u64 {
+    10
+}
+
+fn default_snapshot_every() -> u32 {
+    10
+}
+
+fn default_compression_min_size() -> u64 {
+    1024
+}
+
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+// Names the config file, like --config
+pub const CONFIG_ENV: &str = "BRUSHBLOOM_CONFIG";
+// Environment variables starting with it override config keys, sections are
+// separated by a double underscore: BRUSHBLOOM_LIMITS__BODY_MB for body_mb of
+// [limits]
+const ENV_PREFIX: &str = "BRUSHBLOOM_";
+
 impl AppConfig {
-    pub fn new(path: &str) -> Result<Self> {
-        let mut file = File::open(path)?;
-        let mut buf = BytesMut::with_capacity(4096).to_vec();
-        let _ = file.read_to_end(&mut buf)?;
+    // The config file, overridden by BRUSHBLOOM_* environment variables, which
+    // are overridden by `overrides` ("limits.body_mb=50" of --set). Without
+    // `path` config.toml is read if it exists, a container may configure
+    // everything through the environment.
+    pub fn new(path: Option<&str>, overrides: &[String]) -> Result<Self> {
+        let mut table = match path {
+            Some(path) => read_table(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => read_table(DEFAULT_CONFIG_PATH)?,
+            None => toml::Table::new(),
+        };
 
-        match toml::from_slice(&buf) {
-            Ok(v) => Ok(v),
-            Err(e) => return Err(anyhow!("{}", e)),
+        let mut vars: Vec<(String, String)> = std::env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != CONFIG_ENV)
+            .collect();
+        // sections before their keys, e.g. BRUSHBLOOM_LIMITS before
+        // BRUSHBLOOM_LIMITS__BODY_MB
+        vars.sort();
+        for (name, value) in vars {
+            let key: Vec<String> = name[ENV_PREFIX.len()..]
+                .split("__")
+                .map(|part| part.to_lowercase())
+                .collect();
+            set_key(&mut table, &key, &value).map_err(|e| anyhow!("{}: {}", name, e))?;
         }
+
+        for arg in overrides {
+            let (key, value) = arg
+                .split_once('=')
+                .ok_or_else(|| anyhow!("--set {}: expected key=value", arg))?;
+            let key: Vec<String> = key.trim().split('.').map(|part| part.to_string()).collect();
+            set_key(&mut table, &key, value).map_err(|e| anyhow!("--set {}: {}", arg, e))?;
+        }
+
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| anyhow!("{}", e))
     }
 }
 
+fn read_table(path: &str) -> Result<toml::Table> {
+    let mut file = File::open(path).map_err(|e| anyhow!("{}: {}", path, e))?;
+    let mut buf = BytesMut::with_capacity(4096).to_vec();
+    let _ = file.read_to_end(&mut buf)?;
+
+    toml::from_slice(&buf).map_err(|e| anyhow!("{}: {}", path, e))
+}
+
+// Sets `key` (the section names and the key) to `raw`, read as a TOML value,
+// e.g. 50, true or ["webp", "avif"], or as a string when it is not one or the
+// key holds a string
+fn set_key(table: &mut toml::Table, key: &[String], raw: &str) -> Result<()> {
+    let Some((last, sections)) = key.split_last() else {
+        return Err(anyhow!("empty key"));
+    };
+    let mut table = table;
+    for section in sections {
+        table = table
+            .entry(section.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("{} is not a section", section))?;
+    }
+
+    let value = match table.get(last) {
+        Some(toml::Value::String(_)) => toml::Value::String(raw.to_string()),
+        _ => toml::from_str::<toml::Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(raw.to_string())),
+    };
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
 impl AppState {
-    pub fn new(config: AppConfig) -> Self {
-        Self {
-            inner: Arc::new(AppStateInner { conf: config }),
+    pub fn new(config: AppConfig) -> Result<Self> {
+        let reporter = config
+            .error_reporting
+            .as_ref()
+            .map(ErrorReporter::new)
+            .transpose()?;
+        let egress = EgressPolicy::new(config.egress.clone())?;
+        let budget = MemoryBudget::new(config.memory_budget.as_ref());
+        if let Some(cache) = &config.decode_cache {
+            decode_cache::init(cache);
         }
+        let notifier = config.notifications.as_ref().map(Notifier::new);
+        let jobs = JobStore::new(&config, notifier.clone());
+        let fonts = Arc::new(match &config.text {
+            Some(text) => FontStack::load(text)?,
+            None => FontStack::builtin(),
+        });
+
+        Ok(Self {
+            inner: Arc::new(AppStateInner {
+                conf: config,
+                jobs,
+                access: AccessTracker::default(),
+                reporter,
+                notifier,
+                egress,
+                budget,
+                fonts,
+                clients: ClientLimits::default(),
+                coalescer: Coalescer::default(),
+                disk: DiskStatus::default(),
+                s3_keys: KeyIndex::default(),
+                quota_usage: QuotaUsage::default(),
+            }),
+        })
     }
 }
 
@@ -0,0 +1,955 @@
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use memmap2::Mmap;
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    blobs,
+    changes::{self, ChangeKind},
+    crypt, delta, embedding,
+    format::ImageFormat,
+    handlers::{ImageVersion, ImgMetadata, LinkSource},
+    raster, sigv4, spool,
+    state::{AppConfig, IdVersion, OutputNaming},
+    store::{self, MetaFilter},
+    strip, tagging,
+    tenant::Tenant,
+};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+// Originals at least this large are memory mapped instead of read
+const MMAP_MIN_BYTES: u64 = 256 * 1024;
+
+pub fn new_image_id(conf: &AppConfig) -> String {
+    match conf.id_version {
+        IdVersion::V4 => Uuid::new_v4(),
+        IdVersion::V7 => Uuid::now_v7(),
+    }
+    .to_string()
+}
+
+// Writes the image bytes and its metadata, returning the new image id.
+// fmt, size_in_bytes, content_type, created_at and the dimensions of `meta`
+// are filled in from the stored data.
+//
+// The image is staged under a temporary name and renamed into place before
+// its metadata is stored, so an image is only listed once it is complete. On
+// failure everything written so far is removed again.
+pub async fn store_image(
+    conf: &AppConfig,
+    image_format: &ImageFormat,
+    file_data: &[u8],
+    meta: ImgMetadata,
+) -> Result<String> {
+    let staged_image = upload_staging_path(conf);
+    let sealed = seal_blocking(conf, file_data.to_vec()).await?;
+    if let Err(e) = write_synced(&staged_image, &sealed).await {
+        remove_all(&[&staged_image]).await;
+        return Err(anyhow!("Failed to save file: {}", e));
+    }
+
+    store_staged_image(
+        conf,
+        image_format,
+        &staged_image,
+        file_data.len() as u64,
+        sigv4::sha256_hex(file_data),
+        meta,
+        conf.strip_metadata,
+    )
+    .await
+    .map(|(file_id, _)| file_id)
+}
+
+// Temporary file for an upload that is written as it arrives, see
+// store_staged_image. In the spool directory when one is configured.
+pub fn upload_staging_path(conf: &AppConfig) -> PathBuf {
+    spool::staging_path(conf, &Path::new(&conf.file_path).join("upload"))
+}
+
+// Like store_image for data already written (and synced, encrypted when
+// configured) to `staged`, a path from upload_staging_path. The staged file is
+// moved into place, or removed on failure. `size` and `hash` (the SHA-256 in
+// hex) are of the unencrypted data. With `strip` its EXIF, GPS and XMP are
+// removed first. With dedup_uploads the file is stored once per content and
+// shared by every image with it, see blobs. Returns the id and the stored
+// metadata.
+pub async fn store_staged_image(
+    conf: &AppConfig,
+    image_format: &ImageFormat,
+    staged: &Path,
+    size: u64,
+    hash: String,
+    meta: ImgMetadata,
+    strip: bool,
+) -> Result<(String, ImgMetadata)> {
+    let (size, hash) = match strip {
+        true => match strip_staged(conf, staged, *image_format).await {
+            Ok(v) => v,
+            Err(e) => {
+                remove_all(&[staged]).await;
+                return Err(e);
+            }
+        },
+        false => (size, hash),
+    };
+
+    let file_id = new_image_id(conf);
+    let file_path = image_path(conf, &file_id, image_format.as_str());
+    info!("writing data to file: {:?}", file_path);
+
+    let (task_conf, path) = (conf.clone(), staged.to_path_buf());
+    let fmt = image_format.as_str().to_string();
+    let dimensions =
+        tokio::task::spawn_blocking(move || raster::probe_dimensions(&task_conf, &path, &fmt))
+            .await
+            .ok()
+            .and_then(|res| res.ok())
+            .flatten();
+
+    let meta = ImgMetadata {
+        fmt: image_format.as_str().to_string(),
+        size_in_bytes: size as u32,
+        content_type: Some(image_format.content_type().to_string()),
+        created_at: OffsetDateTime::now_utc().format(&Rfc3339).ok(),
+        content_hash: Some(hash.clone()),
+        width: dimensions.map(|(w, _)| w),
+        height: dimensions.map(|(_, h)| h),
+        ..meta
+    };
+
+    let res = async {
+        let store = store::open(conf).await?;
+        match conf.dedup_uploads {
+            true => {
+                if blobs::store(conf, &hash, staged, &file_path).await? {
+                    info!("{} has the content of an earlier upload", file_id);
+                }
+            }
+            false => spool::persist_blocking(staged, &file_path).await?,
+        }
+        store.put(&file_id, &meta).await
+    }
+    .await;
+
+    if let Err(e) = res {
+        remove_all(&[staged, &file_path]).await;
+        release_blob(conf, &hash).await;
+        return Err(e);
+    }
+    changes::record(conf, ChangeKind::Created, &file_id).await;
+    tagging::spawn(conf.clone(), file_id.clone());
+    embedding::spawn(conf.clone(), file_id.clone());
+    Ok((file_id, meta))
+}
+
+// Rewrites the staged file without its metadata, see strip::strip. Returns
+// the new size and hash.
+async fn strip_staged(conf: &AppConfig, staged: &Path, fmt: ImageFormat) -> Result<(u64, String)> {
+    let data = read_image_data(conf, staged).await?;
+    let stripped = tokio::task::spawn_blocking(move || strip::strip(&data, fmt)).await??;
+    let (size, hash) = (stripped.len() as u64, sigv4::sha256_hex(&stripped));
+    let sealed = seal_blocking(conf, stripped).await?;
+    write_synced(staged, &sealed).await?;
+    Ok((size, hash))
+}
+
+// Rolls back a partially stored image
+async fn remove_all(paths: &[&Path]) {
+    for path in paths {
+        match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                warn!("failed to roll back {:?}: {}", path, err)
+            }
+            _ => {}
+        }
+    }
+}
+
+// blobs::release, a failure only leaves an unused blob behind
+async fn release_blob(conf: &AppConfig, hash: &str) {
+    if let Err(e) = blobs::release(conf, hash).await {
+        warn!("failed to release blob {}: {}", hash, e);
+    }
+}
+
+// Temporary name next to `path`. The leading dot keeps it out of listings.
+fn staging_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!(".{}.tmp", Uuid::new_v4()))
+}
+
+async fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await
+}
+
+// Ids become file names, so only the shapes the server generates are
+// accepted: a UUID or the 32 hex digits of a content hash, optionally dated
+// ("YYYY-MM-DD_{uuid}") and followed by the "_{operation}[_{n}]" suffixes of
+// transform outputs. Anything else, e.g. "../", is refused before it gets
+// near a path.
+pub fn is_safe_id(img_id: &str) -> bool {
+    let rest = match dated_dir(img_id) {
+        Some(_) => &img_id[DATE_PREFIX_LEN..],
+        None => img_id,
+    };
+    let (head, suffixes) = rest.split_once('_').unwrap_or((rest, ""));
+    let is_uuid = (head.len() == 32 || head.len() == 36)
+        && head.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+        && Uuid::try_parse(head).is_ok();
+    is_uuid
+        && (suffixes.is_empty()
+            || suffixes.split('_').all(|part| {
+                !part.is_empty()
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            }))
+}
+
+// Whether `e` is the absence of an image's metadata or file, as opposed to a
+// failure reading it
+pub fn is_not_found(e: &anyhow::Error) -> bool {
+    e.is::<store::NotFound>()
+        || e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+}
+
+fn check_id(img_id: &str) -> Result<()> {
+    if !is_safe_id(img_id) {
+        return Err(anyhow!("invalid image id: {:?}", img_id));
+    }
+    Ok(())
+}
+
+pub fn image_path(conf: &AppConfig, img_id: &str, fmt: &str) -> PathBuf {
+    let dir = Path::new(&conf.file_path);
+    let dir = match dated_dir(img_id) {
+        Some(dated) => dir.join(dated),
+        None => dir.to_path_buf(),
+    };
+    dir.join(format!("{}{}", img_id, fmt))
+}
+
+// Cached thumbnail of an image, see handlers::thumbnail. Each image has its
+// own folder, so deleting the image drops all of its sizes at once.
+pub fn thumbnail_path(conf: &AppConfig, img_id: &str, variant: &str, fmt: &str) -> PathBuf {
+    thumbnail_dir(conf, img_id).join(format!("{}{}", variant, fmt))
+}
+
+fn thumbnail_dir(conf: &AppConfig, img_id: &str) -> PathBuf {
+    Path::new(&conf.file_path).join("thumbs").join(img_id)
+}
+
+// Removes the cached thumbnails of an image whose names start with `prefix`,
+// except `keep`. Returns how many were removed.
+pub async fn remove_thumbnails(
+    conf: &AppConfig,
+    img_id: &str,
+    prefix: &str,
+    keep: &Path,
+) -> Result<usize> {
+    let mut entries = match tokio::fs::read_dir(thumbnail_dir(conf, img_id)).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let matches = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(prefix));
+        if matches && path != keep {
+            tokio::fs::remove_file(&path).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Files an image had before it was re-encoded, see replace_image
+fn version_dir(conf: &AppConfig, img_id: &str) -> PathBuf {
+    Path::new(&conf.file_path).join("versions").join(img_id)
+}
+
+// "YYYY-MM-DD_" of dated ids
+const DATE_PREFIX_LEN: usize = 11;
+
+// Ids of dated outputs ("YYYY-MM-DD_{id}") live in YYYY/MM/DD folders
+fn dated_dir(img_id: &str) -> Option<PathBuf> {
+    let (date, _) = img_id.split_once('_')?;
+    let parts: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return None;
+    };
+    if year.len() != 4
+        || month.len() != 2
+        || day.len() != 2
+        || !date.chars().all(|c| c.is_ascii_digit() || c == '-')
+    {
+        return None;
+    }
+    Some(Path::new(year).join(month).join(day))
+}
+
+// Where transform outputs are written before they are named, the spool
+// directory when one is configured. The leading dot keeps them out of
+// listings.
+pub fn staged_output_path(conf: &AppConfig, fmt: &str) -> PathBuf {
+    spool::staging_path(conf, &image_path(conf, "output", fmt))
+}
+
+// Gives the transform output at `staged` its final name, which is also its
+// image id. Outputs are hard linked into place, so an existing file is never
+// replaced.
+pub fn promote_output(
+    conf: &AppConfig,
+    staged: &Path,
+    fmt: &str,
+    naming: OutputNaming,
+    source_id: &str,
+    operation: &str,
+) -> Result<String> {
+    // hard links cannot leave the spool's file system, so spooled outputs are
+    // moved into file_path first
+    let local;
+    let staged = match &conf.spool {
+        Some(_) => {
+            local = staging_path(&image_path(conf, "output", fmt));
+            if let Err(e) = spool::persist(staged, &local) {
+                let _ = fs::remove_file(staged);
+                return Err(e.into());
+            }
+            local.as_path()
+        }
+        None => staged,
+    };
+
+    let res = match naming {
+        OutputNaming::Uuid => link_output(conf, staged, fmt, new_image_id(conf)),
+        OutputNaming::Dated => {
+            let date = OffsetDateTime::now_utc().date();
+            let id = format!(
+                "{:04}-{:02}-{:02}_{}",
+                date.year(),
+                date.month() as u8,
+                date.day(),
+                new_image_id(conf)
+            );
+            link_output(conf, staged, fmt, id)
+        }
+        OutputNaming::ContentHash => {
+            // of the plaintext, every encryption of it differs
+            let id = sigv4::sha256_hex(&crypt::open(conf, fs::read(staged)?)?)[..32].to_string();
+            match link_output(conf, staged, fmt, id.clone()) {
+                // same content as an earlier output
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(id),
+                res => res,
+            }
+        }
+        OutputNaming::Suffixed => {
+            let base = format!("{}_{}", source_id, operation);
+            let mut n = 1;
+            loop {
+                let id = match n {
+                    1 => base.clone(),
+                    n => format!("{}_{}", base, n),
+                };
+                match link_output(conf, staged, fmt, id) {
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                    res => break res,
+                }
+            }
+        }
+    };
+
+    if let Err(e) = fs::remove_file(staged) {
+        warn!("failed to remove staged output {:?}: {}", staged, e);
+    }
+    Ok(res?)
+}
+
+fn link_output(conf: &AppConfig, staged: &Path, fmt: &str, id: String) -> io::Result<String> {
+    let path = image_path(conf, &id, fmt);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::hard_link(staged, &path)?;
+    Ok(id)
+}
+
+// Contents of a stored image, for sending it unchanged. Large files are memory
+// mapped, so the response body is written from the page cache without copying
+// the file into the heap first. Encrypted files are decrypted into memory.
+pub async fn read_image_bytes(conf: &AppConfig, path: &Path) -> Result<Bytes> {
+    let (conf, path) = (conf.clone(), path.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let mut file = File::open(&path)?;
+        let len = file.metadata()?.len();
+        if len < MMAP_MIN_BYTES {
+            let mut data = Vec::with_capacity(len as usize);
+            file.read_to_end(&mut data)?;
+            return Ok(Bytes::from(crypt::open(&conf, data)?));
+        }
+
+        // SAFETY: stored images are never modified in place, every change is
+        // written under a new id. Deleting the file keeps the mapping valid.
+        let mmap = unsafe { Mmap::map(&file)? };
+        if crypt::key_id(&mmap).is_some() {
+            return Ok(Bytes::from(crypt::open(&conf, mmap.to_vec())?));
+        }
+        Ok(Bytes::from_owner(mmap))
+    })
+    .await?
+}
+
+// A stored image opened for sending
+pub enum ImageContent {
+    // plaintext, read while the response is written
+    File(tokio::fs::File, u64),
+    // decrypted into memory
+    Bytes(Bytes),
+}
+
+impl ImageContent {
+    pub fn len(&self) -> u64 {
+        match self {
+            ImageContent::File(_, len) => *len,
+            ImageContent::Bytes(data) => data.len() as u64,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Opens a stored image for streaming. Encrypted files have to be decrypted as
+// a whole, so they are read like read_image_bytes does.
+pub async fn open_image(conf: &AppConfig, path: &Path) -> Result<ImageContent> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+
+    let mut prefix = Vec::new();
+    (&mut file).take(16).read_to_end(&mut prefix).await?;
+    if crypt::is_sealed(&prefix) {
+        return Ok(ImageContent::Bytes(read_image_bytes(conf, path).await?));
+    }
+
+    file.seek(io::SeekFrom::Start(0)).await?;
+    Ok(ImageContent::File(file, len))
+}
+
+// Decrypted contents of a stored image or metadata file
+pub async fn read_image_data(conf: &AppConfig, path: &Path) -> Result<Vec<u8>> {
+    let (conf, path) = (conf.clone(), path.to_path_buf());
+    tokio::task::spawn_blocking(move || crypt::open(&conf, fs::read(&path)?)).await?
+}
+
+// crypt::seal on the blocking thread pool, images can be large
+pub(crate) async fn seal_blocking(conf: &AppConfig, data: Vec<u8>) -> Result<Vec<u8>> {
+    if conf.encryption.is_none() {
+        return Ok(data);
+    }
+    let conf = conf.clone();
+    tokio::task::spawn_blocking(move || crypt::seal(&conf, data)).await?
+}
+
+// Writes `data` (sealed when encryption is enabled) under a temporary name
+// and renames it into place, so readers never see a partial file
+pub async fn write_cached_file(conf: &AppConfig, path: &Path, data: Vec<u8>) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let data = seal_blocking(conf, data).await?;
+    let staged = staging_path(path);
+    if let Err(e) = write_synced(&staged, &data).await {
+        let _ = tokio::fs::remove_file(&staged).await;
+        return Err(e.into());
+    }
+    tokio::fs::rename(&staged, path).await?;
+    Ok(())
+}
+
+// Rewrites a file under the current encryption key unless it already uses it,
+// plaintext included. Returns whether the file was rewritten. The new file is
+// renamed over the old one, so concurrent readers see either of them, and
+// keeps its modification time, which is reported as the creation time.
+pub async fn reencrypt_file(conf: &AppConfig, path: &Path) -> Result<bool> {
+    let (conf, path) = (conf.clone(), path.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let keyring =
+            crypt::keyring(&conf)?.ok_or_else(|| anyhow!("encryption is not configured"))?;
+        let data = fs::read(&path)?;
+        if crypt::key_id(&data) == Some(keyring.current_id()) {
+            return Ok(false);
+        }
+
+        let modified = fs::metadata(&path)?.modified()?;
+        let sealed = keyring.seal(&keyring.open(data)?)?;
+        let staged = staging_path(&path);
+        let res = File::create(&staged)
+            .and_then(|mut file| {
+                file.write_all(&sealed)?;
+                file.set_modified(modified)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&staged, &path));
+        if let Err(e) = res {
+            let _ = fs::remove_file(&staged);
+            return Err(e.into());
+        }
+        Ok(true)
+    })
+    .await?
+}
+
+// reencrypt_file for the metadata of an image, through update_meta so
+// concurrent updates are not lost
+pub async fn reencrypt_meta(conf: &AppConfig, img_id: &str) -> Result<bool> {
+    let keyring = crypt::keyring(conf)?.ok_or_else(|| anyhow!("encryption is not configured"))?;
+    let data = store::open(conf).await?.raw(img_id).await?;
+    if crypt::key_id(&data) == Some(keyring.current_id()) {
+        return Ok(false);
+    }
+    update_meta_unrecorded(conf, img_id, |_| Ok(())).await?;
+    Ok(true)
+}
+
+// Path and format of a stored image. Uploads are found through the fmt in
+// their metadata, transform outputs (which have none) by trying every known
+// extension.
+pub async fn locate_image(
+    conf: &AppConfig,
+    img_id: &str,
+    meta: Option<&ImgMetadata>,
+) -> Option<(PathBuf, ImageFormat)> {
+    if let Some(meta) = meta {
+        let path = image_path(conf, img_id, &meta.fmt);
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Some((path, ImageFormat::from_fmt(&meta.fmt)));
+        }
+    }
+
+    for fmt in ImageFormat::KNOWN {
+        let path = image_path(conf, img_id, fmt.as_str());
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Some((path, fmt));
+        }
+    }
+    None
+}
+
+pub async fn read_meta(conf: &AppConfig, img_id: &str) -> Result<ImgMetadata> {
+    check_id(img_id)?;
+    store::open(conf).await?.get(img_id).await
+}
+
+pub async fn write_meta(conf: &AppConfig, img_id: &str, meta: &ImgMetadata) -> Result<()> {
+    check_id(img_id)?;
+    store::open(conf).await?.put(img_id, meta).await
+}
+
+// Read-modify-write of the metadata of an image, see MetadataStore::update
+pub async fn update_meta<F>(conf: &AppConfig, img_id: &str, update: F) -> Result<ImgMetadata>
+where
+    F: FnOnce(&mut ImgMetadata) -> Result<()> + Send,
+{
+    let meta = update_meta_unrecorded(conf, img_id, update).await?;
+    changes::record(conf, ChangeKind::Updated, img_id).await;
+    Ok(meta)
+}
+
+// update_meta left out of the changes of GET /api/sync, for bookkeeping
+// clients have no use for such as access counters
+pub async fn update_meta_unrecorded<F>(
+    conf: &AppConfig,
+    img_id: &str,
+    update: F,
+) -> Result<ImgMetadata>
+where
+    F: FnOnce(&mut ImgMetadata) -> Result<()> + Send,
+{
+    check_id(img_id)?;
+    store::open(conf)
+        .await?
+        .update(img_id, Box::new(update))
+        .await
+}
+
+// Error of deleting or replacing an image under a legal hold
+#[derive(Debug, thiserror::Error)]
+#[error("Image {img_id} is under a legal hold")]
+pub struct Held {
+    pub img_id: String,
+}
+
+// Refuses changes to an image under a legal hold
+pub fn check_hold(img_id: &str, meta: &ImgMetadata) -> Result<()> {
+    match meta.legal_hold {
+        Some(_) => Err(Held {
+            img_id: img_id.to_string(),
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+pub async fn delete_image(conf: &AppConfig, img_id: &str) -> Result<()> {
+    let store = store::open(conf).await?;
+    let meta = store.get(img_id).await?;
+    check_hold(img_id, &meta)?;
+    // before the blob is released, a failure here must leave the image whole
+    match tokio::fs::remove_dir_all(version_dir(conf, img_id)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    tokio::fs::remove_file(image_path(conf, img_id, &meta.fmt)).await?;
+    if let Some(hash) = &meta.content_hash {
+        release_blob(conf, hash).await;
+    }
+    store.delete(img_id).await?;
+    changes::record(conf, ChangeKind::Deleted, img_id).await;
+    match tokio::fs::remove_file(embedding::embedding_path(conf, img_id)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    remove_derived(conf, img_id).await
+}
+
+// Adds an image to `to`, another tenant's storage or the same one, sharing
+// the file of `img_id` in `from` instead of copying it. replace_image renames
+// a new file over the old one, so once either image changes the other keeps
+// the shared file. The new image records `source` and the link_count of the
+// original goes up. Returns the id and metadata of the new image.
+pub async fn link_image(
+    from: &AppConfig,
+    img_id: &str,
+    to: &AppConfig,
+    source: LinkSource,
+    collection: Option<String>,
+) -> Result<(String, ImgMetadata)> {
+    let meta = read_meta(from, img_id).await?;
+    let new_id = new_image_id(to);
+    let (src, dst) = (
+        image_path(from, img_id, &meta.fmt),
+        image_path(to, &new_id, &meta.fmt),
+    );
+    let path = dst.clone();
+    tokio::task::spawn_blocking(move || -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        match fs::hard_link(&src, &path) {
+            // storage of the tenants on different volumes
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                let staged = staging_path(&path);
+                let res = fs::copy(&src, &staged).and_then(|_| fs::rename(&staged, &path));
+                if res.is_err() {
+                    let _ = fs::remove_file(&staged);
+                }
+                res
+            }
+            res => res,
+        }
+    })
+    .await??;
+
+    // what describes the picture is kept, what describes its use is not
+    let linked = ImgMetadata {
+        fmt: meta.fmt,
+        size_in_bytes: meta.size_in_bytes,
+        content_type: meta.content_type,
+        original_filename: meta.original_filename,
+        created_at: OffsetDateTime::now_utc().format(&Rfc3339).ok(),
+        content_hash: meta.content_hash,
+        width: meta.width,
+        height: meta.height,
+        tags: meta.tags,
+        tag_confidence: meta.tag_confidence,
+        regions: meta.regions,
+        collection,
+        linked_from: Some(source),
+        ..Default::default()
+    };
+    if let Err(e) = write_meta(to, &new_id, &linked).await {
+        remove_all(&[&dst]).await;
+        return Err(e);
+    }
+    changes::record(to, ChangeKind::Created, &new_id).await;
+    update_meta(from, img_id, |meta| {
+        meta.link_count += 1;
+        Ok(())
+    })
+    .await?;
+    embedding::spawn(to.clone(), new_id.clone());
+    Ok((new_id, linked))
+}
+
+// Ends the sharing of `img_id` with the image it was linked from, after it
+// got a file of its own or before it is deleted. `base` is the config of the
+// default tenant, which the tenant of the source is scoped from. Returns the
+// updated metadata.
+pub async fn unlink_image(base: &AppConfig, conf: &AppConfig, img_id: &str) -> Result<ImgMetadata> {
+    let mut source = None;
+    let meta = update_meta(conf, img_id, |meta| {
+        source = meta.linked_from.take();
+        Ok(())
+    })
+    .await?;
+    let Some(source) = source else {
+        return Ok(meta);
+    };
+
+    let tenant = Tenant {
+        id: source.tenant.unwrap_or_default(),
+        ..Default::default()
+    };
+    let res = update_meta(&tenant.scope(base), &source.img_id, |meta| {
+        meta.link_count = meta.link_count.saturating_sub(1);
+        Ok(())
+    })
+    .await;
+    // the source may have been deleted in the meantime
+    if let Err(e) = res {
+        warn!(
+            "failed to update the link count of {}: {}",
+            source.img_id, e
+        );
+    }
+    Ok(meta)
+}
+
+// Cached thumbnails and rasters of an image
+async fn remove_derived(conf: &AppConfig, img_id: &str) -> Result<()> {
+    match tokio::fs::remove_dir_all(thumbnail_dir(conf, img_id)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    if let Some(cache) = &conf.raster_cache {
+        match tokio::fs::remove_file(raster::cache_path(cache, img_id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Swaps the file of an image for `data` in format `fmt`, e.g. a re-encode of
+// it. The new file keeps the modification time of the old one, which is
+// reported as the creation time, the time of the swap is recorded as
+// modified_at. With `keep_original` the old file is kept in
+// versions/{img_id}/ (see keep_version) and listed in the metadata, otherwise
+// it is deleted.
+// Held images are refused, see check_hold.
+pub async fn replace_image(
+    conf: &AppConfig,
+    img_id: &str,
+    fmt: &str,
+    data: Vec<u8>,
+    keep_original: bool,
+) -> Result<ImgMetadata> {
+    let meta = read_meta(conf, img_id).await?;
+    check_hold(img_id, &meta)?;
+    let old_path = image_path(conf, img_id, &meta.fmt);
+    let new_path = image_path(conf, img_id, fmt);
+    let modified = tokio::fs::metadata(&old_path).await?.modified()?;
+
+    let version = match keep_original {
+        true => Some(keep_version(conf, img_id, &meta, &old_path).await?),
+        false => None,
+    };
+
+    let (size, hash) = (data.len(), sigv4::sha256_hex(&data));
+    let sealed = seal_blocking(conf, data).await?;
+    let path = new_path.clone();
+    tokio::task::spawn_blocking(move || {
+        let staged = staging_path(&path);
+        let res = File::create(&staged)
+            .and_then(|mut file| {
+                file.write_all(&sealed)?;
+                file.set_modified(modified)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&staged, &path));
+        if res.is_err() {
+            let _ = fs::remove_file(&staged);
+        }
+        res
+    })
+    .await??;
+
+    let old_hash = meta.content_hash;
+    let meta = update_meta(conf, img_id, |meta| {
+        meta.fmt = fmt.to_string();
+        meta.size_in_bytes = size as u32;
+        meta.content_type = Some(ImageFormat::from_fmt(fmt).content_type().to_string());
+        meta.modified_at = OffsetDateTime::now_utc().format(&Rfc3339).ok();
+        meta.content_hash = Some(hash);
+        meta.versions.extend(version);
+        Ok(())
+    })
+    .await?;
+    if old_path != new_path {
+        tokio::fs::remove_file(&old_path).await?;
+    }
+    // the new file is not shared
+    if let Some(hash) = &old_hash {
+        release_blob(conf, hash).await;
+    }
+    remove_derived(conf, img_id).await?;
+    Ok(meta)
+}
+
+// Keeps `path`, the file of an image about to be replaced, as its next
+// version. With version_deltas it is stored as a delta against the version
+// before it, unless that is not smaller or the deltas since the last whole
+// version reach snapshot_every, which bounds what read_version applies.
+async fn keep_version(
+    conf: &AppConfig,
+    img_id: &str,
+    meta: &ImgMetadata,
+    path: &Path,
+) -> Result<ImageVersion> {
+    let dir = version_dir(conf, img_id);
+    tokio::fs::create_dir_all(&dir).await?;
+    let n = meta.versions.len() + 1;
+    let mut version = ImageVersion {
+        file: format!("{}{}", n, meta.fmt),
+        fmt: meta.fmt.clone(),
+        size_in_bytes: meta.size_in_bytes,
+        replaced: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+        delta: false,
+    };
+
+    let deltas = meta.versions.iter().rev().take_while(|v| v.delta).count();
+    if let Some(config) = &conf.version_deltas
+        && !meta.versions.is_empty()
+        && deltas + 1 < config.snapshot_every as usize
+    {
+        let base = read_version(conf, img_id, &meta.versions, meta.versions.len() - 1).await?;
+        let data = read_image_data(conf, path).await?;
+        let delta = tokio::task::spawn_blocking(move || delta::encode(&base, &data)).await?;
+        if delta.len() < meta.size_in_bytes as usize {
+            version.file = format!("{}{}.delta", n, meta.fmt);
+            version.delta = true;
+            let sealed = seal_blocking(conf, delta).await?;
+            let (staged, target) = (
+                staging_path(&dir.join(&version.file)),
+                dir.join(&version.file),
+            );
+            let res = async {
+                write_synced(&staged, &sealed).await?;
+                tokio::fs::rename(&staged, &target).await
+            }
+            .await;
+            if let Err(e) = res {
+                remove_all(&[&staged]).await;
+                return Err(e.into());
+            }
+            return Ok(version);
+        }
+    }
+
+    tokio::fs::hard_link(path, dir.join(&version.file)).await?;
+    Ok(version)
+}
+
+// Data of versions[index] of an image, built from the whole version before
+// it and the deltas after that one
+pub async fn read_version(
+    conf: &AppConfig,
+    img_id: &str,
+    versions: &[ImageVersion],
+    index: usize,
+) -> Result<Vec<u8>> {
+    let dir = version_dir(conf, img_id);
+    let start = versions[..=index]
+        .iter()
+        .rposition(|v| !v.delta)
+        .ok_or_else(|| {
+            anyhow!(
+                "no whole version before version {} of {}",
+                index + 1,
+                img_id
+            )
+        })?;
+
+    let mut data = read_image_data(conf, &dir.join(&versions[start].file)).await?;
+    for version in &versions[start + 1..=index] {
+        let delta = read_image_data(conf, &dir.join(&version.file)).await?;
+        data = tokio::task::spawn_blocking(move || delta::apply(&data, &delta)).await??;
+    }
+    Ok(data)
+}
+
+// Ids of every stored image, sorted (creation order for v7 ids)
+pub async fn list_image_ids(conf: &AppConfig) -> Result<Vec<String>> {
+    store::open(conf).await?.list_ids().await
+}
+
+// Metadata and last modification time of every stored image
+pub async fn list_images(conf: &AppConfig) -> Result<Vec<(String, ImgMetadata, SystemTime)>> {
+    list_images_where(conf, &MetaFilter::default()).await
+}
+
+// list_images of the images matching `filter`
+pub async fn list_images_where(
+    conf: &AppConfig,
+    filter: &MetaFilter,
+) -> Result<Vec<(String, ImgMetadata, SystemTime)>> {
+    let mut images = Vec::new();
+
+    for (img_id, meta) in store::open(conf).await?.list(filter).await? {
+        let modified = tokio::fs::metadata(image_path(conf, &img_id, &meta.fmt))
+            .await
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        images.push((img_id, meta, modified));
+    }
+
+    Ok(images)
+}
+
+// Files in the upload directory without a metadata file, e.g. the outputs of
+// transforms. Returns the file name and its size in bytes.
+pub async fn list_untracked_files(conf: &AppConfig) -> Result<Vec<(String, u64)>> {
+    let ids: HashSet<String> = list_image_ids(conf).await?.into_iter().collect();
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(&conf.file_path).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let Some(name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+            continue;
+        };
+        // uploads still being staged
+        if name.starts_with('.') {
+            continue;
+        }
+        let stem = name.split('.').next().unwrap_or_default();
+        if !ids.contains(stem) {
+            files.push((name, metadata.len()));
+        }
+    }
+
+    Ok(files)
+}
@@ -0,0 +1,150 @@
+use anyhow::{Result, anyhow};
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{BoxFuture, MetaFilter, MetadataStore, NotFound, Update, decode, encode};
+use crate::{handlers::ImgMetadata, state::AppConfig};
+
+// Locks of FileStore::update, an image always maps to the same one
+const UPDATE_SHARDS: usize = 64;
+
+static META_UPDATES: [tokio::sync::Mutex<()>; UPDATE_SHARDS] =
+    [const { tokio::sync::Mutex::const_new(()) }; UPDATE_SHARDS];
+
+// One JSON file per image in meta_path, named after the image id. Listings
+// read every file.
+pub struct FileStore {
+    conf: AppConfig,
+}
+
+impl FileStore {
+    pub fn new(conf: &AppConfig) -> Self {
+        FileStore { conf: conf.clone() }
+    }
+
+    fn path(&self, img_id: &str) -> PathBuf {
+        Path::new(&self.conf.meta_path).join(img_id)
+    }
+
+    // Staged under a temporary name and renamed into place, readers never see
+    // a partial file
+    async fn write(&self, img_id: &str, meta: &ImgMetadata) -> Result<()> {
+        let path = self.path(img_id);
+        let staged = path.with_file_name(format!(".{}.tmp", Uuid::new_v4()));
+        let data = encode(&self.conf, meta)?;
+
+        let res = async {
+            let mut file = tokio::fs::File::create(&staged).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&staged, &path).await
+        }
+        .await;
+        if let Err(e) = res {
+            let _ = tokio::fs::remove_file(&staged).await;
+            return Err(anyhow!("Failed to save metadata: {}", e));
+        }
+        Ok(())
+    }
+}
+
+impl MetadataStore for FileStore {
+    fn get<'a>(&'a self, img_id: &'a str) -> BoxFuture<'a, Result<ImgMetadata>> {
+        Box::pin(async move { decode(&self.conf, self.raw(img_id).await?) })
+    }
+
+    fn put<'a>(&'a self, img_id: &'a str, meta: &'a ImgMetadata) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.write(img_id, meta))
+    }
+
+    // Updates of an image are serialized so concurrent writers (e.g. the
+    // access tracker and a review) do not lose each other's changes. Images
+    // are spread over the shards by their metadata path, which includes the
+    // tenant's directory, so updates of others rarely wait.
+    fn update<'a>(
+        &'a self,
+        img_id: &'a str,
+        update: Update<'a>,
+    ) -> BoxFuture<'a, Result<ImgMetadata>> {
+        Box::pin(async move {
+            let mut hasher = DefaultHasher::new();
+            self.path(img_id).hash(&mut hasher);
+            let shard = hasher.finish() as usize % UPDATE_SHARDS;
+            let _guard = META_UPDATES[shard].lock().await;
+            let mut meta = self.get(img_id).await?;
+            update(&mut meta)?;
+            self.write(img_id, &meta).await?;
+            Ok(meta)
+        })
+    }
+
+    fn delete<'a>(&'a self, img_id: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path(img_id)).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Err(NotFound {
+                    img_id: img_id.to_string(),
+                }
+                .into()),
+                res => Ok(res?),
+            }
+        })
+    }
+
+    fn list_ids(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        Box::pin(async move {
+            let mut ids = Vec::new();
+            let mut entries = tokio::fs::read_dir(&self.conf.meta_path).await?;
+
+            while let Some(entry) = entries.next_entry().await? {
+                if !entry.file_type().await?.is_file() {
+                    continue;
+                }
+                if let Some(name) = entry.file_name().to_str()
+                    && !name.starts_with('.')
+                {
+                    ids.push(name.to_string());
+                }
+            }
+
+            ids.sort();
+            Ok(ids)
+        })
+    }
+
+    fn list<'a>(
+        &'a self,
+        filter: &'a MetaFilter,
+    ) -> BoxFuture<'a, Result<Vec<(String, ImgMetadata)>>> {
+        Box::pin(async move {
+            let mut images = Vec::new();
+            for img_id in self.list_ids().await? {
+                match self.get(&img_id).await {
+                    Ok(meta) if filter.matches(&meta) => images.push((img_id, meta)),
+                    Ok(_) => {}
+                    Err(e) => warn!("skipping {} in listing: {}", img_id, e),
+                }
+            }
+            Ok(images)
+        })
+    }
+
+    fn raw<'a>(&'a self, img_id: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            tokio::fs::read(self.path(img_id))
+                .await
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::NotFound => NotFound {
+                        img_id: img_id.to_string(),
+                    }
+                    .into(),
+                    _ => anyhow!("{}", e),
+                })
+        })
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use std::{future::Future, pin::Pin};
+
+use crate::{
+    crypt,
+    handlers::ImgMetadata,
+    review::ReviewState,
+    state::{AppConfig, MetadataBackend},
+};
+
+pub mod file;
+pub mod sqlite;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub type Update<'a> = Box<dyn FnOnce(&mut ImgMetadata) -> Result<()> + Send + 'a>;
+
+// Error of every backend for an image it has no metadata of
+#[derive(Debug, thiserror::Error)]
+#[error("no metadata for {img_id}")]
+pub struct NotFound {
+    pub img_id: String,
+}
+
+// Where the metadata of a tenant's images is kept. Every backend stores the
+// serialized metadata sealed with the configured encryption, see `encode`.
+pub trait MetadataStore: Send + Sync {
+    fn get<'a>(&'a self, img_id: &'a str) -> BoxFuture<'a, Result<ImgMetadata>>;
+
+    // Inserts or replaces the metadata of an image
+    fn put<'a>(&'a self, img_id: &'a str, meta: &'a ImgMetadata) -> BoxFuture<'a, Result<()>>;
+
+    // Read-modify-write, concurrent updates of an image are not lost
+    fn update<'a>(
+        &'a self,
+        img_id: &'a str,
+        update: Update<'a>,
+    ) -> BoxFuture<'a, Result<ImgMetadata>>;
+
+    fn delete<'a>(&'a self, img_id: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    // Ids of every stored image, sorted (creation order for v7 ids)
+    fn list_ids(&self) -> BoxFuture<'_, Result<Vec<String>>>;
+
+    // Images matching `filter`, sorted by id
+    fn list<'a>(
+        &'a self,
+        filter: &'a MetaFilter,
+    ) -> BoxFuture<'a, Result<Vec<(String, ImgMetadata)>>>;
+
+    // Stored (possibly sealed) bytes of the metadata, for checking which key
+    // encrypted it
+    fn raw<'a>(&'a self, img_id: &'a str) -> BoxFuture<'a, Result<Vec<u8>>>;
+}
+
+// Conditions of a listing, absent ones match every image
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MetaFilter {
+    // e.g. "png" or ".png"
+    pub fmt: Option<String>,
+    // size_in_bytes bounds, inclusive
+    pub min_size: Option<u32>,
+    pub max_size: Option<u32>,
+    pub collection: Option<String>,
+    pub review_state: Option<ReviewState>,
+    // e.g. a tag attached by the classifier, see tagging
+    pub tag: Option<String>,
+}
+
+impl MetaFilter {
+    // fmt as stored, with the leading dot
+    fn stored_fmt(&self) -> Option<String> {
+        self.fmt
+            .as_deref()
+            .map(|fmt| format!(".{}", fmt.trim_start_matches('.').to_lowercase()))
+    }
+
+    pub fn matches(&self, meta: &ImgMetadata) -> bool {
+        self.stored_fmt().is_none_or(|fmt| fmt == meta.fmt)
+            && self.min_size.is_none_or(|min| meta.size_in_bytes >= min)
+            && self.max_size.is_none_or(|max| meta.size_in_bytes <= max)
+            && self
+                .collection
+                .as_ref()
+                .is_none_or(|c| meta.collection.as_ref() == Some(c))
+            && self.review_state.is_none_or(|s| s == meta.review_state)
+            && self.has_tag(meta)
+    }
+
+    // tags are not indexed, backends check them on the decoded metadata
+    fn has_tag(&self, meta: &ImgMetadata) -> bool {
+        self.tag.as_ref().is_none_or(|t| meta.tags.contains(t))
+    }
+}
+
+// Store of the (tenant scoped) configuration
+pub async fn open(conf: &AppConfig) -> Result<Box<dyn MetadataStore>> {
+    Ok(match conf.metadata_store {
+        MetadataBackend::File => Box::new(file::FileStore::new(conf)),
+        MetadataBackend::Sqlite => Box::new(sqlite::SqliteStore::open(conf).await?),
+    })
+}
+
+fn encode(conf: &AppConfig, meta: &ImgMetadata) -> Result<Vec<u8>> {
+    crypt::seal(conf, serde_json::to_vec(meta)?)
+}
+
+fn decode(conf: &AppConfig, data: Vec<u8>) -> Result<ImgMetadata> {
+    serde_json::from_slice(&crypt::open(conf, data)?).map_err(|e| anyhow!("{}", e))
+}
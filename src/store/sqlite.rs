@@ -0,0 +1,271 @@
+use anyhow::Result;
+use sqlx::{
+    QueryBuilder, Sqlite, SqliteConnection, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use super::{
+    BoxFuture, MetaFilter, MetadataStore, NotFound, Update, decode, encode, file::FileStore,
+};
+use crate::{handlers::ImgMetadata, state::AppConfig};
+
+// In meta_path, the leading dot keeps it (and its -wal and -shm files) out of
+// the file store's listings
+const DB_FILE: &str = ".metadata.db";
+const MAX_CONNECTIONS: u32 = 8;
+// how long a write waits for another one to finish
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS images (
+    id TEXT PRIMARY KEY NOT NULL,
+    fmt TEXT NOT NULL,
+    size_in_bytes INTEGER NOT NULL,
+    collection TEXT,
+    review_state TEXT NOT NULL,
+    created_at TEXT,
+    data BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS images_fmt_size ON images (fmt, size_in_bytes);
+CREATE INDEX IF NOT EXISTS images_collection ON images (collection);
+";
+
+// Metadata in a SQLite database per tenant, so listings can filter in SQL.
+// `data` holds the sealed JSON like a metadata file does, the other columns
+// are plaintext copies of the fields listings filter on.
+pub struct SqliteStore {
+    conf: AppConfig,
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    // Pools are shared by every request of a tenant. A new database starts
+    // with the metadata files already in meta_path.
+    pub async fn open(conf: &AppConfig) -> Result<Self> {
+        static POOLS: tokio::sync::Mutex<BTreeMap<PathBuf, SqlitePool>> =
+            tokio::sync::Mutex::const_new(BTreeMap::new());
+
+        let path = Path::new(&conf.meta_path).join(DB_FILE);
+        let mut pools = POOLS.lock().await;
+        if let Some(pool) = pools.get(&path) {
+            return Ok(SqliteStore {
+                conf: conf.clone(),
+                pool: pool.clone(),
+            });
+        }
+
+        let is_new = !tokio::fs::try_exists(&path).await?;
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(BUSY_TIMEOUT);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(MAX_CONNECTIONS)
+            .connect_with(options)
+            .await?;
+        sqlx::raw_sql(SCHEMA).execute(&pool).await?;
+
+        let store = SqliteStore {
+            conf: conf.clone(),
+            pool: pool.clone(),
+        };
+        if is_new {
+            let imported = store.import_files().await?;
+            info!("imported {} metadata files into {:?}", imported, path);
+        }
+        pools.insert(path, pool);
+        Ok(store)
+    }
+
+    // Copies the metadata files of the file store, which are left in place
+    async fn import_files(&self) -> Result<usize> {
+        let files = FileStore::new(&self.conf);
+        let mut imported = 0;
+        for img_id in files.list_ids().await? {
+            let res = async {
+                let meta = files.get(&img_id).await?;
+                let mut conn = self.pool.acquire().await?;
+                write(&mut conn, &img_id, &meta, files.raw(&img_id).await?).await
+            }
+            .await;
+            match res {
+                Ok(()) => imported += 1,
+                Err(e) => warn!("failed to import metadata of {}: {}", img_id, e),
+            }
+        }
+        Ok(imported)
+    }
+
+    // `update` in a transaction that holds the write lock from the start,
+    // a deferred one could fail to upgrade when another writer got in first
+    async fn update_locked(&self, img_id: &str, update: Update<'_>) -> Result<ImgMetadata> {
+        let mut conn = self.pool.acquire().await?;
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await?;
+
+        let res = async {
+            let data = read(&mut conn, img_id).await?;
+            let mut meta = decode(&self.conf, data)?;
+            update(&mut meta)?;
+            let data = encode(&self.conf, &meta)?;
+            write(&mut conn, img_id, &meta, data).await?;
+            Ok::<_, anyhow::Error>(meta)
+        }
+        .await;
+
+        match res {
+            Ok(meta) => {
+                sqlx::query("COMMIT").execute(&mut *conn).await?;
+                Ok(meta)
+            }
+            Err(e) => {
+                if let Err(rollback) = sqlx::query("ROLLBACK").execute(&mut *conn).await {
+                    warn!("failed to roll back update of {}: {}", img_id, rollback);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+async fn read(conn: &mut SqliteConnection, img_id: &str) -> Result<Vec<u8>> {
+    sqlx::query_scalar::<_, Vec<u8>>("SELECT data FROM images WHERE id = ?")
+        .bind(img_id)
+        .fetch_optional(conn)
+        .await?
+        .ok_or_else(|| {
+            NotFound {
+                img_id: img_id.to_string(),
+            }
+            .into()
+        })
+}
+
+async fn write(
+    conn: &mut SqliteConnection,
+    img_id: &str,
+    meta: &ImgMetadata,
+    data: Vec<u8>,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO images (id, fmt, size_in_bytes, collection, review_state, created_at, data)
+         VALUES (?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT (id) DO UPDATE SET
+             fmt = excluded.fmt,
+             size_in_bytes = excluded.size_in_bytes,
+             collection = excluded.collection,
+             review_state = excluded.review_state,
+             created_at = excluded.created_at,
+             data = excluded.data",
+    )
+    .bind(img_id)
+    .bind(&meta.fmt)
+    .bind(meta.size_in_bytes as i64)
+    .bind(&meta.collection)
+    .bind(meta.review_state.as_str())
+    .bind(&meta.created_at)
+    .bind(data)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+impl MetadataStore for SqliteStore {
+    fn get<'a>(&'a self, img_id: &'a str) -> BoxFuture<'a, Result<ImgMetadata>> {
+        Box::pin(async move { decode(&self.conf, self.raw(img_id).await?) })
+    }
+
+    fn put<'a>(&'a self, img_id: &'a str, meta: &'a ImgMetadata) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let data = encode(&self.conf, meta)?;
+            let mut conn = self.pool.acquire().await?;
+            write(&mut conn, img_id, meta, data).await
+        })
+    }
+
+    fn update<'a>(
+        &'a self,
+        img_id: &'a str,
+        update: Update<'a>,
+    ) -> BoxFuture<'a, Result<ImgMetadata>> {
+        Box::pin(self.update_locked(img_id, update))
+    }
+
+    fn delete<'a>(&'a self, img_id: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let res = sqlx::query("DELETE FROM images WHERE id = ?")
+                .bind(img_id)
+                .execute(&self.pool)
+                .await?;
+            match res.rows_affected() {
+                0 => Err(NotFound {
+                    img_id: img_id.to_string(),
+                }
+                .into()),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn list_ids(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        Box::pin(async move {
+            let ids = sqlx::query_scalar::<_, String>("SELECT id FROM images ORDER BY id")
+                .fetch_all(&self.pool)
+                .await?;
+            Ok(ids)
+        })
+    }
+
+    fn list<'a>(
+        &'a self,
+        filter: &'a MetaFilter,
+    ) -> BoxFuture<'a, Result<Vec<(String, ImgMetadata)>>> {
+        Box::pin(async move {
+            let mut query = QueryBuilder::<Sqlite>::new("SELECT id, data FROM images WHERE 1 = 1");
+            if let Some(fmt) = filter.stored_fmt() {
+                query.push(" AND fmt = ").push_bind(fmt);
+            }
+            if let Some(min) = filter.min_size {
+                query.push(" AND size_in_bytes >= ").push_bind(min as i64);
+            }
+            if let Some(max) = filter.max_size {
+                query.push(" AND size_in_bytes <= ").push_bind(max as i64);
+            }
+            if let Some(collection) = &filter.collection {
+                query.push(" AND collection = ").push_bind(collection);
+            }
+            if let Some(state) = filter.review_state {
+                query.push(" AND review_state = ").push_bind(state.as_str());
+            }
+            query.push(" ORDER BY id");
+
+            let rows = query
+                .build_query_as::<(String, Vec<u8>)>()
+                .fetch_all(&self.pool)
+                .await?;
+
+            let mut images = Vec::with_capacity(rows.len());
+            for (img_id, data) in rows {
+                match decode(&self.conf, data) {
+                    Ok(meta) if filter.has_tag(&meta) => images.push((img_id, meta)),
+                    Ok(_) => {}
+                    Err(e) => warn!("skipping {} in listing: {}", img_id, e),
+                }
+            }
+            Ok(images)
+        })
+    }
+
+    fn raw<'a>(&'a self, img_id: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut conn = self.pool.acquire().await?;
+            read(&mut conn, img_id).await
+        })
+    }
+}
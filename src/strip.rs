@@ -0,0 +1,367 @@
+use anyhow::{Result, anyhow};
+
+use crate::{exif, format::ImageFormat};
+
+// EXIF tag of the orientation, the only one kept
+const ORIENTATION_TAG: u16 = 0x0112;
+
+// Removes EXIF (with its GPS position), XMP, IPTC, comments and text chunks
+// from an encoded image without re-encoding it. The EXIF orientation is
+// written back on its own so photos are not displayed rotated. GIF and AVIF
+// are returned as they are.
+pub fn strip(data: &[u8], fmt: ImageFormat) -> Result<Vec<u8>> {
+    let orientation = exif::read(data)
+        .ok()
+        .flatten()
+        .and_then(|e| e.orientation)
+        .filter(|o| (2..=8).contains(o))
+        .map(|o| o as u16);
+
+    match fmt {
+        ImageFormat::Jpeg => strip_jpeg(data, orientation),
+        ImageFormat::Png => strip_png(data, orientation),
+        ImageFormat::WebP => strip_webp(data, orientation),
+        _ => Ok(data.to_vec()),
+    }
+}
+
+// Segments up to the start of scan, APP1 (EXIF, XMP), APP13 (IPTC) and COM
+// are dropped
+fn strip_jpeg(data: &[u8], orientation: Option<u16>) -> Result<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(anyhow!("malformed JPEG"));
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    // after SOI and a JFIF APP0
+    let mut pending = orientation.map(|o| {
+        let payload = [b"Exif\0\0".as_slice(), &orientation_tiff(o)].concat();
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend(((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend(payload);
+        segment
+    });
+
+    let mut pos = 2;
+    loop {
+        if pos + 2 > data.len() || data[pos] != 0xFF {
+            return Err(anyhow!("malformed JPEG"));
+        }
+        let marker = data[pos + 1];
+        if marker == 0xFF {
+            // fill byte
+            pos += 1;
+            continue;
+        }
+        if marker != 0xE0
+            && let Some(segment) = pending.take()
+        {
+            out.extend(segment);
+        }
+        // start of scan or end of image, the rest is image data
+        if marker == 0xDA || marker == 0xD9 {
+            out.extend_from_slice(&data[pos..]);
+            return Ok(out);
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            out.extend_from_slice(&data[pos..pos + 2]);
+            pos += 2;
+            continue;
+        }
+
+        if pos + 4 > data.len() {
+            return Err(anyhow!("malformed JPEG"));
+        }
+        let end = pos + 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if end > data.len() {
+            return Err(anyhow!("malformed JPEG"));
+        }
+        if !matches!(marker, 0xE1 | 0xED | 0xFE) {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+}
+
+// eXIf and the text chunks are dropped, the orientation goes after IHDR
+fn strip_png(data: &[u8], orientation: Option<u16>) -> Result<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !data.starts_with(SIGNATURE) {
+        return Err(anyhow!("malformed PNG"));
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(SIGNATURE);
+    let mut pos = SIGNATURE.len();
+    while pos < data.len() {
+        if pos + 12 > data.len() {
+            return Err(anyhow!("malformed PNG"));
+        }
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into()?) as usize;
+        let end = pos + 12 + len;
+        if end > data.len() {
+            return Err(anyhow!("malformed PNG"));
+        }
+        let kind = &data[pos + 4..pos + 8];
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        if kind == b"IHDR"
+            && let Some(o) = orientation
+        {
+            let tiff = orientation_tiff(o);
+            let body = [b"eXIf".as_slice(), &tiff].concat();
+            out.extend((tiff.len() as u32).to_be_bytes());
+            out.extend_from_slice(&body);
+            out.extend(crc32(&body).to_be_bytes());
+        }
+        pos = end;
+    }
+    Ok(out)
+}
+
+// EXIF and XMP chunks are dropped and the extended header's flags updated.
+// Only extended WebPs (with a VP8X chunk) can carry the orientation.
+fn strip_webp(data: &[u8], orientation: Option<u16>) -> Result<Vec<u8>> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(anyhow!("malformed WebP"));
+    }
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+
+    let mut chunks = Vec::with_capacity(data.len());
+    let mut extended = false;
+    let mut pos = 12;
+    while pos < data.len() {
+        if pos + 8 > data.len() {
+            return Err(anyhow!("malformed WebP"));
+        }
+        let fourcc = &data[pos..pos + 4];
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+        // chunks are padded to an even size
+        let end = (pos + 8 + len + (len & 1)).min(data.len());
+        if pos + 8 + len > data.len() {
+            return Err(anyhow!("malformed WebP"));
+        }
+        match fourcc {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if len >= 1 => {
+                extended = true;
+                let flags_at = chunks.len() + 8;
+                chunks.extend_from_slice(&data[pos..end]);
+                chunks[flags_at] &= !(EXIF_FLAG | XMP_FLAG);
+                if orientation.is_some() {
+                    chunks[flags_at] |= EXIF_FLAG;
+                }
+            }
+            _ => chunks.extend_from_slice(&data[pos..end]),
+        }
+        pos = end;
+    }
+    if extended && let Some(o) = orientation {
+        let tiff = orientation_tiff(o);
+        chunks.extend_from_slice(b"EXIF");
+        chunks.extend((tiff.len() as u32).to_le_bytes());
+        chunks.extend(tiff);
+    }
+
+    let mut out = Vec::with_capacity(chunks.len() + 12);
+    out.extend_from_slice(b"RIFF");
+    out.extend(((chunks.len() + 4) as u32).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend(chunks);
+    Ok(out)
+}
+
+// Adds `tiff` (see exif::raw) as the EXIF of an image written by
+// handlers::encode_image, which has none of its own. With `upright` its
+// orientation is reset, for pixels that were already turned. GIF and AVIF,
+// and EXIF too large for a JPEG segment, are returned as they are.
+pub fn insert_exif(data: Vec<u8>, fmt: ImageFormat, tiff: &[u8], upright: bool) -> Result<Vec<u8>> {
+    let mut tiff = tiff.to_vec();
+    if upright {
+        mark_upright(&mut tiff);
+    }
+
+    match fmt {
+        ImageFormat::Jpeg => insert_jpeg_exif(data, &tiff),
+        ImageFormat::Png => insert_png_exif(data, &tiff),
+        ImageFormat::WebP => insert_webp_exif(data, &tiff),
+        _ => Ok(data),
+    }
+}
+
+// After SOI and a JFIF APP0
+fn insert_jpeg_exif(data: Vec<u8>, tiff: &[u8]) -> Result<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) || data.len() < 4 {
+        return Err(anyhow!("malformed JPEG"));
+    }
+    let payload = [b"Exif\0\0".as_slice(), tiff].concat();
+    let Ok(len) = u16::try_from(payload.len() + 2) else {
+        return Ok(data);
+    };
+
+    let mut at = 2;
+    if data[2..4] == [0xFF, 0xE0] {
+        if data.len() < 6 {
+            return Err(anyhow!("malformed JPEG"));
+        }
+        at = (4 + u16::from_be_bytes([data[4], data[5]]) as usize).min(data.len());
+    }
+    let mut out = Vec::with_capacity(data.len() + payload.len() + 4);
+    out.extend_from_slice(&data[..at]);
+    out.extend([0xFF, 0xE1]);
+    out.extend(len.to_be_bytes());
+    out.extend(payload);
+    out.extend_from_slice(&data[at..]);
+    Ok(out)
+}
+
+// An eXIf chunk after IHDR
+fn insert_png_exif(data: Vec<u8>, tiff: &[u8]) -> Result<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    let at = SIGNATURE.len();
+    if !data.starts_with(SIGNATURE) || data.len() < at + 8 || &data[at + 4..at + 8] != b"IHDR" {
+        return Err(anyhow!("malformed PNG"));
+    }
+    let end = at + 12 + u32::from_be_bytes(data[at..at + 4].try_into()?) as usize;
+    if end > data.len() {
+        return Err(anyhow!("malformed PNG"));
+    }
+
+    let body = [b"eXIf".as_slice(), tiff].concat();
+    let mut out = Vec::with_capacity(data.len() + body.len() + 8);
+    out.extend_from_slice(&data[..end]);
+    out.extend((tiff.len() as u32).to_be_bytes());
+    out.extend_from_slice(&body);
+    out.extend(crc32(&body).to_be_bytes());
+    out.extend_from_slice(&data[end..]);
+    Ok(out)
+}
+
+// An EXIF chunk at the end. Simple WebPs are made extended ones first, only
+// those can carry it.
+fn insert_webp_exif(data: Vec<u8>, tiff: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 20 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(anyhow!("malformed WebP"));
+    }
+    const EXIF_FLAG: u8 = 0x08;
+    const ALPHA_FLAG: u8 = 0x10;
+
+    let mut chunks = Vec::with_capacity(data.len() + tiff.len() + 26);
+    let body = &data[20..];
+    match &data[12..16] {
+        b"VP8X" if data.len() > 20 => {
+            chunks.extend_from_slice(&data[12..]);
+            chunks[8] |= EXIF_FLAG;
+        }
+        fourcc => {
+            // canvas size and alpha from the VP8L or VP8 header
+            let (width, height, alpha) = match fourcc {
+                b"VP8L" if body.len() >= 5 && body[0] == 0x2F => {
+                    let bits = u32::from_le_bytes(body[1..5].try_into()?);
+                    (
+                        (bits & 0x3FFF) + 1,
+                        ((bits >> 14) & 0x3FFF) + 1,
+                        (bits >> 28) & 1 == 1,
+                    )
+                }
+                b"VP8 " if body.len() >= 10 && body[3..6] == [0x9D, 0x01, 0x2A] => (
+                    u16::from_le_bytes([body[6], body[7]]) as u32 & 0x3FFF,
+                    u16::from_le_bytes([body[8], body[9]]) as u32 & 0x3FFF,
+                    false,
+                ),
+                _ => return Err(anyhow!("malformed WebP")),
+            };
+            chunks.extend_from_slice(b"VP8X");
+            chunks.extend(10u32.to_le_bytes());
+            chunks.push(if alpha {
+                EXIF_FLAG | ALPHA_FLAG
+            } else {
+                EXIF_FLAG
+            });
+            chunks.extend([0, 0, 0]);
+            chunks.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            chunks.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            chunks.extend_from_slice(&data[12..]);
+        }
+    }
+    chunks.extend_from_slice(b"EXIF");
+    chunks.extend((tiff.len() as u32).to_le_bytes());
+    chunks.extend_from_slice(tiff);
+    if tiff.len() % 2 == 1 {
+        chunks.push(0);
+    }
+
+    let mut out = Vec::with_capacity(chunks.len() + 12);
+    out.extend_from_slice(b"RIFF");
+    out.extend(((chunks.len() + 4) as u32).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend(chunks);
+    Ok(out)
+}
+
+// Sets the orientation in the first IFD of `tiff` to 1, upright
+fn mark_upright(tiff: &mut [u8]) {
+    let big_endian = match tiff.get(..2) {
+        Some(b"MM") => true,
+        Some(b"II") => false,
+        _ => return,
+    };
+    let read = |at: usize, len: usize| -> Option<u32> {
+        let bytes = tiff.get(at..at + len)?;
+        Some(bytes.iter().enumerate().fold(0u32, |v, (i, &b)| {
+            let shift = if big_endian { len - 1 - i } else { i };
+            v | ((b as u32) << (8 * shift))
+        }))
+    };
+
+    let Some(ifd) = read(4, 4).map(|v| v as usize) else {
+        return;
+    };
+    let count = read(ifd, 2).unwrap_or(0) as usize;
+    // a SHORT, its value in the first two bytes of the value field
+    let Some(entry) = (0..count)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&e| read(e, 2) == Some(ORIENTATION_TAG as u32) && read(e + 2, 2) == Some(3))
+    else {
+        return;
+    };
+    if let Some(value) = tiff.get_mut(entry + 8..entry + 10) {
+        value.copy_from_slice(if big_endian { &[0, 1] } else { &[1, 0] });
+    }
+}
+
+// Big-endian TIFF with one IFD holding only the orientation
+fn orientation_tiff(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0\x2a".to_vec();
+    // offset of the IFD
+    tiff.extend(8u32.to_be_bytes());
+    tiff.extend(1u16.to_be_bytes());
+    // tag, SHORT, one value padded to four bytes
+    tiff.extend(ORIENTATION_TAG.to_be_bytes());
+    tiff.extend(3u16.to_be_bytes());
+    tiff.extend(1u32.to_be_bytes());
+    tiff.extend(orientation.to_be_bytes());
+    tiff.extend([0, 0]);
+    // no next IFD
+    tiff.extend(0u32.to_be_bytes());
+    tiff
+}
+
+// CRC-32 of PNG chunks
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
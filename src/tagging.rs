@@ -0,0 +1,264 @@
+use anyhow::{Result, anyhow};
+use image::{ColorType, ImageEncoder, codecs::png::PngEncoder};
+use photon_rs::PhotonImage;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::{
+    decode,
+    format::ImageFormat,
+    resize::{self, Resizer},
+    state::{AppConfig, TaggingConfig},
+    storage::{image_path, read_image_data, read_meta, update_meta},
+    store::BoxFuture,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Label {
+    pub name: String,
+    // 0-1
+    pub confidence: f32,
+}
+
+// A vision model naming what an image shows, e.g. "outdoor", "person", "food"
+pub trait Classifier: Send + Sync {
+    // Labels of the image with their confidence, in any order
+    fn classify<'a>(&'a self, img: &'a PhotonImage) -> BoxFuture<'a, Result<Vec<Label>>>;
+}
+
+// Classifies a stored image and adds the labels reaching min_confidence to its
+// tags, with their confidence in tag_confidence. Returns the added labels.
+pub async fn tag(conf: &AppConfig, img_id: &str) -> Result<Vec<Label>> {
+    let tagging = conf
+        .tagging
+        .as_ref()
+        .ok_or_else(|| anyhow!("tagging is not configured"))?;
+    let meta = read_meta(conf, img_id).await?;
+    let fmt = ImageFormat::from_fmt(&meta.fmt);
+    if !fmt.is_decodable() {
+        return Err(anyhow!("{} images cannot be classified", meta.fmt));
+    }
+
+    let data = read_image_data(conf, &image_path(conf, img_id, &meta.fmt)).await?;
+    // models look at input_size pixels, the shorter edge is decoded to cover it
+    let size = tagging.input_size;
+    let min_size = move |width: u32, height: u32| {
+        let ratio = (size as f32 / width.min(height) as f32).min(1.0);
+        (
+            ((width as f32 * ratio).round() as u32).max(1),
+            ((height as f32 * ratio).round() as u32).max(1),
+        )
+    };
+    let img = decode::run(&conf.decode, fmt, data, Some(&min_size)).await?;
+
+    let mut labels: Vec<Label> = classifier(tagging, conf.resizer)?
+        .classify(&img)
+        .await?
+        .into_iter()
+        .filter(|l| l.confidence >= tagging.min_confidence && !l.name.trim().is_empty())
+        .collect();
+    labels.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    labels.truncate(tagging.max_tags);
+
+    let added = labels.clone();
+    update_meta(conf, img_id, move |meta| {
+        for label in added {
+            if !meta.tags.contains(&label.name) {
+                meta.tags.push(label.name.clone());
+            }
+            meta.tag_confidence.insert(label.name, label.confidence);
+        }
+        Ok(())
+    })
+    .await?;
+    Ok(labels)
+}
+
+// Tags a new upload in the background, failures are only logged
+pub fn spawn(conf: AppConfig, img_id: String) {
+    if conf.tagging.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        match tag(&conf, &img_id).await {
+            Ok(labels) => info!(
+                "tagged {} with {:?}",
+                img_id,
+                labels.iter().map(|l| l.name.as_str()).collect::<Vec<_>>()
+            ),
+            Err(e) => warn!("failed to tag {}: {}", img_id, e),
+        }
+    });
+}
+
+// The classifier of [tagging], loaded on first use. Every tenant shares it.
+fn classifier(conf: &TaggingConfig, resizer: Resizer) -> Result<Arc<dyn Classifier>> {
+    static CLASSIFIER: OnceLock<Arc<dyn Classifier>> = OnceLock::new();
+    if let Some(classifier) = CLASSIFIER.get() {
+        return Ok(classifier.clone());
+    }
+    let classifier = open(conf, resizer)?;
+    Ok(CLASSIFIER.get_or_init(|| classifier).clone())
+}
+
+fn open(conf: &TaggingConfig, resizer: Resizer) -> Result<Arc<dyn Classifier>> {
+    if let Some(url) = &conf.url {
+        return Ok(Arc::new(HttpClassifier::new(conf, url, resizer)?));
+    }
+    match (&conf.model_path, &conf.labels_path) {
+        #[cfg(feature = "onnx")]
+        (Some(model_path), Some(labels_path)) => Ok(Arc::new(model::OnnxClassifier::open(
+            model_path,
+            labels_path,
+            conf.input_size,
+            resizer,
+        )?)),
+        #[cfg(not(feature = "onnx"))]
+        (Some(_), _) => Err(anyhow!("this build does not include the onnx feature")),
+        _ => Err(anyhow!("tagging needs a url or a model_path")),
+    }
+}
+
+// A vision API taking the image as a PNG (POST, no larger than input_size)
+// and answering `{"labels": [{"name": "outdoor", "confidence": 0.93}, ...]}`
+pub struct HttpClassifier {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    max_edge: u32,
+    resizer: Resizer,
+}
+
+#[derive(Debug, Deserialize)]
+struct HttpLabels {
+    labels: Vec<Label>,
+}
+
+impl HttpClassifier {
+    pub fn new(conf: &TaggingConfig, url: &str, resizer: Resizer) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(conf.timeout_secs))
+                .build()?,
+            url: url.to_string(),
+            api_key: conf.api_key.clone(),
+            max_edge: conf.input_size,
+            resizer,
+        })
+    }
+}
+
+impl Classifier for HttpClassifier {
+    fn classify<'a>(&'a self, img: &'a PhotonImage) -> BoxFuture<'a, Result<Vec<Label>>> {
+        Box::pin(async move {
+            let (width, height) = (img.get_width(), img.get_height());
+            let ratio = (self.max_edge as f32 / width.max(height) as f32).min(1.0);
+            let small = resize::lanczos3(
+                img,
+                ((width as f32 * ratio).round() as u32).max(1),
+                ((height as f32 * ratio).round() as u32).max(1),
+                self.resizer,
+            );
+
+            let mut png = Vec::new();
+            PngEncoder::new(&mut png).write_image(
+                &small.get_raw_pixels(),
+                small.get_width(),
+                small.get_height(),
+                ColorType::Rgba8,
+            )?;
+
+            let mut req = self
+                .client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "image/png")
+                .body(png);
+            if let Some(api_key) = &self.api_key {
+                req = req.bearer_auth(api_key);
+            }
+            let res: HttpLabels = req.send().await?.error_for_status()?.json().await?;
+            Ok(res.labels)
+        })
+    }
+}
+
+#[cfg(feature = "onnx")]
+mod model {
+    use anyhow::{Result, anyhow};
+    use ort::session::Session;
+    use photon_rs::PhotonImage;
+    use std::sync::Mutex;
+
+    use super::{Classifier, Label};
+    use crate::{
+        onnx::{self, IMAGENET},
+        resize::Resizer,
+        store::BoxFuture,
+    };
+
+    // An image classification model taking one NCHW float image of
+    // input_size x input_size and returning a score per label
+    pub struct OnnxClassifier {
+        session: Mutex<Session>,
+        labels: Vec<String>,
+        input_size: u32,
+        resizer: Resizer,
+    }
+
+    impl OnnxClassifier {
+        pub fn open(
+            model_path: &str,
+            labels_path: &str,
+            input_size: u32,
+            resizer: Resizer,
+        ) -> Result<Self> {
+            let session = Session::builder()?.commit_from_file(model_path)?;
+            let labels = std::fs::read_to_string(labels_path)?
+                .lines()
+                .map(|l| l.trim().to_string())
+                .collect();
+            Ok(Self {
+                session: Mutex::new(session),
+                labels,
+                input_size,
+                resizer,
+            })
+        }
+
+        fn run(&self, img: &PhotonImage) -> Result<Vec<Label>> {
+            let tensor = onnx::image_input(img, self.input_size, &IMAGENET, self.resizer)?;
+
+            let mut session = self
+                .session
+                .lock()
+                .map_err(|_| anyhow!("onnx session poisoned"))?;
+            let outputs = session.run(ort::inputs![tensor])?;
+            let (_, logits) = outputs[0].try_extract_tensor::<f32>()?;
+
+            // softmax, the scores of most models are logits
+            let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let exp: Vec<f32> = logits.iter().map(|l| (l - max).exp()).collect();
+            let sum: f32 = exp.iter().sum();
+            Ok(self
+                .labels
+                .iter()
+                .zip(exp)
+                .map(|(name, e)| Label {
+                    name: name.clone(),
+                    confidence: e / sum,
+                })
+                .collect())
+        }
+    }
+
+    impl Classifier for OnnxClassifier {
+        fn classify<'a>(&'a self, img: &'a PhotonImage) -> BoxFuture<'a, Result<Vec<Label>>> {
+            // inference is CPU bound, it must not hold up the async threads
+            Box::pin(async move { tokio::task::block_in_place(|| self.run(img)) })
+        }
+    }
+}
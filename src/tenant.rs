@@ -0,0 +1,292 @@
+use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{Query, Request, State},
+    http::{HeaderMap, Response, StatusCode, header},
+    middleware::Next,
+};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::{
+    error::build_err_response,
+    signing::{self, SignedQuery},
+    state::{AppConfig, AppState, TenantConfig, UploadPolicy},
+    storage::{list_images, list_untracked_files},
+};
+
+// Tenant storage lives under this directory of file_path and meta_path
+const TENANTS_DIR: &str = "tenants";
+
+// How long the counted usage of a namespace is trusted. Only uploads keep
+// the count up to date, transform outputs and deletes are picked up by the
+// next count.
+const USAGE_RECOUNT: Duration = Duration::from_secs(5 * 60);
+
+// The tenant a request acts for. When no tenants are configured every request
+// runs as the default tenant, which uses the base storage directories.
+#[derive(Debug, Clone, Default)]
+pub struct Tenant {
+    pub id: String,
+    pub quota_mb: Option<u64>,
+    // see handlers::policy
+    pub upload_policy: Option<UploadPolicy>,
+}
+
+impl Tenant {
+    pub fn is_default(&self) -> bool {
+        self.id.is_empty()
+    }
+
+    // Storage config pointing at the tenant's own directories
+    pub fn scope(&self, conf: &AppConfig) -> AppConfig {
+        if self.is_default() {
+            return conf.clone();
+        }
+
+        AppConfig {
+            file_path: scoped_dir(&conf.file_path, &self.id),
+            meta_path: scoped_dir(&conf.meta_path, &self.id),
+            ..conf.clone()
+        }
+    }
+}
+
+impl From<&TenantConfig> for Tenant {
+    fn from(conf: &TenantConfig) -> Self {
+        Self {
+            id: conf.id.clone(),
+            quota_mb: conf.quota_mb,
+            upload_policy: conf.upload_policy.clone(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuotaError {
+    #[error("{used} bytes of the quota are used")]
+    Exceeded { used: u64 },
+    #[error(transparent)]
+    Count(#[from] anyhow::Error),
+}
+
+// Bytes stored in each namespace, counted from the disk once and then kept
+// up to date by the uploads that check their quota
+#[derive(Debug, Clone, Default)]
+pub struct QuotaUsage {
+    namespaces: Arc<Mutex<HashMap<String, Arc<NamespaceUsage>>>>,
+}
+
+#[derive(Debug, Default)]
+struct NamespaceUsage {
+    // one count of the namespace at a time
+    counting: tokio::sync::Mutex<()>,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    stored: u64,
+    // reserved by uploads that are still being written
+    pending: u64,
+    counted_at: Option<Instant>,
+}
+
+// Bytes reserved for an upload. They count as stored once committed and are
+// given back when dropped before that.
+#[derive(Debug)]
+pub struct Reservation {
+    namespace: Arc<NamespaceUsage>,
+    bytes: u64,
+}
+
+impl QuotaUsage {
+    // Reserves `bytes` for an upload into the namespace of `tenant`, whose
+    // storage config is `conf`, unless
+    // that takes it over `quota`. Check and reservation are done under one
+    // lock, so concurrent uploads cannot pass the check together.
+    pub async fn reserve(
+        &self,
+        conf: &AppConfig,
+        tenant: &Tenant,
+        quota: u64,
+        bytes: u64,
+    ) -> Result<Reservation, QuotaError> {
+        let namespace = self
+            .namespaces
+            .lock()
+            .unwrap()
+            .entry(tenant.id.clone())
+            .or_default()
+            .clone();
+
+        let stale = |usage: &Usage| {
+            usage
+                .counted_at
+                .is_none_or(|at| at.elapsed() > USAGE_RECOUNT)
+        };
+        if stale(&namespace.usage.lock().unwrap()) {
+            let _counting = namespace.counting.lock().await;
+            if stale(&namespace.usage.lock().unwrap()) {
+                let stored = count_usage(conf).await?;
+                let mut usage = namespace.usage.lock().unwrap();
+                usage.stored = stored;
+                usage.counted_at = Some(Instant::now());
+            }
+        }
+
+        let mut usage = namespace.usage.lock().unwrap();
+        let used = usage.stored + usage.pending;
+        if used + bytes > quota {
+            return Err(QuotaError::Exceeded { used });
+        }
+        usage.pending += bytes;
+        drop(usage);
+        Ok(Reservation { namespace, bytes })
+    }
+}
+
+impl Reservation {
+    // The upload was stored
+    pub fn commit(mut self) {
+        let mut usage = self.namespace.usage.lock().unwrap();
+        usage.pending -= self.bytes;
+        usage.stored += self.bytes;
+        drop(usage);
+        self.bytes = 0;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.namespace.usage.lock().unwrap().pending -= self.bytes;
+    }
+}
+
+// Bytes of all files in the namespace of `conf`
+async fn count_usage(conf: &AppConfig) -> Result<u64> {
+    let (images, derived) = tokio::try_join!(list_images(conf), list_untracked_files(conf))?;
+    let stored = images
+        .iter()
+        // linked images share the file of their source
+        .filter(|(_, meta, _)| meta.linked_from.is_none())
+        .map(|(_, meta, _)| meta.size_in_bytes as u64)
+        .sum::<u64>();
+    Ok(stored + derived.iter().map(|(_, size)| size).sum::<u64>())
+}
+
+fn scoped_dir(base: &str, tenant_id: &str) -> String {
+    Path::new(base)
+        .join(TENANTS_DIR)
+        .join(tenant_id)
+        .to_string_lossy()
+        .into_owned()
+}
+
+// API key of a request, from X-Api-Key or a bearer token
+pub fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("X-Api-Key")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+        })
+}
+
+// Tenant id and API key of Basic credentials, which WebDAV clients send
+// instead of headers of their own
+pub fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64_decode(encoded.trim())?).ok()?;
+    let (id, key) = decoded.split_once(':')?;
+    Some((id.to_string(), key.to_string()))
+}
+
+// Standard base64, padded or not
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in input.trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = ((acc << 6) | v as u32) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// Resolves the API key of the request to its tenant. Requests are rejected
+// unless they carry a known key, once any tenant is configured.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response<Body> {
+    // a signed URL stands in for the API key of image reads
+    if let Some(signed) = &state.conf.signed_urls
+        && api_key(req.headers()).is_none()
+        && let Some(img_id) = signing::image_read(&req)
+    {
+        let query: SignedQuery = Query::try_from_uri(req.uri())
+            .map(|q| q.0)
+            .unwrap_or_default();
+        let tenant = match signing::verify(signed, img_id, &query) {
+            Ok(tenant_id) if tenant_id.is_empty() && state.conf.tenants.is_empty() => {
+                Some(Tenant::default())
+            }
+            Ok(tenant_id) => state
+                .conf
+                .tenants
+                .iter()
+                .find(|t| t.id == tenant_id)
+                .map(Tenant::from),
+            Err(msg) => {
+                return build_err_response(StatusCode::FORBIDDEN, msg.to_string());
+            }
+        };
+        let Some(tenant) = tenant else {
+            return build_err_response(StatusCode::FORBIDDEN, "Invalid signature".to_string());
+        };
+        req.extensions_mut().insert(tenant);
+        return next.run(req).await;
+    }
+
+    if state.conf.tenants.is_empty() {
+        req.extensions_mut().insert(Tenant::default());
+        return next.run(req).await;
+    }
+
+    let Some(tenant) = api_key(req.headers())
+        .and_then(|key| state.conf.tenants.iter().find(|t| t.api_key == key.trim()))
+    else {
+        warn!(
+            "rejected request without a valid api key: {}",
+            req.uri().path()
+        );
+        return build_err_response(StatusCode::UNAUTHORIZED, "Invalid API key".to_string());
+    };
+
+    req.extensions_mut().insert(Tenant::from(tenant));
+    next.run(req).await
+}
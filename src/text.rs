@@ -0,0 +1,534 @@
+use anyhow::{Result, anyhow};
+use photon_rs::PhotonImage;
+use rusttype::{Font, GlyphId, PositionedGlyph, Scale, point};
+use serde::Deserialize;
+
+use crate::{alpha::Color, bidi, state::TextConfig};
+
+// Used without [text], the font photon's draw_text has built in
+const BUILTIN_FONT: &[u8] = include_bytes!("../fonts/Roboto-Regular.ttf");
+
+const BLACK: Color = Color([0, 0, 0]);
+const MAX_STROKE_WIDTH: u32 = 16;
+
+// Relative luminance above which black text has a higher WCAG contrast ratio
+// than white text
+const BLACK_TEXT_ABOVE: f32 = 0.179;
+// Luminance behind white text for a contrast ratio of 4.5:1 (WCAG AA)
+const SCRIM_TARGET: f32 = 1.05 / 4.5 - 0.05;
+
+// Layout of watermark text
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    // right to left when the first letter is Arabic or Hebrew, otherwise
+    // left to right
+    #[default]
+    Auto,
+    Ltr,
+    Rtl,
+    // top to bottom, one upright character under the other as in CJK
+    Vertical,
+}
+
+// How the text keeps contrast with the image under it, measured as the mean
+// luminance of the area it covers
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Contrast {
+    // black text on bright areas, white text elsewhere
+    Color,
+    // white text on a black box just dark enough for a 4.5:1 contrast
+    // ratio, none on dark areas. Replaces `background`, keeping its padding.
+    Scrim,
+}
+
+// How watermark text is laid out and what is drawn around it to keep it
+// legible on busy or bright images. Layers are drawn bottom up: background,
+// shadow, stroke, then the text, white unless `contrast` picks black.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TextOptions {
+    #[serde(default)]
+    pub direction: Direction,
+    pub contrast: Option<Contrast>,
+    pub stroke: Option<Stroke>,
+    pub shadow: Option<Shadow>,
+    pub background: Option<TextBackground>,
+}
+
+// Outline around the glyphs
+#[derive(Debug, Clone, Deserialize)]
+pub struct Stroke {
+    #[serde(default = "black")]
+    pub color: Color,
+    // in pixels, 1 to MAX_STROKE_WIDTH
+    #[serde(default = "default_stroke_width")]
+    pub width: u32,
+}
+
+// Copy of the text (and its stroke) offset behind it
+#[derive(Debug, Clone, Deserialize)]
+pub struct Shadow {
+    #[serde(default = "black")]
+    pub color: Color,
+    #[serde(default = "default_shadow_offset")]
+    pub offset_x: i32,
+    #[serde(default = "default_shadow_offset")]
+    pub offset_y: i32,
+    // 0 to 1
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+}
+
+// Box filling the bounds of the text plus `padding`
+#[derive(Debug, Clone, Deserialize)]
+pub struct TextBackground {
+    #[serde(default = "black")]
+    pub color: Color,
+    // 0 to 1
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+    #[serde(default = "default_padding")]
+    pub padding: u32,
+}
+
+fn black() -> Color {
+    BLACK
+}
+
+fn default_stroke_width() -> u32 {
+    2
+}
+
+fn default_shadow_offset() -> i32 {
+    2
+}
+
+fn default_opacity() -> f32 {
+    0.5
+}
+
+fn default_padding() -> u32 {
+    8
+}
+
+impl TextOptions {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(stroke) = &self.stroke
+            && !(1..=MAX_STROKE_WIDTH).contains(&stroke.width)
+        {
+            return Err(anyhow!(
+                "stroke.width must be between 1 and {}",
+                MAX_STROKE_WIDTH
+            ));
+        }
+        if let Some(shadow) = &self.shadow
+            && !(0.0..=1.0).contains(&shadow.opacity)
+        {
+            return Err(anyhow!("shadow.opacity must be between 0 and 1"));
+        }
+        if let Some(background) = &self.background
+            && !(0.0..=1.0).contains(&background.opacity)
+        {
+            return Err(anyhow!("background.opacity must be between 0 and 1"));
+        }
+        Ok(())
+    }
+}
+
+// A horizontal line in the order its characters are drawn from left to
+// right, with Arabic letters in their joined forms
+fn visual_line(text: &str, direction: Direction) -> String {
+    let rtl = match direction {
+        Direction::Rtl => true,
+        Direction::Ltr => false,
+        Direction::Auto | Direction::Vertical => bidi::is_rtl(text),
+    };
+    bidi::reorder(&bidi::shape_arabic(text), rtl)
+}
+
+// Fonts of the configured fallback chain. Every character is drawn with the
+// first font that has a glyph for it, so e.g. CJK or symbols missing from a
+// Latin font come from the next ones. Characters are laid out one by one:
+// Arabic is joined through the presentation forms of the font (see bidi),
+// other scripts that need shaping (Indic conjuncts) and color bitmap emoji
+// fonts are not supported.
+pub struct FontStack {
+    fonts: Vec<Font<'static>>,
+}
+
+impl std::fmt::Debug for FontStack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FontStack")
+            .field("fonts", &self.fonts.len())
+            .finish()
+    }
+}
+
+// Box around drawn text, see FontStack::measure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextBounds {
+    pub left: i32,
+    pub top: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Coverage of text or an outline, `left` and `top` relative to the point the
+// text is drawn at
+struct Mask {
+    left: i32,
+    top: i32,
+    width: usize,
+    height: usize,
+    coverage: Vec<f32>,
+}
+
+impl Mask {
+    fn get(&self, x: i32, y: i32) -> f32 {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return 0.0;
+        }
+        self.coverage[y as usize * self.width + x as usize]
+    }
+
+    // The mask grown by `width` pixels in every direction
+    fn dilate(&self, width: u32) -> Mask {
+        let r = width as i32;
+        let (grown_width, grown_height) =
+            (self.width + 2 * r as usize, self.height + 2 * r as usize);
+        let disk: Vec<(i32, i32)> = (-r..=r)
+            .flat_map(|dy| (-r..=r).map(move |dx| (dx, dy)))
+            .filter(|(dx, dy)| dx * dx + dy * dy <= r * r)
+            .collect();
+
+        let mut coverage = vec![0.0; grown_width * grown_height];
+        for y in 0..grown_height as i32 {
+            for x in 0..grown_width as i32 {
+                coverage[y as usize * grown_width + x as usize] = disk
+                    .iter()
+                    .map(|(dx, dy)| self.get(x - r + dx, y - r + dy))
+                    .fold(0.0, f32::max);
+            }
+        }
+        Mask {
+            left: self.left - r,
+            top: self.top - r,
+            width: grown_width,
+            height: grown_height,
+            coverage,
+        }
+    }
+}
+
+impl FontStack {
+    pub fn load(conf: &TextConfig) -> Result<Self> {
+        if conf.fonts.is_empty() {
+            return Err(anyhow!("at least one font is needed"));
+        }
+
+        let fonts = conf
+            .fonts
+            .iter()
+            .map(|path| {
+                let data = std::fs::read(path).map_err(|e| anyhow!("{}: {}", path, e))?;
+                // the first face of font collections (.ttc)
+                Font::try_from_vec(data).ok_or_else(|| anyhow!("{}: not a TrueType font", path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(FontStack { fonts })
+    }
+
+    // Roboto, Latin only
+    pub fn builtin() -> Self {
+        let font = Font::try_from_bytes(BUILTIN_FONT).expect("bundled font is valid");
+        FontStack { fonts: vec![font] }
+    }
+
+    // Draws white `text` with its top left corner at (x, y), like photon's
+    // draw_text. All fonts share the baseline of the first one.
+    pub fn draw(
+        &self,
+        img: &mut PhotonImage,
+        text: &str,
+        x: i32,
+        y: i32,
+        font_size: f32,
+        options: &TextOptions,
+    ) {
+        let Some(text_mask) = mask(&self.layout(text, font_size, options.direction)) else {
+            return;
+        };
+        let outline = options.stroke.as_ref().map(|s| text_mask.dilate(s.width));
+
+        let (width, height) = (img.get_width(), img.get_height());
+        let mut canvas = Canvas {
+            pixels: img.get_raw_pixels(),
+            width: width as i32,
+            height: height as i32,
+        };
+
+        let bounds = outline.as_ref().unwrap_or(&text_mask);
+        let mut fill = Color::WHITE;
+        let mut background = options.background.clone();
+        match options.contrast {
+            Some(Contrast::Color) => {
+                let luminance = canvas.luminance(
+                    x + bounds.left,
+                    y + bounds.top,
+                    bounds.width as i32,
+                    bounds.height as i32,
+                );
+                if luminance > BLACK_TEXT_ABOVE {
+                    fill = BLACK;
+                }
+            }
+            Some(Contrast::Scrim) => {
+                let padding = background.as_ref().map_or(default_padding(), |b| b.padding);
+                let p = padding as i32;
+                let luminance = canvas.luminance(
+                    x + bounds.left - p,
+                    y + bounds.top - p,
+                    bounds.width as i32 + 2 * p,
+                    bounds.height as i32 + 2 * p,
+                );
+                background = Some(TextBackground {
+                    color: BLACK,
+                    opacity: scrim_opacity(luminance),
+                    padding,
+                });
+            }
+            None => {}
+        }
+
+        if let Some(background) = &background {
+            let padding = background.padding as i32;
+            canvas.fill_rect(
+                x + bounds.left - padding,
+                y + bounds.top - padding,
+                bounds.width as i32 + 2 * padding,
+                bounds.height as i32 + 2 * padding,
+                background.color,
+                background.opacity,
+            );
+        }
+        if let Some(shadow) = &options.shadow {
+            canvas.paint(
+                outline.as_ref().unwrap_or(&text_mask),
+                x + shadow.offset_x,
+                y + shadow.offset_y,
+                shadow.color,
+                shadow.opacity,
+            );
+        }
+        if let (Some(outline), Some(stroke)) = (&outline, &options.stroke) {
+            canvas.paint(outline, x, y, stroke.color, 1.0);
+        }
+        canvas.paint(&text_mask, x, y, fill, 1.0);
+
+        *img = PhotonImage::new(canvas.pixels, width, height);
+    }
+
+    // Area `draw` paints for `text`, relative to the point it is drawn at:
+    // the glyphs, their stroke and the padding of a background box, but not a
+    // shadow. None when the text has no visible glyphs.
+    pub fn measure(&self, text: &str, font_size: f32, options: &TextOptions) -> Option<TextBounds> {
+        let glyphs = self.layout(text, font_size, options.direction);
+        let text_mask = mask(&glyphs)?;
+
+        let stroke = options.stroke.as_ref().map_or(0, |s| s.width) as i32;
+        let padding = match (&options.background, options.contrast) {
+            (Some(background), _) => background.padding,
+            (None, Some(Contrast::Scrim)) => default_padding(),
+            (None, _) => 0,
+        } as i32;
+        let grow = stroke + padding;
+        Some(TextBounds {
+            left: text_mask.left - grow,
+            top: text_mask.top - grow,
+            width: text_mask.width as u32 + 2 * grow as u32,
+            height: text_mask.height as u32 + 2 * grow as u32,
+        })
+    }
+
+    fn layout(&self, text: &str, font_size: f32, direction: Direction) -> Vec<PositionedGlyph<'_>> {
+        match direction {
+            Direction::Vertical => self.layout_vertical(text, font_size),
+            direction => self.layout_line(&visual_line(text, direction), font_size),
+        }
+    }
+
+    // Glyphs of a line with the top left corner at (0, 0), kerned within runs
+    // of the same font
+    fn layout_line(&self, text: &str, font_size: f32) -> Vec<PositionedGlyph<'_>> {
+        let scale = Scale::uniform(font_size);
+        let baseline = self.fonts[0].v_metrics(scale).ascent;
+
+        let mut glyphs = Vec::new();
+        let mut caret = 0.0;
+        let mut previous: Option<(usize, GlyphId)> = None;
+        for c in text.chars() {
+            let (index, font) = self.font_for(c);
+            let glyph = font.glyph(c).scaled(scale);
+            if let Some((prev_index, prev_id)) = previous
+                && prev_index == index
+            {
+                caret += font.pair_kerning(scale, prev_id, glyph.id());
+            }
+            let advance = glyph.h_metrics().advance_width;
+            previous = Some((index, glyph.id()));
+            glyphs.push(glyph.positioned(point(caret, baseline)));
+            caret += advance;
+        }
+        glyphs
+    }
+
+    // One character per line, centred in a column font_size wide, the lines
+    // as far apart as those of the first font
+    fn layout_vertical(&self, text: &str, font_size: f32) -> Vec<PositionedGlyph<'_>> {
+        let scale = Scale::uniform(font_size);
+        let metrics = self.fonts[0].v_metrics(scale);
+        let line_height = metrics.ascent - metrics.descent + metrics.line_gap;
+
+        let mut top = 0.0;
+        let mut glyphs = Vec::new();
+        for c in text.chars().filter(|c| !c.is_control()) {
+            let glyph = self.font_for(c).1.glyph(c).scaled(scale);
+            let left = (font_size - glyph.h_metrics().advance_width) / 2.0;
+            glyphs.push(glyph.positioned(point(left, top + metrics.ascent)));
+            top += line_height;
+        }
+        glyphs
+    }
+
+    // First font with a glyph for `c`, the first one (drawing its "missing"
+    // box) when none has
+    fn font_for(&self, c: char) -> (usize, &Font<'static>) {
+        self.fonts
+            .iter()
+            .enumerate()
+            .find(|(_, font)| font.glyph(c).id() != GlyphId(0))
+            .unwrap_or((0, &self.fonts[0]))
+    }
+}
+
+// Coverage of the glyphs, None when none has any outline (e.g. only spaces)
+fn mask(glyphs: &[PositionedGlyph<'_>]) -> Option<Mask> {
+    let boxes: Vec<_> = glyphs
+        .iter()
+        .filter_map(|g| g.pixel_bounding_box())
+        .collect();
+    let left = boxes.iter().map(|b| b.min.x).min()?;
+    let top = boxes.iter().map(|b| b.min.y).min()?;
+    let width = (boxes.iter().map(|b| b.max.x).max()? - left) as usize;
+    let height = (boxes.iter().map(|b| b.max.y).max()? - top) as usize;
+
+    let mut coverage = vec![0.0f32; width * height];
+    for glyph in glyphs {
+        let Some(bb) = glyph.pixel_bounding_box() else {
+            continue;
+        };
+        glyph.draw(|gx, gy, c| {
+            let px = (bb.min.x - left) as usize + gx as usize;
+            let py = (bb.min.y - top) as usize + gy as usize;
+            let cell = &mut coverage[py * width + px];
+            *cell = (*cell + c).min(1.0);
+        });
+    }
+    Some(Mask {
+        left,
+        top,
+        width,
+        height,
+        coverage,
+    })
+}
+
+// Opacity of a black box that brings `luminance` down to SCRIM_TARGET
+fn scrim_opacity(luminance: f32) -> f32 {
+    if luminance <= SCRIM_TARGET {
+        return 0.0;
+    }
+    // the box scales the sRGB values by 1 - opacity, their luminance by
+    // about that to the power of 2.2
+    1.0 - (SCRIM_TARGET / luminance).powf(1.0 / 2.2)
+}
+
+// Relative luminance of an sRGB pixel (WCAG 2)
+fn luminance(px: &[u8]) -> f32 {
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * linear(px[0]) + 0.7152 * linear(px[1]) + 0.0722 * linear(px[2])
+}
+
+// RGBA pixels drawn onto, clipped to the image
+struct Canvas {
+    pixels: Vec<u8>,
+    width: i32,
+    height: i32,
+}
+
+impl Canvas {
+    fn blend(&mut self, x: i32, y: i32, color: Color, amount: f32) {
+        if amount <= 0.0 || !(0..self.width).contains(&x) || !(0..self.height).contains(&y) {
+            return;
+        }
+        let i = (y * self.width + x) as usize * 4;
+        let px = &mut self.pixels[i..i + 4];
+        // "over" compositing, so text drawn onto transparent pixels (a tile
+        // stamp) keeps its colour instead of fading to black at the edges
+        let below = px[3] as f32 / 255.0 * (1.0 - amount);
+        let alpha = amount + below;
+        for (channel, value) in px[..3].iter_mut().zip(color.0) {
+            *channel = ((value as f32 * amount + *channel as f32 * below) / alpha).round() as u8;
+        }
+        px[3] = (alpha * 255.0).round() as u8;
+    }
+
+    // Draws the mask in `color`, its origin at (x, y)
+    fn paint(&mut self, mask: &Mask, x: i32, y: i32, color: Color, opacity: f32) {
+        for my in 0..mask.height {
+            for mx in 0..mask.width {
+                let coverage = mask.coverage[my * mask.width + mx];
+                self.blend(
+                    x + mask.left + mx as i32,
+                    y + mask.top + my as i32,
+                    color,
+                    coverage * opacity,
+                );
+            }
+        }
+    }
+
+    // Mean luminance of the part of the rectangle within the image, 0 when
+    // it is entirely outside
+    fn luminance(&self, x: i32, y: i32, width: i32, height: i32) -> f32 {
+        let (mut total, mut count) = (0.0, 0);
+        for py in y.max(0)..(y + height).min(self.height) {
+            for px in x.max(0)..(x + width).min(self.width) {
+                let i = (py * self.width + px) as usize * 4;
+                total += luminance(&self.pixels[i..i + 4]);
+                count += 1;
+            }
+        }
+        if count == 0 {
+            0.0
+        } else {
+            total / count as f32
+        }
+    }
+
+    fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: Color, opacity: f32) {
+        for py in y.max(0)..(y + height).min(self.height) {
+            for px in x.max(0)..(x + width).min(self.width) {
+                self.blend(px, py, color, opacity);
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::Request,
+    http::{HeaderValue, Response, header},
+    middleware::Next,
+};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tracing::warn;
+
+// Largest JSON response the breakdown is added to, transform responses are a
+// few ids
+const MAX_ENVELOPE_BYTES: usize = 1024 * 1024;
+
+tokio::task_local! {
+    static CURRENT: Timings;
+}
+
+// Durations of the pipeline stages of a request that asked for
+// `?debug=timings`, in the order they finished
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    stages: Arc<Mutex<Vec<Stage>>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Stage {
+    stage: String,
+    ms: f64,
+}
+
+impl Timings {
+    // Collector of the running request, None unless it asked for timings.
+    // Work moved to the blocking pool takes it along through `within`.
+    pub fn current() -> Option<Timings> {
+        CURRENT.try_with(|t| t.clone()).ok()
+    }
+
+    fn record(&self, stage: &str, started: Instant) {
+        self.stages.lock().unwrap().push(Stage {
+            stage: stage.to_string(),
+            ms: started.elapsed().as_secs_f64() * 1000.0,
+        });
+    }
+
+    fn stages(&self) -> Vec<Stage> {
+        self.stages.lock().unwrap().clone()
+    }
+}
+
+// Records the time since `started` as `stage` of the running request
+pub fn record(stage: &str, started: Instant) {
+    let _ = CURRENT.try_with(|t| t.record(stage, started));
+}
+
+// Runs `f` and records how long it took as `stage`
+pub fn time<T>(stage: &str, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let res = f();
+    record(stage, started);
+    res
+}
+
+// Runs `f` (on a blocking thread) with the collector taken from the request
+pub fn within<T>(timings: Option<Timings>, f: impl FnOnce() -> T) -> T {
+    match timings {
+        Some(timings) => CURRENT.sync_scope(timings, f),
+        None => f(),
+    }
+}
+
+// With `?debug=timings` the stages of the request are added to JSON responses
+// as "timings", other responses (images) get a Server-Timing header. Only
+// routed when debug_timings is enabled.
+pub async fn collect(req: Request, next: Next) -> Response<Body> {
+    let wanted = req.uri().query().is_some_and(|query| {
+        form_urlencoded::parse(query.as_bytes())
+            .any(|(key, value)| key == "debug" && value.split(',').any(|v| v == "timings"))
+    });
+    if !wanted {
+        return next.run(req).await;
+    }
+
+    let timings = Timings::default();
+    let started = Instant::now();
+    let res = CURRENT.scope(timings.clone(), next.run(req)).await;
+    timings.record("total", started);
+
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    match is_json {
+        true => add_to_envelope(res, &timings).await,
+        false => add_server_timing(res, &timings),
+    }
+}
+
+async fn add_to_envelope(res: Response<Body>, timings: &Timings) -> Response<Body> {
+    let (mut parts, body) = res.into_parts();
+    let data = match to_bytes(body, MAX_ENVELOPE_BYTES).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to read response for timings: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    let mut envelope = match serde_json::from_slice::<serde_json::Value>(&data) {
+        Ok(serde_json::Value::Object(v)) => v,
+        _ => return Response::from_parts(parts, Body::from(data)),
+    };
+    envelope.insert(
+        "timings".to_string(),
+        serde_json::to_value(timings.stages()).unwrap_or_default(),
+    );
+    let data = serde_json::to_vec(&envelope).unwrap_or_default();
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, data.len().into());
+    Response::from_parts(parts, Body::from(data))
+}
+
+fn add_server_timing(mut res: Response<Body>, timings: &Timings) -> Response<Body> {
+    let value = timings
+        .stages()
+        .iter()
+        .map(|s| format!("{};dur={:.3}", s.stage, s.ms))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        res.headers_mut().insert("server-timing", value);
+    }
+    res
+}
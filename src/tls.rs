@@ -0,0 +1,190 @@
+use anyhow::{Result, anyhow};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+use tracing::{debug, info, warn};
+
+use crate::{server::Server, state::TlsConfig};
+
+// Rustls config for the certificate in `conf`, offering the given ALPN protocols
+pub fn server_config(conf: &TlsConfig, alpn: &[&[u8]]) -> Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(&conf.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("failed to read certificate {:?}: {}", conf.cert_path, e))?;
+    let key = PrivateKeyDer::from_pem_file(&conf.key_path)
+        .map_err(|e| anyhow!("failed to read private key {:?}: {}", conf.key_path, e))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+    Ok(config)
+}
+
+// HTTPS listener, clients pick HTTP/2 or HTTP/1.1 through ALPN
+pub async fn run(tls: TlsConfig, server: Server) {
+    let config = match server_config(&tls, &[b"h2", b"http/1.1"]) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to load tls config: {}", e);
+            return;
+        }
+    };
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let listener = match TcpListener::bind(&tls.listen).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to bind tls listener on {}: {}", tls.listen, e);
+            return;
+        }
+    };
+    info!("serving https on {}", tls.listen);
+
+    #[cfg(feature = "http3")]
+    let server = match &tls.http3_listen {
+        Some(listen) => server.map_app(|app| http3::advertise(app, listen)),
+        None => server,
+    };
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("failed to accept tls connection: {}", e);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            let handshake = acceptor.accept(stream);
+            let res = match server.header_timeout() {
+                Some(timeout) => tokio::time::timeout(timeout, handshake)
+                    .await
+                    .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+                None => handshake.await,
+            };
+            match res {
+                Ok(stream) => server.serve_connection(stream, addr).await,
+                Err(e) => debug!("tls handshake with {} failed: {}", addr, e),
+            }
+        });
+    }
+}
+
+// Experimental QUIC listener. Request bodies are buffered before they reach
+// the router, which is fine for the upload sizes max_file_size allows.
+#[cfg(feature = "http3")]
+pub mod http3 {
+    use anyhow::Result;
+    use axum::{
+        Router,
+        body::Body,
+        http::{HeaderValue, Request, Response, header},
+        middleware,
+    };
+    use bytes::{Buf, Bytes, BytesMut};
+    use h3::server::RequestResolver;
+    use http_body_util::BodyExt;
+    use std::{net::SocketAddr, sync::Arc};
+    use tower::ServiceExt;
+    use tracing::{debug, info, warn};
+
+    use super::server_config;
+    use crate::state::TlsConfig;
+
+    // Adds the Alt-Svc header that tells browsers HTTP/3 is available
+    pub fn advertise(app: Router, listen: &str) -> Router {
+        let port = listen.rsplit(':').next().unwrap_or_default();
+        let Ok(alt_svc) = HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", port)) else {
+            return app;
+        };
+
+        app.layer(middleware::map_response(move |mut res: Response<Body>| {
+            let alt_svc = alt_svc.clone();
+            async move {
+                res.headers_mut().insert(header::ALT_SVC, alt_svc);
+                res
+            }
+        }))
+    }
+
+    pub async fn run(tls: TlsConfig, listen: String, app: Router) {
+        if let Err(e) = serve(&tls, &listen, app).await {
+            warn!("http3 listener on {} stopped: {}", listen, e);
+        }
+    }
+
+    async fn serve(tls: &TlsConfig, listen: &str, app: Router) -> Result<()> {
+        let crypto =
+            quinn::crypto::rustls::QuicServerConfig::try_from(server_config(tls, &[b"h3"])?)?;
+        let addr: SocketAddr = listen.parse()?;
+        let endpoint =
+            quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr)?;
+        info!("serving http3 on {}", listen);
+
+        while let Some(incoming) = endpoint.accept().await {
+            let app = app.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(incoming, app).await {
+                    debug!("http3 connection closed: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn serve_connection(incoming: quinn::Incoming, app: Router) -> Result<()> {
+        let conn = incoming.await?;
+        let mut conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await?;
+
+        while let Some(resolver) = conn.accept().await? {
+            let app = app.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_request(resolver, app).await {
+                    debug!("http3 request failed: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn serve_request(
+        resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+        app: Router,
+    ) -> Result<()> {
+        let (req, stream) = resolver.resolve_request().await?;
+        let (mut send, mut recv) = stream.split();
+
+        let mut body = BytesMut::new();
+        while let Some(mut chunk) = recv.recv_data().await? {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        let req = Request::from_parts(req.into_parts().0, Body::from(body.freeze()));
+
+        let res = app.oneshot(req).await?;
+        let (parts, mut body) = res.into_parts();
+        send.send_response(Response::from_parts(parts, ())).await?;
+
+        while let Some(frame) = body.frame().await {
+            if let Ok(data) = frame?.into_data() {
+                send.send_data(data).await?;
+            }
+        }
+        send.finish().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use axum::{body::Body, http::Request, http::StatusCode};
+use brushbloom::{
+    alpha,
+    resize::{self, Resizer},
+};
+use photon_rs::PhotonImage;
+use tower::ServiceExt;
+
+// 64x64: the top 48 rows are transparent black on the left half and opaque
+// white on the right, the bottom 16 rows are (40, 120, 200) at alpha 128
+const FIXTURE: &[u8] = include_bytes!("fixtures/semi_transparent.png");
+
+fn fixture() -> PhotonImage {
+    let img = image::load_from_memory(FIXTURE).unwrap().to_rgba8();
+    let (width, height) = img.dimensions();
+    PhotonImage::new(img.into_raw(), width, height)
+}
+
+fn assert_close(actual: &[u8], expected: [u8; 3], tolerance: u8, what: &str) {
+    for (a, e) in actual.iter().zip(expected) {
+        assert!(
+            a.abs_diff(e) <= tolerance,
+            "{}: {:?}, expected about {:?}",
+            what,
+            &actual[..3],
+            expected
+        );
+    }
+}
+
+#[test]
+fn premultiply_round_trips() {
+    let img = fixture();
+    let restored = alpha::unpremultiply(&alpha::premultiply(&img));
+    let (before, after) = (img.get_raw_pixels(), restored.get_raw_pixels());
+    for (a, b) in before.chunks_exact(4).zip(after.chunks_exact(4)) {
+        assert_eq!(a[3], b[3]);
+        assert_close(&b[..3], [a[0], a[1], a[2]], 1, "round trip");
+    }
+}
+
+// Without premultiplying, the black of the transparent half bleeds into the
+// white half along the edge
+#[test]
+fn resize_leaves_no_dark_fringe() {
+    let img = fixture();
+    for resizer in [Resizer::Photon, Resizer::Fast] {
+        let resized = resize::lanczos3(&img, 32, 32, resizer);
+        let pixels = resized.get_raw_pixels();
+        // rows below 18 are out of reach of the semi-transparent band
+        for (i, px) in pixels.chunks_exact(4).enumerate().take(32 * 18) {
+            if px[3] >= 64 {
+                assert!(
+                    px[..3].iter().all(|&c| c >= 240),
+                    "{:?}: dark fringe at {:?}: {:?}",
+                    resizer,
+                    (i % 32, i / 32),
+                    px
+                );
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn compress_flattens_onto_the_background() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = common::app(dir.path(), "background = \"#ff0000\"\n");
+
+    let res = app
+        .clone()
+        .oneshot(common::upload_request("semi_transparent.png", FIXTURE))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let img_id = common::body_json(res).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let res = app
+        .clone()
+        .oneshot(common::json_request(
+            &format!("/api/images/{}/compress", img_id),
+            r#"{"quality": 90, "format": "jpeg"}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let new_id = common::body_json(res).await["new_img_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let res = app
+        .oneshot(
+            Request::get(format!("/api/images/{}", new_id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let jpeg = image::load_from_memory(&common::body_bytes(res).await)
+        .unwrap()
+        .to_rgb8();
+
+    // JPEG is lossy, chroma subsampling most of all
+    assert_close(&jpeg.get_pixel(8, 8).0, [255, 0, 0], 12, "transparent");
+    assert_close(&jpeg.get_pixel(56, 8).0, [255, 255, 255], 12, "opaque");
+    // (40, 120, 200) at alpha 128 over red
+    assert_close(
+        &jpeg.get_pixel(8, 56).0,
+        [147, 60, 100],
+        12,
+        "semi-transparent",
+    );
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use axum::{
+    Extension, Json,
+    body::Bytes,
+    extract::{Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+};
+use brushbloom::{
+    handlers::{
+        DeliveryQuery, ImgId, TransformQuery, UploadQuery,
+        convert::{ConvertImageRequest, convert_image},
+        image::{get_image, resize_img, upload_image},
+        s3,
+    },
+    ingest,
+    state::{AppState, EmailConfig, WatchConfig},
+    tenant::Tenant,
+};
+use std::{
+    io::Cursor,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tower::ServiceExt;
+
+fn assert_send<T: Send>(_: T) {}
+
+// Only type checked: fails to compile when a handler holds something that is
+// not Send (a std::fs::File, a MutexGuard) across an await
+#[test]
+fn handler_futures_are_send() {
+    #[allow(clippy::too_many_arguments)]
+    fn check(
+        state: AppState,
+        tenant: Tenant,
+        headers: HeaderMap,
+        multipart: Multipart,
+        upload: UploadQuery,
+        query: TransformQuery,
+        delivery: DeliveryQuery,
+        convert: ConvertImageRequest,
+        watch: WatchConfig,
+        email: EmailConfig,
+    ) {
+        let id = || ImgId(String::new());
+        assert_send(upload_image(
+            State(state.clone()),
+            Extension(tenant.clone()),
+            Query(upload),
+            multipart,
+        ));
+        assert_send(get_image(
+            headers.clone(),
+            State(state.clone()),
+            Extension(tenant.clone()),
+            id(),
+            Query(query),
+            Query(delivery),
+        ));
+        assert_send(resize_img(
+            headers.clone(),
+            State(state.clone()),
+            Extension(tenant.clone()),
+            id(),
+            Query(TransformQuery::default()),
+            Json(serde_json::Value::Null),
+        ));
+        assert_send(convert_image(
+            State(state.clone()),
+            Extension(tenant),
+            id(),
+            Json(convert),
+        ));
+        assert_send(s3::put_object(
+            State(state.clone()),
+            Path(String::new()),
+            headers,
+            Bytes::new(),
+        ));
+        assert_send(ingest::watch::run(state.clone(), watch));
+        assert_send(ingest::email::run(state, email));
+    }
+    let _ = check;
+}
+
+// Noise, so that the PNG does not compress to almost nothing
+fn noisy_png(width: u32, height: u32) -> Vec<u8> {
+    let img = image::RgbaImage::from_fn(width, height, |x, y| {
+        let v = x
+            .wrapping_mul(2_654_435_761)
+            .wrapping_add(y.wrapping_mul(40_503));
+        image::Rgba([v as u8, (v >> 8) as u8, (v >> 16) as u8, 255])
+    });
+    let mut out = Cursor::new(Vec::new());
+    image::DynamicImage::ImageRgba8(img)
+        .write_to(&mut out, image::ImageOutputFormat::Png)
+        .unwrap();
+    out.into_inner()
+}
+
+// Counts the turns it gets on the runtime, it yields after each one
+fn ticker() -> (Arc<AtomicUsize>, tokio::task::JoinHandle<()>) {
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let counter = ticks.clone();
+    let handle = tokio::spawn(async move {
+        loop {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+        }
+    });
+    (ticks, handle)
+}
+
+// The runtime has a single thread, so the ticker only runs while the handler
+// waits for its writes on the blocking pool instead of doing them in place
+#[tokio::test(flavor = "current_thread")]
+async fn handlers_do_not_stall_the_runtime() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = common::app(dir.path(), "");
+    let (ticks, ticker) = ticker();
+
+    let res = app
+        .clone()
+        .oneshot(common::upload_request("noise.png", &noisy_png(1024, 1024)))
+        .await
+        .unwrap();
+    let during_upload = ticks.load(Ordering::SeqCst);
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body = common::body_json(res).await;
+    let img_id = body["id"].as_str().unwrap();
+
+    ticks.store(0, Ordering::SeqCst);
+    let res = app
+        .oneshot(common::json_request(
+            &format!("/api/images/{}/resize", img_id),
+            r#"{"width": 512, "height": 512, "maintain_aspect": true}"#,
+        ))
+        .await
+        .unwrap();
+    let during_resize = ticks.load(Ordering::SeqCst);
+    ticker.abort();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(during_upload > 0, "the upload never let other tasks run");
+    assert!(during_resize > 0, "the resize never let other tasks run");
+}
@@ -0,0 +1,64 @@
+// Helpers shared by the test crates, each uses only some of them
+#![allow(dead_code)]
+
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, Response, header},
+};
+use brushbloom::{
+    router,
+    state::{AppConfig, AppState},
+};
+use http_body_util::BodyExt;
+use std::path::Path;
+
+// The API over empty storage in `dir`, with the TOML of `extra` added to the
+// config
+pub fn app(dir: &Path, extra: &str) -> Router {
+    let (file_path, meta_path) = (dir.join("images"), dir.join("metadata"));
+    std::fs::create_dir_all(&file_path).unwrap();
+    std::fs::create_dir_all(&meta_path).unwrap();
+    let conf: AppConfig = toml::from_str(&format!(
+        "max_file_size = 20\nfile_path = {:?}\nmeta_path = {:?}\n{}",
+        file_path.to_string_lossy(),
+        meta_path.to_string_lossy(),
+        extra,
+    ))
+    .unwrap();
+    router::routers(AppState::new(conf).unwrap()).unwrap()
+}
+
+// POST /api/images/upload of `data` as the file field
+pub fn upload_request(file_name: &str, data: &[u8]) -> Request<Body> {
+    let boundary = "brushbloom-test";
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n",
+        boundary, file_name
+    )
+    .into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    Request::post("/api/images/upload")
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={}", boundary),
+        )
+        .body(Body::from(body))
+        .unwrap()
+}
+
+pub fn json_request(uri: &str, body: &str) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+pub async fn body_bytes(res: Response<Body>) -> Vec<u8> {
+    res.into_body().collect().await.unwrap().to_bytes().to_vec()
+}
+
+pub async fn body_json(res: Response<Body>) -> serde_json::Value {
+    serde_json::from_slice(&body_bytes(res).await).unwrap()
+}
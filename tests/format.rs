@@ -0,0 +1,61 @@
+use brushbloom::format::ImageFormat;
+
+const PNG_FIXTURE: &[u8] = include_bytes!("fixtures/semi_transparent.png");
+
+// ISOBMFF ftyp box with `major` and `compatible` brands and "avif" as the
+// minor version, which is not a brand
+fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+    let size = 16 + 4 * compatible.len() as u32;
+    let mut data = size.to_be_bytes().to_vec();
+    data.extend_from_slice(b"ftyp");
+    data.extend_from_slice(major);
+    data.extend_from_slice(b"avif");
+    for brand in compatible {
+        data.extend_from_slice(*brand);
+    }
+    data.extend_from_slice(b"\0\0\0\x08meta");
+    data
+}
+
+#[test]
+fn sniffs_formats_from_magic_bytes() {
+    assert_eq!(ImageFormat::sniff(PNG_FIXTURE), ImageFormat::Png);
+    assert_eq!(
+        ImageFormat::sniff(&[0xff, 0xd8, 0xff, 0xe0, 0, 0x10]),
+        ImageFormat::Jpeg
+    );
+    assert_eq!(ImageFormat::sniff(b"GIF87a\x01\0\x01\0"), ImageFormat::Gif);
+    assert_eq!(ImageFormat::sniff(b"GIF89a\x01\0\x01\0"), ImageFormat::Gif);
+    assert_eq!(
+        ImageFormat::sniff(b"RIFF\x24\0\0\0WEBPVP8 "),
+        ImageFormat::WebP
+    );
+    assert_eq!(ImageFormat::sniff(&ftyp(b"avif", &[])), ImageFormat::Avif);
+    assert_eq!(
+        ImageFormat::sniff(&ftyp(b"mif1", &[b"miaf", b"avis"])),
+        ImageFormat::Avif
+    );
+}
+
+#[test]
+fn sniffs_unknown_data() {
+    assert_eq!(ImageFormat::sniff(b""), ImageFormat::Unknown);
+    assert_eq!(ImageFormat::sniff(b"<svg xmlns="), ImageFormat::Unknown);
+    assert_eq!(ImageFormat::sniff(b"%PDF-1.7"), ImageFormat::Unknown);
+    // truncated magic bytes
+    assert_eq!(ImageFormat::sniff(&[0xff, 0xd8]), ImageFormat::Unknown);
+    assert_eq!(ImageFormat::sniff(&PNG_FIXTURE[..4]), ImageFormat::Unknown);
+    assert_eq!(
+        ImageFormat::sniff(b"RIFF\x24\0\0\0WAVE"),
+        ImageFormat::Unknown
+    );
+    // HEIC names avif only as its minor version
+    assert_eq!(
+        ImageFormat::sniff(&ftyp(b"heic", &[b"mif1", b"heic"])),
+        ImageFormat::Unknown
+    );
+    // a box size past the end of the data is not read past it
+    let mut short = ftyp(b"mif1", &[]);
+    short[3] = 0xff;
+    assert_eq!(ImageFormat::sniff(&short), ImageFormat::Unknown);
+}
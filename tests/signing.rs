@@ -0,0 +1,107 @@
+use brushbloom::{
+    signing::{self, SignedQuery, SignedUrlConfig},
+    state::AppConfig,
+};
+
+const IMG_ID: &str = "0192f3a4-5b6c-7d8e-9f01-23456789abcd";
+
+fn conf() -> AppConfig {
+    toml::from_str("max_file_size = 20\nfile_path = \"images\"\nmeta_path = \"metadata\"").unwrap()
+}
+
+fn signed(secret: &str) -> SignedUrlConfig {
+    SignedUrlConfig {
+        secret: secret.to_string(),
+        default_ttl_secs: 3600,
+        max_ttl_secs: 7 * 24 * 3600,
+    }
+}
+
+// The image id and signed query of a URL made by signing::url
+fn parse(url: &str) -> (String, SignedQuery) {
+    let (path, query) = url.split_once('?').unwrap();
+    let img_id = path.rsplit_once('/').unwrap().1.to_string();
+    let mut signed = SignedQuery::default();
+    for (k, v) in form_urlencoded::parse(query.as_bytes()) {
+        match &*k {
+            "expires" => signed.expires = Some(v.parse().unwrap()),
+            "tenant" => signed.tenant = Some(v.into_owned()),
+            "signature" => signed.signature = Some(v.into_owned()),
+            _ => {}
+        }
+    }
+    (img_id, signed)
+}
+
+#[test]
+fn verifies_signed_url() {
+    let secret = signed("a long random secret");
+    let (url, expires) = signing::url(&conf(), &secret, "https://img.test", "acme", IMG_ID, 60);
+    assert!(url.starts_with(&format!("https://img.test/api/images/{}?", IMG_ID)));
+
+    let (img_id, query) = parse(&url);
+    assert_eq!(query.expires, Some(expires));
+    assert_eq!(signing::verify(&secret, &img_id, &query).unwrap(), "acme");
+
+    // the default tenant has no tenant parameter
+    let (url, _) = signing::url(&conf(), &secret, "https://img.test", "", IMG_ID, 60);
+    let (img_id, query) = parse(&url);
+    assert_eq!(query.tenant, None);
+    assert_eq!(signing::verify(&secret, &img_id, &query).unwrap(), "");
+}
+
+#[test]
+fn rejects_expired_signed_url() {
+    let secret = signed("a long random secret");
+    let (url, _) = signing::url(&conf(), &secret, "https://img.test", "acme", IMG_ID, 0);
+    let (img_id, query) = parse(&url);
+
+    assert_eq!(
+        signing::verify(&secret, &img_id, &query),
+        Err("Signed URL expired")
+    );
+}
+
+#[test]
+fn rejects_tampered_signed_url() {
+    let secret = signed("a long random secret");
+    let (url, _) = signing::url(&conf(), &secret, "https://img.test", "acme", IMG_ID, 60);
+    let (img_id, query) = parse(&url);
+    let with = |f: fn(&mut SignedQuery)| {
+        let mut query = SignedQuery {
+            expires: query.expires,
+            tenant: query.tenant.clone(),
+            signature: query.signature.clone(),
+        };
+        f(&mut query);
+        query
+    };
+
+    // another image
+    let other_id = "0192f3a4-5b6c-7d8e-9f01-23456789abce";
+    assert!(signing::verify(&secret, other_id, &query).is_err());
+    // another tenant, or none
+    let other_tenant = with(|q| q.tenant = Some("other".to_string()));
+    assert!(signing::verify(&secret, &img_id, &other_tenant).is_err());
+    let no_tenant = with(|q| q.tenant = None);
+    assert!(signing::verify(&secret, &img_id, &no_tenant).is_err());
+    // a later expiry
+    let extended = with(|q| q.expires = q.expires.map(|e| e + 3600));
+    assert!(signing::verify(&secret, &img_id, &extended).is_err());
+    // a changed, truncated or missing signature
+    let changed = with(|q| {
+        let signature = q.signature.as_mut().unwrap();
+        let last = if signature.ends_with('0') { "1" } else { "0" };
+        signature.replace_range(signature.len() - 1.., last);
+    });
+    assert!(signing::verify(&secret, &img_id, &changed).is_err());
+    let truncated = with(|q| q.signature.as_mut().unwrap().truncate(10));
+    assert!(signing::verify(&secret, &img_id, &truncated).is_err());
+    let missing = with(|q| q.signature = None);
+    assert_eq!(
+        signing::verify(&secret, &img_id, &missing),
+        Err("Missing signature")
+    );
+    // signed with another secret
+    assert!(signing::verify(&signed("another secret"), &img_id, &query).is_err());
+}
@@ -0,0 +1,243 @@
+use axum::http::{HeaderMap, HeaderValue, Method, Uri};
+use brushbloom::sigv4::{self, ChunkVerifier, Credentials};
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use time::{Duration, OffsetDateTime, format_description};
+
+const ACCESS_KEY: &str = "AKIDEXAMPLE";
+const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+const REGION: &str = "us-east-1";
+const HOST: &str = "images.example.com";
+const STREAMING: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD";
+
+fn creds() -> Credentials<'static> {
+    Credentials {
+        access_key: ACCESS_KEY,
+        secret_key: SECRET_KEY,
+        region: REGION,
+    }
+}
+
+fn amz_date(at: OffsetDateTime) -> String {
+    let format = format_description::parse("[year][month][day]T[hour][minute][second]Z").unwrap();
+    at.format(&format).unwrap()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Signature of `string_to_sign` with the key of the day of `amz_date`,
+// computed here rather than with the code under test
+fn sign(amz_date: &str, string_to_sign: &str) -> String {
+    let k_date = hmac(format!("AWS4{}", SECRET_KEY).as_bytes(), &amz_date[..8]);
+    let k_region = hmac(&k_date, REGION);
+    let k_service = hmac(&k_region, "s3");
+    let key = hmac(&k_service, "aws4_request");
+    sigv4::hex(&hmac(&key, string_to_sign))
+}
+
+fn scope(amz_date: &str) -> String {
+    format!("{}/{}/s3/aws4_request", &amz_date[..8], REGION)
+}
+
+fn request_signature(amz_date: &str, canonical_request: &str) -> String {
+    sign(
+        amz_date,
+        &format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope(amz_date),
+            sigv4::sha256_hex(canonical_request.as_bytes())
+        ),
+    )
+}
+
+// Headers of a request to `path` signed in its Authorization header
+fn signed_headers(method: &Method, path: &str, payload_hash: &str, amz_date: &str) -> HeaderMap {
+    let canonical_request = format!(
+        "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+        method, path, HOST, payload_hash, amz_date, payload_hash
+    );
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+        ACCESS_KEY,
+        scope(amz_date),
+        request_signature(amz_date, &canonical_request)
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert("host", HeaderValue::from_static(HOST));
+    headers.insert("x-amz-date", amz_date.parse().unwrap());
+    headers.insert("x-amz-content-sha256", payload_hash.parse().unwrap());
+    headers.insert("authorization", authorization.parse().unwrap());
+    headers
+}
+
+// Presigned URL of a GET of `path`, signed at `signed_at`
+fn presigned_uri(path: &str, signed_at: OffsetDateTime, expires: u32) -> Uri {
+    let amz_date = amz_date(signed_at);
+    let credential = format!("{}/{}", ACCESS_KEY, scope(&amz_date));
+    // already in canonical order
+    let query = format!(
+        "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+        sigv4::uri_encode(&credential),
+        amz_date,
+        expires
+    );
+    let canonical_request = format!(
+        "GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+        path, query, HOST
+    );
+    let signature = request_signature(&amz_date, &canonical_request);
+    format!("{}?{}&X-Amz-Signature={}", path, query, signature)
+        .parse()
+        .unwrap()
+}
+
+fn host_header() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("host", HeaderValue::from_static(HOST));
+    headers
+}
+
+fn chunk_signature(amz_date: &str, previous: &str, data: &[u8]) -> String {
+    sign(
+        amz_date,
+        &format!(
+            "AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+            amz_date,
+            scope(amz_date),
+            previous,
+            sigv4::sha256_hex(b""),
+            sigv4::sha256_hex(data)
+        ),
+    )
+}
+
+fn seed_signature(headers: &HeaderMap) -> String {
+    let auth = headers["authorization"].to_str().unwrap();
+    auth.rsplit_once("Signature=").unwrap().1.to_string()
+}
+
+#[test]
+fn verifies_header_signature() {
+    let date = amz_date(OffsetDateTime::now_utc());
+    let payload_hash = sigv4::sha256_hex(b"image data");
+    let headers = signed_headers(&Method::PUT, "/photos/cat.png", &payload_hash, &date);
+    let uri: Uri = "/photos/cat.png".parse().unwrap();
+
+    sigv4::verify(&creds(), &Method::PUT, &uri, &headers).unwrap();
+}
+
+#[test]
+fn rejects_tampered_header_request() {
+    let date = amz_date(OffsetDateTime::now_utc());
+    let payload_hash = sigv4::sha256_hex(b"image data");
+    let headers = signed_headers(&Method::PUT, "/photos/cat.png", &payload_hash, &date);
+    let uri: Uri = "/photos/cat.png".parse().unwrap();
+
+    // another payload than the one signed
+    let mut tampered = headers.clone();
+    tampered.insert(
+        "x-amz-content-sha256",
+        sigv4::sha256_hex(b"other data").parse().unwrap(),
+    );
+    assert!(sigv4::verify(&creds(), &Method::PUT, &uri, &tampered).is_err());
+
+    // another object or method than the one signed
+    let other: Uri = "/photos/dog.png".parse().unwrap();
+    assert!(sigv4::verify(&creds(), &Method::PUT, &other, &headers).is_err());
+    assert!(sigv4::verify(&creds(), &Method::DELETE, &uri, &headers).is_err());
+
+    // signed with another secret
+    let wrong = Credentials {
+        secret_key: "not the secret",
+        ..creds()
+    };
+    assert!(sigv4::verify(&wrong, &Method::PUT, &uri, &headers).is_err());
+}
+
+#[test]
+fn rejects_skewed_header_request() {
+    let date = amz_date(OffsetDateTime::now_utc() - Duration::hours(1));
+    let payload_hash = sigv4::sha256_hex(b"");
+    let headers = signed_headers(&Method::GET, "/photos/cat.png", &payload_hash, &date);
+    let uri: Uri = "/photos/cat.png".parse().unwrap();
+
+    assert!(sigv4::verify(&creds(), &Method::GET, &uri, &headers).is_err());
+}
+
+#[test]
+fn verifies_presigned_url() {
+    let uri = presigned_uri("/photos/cat.png", OffsetDateTime::now_utc(), 3600);
+
+    sigv4::verify(&creds(), &Method::GET, &uri, &host_header()).unwrap();
+}
+
+#[test]
+fn rejects_expired_presigned_url() {
+    let uri = presigned_uri(
+        "/photos/cat.png",
+        OffsetDateTime::now_utc() - Duration::hours(2),
+        3600,
+    );
+
+    assert!(sigv4::verify(&creds(), &Method::GET, &uri, &host_header()).is_err());
+}
+
+#[test]
+fn rejects_tampered_presigned_url() {
+    let uri = presigned_uri("/photos/cat.png", OffsetDateTime::now_utc(), 3600);
+
+    // a longer lifetime than the one signed
+    let longer: Uri = uri
+        .to_string()
+        .replace("X-Amz-Expires=3600", "X-Amz-Expires=86400")
+        .parse()
+        .unwrap();
+    assert!(sigv4::verify(&creds(), &Method::GET, &longer, &host_header()).is_err());
+
+    let other: Uri = uri
+        .to_string()
+        .replace("/photos/cat.png", "/photos/dog.png")
+        .parse()
+        .unwrap();
+    assert!(sigv4::verify(&creds(), &Method::GET, &other, &host_header()).is_err());
+}
+
+#[test]
+fn verifies_chunk_signatures() {
+    let date = amz_date(OffsetDateTime::now_utc());
+    let headers = signed_headers(&Method::PUT, "/photos/cat.png", STREAMING, &date);
+    let uri: Uri = "/photos/cat.png".parse().unwrap();
+    sigv4::verify(&creds(), &Method::PUT, &uri, &headers).unwrap();
+
+    let mut verifier = ChunkVerifier::new(&creds(), &headers).unwrap();
+    let mut previous = seed_signature(&headers);
+    for chunk in [&b"first chunk"[..], b"second chunk", b""] {
+        let signature = chunk_signature(&date, &previous, chunk);
+        verifier.verify(&signature, chunk).unwrap();
+        previous = signature;
+    }
+}
+
+#[test]
+fn rejects_tampered_chunk() {
+    let date = amz_date(OffsetDateTime::now_utc());
+    let headers = signed_headers(&Method::PUT, "/photos/cat.png", STREAMING, &date);
+    let seed = seed_signature(&headers);
+
+    // data other than the one signed
+    let mut verifier = ChunkVerifier::new(&creds(), &headers).unwrap();
+    let signature = chunk_signature(&date, &seed, b"first chunk");
+    assert!(verifier.verify(&signature, b"first chunK").is_err());
+
+    // chunks out of order
+    let mut verifier = ChunkVerifier::new(&creds(), &headers).unwrap();
+    let first = chunk_signature(&date, &seed, b"first chunk");
+    let second = chunk_signature(&date, &first, b"second chunk");
+    assert!(verifier.verify(&second, b"second chunk").is_err());
+}
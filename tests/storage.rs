@@ -0,0 +1,57 @@
+use brushbloom::storage::is_safe_id;
+
+const UUID: &str = "0192f3a4-5b6c-7d8e-9f01-23456789abcd";
+const SIMPLE_UUID: &str = "0192f3a45b6c7d8e9f0123456789abcd";
+
+#[test]
+fn accepts_image_ids() {
+    assert!(is_safe_id(UUID));
+    assert!(is_safe_id(SIMPLE_UUID));
+    assert!(is_safe_id(&format!("{}_resize", UUID)));
+    assert!(is_safe_id(&format!("{}_resize_2", UUID)));
+    assert!(is_safe_id(&format!("{}_grayscale_blur-3", UUID)));
+    assert!(is_safe_id(&format!("2024-05-17_{}", UUID)));
+    assert!(is_safe_id(&format!("2024-05-17_{}_thumbnail", UUID)));
+}
+
+#[test]
+fn rejects_path_traversal() {
+    for id in [
+        "",
+        ".",
+        "..",
+        "../etc/passwd",
+        "/etc/passwd",
+        "..\\..\\windows",
+        "metadata/../../secret",
+        &format!("../{}", UUID),
+        &format!("{}/..", UUID),
+        &format!("{}/../../etc/passwd", UUID),
+        &format!("{}_../../etc/passwd", UUID),
+        &format!("{}_resize/..", UUID),
+        &format!("{}_..", UUID),
+        &format!("2024-05-17_../{}", UUID),
+        &format!("../../2024-05-17_{}", UUID),
+        &format!("{}%2f..", UUID),
+        &format!("{}\0", UUID),
+    ] {
+        assert!(!is_safe_id(id), "{:?} was accepted", id);
+    }
+}
+
+#[test]
+fn rejects_malformed_ids() {
+    for id in [
+        "not-an-id",
+        &UUID[..35],
+        &format!("{}0", UUID),
+        &UUID.replace('a', "g"),
+        &format!("{}__resize", UUID),
+        &format!("{}_Resize", UUID),
+        &format!("{}_re size", UUID),
+        &format!("{}.png", UUID),
+        &format!("2024-5-17_{}", UUID),
+    ] {
+        assert!(!is_safe_id(id), "{:?} was accepted", id);
+    }
+}